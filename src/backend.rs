@@ -0,0 +1,93 @@
+use cpal::traits::{DeviceTrait, StreamTrait};
+use cpal::{Host, StreamConfig};
+
+use crate::error::Result;
+use crate::utils::{get_input_device_by_name, get_output_device_by_name};
+
+pub type InputCallback<T> = Box<dyn FnMut(&[T]) + Send + 'static>;
+pub type OutputCallback<T> = Box<dyn FnMut(&mut [T]) + Send + 'static>;
+
+pub trait BusStream {
+    fn play(&self) -> Result<()>;
+    fn pause(&self) -> Result<()>;
+}
+
+pub trait AudioBackend<T: 'static + cpal::Sample + Send + Sync> {
+    fn build_input_stream(
+        &self,
+        config: &StreamConfig,
+        data_clb: InputCallback<T>,
+    ) -> Result<Box<dyn BusStream>>;
+
+    fn build_output_stream(
+        &self,
+        config: &StreamConfig,
+        data_clb: OutputCallback<T>,
+    ) -> Result<Box<dyn BusStream>>;
+
+    fn name(&self) -> String;
+}
+
+impl BusStream for cpal::Stream {
+    fn play(&self) -> Result<()> {
+        Ok(StreamTrait::play(self)?)
+    }
+
+    fn pause(&self) -> Result<()> {
+        Ok(StreamTrait::pause(self)?)
+    }
+}
+
+pub struct CpalBackend {
+    host: Host,
+    in_device: String,
+    out_device: String,
+}
+
+impl CpalBackend {
+    //Fails when either device is not available on the host
+    pub fn new(host: Host, in_device: String, out_device: String) -> Result<CpalBackend> {
+        get_input_device_by_name(&host, &in_device)?;
+        get_output_device_by_name(&host, &out_device)?;
+        Ok(CpalBackend {
+            host: host,
+            in_device: in_device,
+            out_device: out_device,
+        })
+    }
+}
+
+impl<T: 'static + cpal::Sample + Send + Sync> AudioBackend<T> for CpalBackend {
+    fn build_input_stream(
+        &self,
+        config: &StreamConfig,
+        mut data_clb: InputCallback<T>,
+    ) -> Result<Box<dyn BusStream>> {
+        let device = get_input_device_by_name(&self.host, &self.in_device)?;
+        let stream =
+            device.build_input_stream(config, move |data: &[T], _: &_| data_clb(data), err_fn)?;
+        Ok(Box::new(stream))
+    }
+
+    fn build_output_stream(
+        &self,
+        config: &StreamConfig,
+        mut data_clb: OutputCallback<T>,
+    ) -> Result<Box<dyn BusStream>> {
+        let device = get_output_device_by_name(&self.host, &self.out_device)?;
+        let stream = device.build_output_stream(
+            config,
+            move |data: &mut [T], _: &_| data_clb(data),
+            err_fn,
+        )?;
+        Ok(Box::new(stream))
+    }
+
+    fn name(&self) -> String {
+        self.host.id().name().to_string()
+    }
+}
+
+pub fn err_fn(error: cpal::StreamError) {
+    eprintln!("an error occurred on stream: {}", error);
+}
@@ -1,64 +1,290 @@
-use cpal::traits::{DeviceTrait, StreamTrait};
-use cpal::{Device, Stream, StreamConfig};
-
-use multiqueue::BroadcastSender;
-use std::sync::mpsc::Receiver;
+use cpal::StreamConfig;
 
 use std::marker::PhantomData;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::backend::{AudioBackend, BusStream};
+use crate::error::{RecorderError, Result};
+use crate::frames::{frame_channel, FrameReceiver, FrameSender};
+use crate::meters::{MeterLevels, MeterWriter};
+use crate::resample::convert_frames;
+
+// Input and output frames are related through the reference clock, the device frames output
+// bus 0 has played. Every input callback notes how many frames its bus has captured against
+// it, and an output bus notes the reference frame a marked frame of its ring played at. The
+// input frame captured while that frame played follows, see FrameSync. Takes line up with
+// what output bus 0 plays, other output busses are only as close as their callbacks are.
+
+const BUS_RING_FRAMES: usize = 8_192;
+const DISPATCH_BLOCK_FRAMES: usize = 512;
+const UNSET: u64 = u64::MAX;
 
-#[derive(Debug)]
-pub enum BusConfig {
-    Mono,
-    Stereo,
+//(input bus frames, reference clock frames) counted at the end of the same input callback
+type ClockPair = Arc<Mutex<Option<(u64, u64)>>>;
+
+pub type ChannelMap = Vec<(u8, u8)>; //(source channel, destination channel), 1-based
+
+#[derive(Debug, Clone)]
+pub struct BusConfig {
+    channel_map: ChannelMap, //(device input channel, bus channel)
 }
 
 impl BusConfig {
-    pub fn get_bus_config(nof_channels: &u8) -> BusConfig {
-        // println!("nof_channels: {}", nof_channels);
-        match nof_channels {
-            1 => BusConfig::Mono,
-            2 => BusConfig::Stereo,
-            n => panic!("get_bus_config: Oh no! invalid nof channels: {}", n),
+    //Device channels in the given order become bus channels 1..=n
+    pub fn get_bus_config(channel_ids: &Vec<u8>) -> Result<BusConfig> {
+        check_channel_ids(channel_ids, None)?;
+        let mut channel_map = ChannelMap::new();
+        for (idx, ch) in channel_ids.iter().enumerate() {
+            channel_map.push((*ch, (idx + 1) as u8));
+        }
+        Ok(BusConfig {
+            channel_map: channel_map,
+        })
+    }
+
+    pub fn nof_channels(&self) -> u8 {
+        self.channel_map.iter().map(|x| x.1).max().unwrap_or(0)
+    }
+}
+
+//Channel ids are 1-based, unique and, when the width of the device or bus is known, on it.
+pub fn check_channel_ids(channel_ids: &Vec<u8>, nof_channels: Option<u16>) -> Result<()> {
+    if channel_ids.is_empty() {
+        return Err(RecorderError::InvalidChannels(
+            "no channels selected".to_string(),
+        ));
+    }
+    for (idx, ch) in channel_ids.iter().enumerate() {
+        if *ch == 0 {
+            return Err(RecorderError::InvalidChannels(
+                "channel ids start at 1".to_string(),
+            ));
+        }
+        if channel_ids[..idx].contains(ch) {
+            return Err(RecorderError::InvalidChannels(format!(
+                "channel {} is selected twice",
+                ch
+            )));
         }
+        if let Some(nof_chs) = nof_channels {
+            if *ch as u16 > nof_chs {
+                return Err(RecorderError::InvalidChannels(format!(
+                    "channel {} does not exist, there are {} channels",
+                    ch, nof_chs
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+//Every source channel is sent to at least one destination and every destination is fed.
+//Source channel n goes to destination n and the narrower side wraps around: a mono source
+//is spread over all destinations, a stereo source feeds destinations 1, 3, .. from its
+//channel 1 and 2, 4, .. from its channel 2, and source channels past the last destination
+//are summed into the destinations from the first one on (4 into 2: 1+3, 2+4). Set a
+//channel map to route anything else.
+pub fn default_channel_map(nof_src_channels: u8, dest_channels: &Vec<u8>) -> ChannelMap {
+    let mut channel_map = ChannelMap::new();
+    if nof_src_channels == 0 || dest_channels.is_empty() {
+        return channel_map;
+    }
+    let nof_routes = std::cmp::max(nof_src_channels as usize, dest_channels.len());
+    for idx in 0..nof_routes {
+        let src = (idx % nof_src_channels as usize) as u8 + 1;
+        channel_map.push((src, dest_channels[idx % dest_channels.len()]));
+    }
+    channel_map
+}
+
+//Sums one frame of source channels into out, one sample per destination channel.
+pub fn map_frame<T: cpal::Sample>(
+    frame: &[T],
+    channel_map: &ChannelMap,
+    dest_channels: &Vec<u8>,
+    out: &mut [T],
+) {
+    for (idx, dest) in dest_channels.iter().enumerate() {
+        let mut sum = 0.0;
+        for (src, _) in channel_map.iter().filter(|x| x.1 == *dest) {
+            if let Some(sample) = frame.get((*src - 1) as usize) {
+                sum += sample.to_f32();
+            }
+        }
+        out[idx] = cpal::Sample::from(&sum);
     }
 }
 
-pub struct InputBus<T: 'static + std::clone::Clone + cpal::Sample + Send + Sync> {
+// A frame of an output ring whose play time is wanted, e.g. the first frame of a roll.
+pub struct PlayMark {
+    ring_frame: AtomicU64,   //position in the ring, see FrameSender::get_pushed
+    device_frame: AtomicU64, //reference clock frame it played at
+}
+
+impl PlayMark {
+    pub fn new() -> PlayMark {
+        PlayMark {
+            ring_frame: AtomicU64::new(UNSET),
+            device_frame: AtomicU64::new(UNSET),
+        }
+    }
+
+    //Marks ring_frame, it must not have been pushed yet
+    pub fn set(&self, ring_frame: u64) {
+        self.device_frame.store(UNSET, Ordering::SeqCst);
+        self.ring_frame.store(ring_frame, Ordering::SeqCst);
+    }
+
+    pub fn clear(&self) {
+        self.set(UNSET);
+    }
+
+    //Reference clock frame the marked frame played at, None until it has played
+    pub fn get_device_frame(&self) -> Option<u64> {
+        match self.device_frame.load(Ordering::SeqCst) {
+            UNSET => None,
+            frame => Some(frame),
+        }
+    }
+
+    //Audio thread: ring_frame was just popped and played at device_frame
+    fn played(&self, ring_frame: u64, device_frame: u64) {
+        if self.ring_frame.load(Ordering::SeqCst) == ring_frame {
+            self.device_frame.store(device_frame, Ordering::SeqCst);
+        }
+    }
+}
+
+// The input bus frame captured while the marked frame of an output bus played.
+pub struct FrameSync {
+    mark: Arc<PlayMark>,
+    clocks: ClockPair,
+    in_rate: u32,
+    out_rate: u32,
+}
+
+impl FrameSync {
+    //Input bus frame, see InputBus::subscribe_from, None until the marked frame has played
+    //and the input bus has counted its frames. It is negative when the frame played before
+    //the bus started.
+    pub fn get_input_frame(&self) -> Option<i64> {
+        let device_frame = self.mark.get_device_frame()?;
+        let (in_frames, out_frames) = (*self.clocks.lock().unwrap())?;
+        let out_diff = device_frame as i64 - out_frames as i64;
+        let in_diff = convert_frames(out_diff.unsigned_abs(), self.out_rate, self.in_rate) as i64;
+        Some(match out_diff < 0 {
+            true => in_frames as i64 - in_diff,
+            false => in_frames as i64 + in_diff,
+        })
+    }
+}
+
+pub struct InputBus<T: 'static + cpal::Sample + Send + Sync> {
     id: u8,
     track_ids: Vec<u8>,
     channel_ids: Vec<u8>,
-    pub stream: Stream,
-    _type: PhantomData<T>,
+    pub stream: Box<dyn BusStream>,
+    subscribers: Arc<Mutex<Vec<FrameSender<T>>>>,
+    dispatched: Arc<AtomicU64>, //bus frames handed to the subscribers so far
+    clocks: ClockPair,
+    overruns: Arc<AtomicU64>,
+    meter: Arc<MeterLevels>,
+    dispatch_alive: Arc<AtomicBool>,
 }
 
-impl<T: 'static + std::clone::Clone + cpal::Sample + Send + Sync> InputBus<T> {
+impl<T: 'static + cpal::Sample + Send + Sync> InputBus<T> {
     pub fn new(
         id: u8,
-        device: Device,
+        backend: &dyn AudioBackend<T>,
         stream_config: StreamConfig,
         bus_config: BusConfig,
         channel_ids: Vec<u8>,
-        txs: Vec<BroadcastSender<(u8, T)>>,
-    ) -> InputBus<T> {
+        ref_clock: Arc<AtomicU64>,
+    ) -> Result<InputBus<T>> {
+        let device_channels: Vec<u8> = bus_config.channel_map.iter().map(|x| x.0).collect();
+        check_channel_ids(&device_channels, Some(stream_config.channels))?;
         let nof_channels = stream_config.channels as u8;
-        let ch_ids = channel_ids.clone();
+        let (bus_tx, bus_rx) =
+            frame_channel::<T>(bus_config.nof_channels() as usize, BUS_RING_FRAMES);
+        let overruns = bus_tx.get_overruns();
 
-        let stream = device
-            .build_input_stream(
-                &stream_config,
-                move |data, _: &_| {
-                    broadcast_clb::<T>(data, &txs.clone(), &ch_ids, &nof_channels, &bus_config)
-                },
-                err_fn,
-            )
-            .unwrap();
-
-        InputBus::<T> {
+        let mut meter_writer = MeterWriter::new(
+            bus_config.nof_channels() as usize,
+            stream_config.sample_rate.0,
+        );
+        let meter = meter_writer.get_levels();
+
+        let mut bus_tx = bus_tx;
+        let mut frame = vec![cpal::Sample::from(&0.0f32); bus_config.nof_channels() as usize];
+        let clocks: ClockPair = Arc::new(Mutex::new(None));
+        let clb_clocks = clocks.clone();
+        let stream = backend.build_input_stream(
+            &stream_config,
+            Box::new(move |data| {
+                capture_clb::<T>(
+                    data,
+                    &mut bus_tx,
+                    &mut frame,
+                    &mut meter_writer,
+                    &nof_channels,
+                    &bus_config.channel_map,
+                );
+                //skipped when a reader holds it, the next callback counts again
+                if let Ok(mut clocks) = clb_clocks.try_lock() {
+                    *clocks = Some((bus_tx.get_pushed(), ref_clock.load(Ordering::SeqCst)));
+                }
+            }),
+        )?;
+
+        let subscribers = Arc::new(Mutex::new(Vec::<FrameSender<T>>::new()));
+        let dispatched = Arc::new(AtomicU64::new(0));
+        let dispatch_alive = Arc::new(AtomicBool::new(true));
+        dispatch_thread(
+            bus_rx,
+            subscribers.clone(),
+            dispatched.clone(),
+            dispatch_alive.clone(),
+        );
+
+        Ok(InputBus::<T> {
             id: id,
             track_ids: Vec::<u8>::new(),
             channel_ids: channel_ids,
             stream: stream,
-            _type: PhantomData::<T>,
+            subscribers: subscribers,
+            dispatched: dispatched,
+            clocks: clocks,
+            overruns: overruns,
+            meter: meter,
+            dispatch_alive: dispatch_alive,
+        })
+    }
+
+    //New reader of the bus frames from now on, it is dropped from the bus with the receiver.
+    pub fn subscribe(&self, capacity_frames: usize) -> FrameReceiver<T> {
+        self.subscribe_from(capacity_frames).0
+    }
+
+    //Like subscribe, also returns the bus frame of the first frame the reader gets, counted
+    //from the start of the bus
+    pub fn subscribe_from(&self, capacity_frames: usize) -> (FrameReceiver<T>, u64) {
+        let (mut tx, rx) = frame_channel::<T>(self.channel_ids.len(), capacity_frames);
+        tx.set_overrun_counter(self.overruns.clone());
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers.push(tx);
+        (rx, self.dispatched.load(Ordering::SeqCst))
+    }
+
+    //Finds the bus frame captured while the frame marked by mark played
+    pub fn sync_to(&self, mark: Arc<PlayMark>, in_rate: u32, out_rate: u32) -> FrameSync {
+        FrameSync {
+            mark: mark,
+            clocks: self.clocks.clone(),
+            in_rate: in_rate,
+            out_rate: out_rate,
         }
     }
 
@@ -66,9 +292,13 @@ impl<T: 'static + std::clone::Clone + cpal::Sample + Send + Sync> InputBus<T> {
         self.track_ids.push(track_id);
     }
 
-    pub fn play_stream(&self) {
+    pub fn remove_track(&mut self, track_id: u8) {
+        self.track_ids.retain(|id| *id != track_id);
+    }
+
+    pub fn play_stream(&self) -> Result<()> {
         println!("Broadcast stream started!");
-        self.stream.play();
+        self.stream.play()
     }
 
     pub fn get_id(&self) -> u8 {
@@ -83,59 +313,110 @@ impl<T: 'static + std::clone::Clone + cpal::Sample + Send + Sync> InputBus<T> {
         self.channel_ids.clone()
     }
 
-    pub fn to_string(&self) -> String {
-        return format!("Channels: {:?}", self.channel_ids);
+    //frames lost because the bus or one of its readers fell behind
+    pub fn get_overruns(&self) -> u64 {
+        self.overruns.load(Ordering::Relaxed)
+    }
+
+    //levels of the bus channels as captured, before any track processing
+    pub fn get_meter(&self) -> Arc<MeterLevels> {
+        self.meter.clone()
     }
 }
 
-pub struct OutputBus<T: 'static + std::clone::Clone + cpal::Sample + Send + Sync> {
+impl<T: 'static + cpal::Sample + Send + Sync> Drop for InputBus<T> {
+    fn drop(&mut self) {
+        self.dispatch_alive.store(false, Ordering::SeqCst);
+    }
+}
+
+pub struct OutputBus<T: 'static + cpal::Sample + Send + Sync> {
     id: u8,
     track_ids: Vec<u8>,
     channel_ids: Vec<u8>,
-    pub stream: Stream,
-    backlog: Box<Vec<(u8, T)>>,
+    pub stream: Box<dyn BusStream>,
+    underruns: Arc<AtomicU64>,
+    meter: Arc<MeterLevels>,
+    mark: Arc<PlayMark>,
     _type: PhantomData<T>,
 }
 
-impl<T: 'static + std::clone::Clone + cpal::Sample + Send + Sync> OutputBus<T> {
+impl<T: 'static + cpal::Sample + Send + Sync> OutputBus<T> {
+    //rx carries frames of channel_ids.len() channels, usually written by a mix thread. Bus 0
+    //counts the frames it plays on ref_clock, the others only read it.
     pub fn new(
         id: u8,
-        device: Device,
+        backend: &dyn AudioBackend<T>,
         config: StreamConfig,
         channel_ids: Vec<u8>,
-        rx: Receiver<(u8, T)>,
-    ) -> OutputBus<T> {
+        rx: FrameReceiver<T>,
+        ref_clock: Arc<AtomicU64>,
+    ) -> Result<OutputBus<T>> {
+        check_channel_ids(&channel_ids, Some(config.channels))?;
         let nof_channels = config.channels as u8;
         let ch_ids = channel_ids.clone();
-        let backlog = Box::new(Vec::<(u8, T)>::new());
-        let mut backlog_ref = backlog.clone();
-        let stream = device
-            .build_output_stream(
-                &config,
-                move |data, _: &_| {
-                    playback_clb::<T>(data, &rx, &ch_ids, &mut backlog_ref);
-                },
-                err_fn,
-            )
-            .unwrap();
-
-        OutputBus::<T> {
+        let underruns = rx.get_underruns();
+
+        let mut meter_writer = MeterWriter::new(channel_ids.len(), config.sample_rate.0);
+        let meter = meter_writer.get_levels();
+
+        let mut rx = rx;
+        let mut frame = vec![cpal::Sample::from(&0.0f32); channel_ids.len()];
+        let mark = Arc::new(PlayMark::new());
+        let clb_mark = mark.clone();
+        let mut popped: u64 = 0;
+        let stream = backend.build_output_stream(
+            &config,
+            Box::new(move |data| {
+                let first_frame = ref_clock.load(Ordering::SeqCst);
+                let nof_frames = (data.len() / nof_channels as usize) as u64;
+                let mut is_popped = |idx: usize| {
+                    clb_mark.played(popped, first_frame + idx as u64);
+                    popped += 1;
+                };
+                playback_clb::<T>(
+                    data,
+                    &mut rx,
+                    &mut frame,
+                    &mut meter_writer,
+                    &ch_ids,
+                    &nof_channels,
+                    &mut is_popped,
+                );
+                if id == 0 {
+                    ref_clock.fetch_add(nof_frames, Ordering::SeqCst);
+                }
+            }),
+        )?;
+
+        Ok(OutputBus::<T> {
             id: id,
             track_ids: Vec::<u8>::new(),
             channel_ids: channel_ids,
             stream: stream,
-            backlog: backlog,
+            underruns: underruns,
+            meter: meter,
+            mark: mark,
             _type: PhantomData::<T>,
-        }
+        })
+    }
+
+    //Mark of a frame of the bus ring, see PlayMark
+    pub fn get_mark(&self) -> Arc<PlayMark> {
+        self.mark.clone()
     }
 
     pub fn add_track(&mut self, track_id: u8) {
         self.track_ids.push(track_id);
     }
 
-    pub fn play_stream(&self) {
+    pub fn remove_track(&mut self, track_id: u8) {
+        self.track_ids.retain(|id| *id != track_id);
+    }
+
+    pub fn play_stream(&self) -> Result<()> {
         println!("Playback stream started!");
-        self.stream.play();
+        self.stream.play()
     }
 
     pub fn get_id(&self) -> u8 {
@@ -150,74 +431,175 @@ impl<T: 'static + std::clone::Clone + cpal::Sample + Send + Sync> OutputBus<T> {
         self.channel_ids.clone()
     }
 
-    pub fn to_string(&self) -> String {
-        return format!("Channels: {:?}", self.channel_ids);
+    //frames the device asked for that the mix had not delivered yet
+    pub fn get_underruns(&self) -> u64 {
+        self.underruns.load(Ordering::Relaxed)
+    }
+
+    //levels of the mix as it is handed to the device
+    pub fn get_meter(&self) -> Arc<MeterLevels> {
+        self.meter.clone()
     }
 }
 
-fn broadcast_clb<T: cpal::Sample>(
+//Audio thread: picks the bus channels out of every device frame and queues them.
+fn capture_clb<T: cpal::Sample>(
     data: &[T],
-    txs: &Vec<BroadcastSender<(u8, T)>>,
-    in_chs: &Vec<u8>,
+    bus_tx: &mut FrameSender<T>,
+    frame: &mut Vec<T>,
+    meter: &mut MeterWriter,
     nof_chs: &u8,
-    bus_config: &BusConfig,
+    channel_map: &ChannelMap,
 ) {
-    let mut cur_ch = 1;
-    for &sample in data {
-        if in_chs.contains(&cur_ch) {
-            for tx in txs {
-                match bus_config {
-                    BusConfig::Mono => {
-                        tx.try_send((1 as u8, sample));
-                        tx.try_send((2 as u8, sample));
-                    }
-                    BusConfig::Stereo => {
-                        tx.try_send((cur_ch, sample));
-                    }
-                };
-            }
-            cur_ch += 1;
-            if cur_ch > *nof_chs {
-                cur_ch = 1;
-            }
-        } else {
-            cur_ch += 1;
-            if cur_ch > *nof_chs {
-                cur_ch = 1;
+    for device_frame in data.chunks(*nof_chs as usize) {
+        for (dev_ch, bus_ch) in channel_map.iter() {
+            if let Some(sample) = device_frame.get((*dev_ch - 1) as usize) {
+                frame[(*bus_ch - 1) as usize] = *sample;
             }
         }
+        meter.add_frame(frame);
+        bus_tx.push_frames(frame);
     }
+    meter.publish(data.len() / *nof_chs as usize);
 }
 
+//Audio thread: writes queued bus frames into the bus channels of the device buffer,
+//silence (and an underrun) when nothing is queued. popped gets the index in data of every
+//frame that came from the ring.
 fn playback_clb<T: 'static + cpal::Sample + Send + Sync>(
     data: &mut [T],
-    mut rx: &Receiver<(u8, T)>,
+    rx: &mut FrameReceiver<T>,
+    frame: &mut Vec<T>,
+    meter: &mut MeterWriter,
     out_channels: &Vec<u8>,
-    backlog: &mut Box<Vec<(u8, T)>>,
+    nof_chs: &u8,
+    popped: &mut impl FnMut(usize),
 ) {
-    let mut ch_idx = 0;
-    for sample in data {
-        loop {
-            let (dest_ch, s_data) = match rx.recv().ok() {
-                Some(t) => t,
-                None => {
-                    ch_idx = 0;
-                    continue;
-                }
-            };
-
-            if dest_ch == out_channels[ch_idx] {
-                ch_idx += 1;
-                if ch_idx >= out_channels.len() {
-                    ch_idx = 0;
-                }
-                *sample = s_data;
-                break;
+    for (idx, device_frame) in data.chunks_mut(*nof_chs as usize).enumerate() {
+        for sample in device_frame.iter_mut() {
+            *sample = cpal::Sample::from(&0.0f32);
+        }
+        if rx.pop_frames_counted(frame) == 0 {
+            continue;
+        }
+        popped(idx);
+        meter.add_frame(frame);
+        for (idx, out_ch) in out_channels.iter().enumerate() {
+            if let Some(sample) = device_frame.get_mut((*out_ch - 1) as usize) {
+                *sample = frame[idx];
             }
         }
     }
+    meter.publish(data.len() / *nof_chs as usize);
+}
+
+//Fans the bus frames out to every subscriber, off the audio thread.
+fn dispatch_thread<T: 'static + cpal::Sample + Send + Sync>(
+    mut bus_rx: FrameReceiver<T>,
+    subscribers: Arc<Mutex<Vec<FrameSender<T>>>>,
+    dispatched: Arc<AtomicU64>,
+    alive: Arc<AtomicBool>,
+) {
+    thread::spawn(move || {
+        let mut buffer =
+            vec![cpal::Sample::from(&0.0f32); DISPATCH_BLOCK_FRAMES * bus_rx.get_nof_channels()];
+        while alive.load(Ordering::SeqCst) {
+            let nof_frames = bus_rx.pop_frames(&mut buffer);
+            if nof_frames == 0 {
+                thread::sleep(Duration::from_micros(500));
+                continue;
+            }
+
+            let data = &buffer[..nof_frames * bus_rx.get_nof_channels()];
+            let mut subscribers = subscribers.lock().unwrap();
+            subscribers.retain(|tx| tx.is_connected());
+            for tx in subscribers.iter_mut() {
+                tx.push_frames(data);
+            }
+            dispatched.fetch_add(nof_frames as u64, Ordering::SeqCst);
+        }
+    });
 }
 
-pub fn err_fn(error: cpal::StreamError) {
-    eprintln!("an error occurred on stream: {}", error);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOF_FRAMES: usize = 3;
+
+    //sample of device channel ch in frame, 1-based channels
+    fn sample(frame: usize, ch: u8) -> f32 {
+        (frame * 10) as f32 + ch as f32
+    }
+
+    //the map Track::new builds: bus channels in file channel order
+    fn track_map(in_channels: &[u8]) -> ChannelMap {
+        let mut in_map = ChannelMap::new();
+        for (idx, ch) in in_channels.iter().enumerate() {
+            in_map.push((*ch, (idx + 1) as u8));
+        }
+        in_map
+    }
+
+    #[test]
+    fn deinterleaves_a_four_channel_bus_per_track() {
+        //channels 3 to 6 of a 6 channel device
+        const DEVICE_CHANNELS: u8 = 6;
+        let bus_config = BusConfig::get_bus_config(&vec![3, 4, 5, 6]).unwrap();
+        let nof_bus_channels = bus_config.nof_channels() as usize;
+        assert_eq!(nof_bus_channels, 4);
+
+        let mut data = Vec::<f32>::new();
+        for frame in 0..NOF_FRAMES {
+            data.extend((1..=DEVICE_CHANNELS).map(|ch| sample(frame, ch)));
+        }
+        let (mut bus_tx, mut bus_rx) = frame_channel::<f32>(nof_bus_channels, 64);
+        let mut frame = vec![0.0f32; nof_bus_channels];
+        let mut meter = MeterWriter::new(nof_bus_channels, 48_000);
+        capture_clb(
+            &data,
+            &mut bus_tx,
+            &mut frame,
+            &mut meter,
+            &DEVICE_CHANNELS,
+            &bus_config.channel_map,
+        );
+        let mut bus_data = vec![0.0f32; NOF_FRAMES * nof_bus_channels];
+        assert_eq!(bus_rx.pop_frames(&mut bus_data), NOF_FRAMES);
+
+        //(bus channels of the track, device channels expected in its file)
+        let tracks = [
+            (vec![1, 2], vec![3, 4]),
+            (vec![4], vec![6]),
+            (vec![4, 1, 3], vec![6, 3, 5]),
+        ];
+        for (in_channels, device_channels) in tracks.iter() {
+            let in_map = track_map(in_channels);
+            let track_channels: Vec<u8> = (1..=in_channels.len() as u8).collect();
+            let mut track_frame = vec![0.0f32; track_channels.len()];
+            for (idx, bus_frame) in bus_data.chunks(nof_bus_channels).enumerate() {
+                map_frame(bus_frame, &in_map, &track_channels, &mut track_frame);
+                let expected: Vec<f32> =
+                    device_channels.iter().map(|ch| sample(idx, *ch)).collect();
+                assert_eq!(
+                    track_frame, expected,
+                    "track on {:?}, frame {}",
+                    in_channels, idx
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn wraps_the_default_map() {
+        assert_eq!(default_channel_map(1, &vec![1, 2]), vec![(1, 1), (1, 2)]);
+        assert_eq!(
+            default_channel_map(2, &vec![3, 4, 5, 6]),
+            vec![(1, 3), (2, 4), (1, 5), (2, 6)]
+        );
+        assert_eq!(
+            default_channel_map(4, &vec![1, 2]),
+            vec![(1, 1), (2, 2), (3, 1), (4, 2)]
+        );
+    }
 }
@@ -0,0 +1,222 @@
+use std::ops::Range;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::busses::FrameSync;
+use crate::error::{RecorderError, Result};
+use crate::frames::{FrameReceiver, FrameSender};
+use crate::resample::convert_frames;
+
+// Round trip latency measurement. Test pulses are played on every channel of an output bus
+// and listened for on every channel of an input bus that is connected to it, by a loopback
+// cable or a virtual device. Where a pulse should be heard is found the way a recording
+// lines up with its playback, through the input frame captured while the first frame
+// played (see FrameSync), so the delay found is what the device path adds on top of that:
+// device buffers, converters and cables.
+
+const PRE_SILENCE_SECS: f64 = 0.2; //lets the output settle before the first pulse
+const PULSE_INTERVAL_SECS: f64 = 0.5; //the longest latency that can be measured
+const NOF_PULSES: usize = 4;
+const PULSE_LEVEL: f32 = 0.8;
+const DETECT_LEVEL: f32 = 0.05; //a pulse that comes back quieter is not found
+const MAX_SPREAD_SECS: f64 = 0.005; //pulses further apart are reported
+const FEED_BLOCK_FRAMES: usize = 256;
+const CAPTURE_TIMEOUT_SECS: f64 = 10.0;
+
+//Frames of input capacity a measurement needs at in_rate
+pub fn capture_frames(in_rate: u32) -> usize {
+    let secs = PRE_SILENCE_SECS + (NOF_PULSES + 1) as f64 * PULSE_INTERVAL_SECS;
+    (secs * in_rate as f64) as usize
+}
+
+//Plays the pulses into out_tx at out_rate and finds them in in_rx at in_rate. sync must
+//follow the next frame pushed into out_tx and first_frame is the input bus frame in_rx
+//starts at. Returns the round trip latency in frames at sample_rate, the median of the
+//pulses.
+pub fn measure_round_trip<T: cpal::Sample>(
+    out_tx: &mut FrameSender<T>,
+    in_rx: &mut FrameReceiver<T>,
+    sync: &FrameSync,
+    first_frame: u64,
+    out_rate: u32,
+    in_rate: u32,
+    sample_rate: u32,
+) -> Result<u64> {
+    let (captured, start) = play_and_capture(out_tx, in_rx, sync, first_frame, out_rate, in_rate)?;
+    let nof_in_channels = in_rx.get_nof_channels();
+    let secs_to_in = |secs: f64| (secs * in_rate as f64) as usize;
+
+    let mut latencies = Vec::<usize>::with_capacity(NOF_PULSES);
+    for pulse in 0..NOF_PULSES {
+        let played_at = start + secs_to_in(PRE_SILENCE_SECS + pulse as f64 * PULSE_INTERVAL_SECS);
+        let window = played_at..played_at + secs_to_in(PULSE_INTERVAL_SECS);
+        let (frame, level) = loudest_frame(&captured, nof_in_channels, window);
+        if level < DETECT_LEVEL {
+            return Err(RecorderError::InvalidArgument(format!(
+                "test pulse {} did not come back, is the output connected to the input?",
+                pulse + 1
+            )));
+        }
+        latencies.push(frame - played_at);
+    }
+    latencies.sort_unstable();
+    let spread = (latencies[NOF_PULSES - 1] - latencies[0]) as f64 / in_rate as f64;
+    if spread > MAX_SPREAD_SECS {
+        eprintln!(
+            "measure_round_trip: pulses came back {:.1} ms apart",
+            spread * 1000.0
+        );
+    }
+    let median = latencies[NOF_PULSES / 2] as u64;
+    Ok(convert_frames(median, in_rate, sample_rate))
+}
+
+//Keeps the output fed with silence and the pulses while the input is read, returns the
+//interleaved input frames and the index of the frame captured while the first frame played
+fn play_and_capture<T: cpal::Sample>(
+    out_tx: &mut FrameSender<T>,
+    in_rx: &mut FrameReceiver<T>,
+    sync: &FrameSync,
+    first_frame: u64,
+    out_rate: u32,
+    in_rate: u32,
+) -> Result<(Vec<T>, usize)> {
+    let nof_out_channels = out_tx.get_nof_channels();
+    let nof_in_channels = in_rx.get_nof_channels();
+    let secs_to_out = |secs: f64| (secs * out_rate as f64) as u64;
+    let pulses: Vec<u64> = (0..NOF_PULSES)
+        .map(|p| secs_to_out(PRE_SILENCE_SECS + p as f64 * PULSE_INTERVAL_SECS))
+        .collect();
+    let nof_frames = capture_frames(in_rate);
+
+    let silence = cpal::Sample::from(&0.0f32);
+    let mut block = vec![silence; FEED_BLOCK_FRAMES * nof_out_channels];
+    let mut input = vec![silence; FEED_BLOCK_FRAMES * nof_in_channels];
+    let mut captured = Vec::<T>::with_capacity(nof_frames * nof_in_channels);
+    let mut played: u64 = 0;
+    let mut start: Option<usize> = None;
+    let started = Instant::now();
+    while start.is_none_or(|s| captured.len() < (s + nof_frames) * nof_in_channels) {
+        if started.elapsed() > Duration::from_secs_f64(CAPTURE_TIMEOUT_SECS) {
+            return Err(RecorderError::InvalidArgument(
+                "no input while measuring the latency".to_string(),
+            ));
+        }
+        while out_tx.free_frames() >= FEED_BLOCK_FRAMES {
+            for (idx, frame) in block.chunks_mut(nof_out_channels).enumerate() {
+                let level = match pulses.contains(&(played + idx as u64)) {
+                    true => PULSE_LEVEL,
+                    false => 0.0,
+                };
+                for sample in frame.iter_mut() {
+                    *sample = cpal::Sample::from(&level);
+                }
+            }
+            played += out_tx.push_frames(&block) as u64;
+        }
+        if start.is_none() {
+            start = match sync.get_input_frame() {
+                Some(frame) if frame < first_frame as i64 => {
+                    return Err(RecorderError::InvalidArgument(
+                        "the output played before the input was read".to_string(),
+                    ))
+                }
+                Some(frame) => Some((frame - first_frame as i64) as usize),
+                None => None,
+            };
+        }
+        let nof_read = in_rx.pop_frames(&mut input);
+        captured.extend_from_slice(&input[..nof_read * nof_in_channels]);
+        if nof_read == 0 {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+    Ok((captured, start.unwrap_or(0)))
+}
+
+//(frame, level) of the loudest sample of any channel over frames
+fn loudest_frame<T: cpal::Sample>(
+    captured: &[T],
+    nof_channels: usize,
+    frames: Range<usize>,
+) -> (usize, f32) {
+    let mut loudest = (frames.start, 0.0);
+    for frame in frames {
+        let samples = match captured.get(frame * nof_channels..(frame + 1) * nof_channels) {
+            Some(s) => s,
+            None => break,
+        };
+        for sample in samples.iter() {
+            let level = sample.to_f32().abs();
+            if level > loudest.1 {
+                loudest = (frame, level);
+            }
+        }
+    }
+    loudest
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::RecorderError;
+    use crate::router::Router;
+    use crate::virtual_backend::VirtualBackend;
+    use cpal::{BufferSize, SampleFormat, SampleRate, StreamConfig};
+
+    fn router(backend: VirtualBackend<f32>) -> Router<f32> {
+        let config = StreamConfig {
+            channels: 2,
+            sample_rate: SampleRate(48_000),
+            buffer_size: BufferSize::Default,
+        };
+        let mut rout = Router::with_backend(
+            Box::new(backend.buffer_frames(256)),
+            config.clone(),
+            config,
+            SampleFormat::F32,
+        );
+        rout.new_output_bus(vec![1, 2]).unwrap();
+        rout.new_input_bus(vec![1, 2]).unwrap();
+        rout
+    }
+
+    #[test]
+    fn measures_the_loopback_delay() {
+        for delay_frames in [0, 1_000, 4_800] {
+            let mut rout = router(VirtualBackend::loopback(delay_frames));
+            let latency = rout.calibrate_latency(0, 0).unwrap();
+            assert!(
+                (latency as i64 - delay_frames as i64).abs() <= 1,
+                "measured {} frames for a delay of {}",
+                latency,
+                delay_frames
+            );
+            assert_eq!(rout.get_latency(), latency);
+        }
+    }
+
+    #[test]
+    fn fails_without_pulses() {
+        //nothing comes back from the output
+        let mut rout = router(VirtualBackend::silent());
+        let latency = rout.get_latency();
+        match rout.calibrate_latency(0, 0) {
+            Err(RecorderError::InvalidArgument(msg)) => assert!(msg.contains("did not come back")),
+            result => panic!("expected a missing pulse, got {:?}", result),
+        }
+        assert_eq!(rout.get_latency(), latency);
+    }
+
+    #[test]
+    fn needs_the_busses() {
+        let mut rout = router(VirtualBackend::loopback(0));
+        assert!(matches!(
+            rout.calibrate_latency(1, 0),
+            Err(RecorderError::BusNotFound(1))
+        ));
+        assert!(matches!(
+            rout.calibrate_latency(0, 1),
+            Err(RecorderError::BusNotFound(1))
+        ));
+    }
+}
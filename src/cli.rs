@@ -0,0 +1,510 @@
+use cpal::traits::{DeviceTrait, HostTrait};
+use cpal::{Device, Host, SampleFormat};
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::error::{RecorderError, Result};
+use crate::formats::RecordFormat;
+use crate::metronome::MetronomeSettings;
+use crate::recovery;
+use crate::router::Router;
+use crate::session::Session;
+use crate::utils;
+use crate::wav;
+
+const USAGE: &str = "Usage:
+  example2                       start the GUI
+  example2 list                  list hosts and their devices
+  example2 record [options]      record without a GUI until the duration ends or Ctrl-C/SIGTERM
+  example2 recover <path>...     repair takes left unfinalized by a crash, path is a take
+                                 or a session whose takes are checked
+
+Record options:
+  --host <name>                  audio host, default host when omitted
+  --in <device>                  input device, default input device when omitted
+  --out <device>                 output device, default output device when omitted
+  --track <name>:<in>[:<out>]    track fed by input channels <in> (e.g. 1,2) and played on
+                                 output channels <out> (default 1,2), repeat for more tracks
+  --import <path>[@<secs>]       play an audio file (wav or flac) from secs on (default 0)
+                                 while recording, on output channels 1,2, repeat for more
+  --duration <secs>              stop after secs seconds of recording
+  --click <bpm>[:<beats>/<unit>] play a click at bpm quarter notes per minute in the
+                                 time signature beats/unit (default 4/4)
+  --count-in <bars>              bars of click before recording starts
+  --click-out <channels>         output channels of the click (default 1,2)
+  --cycle <start>:<end>          loop between start and end secs, every pass is a new take
+  --format <format>              file format of the takes: i16, i24, i32 or f32 (wav),
+                                 flac16 or flac24 (FLAC), default f32
+  --rate <hz>                    sample rate of the takes, the input is converted to it,
+                                 default the input device rate
+  --latency <frames>             round trip latency of the devices, new takes are placed
+                                 this many frames (at the take rate) earlier, default the
+                                 buffer sizes of the devices
+  --calibrate <channels>         measure the round trip latency before recording with test
+                                 pulses on output channels <channels>, which must be cabled
+                                 back to the recorded inputs, instead of --latency
+  --time-of-day                  BWF time references of the takes are the time of day (UTC)
+                                 they were recorded at, 00:00:00 + take position otherwise
+  --session <path>               save the session with the new takes to path, it is
+                                 saved when recording starts as well so a crash can be
+                                 recovered from it";
+
+struct TrackArg {
+    name: String,
+    in_channels: Vec<u8>,
+    out_channels: Option<Vec<u8>>,
+}
+
+struct RecordArgs {
+    host: Option<String>,
+    in_device: Option<String>,
+    out_device: Option<String>,
+    tracks: Vec<TrackArg>,
+    imports: Vec<(String, f64)>, //(path, timeline position in seconds)
+    duration: Option<Duration>,
+    metronome: MetronomeSettings,
+    click_channels: Vec<u8>,
+    cycle: Option<(f64, f64)>, //locators in seconds
+    record_format: RecordFormat,
+    sample_rate: Option<u32>,
+    latency: Option<u64>,
+    calibrate_channels: Option<Vec<u8>>,
+    time_of_day: bool,
+    session: Option<String>,
+}
+
+//Runs a command line command, returns the process exit code.
+pub fn run(args: &[String]) -> i32 {
+    let result = match args[0].as_str() {
+        "list" => list_devices(),
+        "record" => parse_record_args(&args[1..]).and_then(|a| record(a)),
+        "recover" => recover(&args[1..]),
+        "help" | "-h" | "--help" => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+        cmd => Err(invalid(format!("unknown command {}\n\n{}", cmd, USAGE))),
+    };
+    match result {
+        Ok(_) => 0,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            1
+        }
+    }
+}
+
+fn list_devices() -> Result<()> {
+    let default_host = cpal::default_host().id();
+    for host_id in utils::get_host_ids() {
+        let marker = if host_id == default_host {
+            " (default)"
+        } else {
+            ""
+        };
+        println!("Host: {}{}", host_id.name(), marker);
+
+        let host = match cpal::host_from_id(host_id) {
+            Ok(h) => h,
+            Err(e) => {
+                println!("  unavailable: {}", e);
+                continue;
+            }
+        };
+        let default_in = device_name(host.default_input_device());
+        let default_out = device_name(host.default_output_device());
+        let (in_devices, out_devices) = match utils::get_host_devices(host_id) {
+            Ok(devices) => devices,
+            Err(e) => {
+                println!("  unavailable: {}", e);
+                continue;
+            }
+        };
+
+        println!("  Input devices:");
+        for device in in_devices.iter() {
+            let config = device.default_input_config().map(|c| c.config());
+            print_device(device, config.ok(), &default_in);
+        }
+        println!("  Output devices:");
+        for device in out_devices.iter() {
+            let config = device.default_output_config().map(|c| c.config());
+            print_device(device, config.ok(), &default_out);
+        }
+    }
+    Ok(())
+}
+
+fn print_device(device: &Device, config: Option<cpal::StreamConfig>, default_name: &str) {
+    let name = device.name().unwrap_or("<unnamed>".to_string());
+    let marker = if name == default_name {
+        " (default)"
+    } else {
+        ""
+    };
+    match config {
+        Some(c) => println!(
+            "    {}{}: {} channels, {} Hz",
+            name, marker, c.channels, c.sample_rate.0
+        ),
+        None => println!("    {}{}: no default config", name, marker),
+    }
+}
+
+fn device_name(device: Option<Device>) -> String {
+    device.and_then(|d| d.name().ok()).unwrap_or(String::new())
+}
+
+fn parse_record_args(args: &[String]) -> Result<RecordArgs> {
+    let mut record_args = RecordArgs {
+        host: None,
+        in_device: None,
+        out_device: None,
+        tracks: Vec::<TrackArg>::new(),
+        imports: Vec::<(String, f64)>::new(),
+        duration: None,
+        metronome: MetronomeSettings::default(),
+        click_channels: vec![1, 2],
+        cycle: None,
+        record_format: RecordFormat::Float32,
+        sample_rate: None,
+        latency: None,
+        calibrate_channels: None,
+        time_of_day: false,
+        session: None,
+    };
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .cloned()
+                .ok_or(invalid(format!("{} needs a value", arg)))
+        };
+        match arg.as_str() {
+            "--host" => record_args.host = Some(value()?),
+            "--in" => record_args.in_device = Some(value()?),
+            "--out" => record_args.out_device = Some(value()?),
+            "--track" => record_args.tracks.push(parse_track(&value()?)?),
+            "--import" => record_args.imports.push(parse_import(&value()?)?),
+            "--duration" => {
+                let secs = value()?;
+                let secs = secs
+                    .parse::<f64>()
+                    .map_err(|_| invalid(format!("invalid duration {}", secs)))?;
+                record_args.duration = Some(Duration::from_secs_f64(secs.max(0.)));
+            }
+            "--click" => parse_click(&value()?, &mut record_args.metronome)?,
+            "--count-in" => {
+                let bars = value()?;
+                record_args.metronome.count_in_bars = bars
+                    .parse::<u8>()
+                    .map_err(|_| invalid(format!("invalid count-in {}", bars)))?;
+            }
+            "--click-out" => record_args.click_channels = parse_channels(&value()?)?,
+            "--cycle" => record_args.cycle = Some(parse_cycle(&value()?)?),
+            "--format" => {
+                let format = value()?;
+                record_args.record_format = RecordFormat::from_id(&format)
+                    .ok_or(invalid(format!("unknown format {}", format)))?;
+            }
+            "--rate" => {
+                let rate = value()?;
+                match rate.parse::<u32>() {
+                    Ok(r) if r > 0 => record_args.sample_rate = Some(r),
+                    _ => return Err(invalid(format!("invalid sample rate {}", rate))),
+                }
+            }
+            "--latency" => {
+                let frames = value()?;
+                record_args.latency = Some(
+                    frames
+                        .parse::<u64>()
+                        .map_err(|_| invalid(format!("invalid latency {}", frames)))?,
+                );
+            }
+            "--calibrate" => record_args.calibrate_channels = Some(parse_channels(&value()?)?),
+            "--time-of-day" => record_args.time_of_day = true,
+            "--session" => record_args.session = Some(value()?),
+            _ => return Err(invalid(format!("unknown option {}\n\n{}", arg, USAGE))),
+        }
+    }
+
+    if record_args.tracks.is_empty() {
+        return Err(invalid("at least one --track is needed".to_string()));
+    }
+    Ok(record_args)
+}
+
+//name:in_channels[:out_channels], channels are comma separated and 1-based
+fn parse_track(arg: &str) -> Result<TrackArg> {
+    let parts: Vec<&str> = arg.split(':').collect();
+    if parts.len() < 2 || parts.len() > 3 || parts[0].is_empty() {
+        return Err(invalid(format!(
+            "invalid track {}, expected <name>:<in>[:<out>]",
+            arg
+        )));
+    }
+    let out_channels = match parts.get(2) {
+        Some(p) => Some(parse_channels(p)?),
+        None => None,
+    };
+    Ok(TrackArg {
+        name: parts[0].to_string(),
+        in_channels: parse_channels(parts[1])?,
+        out_channels: out_channels,
+    })
+}
+
+//path[@secs]
+fn parse_import(arg: &str) -> Result<(String, f64)> {
+    match arg.rsplit_once('@') {
+        Some((path, secs)) => match secs.parse::<f64>() {
+            Ok(s) if s >= 0. && !path.is_empty() => Ok((path.to_string(), s)),
+            _ => Err(invalid(format!(
+                "invalid import {}, expected <path>[@<secs>]",
+                arg
+            ))),
+        },
+        None => Ok((arg.to_string(), 0.)),
+    }
+}
+
+//bpm[:beats/unit]
+fn parse_click(arg: &str, metronome: &mut MetronomeSettings) -> Result<()> {
+    let error = || {
+        invalid(format!(
+            "invalid click {}, expected <bpm>[:<beats>/<unit>]",
+            arg
+        ))
+    };
+    let (tempo, signature) = match arg.split_once(':') {
+        Some((tempo, signature)) => (tempo, Some(signature)),
+        None => (arg, None),
+    };
+    metronome.enabled = true;
+    metronome.tempo = tempo.parse::<f64>().map_err(|_| error())?;
+    if let Some(signature) = signature {
+        let (beats, unit) = signature.split_once('/').ok_or(error())?;
+        metronome.beats_per_bar = beats.parse::<u8>().map_err(|_| error())?;
+        metronome.beat_unit = unit.parse::<u8>().map_err(|_| error())?;
+    }
+    metronome.check()
+}
+
+//start:end
+fn parse_cycle(arg: &str) -> Result<(f64, f64)> {
+    let error = || invalid(format!("invalid cycle {}, expected <start>:<end>", arg));
+    let (start, end) = arg.split_once(':').ok_or(error())?;
+    match (start.parse::<f64>(), end.parse::<f64>()) {
+        (Ok(start), Ok(end)) if start >= 0. && end > start => Ok((start, end)),
+        _ => Err(error()),
+    }
+}
+
+fn parse_channels(arg: &str) -> Result<Vec<u8>> {
+    let mut channels = Vec::<u8>::new();
+    for ch in arg.split(',') {
+        match ch.trim().parse::<u8>() {
+            Ok(c) => channels.push(c),
+            _ => return Err(invalid(format!("invalid channel {} in {}", ch, arg))),
+        }
+    }
+    Ok(channels)
+}
+
+fn invalid(msg: String) -> RecorderError {
+    RecorderError::InvalidArgument(msg)
+}
+
+fn find_host(name: &Option<String>) -> Result<Host> {
+    let host_id = match name {
+        Some(n) => utils::get_host_ids()
+            .into_iter()
+            .find(|h| h.name() == n)
+            .ok_or(RecorderError::HostUnavailable(n.clone()))?,
+        None => cpal::default_host().id(),
+    };
+    Ok(cpal::host_from_id(host_id)?)
+}
+
+fn record(args: RecordArgs) -> Result<()> {
+    let host = find_host(&args.host)?;
+    let in_name = match args.in_device {
+        Some(n) => n,
+        None => device_name(host.default_input_device()),
+    };
+    let out_name = match args.out_device {
+        Some(n) => n,
+        None => device_name(host.default_output_device()),
+    };
+
+    let in_conf = utils::get_input_device_by_name(&host, &in_name)?
+        .default_input_config()?
+        .config();
+    let out_conf = utils::get_output_device_by_name(&host, &out_name)?
+        .default_output_config()?
+        .config();
+
+    let mut router = Router::<f32>::new(
+        host,
+        in_conf,
+        out_conf,
+        in_name.clone(),
+        out_name.clone(),
+        SampleFormat::F32,
+    )?;
+    router.set_record_format(args.record_format);
+    if let Some(rate) = args.sample_rate {
+        router.set_sample_rate(rate)?;
+    }
+    //one input bus over every channel that is recorded, each track picks its channels
+    //from it so a take has exactly the channels given for its track
+    let mut in_channels = Vec::<u8>::new();
+    for track in args.tracks.iter() {
+        for ch in track.in_channels.iter() {
+            if !in_channels.contains(ch) {
+                in_channels.push(*ch);
+            }
+        }
+    }
+    let in_bus = router.new_input_bus(in_channels.clone())?;
+    for track in args.tracks.iter() {
+        let bus_channels = track
+            .in_channels
+            .iter()
+            .map(|ch| in_channels.iter().position(|x| x == ch).unwrap() as u8 + 1)
+            .collect();
+        let out_channels = track.out_channels.clone().unwrap_or(vec![1, 2]);
+        let out_bus = router.new_output_bus(out_channels)?;
+        let track_id =
+            router.new_track_on_channels(track.name.clone(), in_bus, bus_channels, out_bus)?;
+        router.set_recording(track_id, true)?;
+    }
+
+    if let Some(frames) = args.latency {
+        router.set_latency(frames);
+    }
+    //the pulses go out on a bus of their own and come back on the recorded inputs
+    if let Some(channels) = &args.calibrate_channels {
+        let out_bus = router.new_output_bus(channels.clone())?;
+        let latency = router.calibrate_latency(in_bus, out_bus)?;
+        println!(
+            "Takes are placed {:.1} ms earlier",
+            latency as f64 * 1000.0 / router.get_sample_rate() as f64
+        );
+    }
+
+    //backing tracks, they are not armed so they play while the others record
+    if !args.imports.is_empty() {
+        let out_bus = router.new_output_bus(vec![1, 2])?;
+        for (path, secs) in args.imports.iter() {
+            let start_frame = (secs * router.get_sample_rate() as f64) as u64;
+            router.import_track(path, in_bus, out_bus, start_frame)?;
+            println!("Imported {}", path);
+        }
+    }
+
+    //the click gets a bus of its own so it can go to other outputs than the tracks
+    let mut metronome = args.metronome.clone();
+    if metronome.enabled || metronome.count_in_bars > 0 {
+        metronome.out_bus_id = Some(router.new_output_bus(args.click_channels.clone())?);
+    }
+    router.set_metronome(metronome.clone())?;
+    let count_in = metronome.bars_to_frames(metronome.count_in_bars, router.get_sample_rate());
+    let count_in = Duration::from_secs_f64(count_in as f64 / router.get_sample_rate() as f64);
+    if let Some((start, end)) = args.cycle {
+        let rate = router.get_sample_rate() as f64;
+        router.set_cycle(Some((start * rate) as u64..(end * rate) as u64))?;
+    }
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let stop_ref = stop.clone();
+    ctrlc::set_handler(move || stop_ref.store(true, Ordering::SeqCst))
+        .map_err(|e| invalid(format!("could not install signal handler: {}", e)))?;
+
+    if metronome.count_in_bars > 0 {
+        println!("Counting in {} bars", metronome.count_in_bars);
+    }
+    println!("Recording from {} (Ctrl-C to stop)", in_name);
+    if args.time_of_day {
+        //the transport is at frame 0, so that is now
+        router.set_timecode_origin(wav::time_of_day(router.get_sample_rate()));
+    }
+    router.record()?;
+    if let Some(path) = &args.session {
+        router.to_session().save(path)?;
+    }
+    let start = Instant::now();
+    let mut last_report = 0;
+    while !stop.load(Ordering::SeqCst) {
+        let elapsed = start.elapsed().saturating_sub(count_in);
+        if let Some(d) = args.duration {
+            if elapsed >= d {
+                break;
+            }
+        }
+        if elapsed.as_secs() >= last_report + 10 {
+            last_report = elapsed.as_secs();
+            let (overruns, underruns) = router.get_xruns();
+            println!(
+                "{:02}:{:02} recorded, xruns: {} in / {} out",
+                last_report / 60,
+                last_report % 60,
+                overruns,
+                underruns
+            );
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    //stop returns once every take is finalized, report the takes even if one failed. The
+    //armed tracks are new, all their takes (one per pass when cycling) are from this run.
+    let stopped = router.stop();
+    for track in router.get_tracks().iter().filter(|t| t.is_rec_armed()) {
+        for take in track.get_files().iter() {
+            println!("Wrote {}", take.file);
+        }
+    }
+    if let Some(path) = &args.session {
+        router.to_session().save(path)?;
+        println!("Saved session {}", path);
+    }
+    stopped
+}
+
+fn recover(paths: &[String]) -> Result<()> {
+    if paths.is_empty() {
+        return Err(invalid("recover needs a take or session path".to_string()));
+    }
+    let mut files = Vec::<String>::new();
+    for path in paths.iter() {
+        if path.ends_with(".json") {
+            files.extend(Session::load(path)?.get_take_files());
+        } else {
+            files.push(path.clone());
+        }
+    }
+
+    let mut failed = 0;
+    let recovered = recovery::recover_takes(&files);
+    for (file, result) in recovered.iter() {
+        match result {
+            Ok(frames) => println!("Recovered {} ({} frames)", file, frames),
+            Err(e) => {
+                eprintln!("Could not recover {}: {}", file, e);
+                failed += 1;
+            }
+        }
+    }
+    if recovered.is_empty() {
+        println!("Nothing to recover");
+    }
+    match failed {
+        0 => Ok(()),
+        _ => Err(invalid(format!("{} takes could not be recovered", failed))),
+    }
+}
@@ -0,0 +1,278 @@
+use serde::{Deserialize, Serialize};
+
+use crate::error::{RecorderError, Result};
+use crate::formats::TakeReader;
+use crate::tracks::Take;
+
+// Comping: a track's playback assembled from ranges of several of its takes. Regions
+// never overlap on the timeline, where one ends and the next begins the two are
+// crossfaded over CROSSFADE_SECS centred on the boundary, so edits do not click.
+
+pub const CROSSFADE_SECS: f64 = 0.01;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct CompRegion {
+    pub take: String,     //file of the take the region plays
+    pub start_frame: u64, //timeline frames at the session rate
+    pub end_frame: u64,
+}
+
+//Puts region into comp, it replaces whatever the comp played in its range before, regions
+//it covers partly are trimmed or split.
+pub fn place_region(comp: &mut Vec<CompRegion>, region: CompRegion) -> Result<()> {
+    if region.start_frame >= region.end_frame {
+        return Err(RecorderError::InvalidArgument(format!(
+            "empty comp region {}..{}",
+            region.start_frame, region.end_frame
+        )));
+    }
+    let mut placed = Vec::<CompRegion>::with_capacity(comp.len() + 2);
+    for existing in comp.drain(..) {
+        if existing.start_frame < region.start_frame {
+            placed.push(CompRegion {
+                end_frame: std::cmp::min(existing.end_frame, region.start_frame),
+                ..existing.clone()
+            });
+        }
+        if existing.end_frame > region.end_frame {
+            placed.push(CompRegion {
+                start_frame: std::cmp::max(existing.start_frame, region.end_frame),
+                ..existing
+            });
+        }
+    }
+    placed.push(region);
+    placed.sort_by_key(|r| r.start_frame);
+    *comp = placed;
+    Ok(())
+}
+
+//Reads one region's take in timeline order
+struct RegionCursor {
+    region: CompRegion,
+    take_start: u64, //timeline frame of the take's first frame
+    reader: Option<TakeReader>,
+    frame: Vec<f32>,
+}
+
+// Renders a comp frame by frame at the session rate, like a TakeReader renders a take.
+pub struct CompReader {
+    cursors: Vec<RegionCursor>,
+    position: u64, //timeline frame read next
+    end: u64,
+    half_fade: u64,
+    nof_channels: usize,
+    mix: Vec<f32>,
+}
+
+impl CompReader {
+    //Comp of regions over takes, positioned at start_frame or where the comp starts if
+    //that is later. Takes must be at sample_rate, the rate the regions are counted in.
+    pub fn open(
+        regions: &[CompRegion],
+        takes: &[Take],
+        sample_rate: u32,
+        nof_channels: usize,
+        start_frame: u64,
+    ) -> Result<CompReader> {
+        let half_fade = (CROSSFADE_SECS * sample_rate as f64 / 2.0) as u64;
+        let mut cursors = Vec::<RegionCursor>::new();
+        for region in regions.iter() {
+            let take = takes.iter().find(|t| t.file == region.take).ok_or(
+                RecorderError::InvalidArgument(format!(
+                    "comp region of unknown take {}",
+                    region.take
+                )),
+            )?;
+            cursors.push(RegionCursor {
+                region: region.clone(),
+                take_start: take.start_frame,
+                reader: None,
+                frame: Vec::<f32>::with_capacity(nof_channels),
+            });
+        }
+        let (start, end) = match (cursors.first(), cursors.last()) {
+            (Some(first), Some(last)) => (
+                first.region.start_frame.saturating_sub(half_fade),
+                last.region.end_frame + half_fade,
+            ),
+            _ => (0, 0),
+        };
+
+        let reader = CompReader {
+            cursors: cursors,
+            position: std::cmp::max(start, start_frame),
+            end: end,
+            half_fade: half_fade,
+            nof_channels: nof_channels,
+            mix: vec![0.0; nof_channels],
+        };
+        reader.check_rates(sample_rate)?;
+        Ok(reader)
+    }
+
+    //timeline frame the comp is read from next
+    pub fn get_position(&self) -> u64 {
+        self.position
+    }
+
+    //timeline frame after the last one the comp plays
+    pub fn get_end(&self) -> u64 {
+        self.end
+    }
+
+    pub fn get_nof_channels(&self) -> u16 {
+        self.nof_channels as u16
+    }
+
+    //Replaces frame with the next frame, false at the end of the comp
+    pub fn read_frame<T: cpal::Sample>(&mut self, frame: &mut Vec<T>) -> Result<bool> {
+        frame.clear();
+        if self.position >= self.end {
+            return Ok(false);
+        }
+        for sample in self.mix.iter_mut() {
+            *sample = 0.0;
+        }
+
+        let (position, half_fade) = (self.position, self.half_fade);
+        for cursor in self.cursors.iter_mut() {
+            let region = &cursor.region;
+            let fade_in = region.start_frame.saturating_sub(half_fade);
+            let fade_out = region.end_frame + half_fade;
+            if position < fade_in || position >= fade_out {
+                cursor.reader = None;
+                continue;
+            }
+            if cursor.reader.is_none() {
+                let mut reader = TakeReader::open(&region.take)?;
+                reader.seek(position.saturating_sub(cursor.take_start))?;
+                cursor.reader = Some(reader);
+            }
+            if position < cursor.take_start {
+                continue;
+            }
+            let reader = cursor.reader.as_mut().unwrap();
+            if !reader.read_frame::<f32>(&mut cursor.frame)? {
+                continue;
+            }
+
+            let gain = fade_gain(position, region, half_fade);
+            for (ch, sample) in self.mix.iter_mut().enumerate() {
+                if let Some(s) = cursor.frame.get(ch) {
+                    *sample += s * gain;
+                }
+            }
+        }
+
+        for sample in self.mix.iter() {
+            frame.push(cpal::Sample::from(sample));
+        }
+        self.position += 1;
+        Ok(true)
+    }
+
+    fn check_rates(&self, sample_rate: u32) -> Result<()> {
+        for cursor in self.cursors.iter() {
+            let take_rate = TakeReader::open(&cursor.region.take)?.get_sample_rate();
+            if take_rate != sample_rate {
+                return Err(RecorderError::InvalidArgument(format!(
+                    "take {} is at {} Hz, the comp at {} Hz",
+                    cursor.region.take, take_rate, sample_rate
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+//Linear fade in over the region start and fade out over its end, 1.0 in between
+fn fade_gain(position: u64, region: &CompRegion, half_fade: u64) -> f32 {
+    if half_fade == 0 {
+        return 1.0;
+    }
+    let fade_len = 2.0 * half_fade as f32;
+    let fade_in = (position + half_fade) as i64 - region.start_frame as i64;
+    let fade_out = (region.end_frame + half_fade) as i64 - position as i64;
+    let (fade_in, fade_out) = (fade_in as f32, fade_out as f32);
+    (fade_in / fade_len)
+        .min(fade_out / fade_len)
+        .clamp(0.0, 1.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 1_000; //10 frame crossfades
+
+    //mono take of nof_frames frames of value
+    fn constant_take(name: &str, value: f32, nof_frames: usize) -> Take {
+        let path =
+            std::env::temp_dir().join(format!("example2-comp-{}-{}.wav", name, std::process::id()));
+        let file = path.to_str().unwrap().to_string();
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: RATE,
+            bits_per_sample: 32,
+            sample_format: hound::SampleFormat::Float,
+        };
+        let mut writer = hound::WavWriter::create(&file, spec).unwrap();
+        for _ in 0..nof_frames {
+            writer.write_sample(value).unwrap();
+        }
+        writer.finalize().unwrap();
+        Take {
+            file: file,
+            start_frame: 0,
+            name: name.to_string(),
+        }
+    }
+
+    #[test]
+    fn crossfades_takes_at_the_boundary() {
+        let takes = vec![constant_take("a", 1.0, 300), constant_take("b", 0.5, 300)];
+        let mut comp = Vec::<CompRegion>::new();
+        for (take, range) in takes.iter().zip([0..100, 100..200]) {
+            let region = CompRegion {
+                take: take.file.clone(),
+                start_frame: range.start,
+                end_frame: range.end,
+            };
+            place_region(&mut comp, region).unwrap();
+        }
+        let mut reader = CompReader::open(&comp, &takes, RATE, 1, 50).unwrap();
+        let mut frame = Vec::<f32>::new();
+        let mut played = Vec::<f32>::new();
+        while reader.read_frame(&mut frame).unwrap() {
+            played.push(frame[0]);
+        }
+        for take in takes.iter() {
+            std::fs::remove_file(&take.file).unwrap();
+        }
+
+        let half_fade = (CROSSFADE_SECS * RATE as f64 / 2.0) as u64;
+        assert_eq!(reader.get_end(), 200 + half_fade);
+        assert_eq!(played.len() as u64, reader.get_end() - 50);
+        for (idx, sample) in played.iter().enumerate() {
+            let position = 50 + idx as u64;
+            let expected = match position {
+                p if p < 100 - half_fade => 1.0,
+                p if p < 100 + half_fade => {
+                    //a fades out while b fades in, the gains add up to 1
+                    let b_gain = (p + half_fade - 100) as f32 / (2 * half_fade) as f32;
+                    1.0 * (1.0 - b_gain) + 0.5 * b_gain
+                }
+                p if p < 200 - half_fade => 0.5,
+                p => 0.5 * (200 + half_fade - p) as f32 / (2 * half_fade) as f32,
+            };
+            assert!(
+                (sample - expected).abs() < 1e-6,
+                "frame {}: {} instead of {}",
+                position,
+                sample,
+                expected
+            );
+        }
+    }
+}
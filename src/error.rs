@@ -0,0 +1,103 @@
+use std::fmt;
+use std::io;
+
+#[derive(Debug)]
+pub enum RecorderError {
+    HostUnavailable(String), //host name
+    DeviceNotFound(String),  //device name
+    Device(String),          //device or its configs could not be queried
+    Stream(String),          //stream could not be built, started or paused
+    InvalidChannels(String),
+    TrackNotFound(u8),
+    BusNotFound(u8),
+    InvalidArgument(String),
+    Io(io::Error),
+    Wav(hound::Error),
+    Flac(claxon::Error),
+}
+
+pub type Result<T> = std::result::Result<T, RecorderError>;
+
+impl fmt::Display for RecorderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RecorderError::HostUnavailable(host) => write!(f, "host {} is not available", host),
+            RecorderError::DeviceNotFound(device) => write!(f, "device {} not found", device),
+            RecorderError::Device(e) => write!(f, "device error: {}", e),
+            RecorderError::Stream(e) => write!(f, "stream error: {}", e),
+            RecorderError::InvalidChannels(e) => write!(f, "invalid channels: {}", e),
+            RecorderError::TrackNotFound(id) => write!(f, "track {} does not exist", id),
+            RecorderError::BusNotFound(id) => write!(f, "bus {} does not exist", id),
+            RecorderError::InvalidArgument(e) => write!(f, "{}", e),
+            RecorderError::Io(e) => write!(f, "{}", e),
+            RecorderError::Wav(e) => write!(f, "wav error: {}", e),
+            RecorderError::Flac(e) => write!(f, "flac error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for RecorderError {}
+
+impl From<io::Error> for RecorderError {
+    fn from(e: io::Error) -> Self {
+        RecorderError::Io(e)
+    }
+}
+
+impl From<hound::Error> for RecorderError {
+    fn from(e: hound::Error) -> Self {
+        RecorderError::Wav(e)
+    }
+}
+
+//read errors stay io errors, only what is wrong with the stream is a FLAC error
+impl From<claxon::Error> for RecorderError {
+    fn from(e: claxon::Error) -> Self {
+        match e {
+            claxon::Error::IoError(e) => RecorderError::Io(e),
+            e => RecorderError::Flac(e),
+        }
+    }
+}
+
+impl From<cpal::HostUnavailable> for RecorderError {
+    fn from(e: cpal::HostUnavailable) -> Self {
+        RecorderError::HostUnavailable(e.to_string())
+    }
+}
+
+impl From<cpal::DevicesError> for RecorderError {
+    fn from(e: cpal::DevicesError) -> Self {
+        RecorderError::Device(e.to_string())
+    }
+}
+
+impl From<cpal::DefaultStreamConfigError> for RecorderError {
+    fn from(e: cpal::DefaultStreamConfigError) -> Self {
+        RecorderError::Device(e.to_string())
+    }
+}
+
+impl From<cpal::SupportedStreamConfigsError> for RecorderError {
+    fn from(e: cpal::SupportedStreamConfigsError) -> Self {
+        RecorderError::Device(e.to_string())
+    }
+}
+
+impl From<cpal::BuildStreamError> for RecorderError {
+    fn from(e: cpal::BuildStreamError) -> Self {
+        RecorderError::Stream(e.to_string())
+    }
+}
+
+impl From<cpal::PlayStreamError> for RecorderError {
+    fn from(e: cpal::PlayStreamError) -> Self {
+        RecorderError::Stream(e.to_string())
+    }
+}
+
+impl From<cpal::PauseStreamError> for RecorderError {
+    fn from(e: cpal::PauseStreamError) -> Self {
+        RecorderError::Stream(e.to_string())
+    }
+}
@@ -0,0 +1,542 @@
+use std::fs::File;
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+
+use crate::error::{RecorderError, Result};
+
+// Lossless FLAC encoder for takes. Every channel is coded on its own with the best fixed
+// predictor (order 0 to 4) and partitioned Rice residuals, falling back to verbatim or
+// constant subframes. Fixed block size, frames are written as soon as a block is full.
+// The total sample count in STREAMINFO stays 0 (unknown) until finalize, so a take that
+// was cut short decodes up to its last complete frame.
+
+const BLOCK_SIZE: usize = 4_096;
+const MAX_FIXED_ORDER: usize = 4;
+const MAX_PARTITION_ORDER: u32 = 8;
+const MAX_RICE_PARAM: u32 = 14; //15 is the escape code
+const STREAMINFO_OFFSET: u64 = 8; //"fLaC" and the metadata block header
+
+pub struct FlacWriter {
+    writer: BufWriter<File>,
+    nof_channels: usize,
+    bits_per_sample: u32,
+    sample_rate: u32,
+    block: Vec<Vec<i32>>, //per channel, the samples of the block being filled
+    frame_idx: u64,
+    nof_frames: u64, //inter-channel samples written
+    finalized: bool,
+}
+
+impl FlacWriter {
+    //tags are (name, value) vorbis comments, e.g. ("DESCRIPTION", "...")
+    pub fn create(
+        path: &str,
+        sample_rate: u32,
+        nof_channels: u16,
+        bits_per_sample: u16,
+        tags: &[(String, String)],
+    ) -> Result<FlacWriter> {
+        if ![16, 24].contains(&bits_per_sample) || nof_channels == 0 || nof_channels > 8 {
+            return Err(RecorderError::InvalidArgument(format!(
+                "FLAC takes need 16 or 24 bits and 1 to 8 channels, not {} bits and {} channels",
+                bits_per_sample, nof_channels
+            )));
+        }
+        let mut flac = FlacWriter {
+            writer: BufWriter::new(File::create(path)?),
+            nof_channels: nof_channels as usize,
+            bits_per_sample: bits_per_sample as u32,
+            sample_rate: sample_rate,
+            block: vec![Vec::<i32>::with_capacity(BLOCK_SIZE); nof_channels as usize],
+            frame_idx: 0,
+            nof_frames: 0,
+            finalized: false,
+        };
+        let streaminfo = flac.streaminfo();
+        let comment = vorbis_comment(tags);
+        let len = comment.len() as u32;
+        flac.writer.write_all(b"fLaC")?;
+        flac.writer.write_all(&[0x00, 0x00, 0x00, 34])?; //STREAMINFO, more blocks follow
+        flac.writer.write_all(&streaminfo)?;
+        //VORBIS_COMMENT, the last metadata block
+        flac.writer
+            .write_all(&[0x84, (len >> 16) as u8, (len >> 8) as u8, len as u8])?;
+        flac.writer.write_all(&comment)?;
+        Ok(flac)
+    }
+
+    //One sample per channel, full scale is +-2^(bits_per_sample - 1)
+    pub fn write_frame(&mut self, frame: &[i32]) -> Result<()> {
+        for (ch, sample) in frame.iter().enumerate().take(self.nof_channels) {
+            self.block[ch].push(*sample);
+        }
+        if self.block[0].len() == BLOCK_SIZE {
+            self.write_block()?;
+        }
+        Ok(())
+    }
+
+    //Writes out the complete frames, a partial block stays buffered
+    pub fn flush(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    pub fn finalize(mut self) -> Result<()> {
+        self.finalized = true;
+        self.write_block()?;
+        self.writer.seek(SeekFrom::Start(STREAMINFO_OFFSET))?;
+        let streaminfo = self.streaminfo();
+        self.writer.write_all(&streaminfo)?;
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    fn streaminfo(&self) -> Vec<u8> {
+        let mut info = Vec::<u8>::with_capacity(34);
+        info.extend(&(BLOCK_SIZE as u16).to_be_bytes()); //min block size
+        info.extend(&(BLOCK_SIZE as u16).to_be_bytes()); //max block size
+        info.extend(&[0u8; 6]); //min and max frame size unknown
+        let packed = (self.sample_rate as u64) << 44
+            | ((self.nof_channels as u64 - 1) << 41)
+            | ((self.bits_per_sample as u64 - 1) << 36)
+            | (self.nof_frames & 0xF_FFFF_FFFF);
+        info.extend(&packed.to_be_bytes());
+        info.extend(&[0u8; 16]); //no MD5
+        info
+    }
+
+    fn write_block(&mut self) -> Result<()> {
+        let block_size = self.block[0].len();
+        if block_size == 0 {
+            return Ok(());
+        }
+        let mut bits = BitWriter::new();
+        self.frame_header(&mut bits, block_size);
+        for ch in 0..self.nof_channels {
+            encode_subframe(&mut bits, &self.block[ch], self.bits_per_sample);
+        }
+        bits.align();
+        let crc = crc16(&bits.bytes);
+        bits.write(crc as u64, 16);
+        self.writer.write_all(&bits.bytes)?;
+
+        for channel in self.block.iter_mut() {
+            channel.clear();
+        }
+        self.frame_idx += 1;
+        self.nof_frames += block_size as u64;
+        Ok(())
+    }
+
+    fn frame_header(&self, bits: &mut BitWriter, block_size: usize) {
+        bits.write(0b11_1111_1111_1110, 14); //sync
+        bits.write(0, 1); //reserved
+        bits.write(0, 1); //fixed block size
+        let block_size_code = match block_size {
+            BLOCK_SIZE => 0b1100,
+            _ => 0b0111, //16 bit block size - 1 after the frame number
+        };
+        bits.write(block_size_code, 4);
+        bits.write(0b0000, 4); //sample rate from STREAMINFO
+        bits.write(self.nof_channels as u64 - 1, 4); //independent channels
+        let sample_size_code = match self.bits_per_sample {
+            16 => 0b100,
+            _ => 0b110,
+        };
+        bits.write(sample_size_code, 3);
+        bits.write(0, 1); //reserved
+        write_utf8_number(bits, self.frame_idx);
+        if block_size != BLOCK_SIZE {
+            bits.write(block_size as u64 - 1, 16);
+        }
+        let crc = crc8(&bits.bytes);
+        bits.write(crc as u64, 8);
+    }
+}
+
+//Sets the total sample count in the STREAMINFO of a FLAC file written by FlacWriter
+pub fn write_total_samples<F: Read + Write + Seek>(file: &mut F, nof_frames: u64) -> Result<()> {
+    let offset = STREAMINFO_OFFSET + 10; //sample rate, channels, bits and total samples
+    let mut packed = [0u8; 8];
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut packed)?;
+    let packed = u64::from_be_bytes(packed) & !0xF_FFFF_FFFF | (nof_frames & 0xF_FFFF_FFFF);
+    file.seek(SeekFrom::Start(offset))?;
+    file.write_all(&packed.to_be_bytes())?;
+    Ok(())
+}
+
+impl Drop for FlacWriter {
+    fn drop(&mut self) {
+        if !self.finalized {
+            self.write_block().ok();
+            self.writer.flush().ok();
+        }
+    }
+}
+
+//(bits, order, residuals, partition order, Rice parameters) of a fixed predictor subframe
+type FixedCoding = (u64, usize, Vec<i32>, u32, Vec<u32>);
+
+fn encode_subframe(bits: &mut BitWriter, samples: &[i32], bps: u32) {
+    if samples.iter().all(|x| *x == samples[0]) {
+        bits.write(0b0000_0000, 8); //constant
+        bits.write_signed(samples[0] as i64, bps);
+        return;
+    }
+
+    let verbatim_bits = samples.len() as u64 * bps as u64;
+    let mut best: Option<FixedCoding> = None;
+    for order in 0..=std::cmp::min(MAX_FIXED_ORDER, samples.len() - 1) {
+        let residuals = match fixed_residuals(samples, order) {
+            Some(r) => r,
+            None => continue,
+        };
+        let (partition_order, params, residual_bits) =
+            rice_partitions(&residuals, order, samples.len());
+        let total = 8 + order as u64 * bps as u64 + 6 + residual_bits;
+        if best.as_ref().map(|b| total < b.0).unwrap_or(true) {
+            best = Some((total, order, residuals, partition_order, params));
+        }
+    }
+
+    match best {
+        Some((total, order, residuals, partition_order, params)) if total < verbatim_bits + 8 => {
+            bits.write(0b0001_0000 | (order as u64) << 1, 8); //fixed, no wasted bits
+            for sample in samples[..order].iter() {
+                bits.write_signed(*sample as i64, bps);
+            }
+            bits.write(0b00, 2); //Rice coding with 4 bit parameters
+            bits.write(partition_order as u64, 4);
+            let partition_len = samples.len() >> partition_order;
+            let mut start = 0;
+            for (idx, param) in params.iter().enumerate() {
+                let end = (idx + 1) * partition_len - order;
+                bits.write(*param as u64, 4);
+                for residual in residuals[start..end].iter() {
+                    bits.write_rice(*residual, *param);
+                }
+                start = end;
+            }
+        }
+        _ => {
+            bits.write(0b0000_0010, 8); //verbatim
+            for sample in samples.iter() {
+                bits.write_signed(*sample as i64, bps);
+            }
+        }
+    }
+}
+
+//Residuals of the fixed predictor of an order, None when one does not fit 32 bits
+fn fixed_residuals(samples: &[i32], order: usize) -> Option<Vec<i32>> {
+    let mut residuals = Vec::<i32>::with_capacity(samples.len() - order);
+    for idx in order..samples.len() {
+        let s = |back: usize| samples[idx - back] as i64;
+        let residual = match order {
+            0 => s(0),
+            1 => s(0) - s(1),
+            2 => s(0) - 2 * s(1) + s(2),
+            3 => s(0) - 3 * s(1) + 3 * s(2) - s(3),
+            _ => s(0) - 4 * s(1) + 6 * s(2) - 4 * s(3) + s(4),
+        };
+        if residual > i32::MAX as i64 || residual < i32::MIN as i64 + 1 {
+            return None;
+        }
+        residuals.push(residual as i32);
+    }
+    Some(residuals)
+}
+
+//(partition order, Rice parameter per partition, bits) of the cheapest partitioning
+fn rice_partitions(residuals: &[i32], order: usize, block_size: usize) -> (u32, Vec<u32>, u64) {
+    let mut best = (0, Vec::<u32>::new(), u64::MAX);
+    for partition_order in 0..=MAX_PARTITION_ORDER {
+        let nof_partitions = 1 << partition_order;
+        let partition_len = block_size >> partition_order;
+        if !block_size.is_multiple_of(nof_partitions) || partition_len <= order {
+            break;
+        }
+        let mut params = Vec::<u32>::with_capacity(nof_partitions);
+        let mut total = 0;
+        let mut start = 0;
+        for idx in 0..nof_partitions {
+            let end = (idx + 1) * partition_len - order;
+            let (param, cost) = best_rice_param(&residuals[start..end]);
+            params.push(param);
+            total += 4 + cost;
+            start = end;
+        }
+        if total < best.2 {
+            best = (partition_order, params, total);
+        }
+    }
+    best
+}
+
+//(parameter, bits) of the cheapest Rice parameter for a partition. The parameter is close
+//to log2 of the mean folded residual, only its neighbours are costed exactly.
+fn best_rice_param(residuals: &[i32]) -> (u32, u64) {
+    let sum: u64 = residuals.iter().map(|r| zigzag(*r)).sum();
+    let mean = sum / residuals.len().max(1) as u64;
+    let estimate = std::cmp::min(64 - mean.leading_zeros(), MAX_RICE_PARAM);
+    let mut best = (0, u64::MAX);
+    for param in estimate.saturating_sub(1)..=std::cmp::min(estimate + 1, MAX_RICE_PARAM) {
+        let cost: u64 = residuals
+            .iter()
+            .map(|r| (zigzag(*r) >> param) + 1 + param as u64)
+            .sum();
+        if cost < best.1 {
+            best = (param, cost);
+        }
+    }
+    best
+}
+
+fn zigzag(residual: i32) -> u64 {
+    ((residual << 1) ^ (residual >> 31)) as u32 as u64
+}
+
+fn write_utf8_number(bits: &mut BitWriter, number: u64) {
+    if number < 0x80 {
+        bits.write(number, 8);
+        return;
+    }
+    let nof_bytes = match number {
+        0..=0x7FF => 2,
+        0x800..=0xFFFF => 3,
+        0x1_0000..=0x1F_FFFF => 4,
+        0x20_0000..=0x3FF_FFFF => 5,
+        0x400_0000..=0x7FFF_FFFF => 6,
+        _ => 7,
+    };
+    let lead = (0xFF00u64 >> nof_bytes) & 0xFF;
+    bits.write(lead | (number >> (6 * (nof_bytes - 1))), 8);
+    for idx in (0..nof_bytes - 1).rev() {
+        bits.write(0x80 | ((number >> (6 * idx)) & 0x3F), 8);
+    }
+}
+
+fn vorbis_comment(tags: &[(String, String)]) -> Vec<u8> {
+    let vendor = crate::wav::ORIGINATOR.as_bytes();
+    let mut comment = Vec::<u8>::new();
+    comment.extend(&(vendor.len() as u32).to_le_bytes());
+    comment.extend(vendor);
+    comment.extend(&(tags.len() as u32).to_le_bytes());
+    for (name, value) in tags.iter() {
+        let field = format!("{}={}", name, value);
+        comment.extend(&(field.len() as u32).to_le_bytes());
+        comment.extend(field.as_bytes());
+    }
+    comment
+}
+
+struct BitWriter {
+    bytes: Vec<u8>,
+    acc: u64,
+    nof_bits: u32, //bits in acc
+}
+
+impl BitWriter {
+    fn new() -> BitWriter {
+        BitWriter {
+            bytes: Vec::<u8>::new(),
+            acc: 0,
+            nof_bits: 0,
+        }
+    }
+
+    //the lowest nof_bits of value, up to 32 at a time
+    fn write(&mut self, value: u64, nof_bits: u32) {
+        if nof_bits > 32 {
+            self.write(value >> 32, nof_bits - 32);
+            self.write(value & 0xFFFF_FFFF, 32);
+            return;
+        }
+        self.acc = (self.acc << nof_bits) | (value & ((1u64 << nof_bits) - 1));
+        self.nof_bits += nof_bits;
+        while self.nof_bits >= 8 {
+            self.nof_bits -= 8;
+            self.bytes.push((self.acc >> self.nof_bits) as u8);
+        }
+        self.acc &= (1u64 << self.nof_bits) - 1;
+    }
+
+    fn write_signed(&mut self, value: i64, nof_bits: u32) {
+        self.write(value as u64 & ((1u64 << nof_bits) - 1), nof_bits);
+    }
+
+    fn write_rice(&mut self, residual: i32, param: u32) {
+        let folded = zigzag(residual);
+        let mut quotient = folded >> param;
+        while quotient >= 32 {
+            self.write(0, 32);
+            quotient -= 32;
+        }
+        self.write(1, quotient as u32 + 1);
+        if param > 0 {
+            self.write(folded, param);
+        }
+    }
+
+    //pads the last byte with zeros
+    fn align(&mut self) {
+        if self.nof_bits > 0 {
+            self.write(0, 8 - self.nof_bits);
+        }
+    }
+}
+
+fn crc8(bytes: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for byte in bytes.iter() {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = match crc & 0x80 {
+                0 => crc << 1,
+                _ => (crc << 1) ^ 0x07,
+            };
+        }
+    }
+    crc
+}
+
+fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for byte in bytes.iter() {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = match crc & 0x8000 {
+                0 => crc << 1,
+                _ => (crc << 1) ^ 0x8005,
+            };
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 48_000;
+
+    //Writes the channels to a FLAC file, decodes it with claxon and checks every sample and
+    //the STREAMINFO totals
+    fn round_trip(name: &str, bits_per_sample: u16, channels: &[Vec<i32>]) {
+        let path = std::env::temp_dir().join(format!(
+            "example2-flac-{}-{}.flac",
+            name,
+            std::process::id()
+        ));
+        let path = path.to_str().unwrap();
+        let nof_frames = channels[0].len();
+        let tags = vec![("DESCRIPTION".to_string(), name.to_string())];
+        let mut writer =
+            FlacWriter::create(path, RATE, channels.len() as u16, bits_per_sample, &tags).unwrap();
+        let mut frame = vec![0; channels.len()];
+        for idx in 0..nof_frames {
+            for (ch, channel) in channels.iter().enumerate() {
+                frame[ch] = channel[idx];
+            }
+            writer.write_frame(&frame).unwrap();
+        }
+        writer.finalize().unwrap();
+
+        let mut reader = claxon::FlacReader::open(path).unwrap();
+        let info = reader.streaminfo();
+        assert_eq!(info.samples, Some(nof_frames as u64));
+        assert_eq!(info.channels, channels.len() as u32);
+        assert_eq!(info.bits_per_sample, bits_per_sample as u32);
+        assert_eq!(info.sample_rate, RATE);
+        assert_eq!(reader.get_tag("DESCRIPTION").next(), Some(name));
+        let samples: Vec<i32> = reader.samples().map(|s| s.unwrap()).collect();
+        assert_eq!(samples.len(), nof_frames * channels.len());
+        for (idx, sample) in samples.iter().enumerate() {
+            let (frame_idx, ch) = (idx / channels.len(), idx % channels.len());
+            assert_eq!(
+                *sample, channels[ch][frame_idx],
+                "frame {} channel {}",
+                frame_idx, ch
+            );
+        }
+        std::fs::remove_file(path).ok();
+    }
+
+    //full scale noise, no predictor helps with it
+    fn noise(nof_frames: usize, bits_per_sample: u32, seed: u32) -> Vec<i32> {
+        let mut state = seed;
+        (0..nof_frames)
+            .map(|_| {
+                state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                (state as i32) >> (32 - bits_per_sample)
+            })
+            .collect()
+    }
+
+    fn sine(nof_frames: usize, bits_per_sample: u32, freq: f64) -> Vec<i32> {
+        let amplitude = ((1 << (bits_per_sample - 1)) - 1) as f64 * 0.9;
+        (0..nof_frames)
+            .map(|idx| {
+                let phase = 2.0 * std::f64::consts::PI * freq * idx as f64 / RATE as f64;
+                (amplitude * phase.sin()).round() as i32
+            })
+            .collect()
+    }
+
+    //first byte of the subframe header: the subframe type and wasted bits flag
+    fn subframe_type(samples: &[i32], bps: u32) -> u8 {
+        let mut bits = BitWriter::new();
+        encode_subframe(&mut bits, samples, bps);
+        bits.bytes[0]
+    }
+
+    #[test]
+    fn picks_the_subframe_types() {
+        assert_eq!(subframe_type(&[1234; BLOCK_SIZE], 16), 0b0000_0000);
+        assert_eq!(subframe_type(&noise(BLOCK_SIZE, 16, 1), 16), 0b0000_0010);
+        let fixed = subframe_type(&sine(BLOCK_SIZE, 24, 440.0), 24);
+        assert_eq!(fixed & 0b0111_0000, 0b0001_0000);
+        assert!((fixed >> 1) & 0b111 > 0, "a sine is predicted");
+    }
+
+    #[test]
+    fn round_trips_16_bit_mono() {
+        let nof_frames = 2 * BLOCK_SIZE + 123; //partial last block
+        let mut samples = sine(nof_frames, 16, 997.0);
+        samples[10] = i16::MIN as i32;
+        samples[11] = i16::MAX as i32;
+        round_trip("mono16", 16, &[samples]);
+    }
+
+    #[test]
+    fn round_trips_24_bit_stereo() {
+        let nof_frames = 3 * BLOCK_SIZE + 1;
+        let mut left = sine(nof_frames, 24, 220.0);
+        left[BLOCK_SIZE] = -(1 << 23);
+        left[BLOCK_SIZE + 1] = (1 << 23) - 1;
+        round_trip("stereo24", 24, &[left, noise(nof_frames, 24, 7)]);
+    }
+
+    #[test]
+    fn round_trips_constant_and_verbatim_blocks() {
+        let nof_frames = 2 * BLOCK_SIZE + 500;
+        //silence, a constant block, then noise that is stored verbatim
+        let mut samples = vec![0; BLOCK_SIZE];
+        samples.extend(vec![-321; BLOCK_SIZE]);
+        samples.extend(noise(500, 16, 3));
+        assert_eq!(samples.len(), nof_frames);
+        round_trip(
+            "constant16",
+            16,
+            &[samples.clone(), noise(nof_frames, 16, 5)],
+        );
+        round_trip("short24", 24, &[vec![42; 7]]);
+    }
+
+    #[test]
+    fn checks_crcs() {
+        assert_eq!(crc8(b"123456789"), 0xF4);
+        assert_eq!(crc16(b"123456789"), 0xFEE8);
+    }
+}
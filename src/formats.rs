@@ -0,0 +1,668 @@
+use cpal::SampleFormat;
+use hound::WavSpec;
+use serde::{Deserialize, Serialize};
+
+use claxon::frame::FrameReader;
+use claxon::input::BufferedReader;
+
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+
+use crate::error::{RecorderError, Result};
+use crate::flac::FlacWriter;
+use crate::wav::{format_error, BextInfo, BwfReader, BwfWriter};
+
+// File format takes are recorded in, independent of the device sample format.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum RecordFormat {
+    Int16,
+    Int24,
+    Int32,
+    Float32,
+    Flac16,
+    Flac24,
+}
+
+impl RecordFormat {
+    pub fn all() -> Vec<RecordFormat> {
+        vec![
+            RecordFormat::Int16,
+            RecordFormat::Int24,
+            RecordFormat::Int32,
+            RecordFormat::Float32,
+            RecordFormat::Flac16,
+            RecordFormat::Flac24,
+        ]
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            RecordFormat::Int16 => "WAV 16-bit",
+            RecordFormat::Int24 => "WAV 24-bit",
+            RecordFormat::Int32 => "WAV 32-bit",
+            RecordFormat::Float32 => "WAV 32-bit float",
+            RecordFormat::Flac16 => "FLAC 16-bit",
+            RecordFormat::Flac24 => "FLAC 24-bit",
+        }
+    }
+
+    //command line name
+    pub fn id(&self) -> &'static str {
+        match self {
+            RecordFormat::Int16 => "i16",
+            RecordFormat::Int24 => "i24",
+            RecordFormat::Int32 => "i32",
+            RecordFormat::Float32 => "f32",
+            RecordFormat::Flac16 => "flac16",
+            RecordFormat::Flac24 => "flac24",
+        }
+    }
+
+    pub fn from_id(id: &str) -> Option<RecordFormat> {
+        RecordFormat::all().into_iter().find(|f| f.id() == id)
+    }
+
+    //what takes were recorded as before the format could be chosen
+    pub fn from_sample_format(format: SampleFormat) -> RecordFormat {
+        match format {
+            SampleFormat::F32 => RecordFormat::Float32,
+            SampleFormat::I16 | SampleFormat::U16 => RecordFormat::Int16,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            RecordFormat::Flac16 | RecordFormat::Flac24 => "flac",
+            _ => "wav",
+        }
+    }
+
+    pub fn bits(&self) -> u16 {
+        match self {
+            RecordFormat::Int16 | RecordFormat::Flac16 => 16,
+            RecordFormat::Int24 | RecordFormat::Flac24 => 24,
+            RecordFormat::Int32 | RecordFormat::Float32 => 32,
+        }
+    }
+
+    //WAV format with the same samples, for files that are always wavs (bounces)
+    pub fn to_wav(self) -> RecordFormat {
+        match self {
+            RecordFormat::Flac16 => RecordFormat::Int16,
+            RecordFormat::Flac24 => RecordFormat::Int24,
+            f => f,
+        }
+    }
+
+    fn wav_spec(&self, sample_rate: u32, nof_channels: u16) -> WavSpec {
+        WavSpec {
+            channels: nof_channels,
+            sample_rate: sample_rate,
+            bits_per_sample: self.bits(),
+            sample_format: match self {
+                RecordFormat::Float32 => hound::SampleFormat::Float,
+                _ => hound::SampleFormat::Int,
+            },
+        }
+    }
+}
+
+// Converts engine samples to integer samples of a bit depth. Float samples are reduced
+// with 1 LSB of TPDF dither, integer engine samples (16 bit) are only ever widened.
+pub struct Quantizer {
+    bits: u16,
+    dither: bool,
+    seed: u32,
+}
+
+impl Quantizer {
+    pub fn new<T: cpal::Sample>(bits: u16) -> Quantizer {
+        Quantizer {
+            bits: bits,
+            dither: T::FORMAT == SampleFormat::F32 && bits < 32,
+            seed: 0x9E37_79B9,
+        }
+    }
+
+    pub fn quantize<T: cpal::Sample>(&mut self, sample: T) -> i32 {
+        if T::FORMAT != SampleFormat::F32 {
+            return (sample.to_i16() as i32) << (self.bits.max(16) - 16);
+        }
+        let scale = (1u64 << (self.bits - 1)) as f64;
+        let mut value = sample.to_f32() as f64 * scale;
+        if self.dither {
+            value += self.uniform() - self.uniform();
+        }
+        value.round().clamp(-scale, scale - 1.0) as i32
+    }
+
+    //xorshift, 0.0..1.0
+    fn uniform(&mut self) -> f64 {
+        self.seed ^= self.seed << 13;
+        self.seed ^= self.seed >> 17;
+        self.seed ^= self.seed << 5;
+        self.seed as f64 / u32::MAX as f64
+    }
+}
+
+enum Encoder {
+    Wav(BwfWriter),
+    Flac(FlacWriter),
+}
+
+// Writes the frames of a take in its record format.
+pub struct TakeWriter {
+    encoder: Encoder,
+    format: RecordFormat,
+    quantizer: Quantizer,
+    sample_rate: u32,
+    int_frame: Vec<i32>,
+}
+
+impl TakeWriter {
+    //T is the engine sample type, it decides whether reducing the bit depth is dithered
+    pub fn create<T: cpal::Sample>(
+        path: &str,
+        format: RecordFormat,
+        sample_rate: u32,
+        nof_channels: u16,
+        bext: &BextInfo,
+    ) -> Result<TakeWriter> {
+        let encoder = match format {
+            RecordFormat::Flac16 | RecordFormat::Flac24 => {
+                //FLAC has no bext chunk, the same metadata goes into vorbis comments
+                let tags = vec![
+                    ("DESCRIPTION".to_string(), bext.description.clone()),
+                    ("DATE".to_string(), bext.origination_date.clone()),
+                    (
+                        "TIME_REFERENCE".to_string(),
+                        bext.time_reference.to_string(),
+                    ),
+                ];
+                Encoder::Flac(FlacWriter::create(
+                    path,
+                    sample_rate,
+                    nof_channels,
+                    format.bits(),
+                    &tags,
+                )?)
+            }
+            _ => {
+                let spec = format.wav_spec(sample_rate, nof_channels);
+                Encoder::Wav(BwfWriter::create(path, spec, bext)?)
+            }
+        };
+        Ok(TakeWriter {
+            encoder: encoder,
+            format: format,
+            quantizer: Quantizer::new::<T>(format.bits()),
+            sample_rate: sample_rate,
+            int_frame: vec![0; nof_channels as usize],
+        })
+    }
+
+    pub fn write_frame<T: cpal::Sample>(&mut self, frame: &[T]) -> Result<()> {
+        if self.format == RecordFormat::Float32 {
+            if let Encoder::Wav(writer) = &mut self.encoder {
+                for sample in frame.iter() {
+                    writer.write_sample(sample.to_f32())?;
+                }
+            }
+            return Ok(());
+        }
+
+        for (idx, sample) in frame.iter().enumerate().take(self.int_frame.len()) {
+            self.int_frame[idx] = self.quantizer.quantize(*sample);
+        }
+        match &mut self.encoder {
+            Encoder::Wav(writer) => {
+                for sample in self.int_frame.iter() {
+                    writer.write_sample(*sample)?;
+                }
+            }
+            Encoder::Flac(writer) => writer.write_frame(&self.int_frame)?,
+        }
+        Ok(())
+    }
+
+    //Makes what was written so far readable after a crash
+    pub fn flush(&mut self) -> Result<()> {
+        match &mut self.encoder {
+            Encoder::Wav(writer) => writer.flush(),
+            Encoder::Flac(writer) => writer.flush(),
+        }
+    }
+
+    pub fn finalize(self) -> Result<()> {
+        match self.encoder {
+            Encoder::Wav(writer) => writer.finalize(),
+            Encoder::Flac(writer) => writer.finalize(),
+        }
+    }
+
+    pub fn get_sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    pub fn get_nof_channels(&self) -> u16 {
+        self.int_frame.len() as u16
+    }
+
+    pub fn get_format(&self) -> RecordFormat {
+        self.format
+    }
+}
+
+const FLAC_SEEK_SCAN_BYTES: u64 = 64 * 1024; //decoded from a frame before the seek target
+const FLAC_MAX_FRAME_BYTES: u64 = 256 * 1024; //searched for the sync code of a frame
+
+enum Decoder {
+    Wav(BwfReader),
+    Flac {
+        path: String,
+        frames: FrameReader<BufferedReader<File>>,
+        audio_start: u64, //file offset of the first frame
+        block_size: u64,  //of all frames but the last, with a fixed block size
+        block: Vec<i32>,  //deinterleaved samples of the decoded block
+        block_len: usize,
+        pos: usize, //next frame in block
+    },
+}
+
+// Reads takes of any record format (and plain wavs) as engine samples.
+pub struct TakeReader {
+    decoder: Decoder,
+    nof_channels: u16,
+    sample_rate: u32,
+    bits: u16,
+    float: bool,
+    duration: u64,
+}
+
+impl TakeReader {
+    pub fn open(path: &str) -> Result<TakeReader> {
+        let mut magic = [0u8; 4];
+        File::open(path)?
+            .read_exact(&mut magic)
+            .map_err(|_| format_error("file is too short for a take"))?;
+        if &magic != b"fLaC" {
+            let reader = BwfReader::open(path)?;
+            let spec = reader.spec();
+            return Ok(TakeReader {
+                nof_channels: spec.channels,
+                sample_rate: spec.sample_rate,
+                bits: spec.bits_per_sample,
+                float: spec.sample_format == hound::SampleFormat::Float,
+                duration: reader.duration(),
+                decoder: Decoder::Wav(reader),
+            });
+        }
+
+        let info = claxon::FlacReader::open(path)?.streaminfo();
+        let duration = match info.samples {
+            Some(n) => n,
+            //not finalized, e.g. while it is being recorded
+            None => count_flac_frames(path)?,
+        };
+        let mut file = File::open(path)?;
+        let audio_start = flac_audio_start(&mut file)?;
+        Ok(TakeReader {
+            nof_channels: info.channels as u16,
+            sample_rate: info.sample_rate,
+            bits: info.bits_per_sample as u16,
+            float: false,
+            duration: duration,
+            decoder: Decoder::Flac {
+                path: path.to_string(),
+                frames: FrameReader::new(BufferedReader::new(file)),
+                audio_start: audio_start,
+                block_size: info.max_block_size as u64,
+                block: Vec::<i32>::new(),
+                block_len: 0,
+                pos: 0,
+            },
+        })
+    }
+
+    pub fn get_nof_channels(&self) -> u16 {
+        self.nof_channels
+    }
+
+    pub fn get_sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    //in frames
+    pub fn duration(&self) -> u64 {
+        self.duration
+    }
+
+    //FLAC finds the frame with a bisection of the file, then decodes from there, so a seek
+    //deep into a long take costs about as much as one at its start
+    pub fn seek(&mut self, frame: u64) -> Result<()> {
+        match &mut self.decoder {
+            Decoder::Wav(reader) => reader.seek(frame),
+            Decoder::Flac {
+                path,
+                frames,
+                audio_start,
+                block_size,
+                block,
+                block_len,
+                pos,
+            } => {
+                let mut file = File::open(path)?;
+                let (offset, mut time) =
+                    find_flac_frame(&mut file, *audio_start, *block_size, frame)?;
+                file.seek(SeekFrom::Start(offset))?;
+                *frames = FrameReader::new(BufferedReader::new(file));
+                //the blocks before the one with frame are decoded, not converted
+                loop {
+                    let buffer = std::mem::take(block);
+                    match next_flac_block(frames, buffer)? {
+                        Some(b) => {
+                            let duration = b.duration() as u64;
+                            *block_len = duration as usize;
+                            *block = b.into_buffer();
+                            if frame < time + duration {
+                                *pos = (frame - time) as usize;
+                                return Ok(());
+                            }
+                            time += duration;
+                        }
+                        None => {
+                            //past the end, like a wav
+                            *pos = *block_len;
+                            return Ok(());
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    //Replaces frame with the next frame, false at the end of the take
+    pub fn read_frame<T: cpal::Sample>(&mut self, frame: &mut Vec<T>) -> Result<bool> {
+        frame.clear();
+        let (bits, float) = (self.bits, self.float);
+        match &mut self.decoder {
+            Decoder::Wav(reader) => {
+                for _ in 0..self.nof_channels {
+                    let sample = match float {
+                        true => reader.read_sample::<f32>().map(|s| s.map(|s| T::from(&s))),
+                        false => reader
+                            .read_sample::<i32>()
+                            .map(|s| s.map(|s| int_to_sample(s, bits))),
+                    };
+                    match sample {
+                        Some(s) => frame.push(s?),
+                        None => return Ok(false),
+                    }
+                }
+            }
+            Decoder::Flac {
+                frames,
+                block,
+                block_len,
+                pos,
+                ..
+            } => {
+                if *pos == *block_len {
+                    let buffer = std::mem::take(block);
+                    match next_flac_block(frames, buffer)? {
+                        Some(b) => {
+                            *block_len = b.duration() as usize;
+                            *block = b.into_buffer();
+                            *pos = 0;
+                        }
+                        None => return Ok(false),
+                    }
+                }
+                for ch in 0..self.nof_channels as usize {
+                    frame.push(int_to_sample(block[ch * *block_len + *pos], bits));
+                }
+                *pos += 1;
+            }
+        }
+        Ok(true)
+    }
+}
+
+fn int_to_sample<T: cpal::Sample>(sample: i32, bits: u16) -> T {
+    match bits {
+        16 => T::from(&(sample as i16)),
+        _ => T::from(&(sample as f32 / (1u64 << (bits - 1)) as f32)),
+    }
+}
+
+//A frame cut off at the end, of a take being recorded, is where it ends
+fn next_flac_block(
+    frames: &mut FrameReader<BufferedReader<File>>,
+    buffer: Vec<i32>,
+) -> Result<Option<claxon::Block>> {
+    match frames.read_next_or_eof(buffer) {
+        Err(claxon::Error::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(None),
+        decoded => Ok(decoded?),
+    }
+}
+
+//File offset of the first frame, after the metadata blocks
+fn flac_audio_start(file: &mut File) -> Result<u64> {
+    let mut offset = 4; //"fLaC"
+    loop {
+        let mut header = [0u8; 4];
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut header).map_err(|_| {
+            RecorderError::Flac(claxon::Error::FormatError("metadata is cut short"))
+        })?;
+        let len = u32::from_be_bytes([0, header[1], header[2], header[3]]) as u64;
+        offset += 4 + len;
+        if header[0] & 0x80 != 0 {
+            return Ok(offset);
+        }
+    }
+}
+
+//(offset, first frame) of the FLAC frame that holds frame or the last one before it, a
+//short decode away from frame. Bisects the file from the first frame at audio_start on.
+fn find_flac_frame(
+    file: &mut File,
+    audio_start: u64,
+    block_size: u64,
+    frame: u64,
+) -> Result<(u64, u64)> {
+    let (mut lo, mut lo_time) = (audio_start, 0);
+    let mut hi = file.metadata()?.len();
+    while hi - lo > FLAC_SEEK_SCAN_BYTES {
+        let mid = lo + (hi - lo) / 2;
+        match next_flac_frame(file, mid, hi, block_size)? {
+            Some((offset, time)) if time <= frame => {
+                lo = offset;
+                lo_time = time;
+            }
+            _ => hi = mid,
+        }
+    }
+    Ok((lo, lo_time))
+}
+
+//(offset, first frame) of the first FLAC frame from offset on that starts before end. A
+//frame is a sync code that decodes with valid CRCs.
+fn next_flac_frame(
+    file: &mut File,
+    offset: u64,
+    end: u64,
+    block_size: u64,
+) -> Result<Option<(u64, u64)>> {
+    let len = std::cmp::min(end - offset, FLAC_MAX_FRAME_BYTES) as usize;
+    let mut bytes = vec![0u8; len + 1];
+    file.seek(SeekFrom::Start(offset))?;
+    let nof_read = read_up_to(file, &mut bytes)?;
+    for idx in 0..nof_read.saturating_sub(1).min(len) {
+        //0xF8 with a fixed block size, 0xF9 with a variable one
+        if bytes[idx] != 0xFF || bytes[idx + 1] & 0xFE != 0xF8 {
+            continue;
+        }
+        let candidate = offset + idx as u64;
+        file.seek(SeekFrom::Start(candidate))?;
+        let mut frames = FrameReader::new(BufferedReader::new(&mut *file));
+        if let Ok(Some(block)) = frames.read_next_or_eof(Vec::<i32>::new()) {
+            //claxon counts the frame number of a fixed block size stream in blocks of this
+            //frame's size, a short last frame would be placed too early
+            let time = match bytes[idx + 1] {
+                0xF8 => block.time() / block.duration() as u64 * block_size,
+                _ => block.time(),
+            };
+            return Ok(Some((candidate, time)));
+        }
+    }
+    Ok(None)
+}
+
+fn read_up_to(file: &mut File, bytes: &mut [u8]) -> Result<usize> {
+    let mut nof_read = 0;
+    while nof_read < bytes.len() {
+        match file.read(&mut bytes[nof_read..])? {
+            0 => break,
+            n => nof_read += n,
+        }
+    }
+    Ok(nof_read)
+}
+
+//Frames in the complete FLAC frames of a file
+pub fn count_flac_frames(path: &str) -> Result<u64> {
+    let mut reader = claxon::FlacReader::open(path)?;
+    let mut blocks = reader.blocks();
+    let mut buffer = Vec::<i32>::new();
+    let mut nof_frames = 0;
+    //a cut off frame at the end is where the take ends
+    while let Ok(Some(block)) = blocks.read_next_or_eof(buffer) {
+        nof_frames += block.duration() as u64;
+        buffer = block.into_buffer();
+    }
+    Ok(nof_frames)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLOCK: u64 = 4_096; //FlacWriter's block size
+
+    //24-bit mono take whose samples count the frames, 200.5 blocks long
+    fn counting_flac(name: &str) -> (String, u64) {
+        let path = std::env::temp_dir().join(format!(
+            "example2-seek-{}-{}.flac",
+            name,
+            std::process::id()
+        ));
+        let path = path.to_str().unwrap().to_string();
+        let nof_frames = 200 * BLOCK + BLOCK / 2;
+        let mut writer = FlacWriter::create(&path, 48_000, 1, 24, &[]).unwrap();
+        for idx in 0..nof_frames {
+            writer.write_frame(&[idx as i32]).unwrap();
+        }
+        writer.finalize().unwrap();
+        (path, nof_frames)
+    }
+
+    fn frame_at(reader: &mut TakeReader, frame: u64) -> Option<u64> {
+        let mut samples = Vec::<f32>::new();
+        reader.seek(frame).unwrap();
+        match reader.read_frame(&mut samples).unwrap() {
+            true => Some((samples[0] * (1 << 23) as f32).round() as u64),
+            false => None,
+        }
+    }
+
+    #[test]
+    fn seeks_flac_takes() {
+        let (path, nof_frames) = counting_flac("any");
+        let mut reader = TakeReader::open(&path).unwrap();
+        assert_eq!(reader.duration(), nof_frames);
+        //block starts and ends, inside blocks, the short last block, backwards
+        let targets = [
+            0,
+            1,
+            BLOCK - 1,
+            BLOCK,
+            123 * BLOCK + 77,
+            nof_frames - 1,
+            5 * BLOCK + 4_000,
+            BLOCK / 2,
+            200 * BLOCK,
+            199 * BLOCK + 1,
+        ];
+        for target in targets {
+            assert_eq!(
+                frame_at(&mut reader, target),
+                Some(target),
+                "seek to {}",
+                target
+            );
+        }
+        assert_eq!(frame_at(&mut reader, nof_frames), None);
+        assert_eq!(frame_at(&mut reader, nof_frames + BLOCK * 10), None);
+
+        //reading on after a seek crosses into the next blocks
+        reader.seek(17 * BLOCK - 2).unwrap();
+        let mut samples = Vec::<f32>::new();
+        for expected in 17 * BLOCK - 2..17 * BLOCK + 3 {
+            assert!(reader.read_frame(&mut samples).unwrap());
+            assert_eq!((samples[0] * (1 << 23) as f32).round() as u64, expected);
+        }
+        std::fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn reports_corrupt_flac_takes_as_flac_errors() {
+        let (path, _) = counting_flac("corrupt");
+        let mut bytes = std::fs::read(&path).unwrap();
+        let middle = bytes.len() / 2;
+        bytes[middle] ^= 0x55;
+        std::fs::write(&path, bytes).unwrap();
+
+        let mut reader = TakeReader::open(&path).unwrap();
+        let mut samples = Vec::<f32>::new();
+        let error = loop {
+            match reader.read_frame(&mut samples) {
+                Ok(true) => continue,
+                Ok(false) => panic!("the corrupt frame decoded"),
+                Err(e) => break e,
+            }
+        };
+        assert!(matches!(error, RecorderError::Flac(_)));
+        assert!(error.to_string().starts_with("flac error"), "{}", error);
+        std::fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn seeks_flac_takes_being_written() {
+        //no total in STREAMINFO and a frame cut off at the end
+        let (path, _) = counting_flac("cut");
+        let len = std::fs::metadata(&path).unwrap().len();
+        let file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(len - 100).unwrap();
+        let mut file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .unwrap();
+        crate::flac::write_total_samples(&mut file, 0).unwrap();
+        drop(file);
+
+        let mut reader = TakeReader::open(&path).unwrap();
+        assert_eq!(reader.duration(), 200 * BLOCK);
+        assert_eq!(
+            frame_at(&mut reader, 150 * BLOCK + 9),
+            Some(150 * BLOCK + 9)
+        );
+        assert_eq!(
+            frame_at(&mut reader, 200 * BLOCK - 1),
+            Some(200 * BLOCK - 1)
+        );
+        assert_eq!(frame_at(&mut reader, 200 * BLOCK + 5), None);
+        std::fs::remove_file(path).ok();
+    }
+}
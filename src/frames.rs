@@ -0,0 +1,151 @@
+use ringbuf::{Consumer, Producer, RingBuffer};
+
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+
+// Single producer/single consumer ring buffer of interleaved frames. Both ends only
+// move whole frames and never block or allocate, so either end can live on an audio thread.
+
+pub struct FrameSender<T> {
+    producer: Producer<T>,
+    nof_channels: usize,
+    pushed: u64,              //frames pushed since the ring was created
+    overruns: Arc<AtomicU64>, //frames dropped because the ring was full
+    sender_alive: Arc<AtomicBool>,
+    receiver_alive: Arc<AtomicBool>,
+}
+
+pub struct FrameReceiver<T> {
+    consumer: Consumer<T>,
+    nof_channels: usize,
+    underruns: Arc<AtomicU64>, //frames requested while the ring was empty
+    sender_alive: Arc<AtomicBool>,
+    receiver_alive: Arc<AtomicBool>,
+}
+
+pub fn frame_channel<T: Copy>(
+    nof_channels: usize,
+    capacity_frames: usize,
+) -> (FrameSender<T>, FrameReceiver<T>) {
+    let (producer, consumer) = RingBuffer::<T>::new(nof_channels * capacity_frames).split();
+    let sender_alive = Arc::new(AtomicBool::new(true));
+    let receiver_alive = Arc::new(AtomicBool::new(true));
+    (
+        FrameSender {
+            producer: producer,
+            nof_channels: nof_channels,
+            pushed: 0,
+            overruns: Arc::new(AtomicU64::new(0)),
+            sender_alive: sender_alive.clone(),
+            receiver_alive: receiver_alive.clone(),
+        },
+        FrameReceiver {
+            consumer: consumer,
+            nof_channels: nof_channels,
+            underruns: Arc::new(AtomicU64::new(0)),
+            sender_alive: sender_alive,
+            receiver_alive: receiver_alive,
+        },
+    )
+}
+
+impl<T: Copy> FrameSender<T> {
+    //Pushes as many whole frames of data as fit, the rest is counted as overrun.
+    pub fn push_frames(&mut self, data: &[T]) -> usize {
+        let nof_frames = data.len() / self.nof_channels;
+        let fits = std::cmp::min(nof_frames, self.free_frames());
+        self.producer.push_slice(&data[..fits * self.nof_channels]);
+        self.pushed += fits as u64;
+        if fits < nof_frames {
+            self.overruns
+                .fetch_add((nof_frames - fits) as u64, Ordering::Relaxed);
+        }
+        fits
+    }
+
+    pub fn free_frames(&self) -> usize {
+        self.producer.remaining() / self.nof_channels
+    }
+
+    pub fn queued_frames(&self) -> usize {
+        self.producer.len() / self.nof_channels
+    }
+
+    //Frames pushed so far, the position in the ring of the next frame pushed
+    pub fn get_pushed(&self) -> u64 {
+        self.pushed
+    }
+
+    pub fn get_nof_channels(&self) -> usize {
+        self.nof_channels
+    }
+
+    pub fn get_overruns(&self) -> Arc<AtomicU64> {
+        self.overruns.clone()
+    }
+
+    //counts overruns into a counter shared with other senders
+    pub fn set_overrun_counter(&mut self, overruns: Arc<AtomicU64>) {
+        self.overruns = overruns;
+    }
+
+    //false once the receiving end was dropped
+    pub fn is_connected(&self) -> bool {
+        self.receiver_alive.load(Ordering::SeqCst)
+    }
+}
+
+impl<T: Copy> FrameReceiver<T> {
+    //Pops up to out.len() / nof_channels whole frames, returns the number of frames popped.
+    pub fn pop_frames(&mut self, out: &mut [T]) -> usize {
+        let nof_frames = std::cmp::min(out.len() / self.nof_channels, self.available_frames());
+        self.consumer
+            .pop_slice(&mut out[..nof_frames * self.nof_channels]);
+        nof_frames
+    }
+
+    //Like pop_frames but counts the frames that could not be delivered as underrun.
+    pub fn pop_frames_counted(&mut self, out: &mut [T]) -> usize {
+        let nof_frames = self.pop_frames(out);
+        let requested = out.len() / self.nof_channels;
+        if nof_frames < requested {
+            self.underruns
+                .fetch_add((requested - nof_frames) as u64, Ordering::Relaxed);
+        }
+        nof_frames
+    }
+
+    pub fn available_frames(&self) -> usize {
+        self.consumer.len() / self.nof_channels
+    }
+
+    pub fn get_nof_channels(&self) -> usize {
+        self.nof_channels
+    }
+
+    pub fn get_underruns(&self) -> Arc<AtomicU64> {
+        self.underruns.clone()
+    }
+
+    //true once the sender was dropped, the frames still queued can be read
+    pub fn is_closed(&self) -> bool {
+        !self.sender_alive.load(Ordering::SeqCst)
+    }
+
+    //true once the sender was dropped and every frame has been read
+    pub fn is_finished(&self) -> bool {
+        !self.sender_alive.load(Ordering::SeqCst) && self.consumer.is_empty()
+    }
+}
+
+impl<T> Drop for FrameSender<T> {
+    fn drop(&mut self) {
+        self.sender_alive.store(false, Ordering::SeqCst);
+    }
+}
+
+impl<T> Drop for FrameReceiver<T> {
+    fn drop(&mut self) {
+        self.receiver_alive.store(false, Ordering::SeqCst);
+    }
+}
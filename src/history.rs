@@ -0,0 +1,163 @@
+use serde::{Deserialize, Serialize};
+
+use std::ops::Range;
+use std::time::{Duration, Instant};
+
+use crate::metronome::MetronomeSettings;
+use crate::mixer::PanLaw;
+use crate::session::TrackState;
+
+// Undo history of a session. Every change the user makes to the router or its tracks is a
+// command that holds the state of what it changed before and after, undoing a command puts
+// the state before it back and redoing it the state after. Changes of the same thing in
+// quick succession, like the steps of a fader drag, are merged into one command. See
+// Router::edit.
+
+const MERGE_SECS: f64 = 1.0;
+
+//State of what a command changed
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum Change {
+    Track {
+        before: Box<TrackState>,
+        after: Box<TrackState>,
+    },
+    NewTrack {
+        track: TrackState, //as it was after the command
+        in_bus_id: u8,
+        out_bus_id: u8,
+    },
+    Cycle {
+        before: Option<Range<u64>>,
+        after: Option<Range<u64>>,
+    },
+    Metronome {
+        before: MetronomeSettings,
+        after: MetronomeSettings,
+    },
+    PanLaw {
+        before: PanLaw,
+        after: PanLaw,
+    },
+}
+
+impl Change {
+    //Whether other changes the same thing, so the two can be merged
+    fn is_same_target(&self, other: &Change) -> bool {
+        match (self, other) {
+            (Change::Track { before, .. }, Change::Track { before: other, .. }) => {
+                before.id == other.id
+            }
+            (Change::Cycle { .. }, Change::Cycle { .. }) => true,
+            (Change::Metronome { .. }, Change::Metronome { .. }) => true,
+            (Change::PanLaw { .. }, Change::PanLaw { .. }) => true,
+            _ => false,
+        }
+    }
+
+    //other continued this change, keeps the state before this one and the one after other
+    fn merge(&mut self, other: Change) {
+        match (self, other) {
+            (Change::Track { after, .. }, Change::Track { after: other, .. }) => *after = other,
+            (Change::Cycle { after, .. }, Change::Cycle { after: other, .. }) => *after = other,
+            (Change::Metronome { after, .. }, Change::Metronome { after: other, .. }) => {
+                *after = other
+            }
+            (Change::PanLaw { after, .. }, Change::PanLaw { after: other, .. }) => *after = other,
+            _ => (),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Command {
+    pub name: String, //what the user did, shown as "Undo {name}"
+    pub changes: Vec<Change>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct History {
+    undo: Vec<Command>,
+    redo: Vec<Command>,
+    #[serde(skip)]
+    last_push: Option<Instant>, //for merging
+    #[serde(skip)]
+    paused: u32, //nested edits and undo do not record commands of their own
+}
+
+impl History {
+    pub fn new() -> History {
+        History::default()
+    }
+
+    //Adds a command that was just done, nothing can be redone after it
+    pub fn push(&mut self, command: Command) {
+        if command.changes.is_empty() {
+            return;
+        }
+        self.redo.clear();
+        let now = Instant::now();
+        let recent = self
+            .last_push
+            .map(|t| now.duration_since(t) < Duration::from_secs_f64(MERGE_SECS))
+            .unwrap_or(false);
+        self.last_push = Some(now);
+        if let Some(last) = self.undo.last_mut() {
+            let same_targets = last.changes.len() == command.changes.len()
+                && last
+                    .changes
+                    .iter()
+                    .zip(command.changes.iter())
+                    .all(|(a, b)| a.is_same_target(b));
+            if recent && last.name == command.name && same_targets {
+                for (change, next) in last.changes.iter_mut().zip(command.changes) {
+                    change.merge(next);
+                }
+                return;
+            }
+        }
+        self.undo.push(command);
+    }
+
+    //The command to undo, it moves to the redo list
+    pub fn take_undo(&mut self) -> Option<Command> {
+        let command = self.undo.pop()?;
+        self.redo.push(command.clone());
+        self.last_push = None;
+        Some(command)
+    }
+
+    //The command to redo, it moves back to the undo list
+    pub fn take_redo(&mut self) -> Option<Command> {
+        let command = self.redo.pop()?;
+        self.undo.push(command.clone());
+        self.last_push = None;
+        Some(command)
+    }
+
+    pub fn get_undo_name(&self) -> Option<String> {
+        self.undo.last().map(|c| c.name.clone())
+    }
+
+    pub fn get_redo_name(&self) -> Option<String> {
+        self.redo.last().map(|c| c.name.clone())
+    }
+
+    pub fn clear(&mut self) {
+        self.undo.clear();
+        self.redo.clear();
+        self.last_push = None;
+    }
+
+    pub fn pause(&mut self) {
+        self.paused += 1;
+    }
+
+    pub fn resume(&mut self) {
+        self.paused = self.paused.saturating_sub(1);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused > 0
+    }
+}
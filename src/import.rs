@@ -0,0 +1,88 @@
+use crate::error::Result;
+use crate::formats::{RecordFormat, TakeReader, TakeWriter};
+use crate::resample::{FrameResampler, RESAMPLE_CHUNK_FRAMES};
+use crate::wav::BextInfo;
+
+// Imports audio files (WAV in any format hound reads, RF64, FLAC) as takes. The file is
+// converted once to the session rate, the track's channel count and the record format,
+// so it plays, bounces and recovers like a recorded take.
+
+const IMPORT_BLOCK_FRAMES: usize = 4_096;
+
+//(channels, sample rate, frames) of an audio file
+pub fn probe(path: &str) -> Result<(u16, u32, u64)> {
+    let reader = TakeReader::open(path)?;
+    Ok((
+        reader.get_nof_channels(),
+        reader.get_sample_rate(),
+        reader.duration(),
+    ))
+}
+
+//Writes src converted to dest, returns the frames written
+pub fn convert_file(
+    src: &str,
+    dest: &str,
+    format: RecordFormat,
+    sample_rate: u32,
+    nof_channels: u16,
+    bext: &BextInfo,
+) -> Result<u64> {
+    let mut reader = TakeReader::open(src)?;
+    let src_channels = reader.get_nof_channels() as usize;
+    let mut resampler = FrameResampler::<f32>::new(
+        reader.get_sample_rate(),
+        sample_rate,
+        nof_channels as usize,
+        RESAMPLE_CHUNK_FRAMES,
+    )?;
+    let mut writer = TakeWriter::create::<f32>(dest, format, sample_rate, nof_channels, bext)?;
+
+    let mut frame = Vec::<f32>::with_capacity(src_channels);
+    let mut remixed = vec![0.0f32; nof_channels as usize];
+    let mut block = Vec::<f32>::with_capacity(IMPORT_BLOCK_FRAMES * nof_channels as usize);
+    let mut converted = Vec::<f32>::new();
+    let mut nof_frames = 0;
+    let mut finished = false;
+    while !finished {
+        block.clear();
+        while block.len() < IMPORT_BLOCK_FRAMES * remixed.len() {
+            if !reader.read_frame::<f32>(&mut frame)? {
+                finished = true;
+                break;
+            }
+            remix(&frame, &mut remixed);
+            block.extend_from_slice(&remixed);
+        }
+
+        converted.clear();
+        resampler.process(&block, &mut converted)?;
+        if finished {
+            resampler.flush(&mut converted)?;
+        }
+        for frame in converted.chunks(remixed.len()) {
+            writer.write_frame(frame)?;
+            nof_frames += 1;
+        }
+    }
+    writer.finalize()?;
+    Ok(nof_frames)
+}
+
+//Channel layout conversion. With more channels than the file the file's channels repeat
+//(mono goes to every channel), with fewer the file's channels are averaged into the channel
+//they wrap onto (everything into mono).
+fn remix(frame: &[f32], out: &mut [f32]) {
+    if frame.len() <= out.len() {
+        for (ch, sample) in out.iter_mut().enumerate() {
+            *sample = frame[ch % frame.len()];
+        }
+        return;
+    }
+    let nof_channels = out.len();
+    for (ch, sample) in out.iter_mut().enumerate() {
+        let wrapped = frame.iter().skip(ch).step_by(nof_channels);
+        let (sum, count) = wrapped.fold((0.0, 0), |(sum, count), s| (sum + s, count + 1));
+        *sample = sum / count as f32;
+    }
+}
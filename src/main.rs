@@ -1,30 +1,207 @@
 use cpal::traits::{DeviceTrait, HostTrait};
-use cpal::{Device, Host, HostId, StreamConfig, SupportedInputConfigs, SupportedOutputConfigs};
+use cpal::{Device, HostId};
 use std::borrow::Cow;
-use std::thread;
+use std::ops::Range;
+use std::time::Instant;
 
+mod backend;
 mod busses;
+mod calibration;
+mod cli;
+mod comp;
+mod error;
+mod flac;
+mod formats;
+mod frames;
+mod history;
+mod import;
+mod meters;
+mod metronome;
+mod mixer;
+mod peaks;
+mod recovery;
+mod regions;
+mod resample;
 mod router;
+mod session;
 mod tracks;
+mod transport;
 mod utils;
+//in-memory device for the tests, the app only opens the hosts cpal finds
+#[cfg(test)]
+mod virtual_backend;
+mod wav;
+mod waveform;
 
-use crate::router::Router;
+use crate::error::{RecorderError, Result};
+use crate::formats::RecordFormat;
+use crate::meters::{gain_to_db, MeterLevels};
+use crate::metronome::{BEAT_UNITS, MAX_TEMPO, MIN_TEMPO};
+use crate::mixer::{PanLaw, MAX_GAIN_DB, MIN_GAIN_DB};
+use crate::regions::{FadeCurve, Region};
+use crate::resample::convert_frames;
+use crate::router::{Bounce, Router};
+use crate::session::{sample_format_from_str, Session};
+use crate::tracks::{TakeSelection, Track};
+use crate::transport::TransportState;
+use crate::waveform::{Waveform, WaveformCache};
 
 use eframe::egui::containers::ScrollArea;
 use eframe::egui::containers::Window;
 use eframe::egui::{
-    Align, ComboBox, FontData, FontDefinitions, InnerResponse, Response, TextEdit, Vec2,
+    Color32, ComboBox, FontData, FontDefinitions, InnerResponse, Pos2, Rect, Response, Sense,
+    Stroke, Vec2,
 };
 use eframe::run_native;
 use eframe::NativeOptions;
 use eframe::{egui, epi};
 
+//Shows engine errors in a dialog instead of taking the session down.
+pub struct ErrorUi {
+    messages: Vec<String>,
+}
+
+impl ErrorUi {
+    fn new() -> Self {
+        Self {
+            messages: Vec::<String>::new(),
+        }
+    }
+
+    //Keeps the error for the dialog, returns the value on success
+    fn report<T>(&mut self, result: Result<T>) -> Option<T> {
+        match result {
+            Ok(v) => Some(v),
+            Err(e) => {
+                eprintln!("error: {}", e);
+                self.messages.push(e.to_string());
+                None
+            }
+        }
+    }
+
+    fn get_window(&mut self, ctx: &egui::CtxRef) {
+        if self.messages.is_empty() {
+            return;
+        }
+        let mut close = false;
+        Window::new("Error").collapsible(false).show(ctx, |ui| {
+            for msg in self.messages.iter() {
+                ui.label(msg);
+            }
+            if ui.button("OK").clicked() {
+                close = true;
+            }
+        });
+        if close {
+            self.messages.clear();
+        }
+    }
+}
+
+const METER_MIN_DB: f32 = -60.0;
+const METER_HOLD_SECS: f32 = 1.5;
+const TIMELINE_ROW_HEIGHT: f32 = 80.0;
+const TIMELINE_MIN_WIDTH: f32 = 200.0;
+const TIMELINE_MAX_FRAMES_PER_PX: f64 = 1_000_000.0;
+
+//Peak/RMS bars of one bus with peak hold and clip indicators, click to reset.
+pub struct MeterUi {
+    holds: Vec<(f32, Instant)>, //(held peak, time it was set) per channel
+}
+
+impl MeterUi {
+    fn new() -> Self {
+        Self {
+            holds: Vec::<(f32, Instant)>::new(),
+        }
+    }
+
+    fn show(&mut self, ui: &mut egui::Ui, levels: &MeterLevels) -> Response {
+        let nof_channels = levels.get_nof_channels();
+        self.holds.resize(nof_channels, (0.0, Instant::now()));
+
+        let (bar_height, spacing) = (6., 2.);
+        let height = nof_channels as f32 * (bar_height + spacing);
+        let (rect, response) = ui.allocate_exact_size(Vec2::new(160., height), Sense::click());
+        let painter = ui.painter();
+
+        let mut hover_txt = Vec::<String>::new();
+        for ch in 0..nof_channels {
+            let (peak, rms, clipped) = levels.get_level(ch);
+            let hold = &mut self.holds[ch];
+            if peak >= hold.0 || hold.1.elapsed().as_secs_f32() > METER_HOLD_SECS {
+                *hold = (peak, Instant::now());
+            }
+
+            let top = rect.top() + ch as f32 * (bar_height + spacing);
+            let bar = Rect::from_min_size(
+                Pos2::new(rect.left(), top),
+                Vec2::new(rect.width() - bar_height - spacing, bar_height),
+            );
+            let x = |gain: f32| bar.left() + bar.width() * meter_position(gain);
+            painter.rect_filled(bar, 0., Color32::from_gray(40));
+            painter.rect_filled(
+                Rect::from_min_max(bar.min, Pos2::new(x(peak), bar.bottom())),
+                0.,
+                Color32::from_rgb(30, 110, 30),
+            );
+            painter.rect_filled(
+                Rect::from_min_max(bar.min, Pos2::new(x(rms), bar.bottom())),
+                0.,
+                Color32::from_rgb(60, 200, 60),
+            );
+            painter.line_segment(
+                [
+                    Pos2::new(x(hold.0), bar.top()),
+                    Pos2::new(x(hold.0), bar.bottom()),
+                ],
+                Stroke::new(1., Color32::YELLOW),
+            );
+            let clip_color = match clipped {
+                true => Color32::RED,
+                false => Color32::from_gray(40),
+            };
+            painter.rect_filled(
+                Rect::from_min_size(
+                    Pos2::new(bar.right() + spacing, top),
+                    Vec2::splat(bar_height),
+                ),
+                0.,
+                clip_color,
+            );
+            hover_txt.push(format!(
+                "{}: peak {:.1} dB, rms {:.1} dB",
+                ch + 1,
+                gain_to_db(hold.0),
+                gain_to_db(rms)
+            ));
+        }
+
+        if response.clicked() {
+            levels.reset_clip();
+            self.holds.clear();
+        }
+        response.on_hover_text(hover_txt.join("\n"))
+    }
+}
+
+fn meter_position(gain: f32) -> f32 {
+    ((gain_to_db(gain) - METER_MIN_DB) / -METER_MIN_DB).clamp(0., 1.)
+}
+
 pub struct TrackUi {
     id: u8,
     name: String,
     is_monitored: bool,
     is_recorded: bool,
     state: (bool, bool), //(is_rec, is_monitored)
+    gain_db: f32,
+    pan: f32,
+    is_muted: bool,
+    is_soloed: bool,
+    meter: MeterUi,
+    takes: TakesUi,
 }
 
 impl Default for TrackUi {
@@ -35,12 +212,24 @@ impl Default for TrackUi {
             is_monitored: false,
             is_recorded: false,
             state: (false, false), //(is_rec, is_monitored)
+            gain_db: 0.0,
+            pan: 0.0,
+            is_muted: false,
+            is_soloed: false,
+            meter: MeterUi::new(),
+            takes: TakesUi::default(),
         }
     }
 }
 
 impl TrackUi {
-    fn new(id: u8, name: String, is_monitored: bool, is_recorded: bool) -> Self {
+    fn new(
+        id: u8,
+        name: String,
+        is_monitored: bool,
+        is_recorded: bool,
+        mix: (f32, f32, bool, bool), //(gain_db, pan, mute, solo)
+    ) -> Self {
         let state = (is_recorded, is_monitored);
         Self {
             id,
@@ -48,10 +237,35 @@ impl TrackUi {
             is_recorded,
             is_monitored,
             state,
+            gain_db: mix.0,
+            pan: mix.1,
+            is_muted: mix.2,
+            is_soloed: mix.3,
+            meter: MeterUi::new(),
+            takes: TakesUi::default(),
         }
     }
 
-    fn show(&mut self, ui: &mut eframe::egui::Ui, app_router: &mut router::Router<f32>) {
+    //Takes over the state of the track, which may have changed without the widgets
+    fn sync(&mut self, track: &Track) {
+        let (_, _, is_recorded, is_monitored) = track.as_tup();
+        let (gain_db, pan, is_muted, is_soloed) = track.get_mix_params().as_tup();
+        self.is_recorded = is_recorded;
+        self.is_monitored = is_monitored;
+        self.state = (is_recorded, is_monitored);
+        self.gain_db = gain_db;
+        self.pan = pan;
+        self.is_muted = is_muted;
+        self.is_soloed = is_soloed;
+    }
+
+    fn show(
+        &mut self,
+        ui: &mut eframe::egui::Ui,
+        app_router: &mut router::Router<f32>,
+        timeline: &mut TimelineUi,
+        errors: &mut ErrorUi,
+    ) {
         ui.horizontal(|ui| {
             ui.label(format!("{}.", self.id.to_string()));
             ui.vertical(|ui| {
@@ -59,21 +273,58 @@ impl TrackUi {
                 ui.horizontal(|ui| {
                     ui.checkbox(&mut self.is_monitored, "Monitored");
                     ui.checkbox(&mut self.is_recorded, "Rec.");
+                    if ui.button("Takes").clicked() {
+                        self.takes.open = true;
+                    }
                 });
+                self.show_mix(ui, app_router, errors);
             });
+            if let Some(levels) = app_router.get_input_meter(self.id) {
+                self.meter.show(ui, &levels);
+            }
+            timeline.show_row(ui, app_router, self.id, errors);
+        });
+        self.apply_changes(app_router, errors);
+        self.takes
+            .get_window(ui.ctx(), self.id, &self.name, app_router, errors);
+    }
+
+    //Fader, pan, mute and solo are applied by the mix threads on their next block
+    fn show_mix(
+        &mut self,
+        ui: &mut eframe::egui::Ui,
+        app_router: &mut router::Router<f32>,
+        errors: &mut ErrorUi,
+    ) {
+        ui.horizontal(|ui| {
+            let gain = egui::Slider::new(&mut self.gain_db, MIN_GAIN_DB..=MAX_GAIN_DB)
+                .suffix(" dB")
+                .text("Gain");
+            if ui.add(gain).changed() {
+                errors.report(app_router.set_gain(self.id, self.gain_db));
+            }
+            let pan = egui::Slider::new(&mut self.pan, -1.0..=1.0).text("Pan");
+            if ui.add(pan).changed() {
+                errors.report(app_router.set_pan(self.id, self.pan));
+            }
+            if ui.checkbox(&mut self.is_muted, "M").changed() {
+                errors.report(app_router.set_mute(self.id, self.is_muted));
+            }
+            if ui.checkbox(&mut self.is_soloed, "S").changed() {
+                errors.report(app_router.set_solo(self.id, self.is_soloed));
+            }
         });
-        self.apply_changes(app_router);
     }
 
-    fn apply_changes(&mut self, app_router: &mut router::Router<f32>) {
+    fn apply_changes(&mut self, app_router: &mut router::Router<f32>, errors: &mut ErrorUi) {
         let (rec_changed, monitor_changed) = self.get_changed();
         if rec_changed {
-            app_router.set_recording(self.id, self.is_recorded);
+            errors.report(app_router.set_recording(self.id, self.is_recorded));
         }
         if monitor_changed {
-            app_router.set_monitor(self.id, self.is_monitored);
+            errors.report(app_router.set_monitor(self.id, self.is_monitored));
             app_router.stop_monitor();
-            app_router.monitor();
+            errors.report(app_router.monitor());
 
             // if self.is_monitored {
             //     app_router.stop_monitor();
@@ -100,9 +351,469 @@ impl TrackUi {
     }
 }
 
+//Take list of a track: choose what it plays, audition, rename and delete takes and build
+//a comp from ranges of them
+pub struct TakesUi {
+    open: bool,
+    comp_range: (f64, f64), //(start, end) in seconds of the range the Comp buttons place
+}
+
+impl Default for TakesUi {
+    fn default() -> Self {
+        Self {
+            open: false,
+            comp_range: (0.0, 0.0),
+        }
+    }
+}
+
+impl TakesUi {
+    fn get_window(
+        &mut self,
+        ctx: &egui::CtxRef,
+        track_id: u8,
+        track_name: &str,
+        app_router: &mut Router<f32>,
+        errors: &mut ErrorUi,
+    ) {
+        let track = match app_router.get_track(track_id) {
+            Ok(t) => t,
+            Err(_) => return,
+        };
+        let (takes, comp, regions) = (track.get_files(), track.get_comp(), track.get_regions());
+        let (selection, audition) = (track.get_selection(), track.get_audition());
+        let sample_rate = app_router.get_sample_rate() as f64;
+        let comp_range = &mut self.comp_range;
+
+        Window::new(format!("Takes: {}", track_name))
+            .open(&mut self.open)
+            .show(ctx, |ui| {
+                let latest = selection == TakeSelection::Latest;
+                if ui.radio(latest, "Latest take").clicked() {
+                    errors.report(app_router.select_take(track_id, TakeSelection::Latest));
+                }
+                for take in takes.iter() {
+                    ui.horizontal(|ui| {
+                        let selected = selection == TakeSelection::Take(take.file.clone());
+                        if ui.radio(selected, "").clicked() {
+                            let selection = TakeSelection::Take(take.file.clone());
+                            errors.report(app_router.select_take(track_id, selection));
+                        }
+                        let mut name = take.get_name();
+                        if ui.text_edit_singleline(&mut name).changed() {
+                            errors.report(app_router.rename_take(track_id, &take.file, name));
+                        }
+                        ui.label(format!("at {:.3} s", take.start_frame as f64 / sample_rate));
+                        let auditioned = audition.as_ref() == Some(&take.file);
+                        if ui.selectable_label(auditioned, "Audition").clicked() {
+                            let file = match auditioned {
+                                true => None,
+                                false => Some(take.file.clone()),
+                            };
+                            errors.report(app_router.audition_take(track_id, file));
+                        }
+                        if ui.button("Comp").clicked() {
+                            let start = (comp_range.0 * sample_rate) as u64;
+                            let end = (comp_range.1 * sample_rate) as u64;
+                            errors.report(app_router.comp_take(track_id, &take.file, start..end));
+                        }
+                        if ui.button("Delete").clicked() {
+                            errors.report(app_router.delete_take(track_id, &take.file));
+                        }
+                    });
+                }
+                ui.horizontal(|ui| {
+                    ui.label("Comp range:");
+                    ui.add(
+                        egui::DragValue::new(&mut comp_range.0)
+                            .clamp_range(0.0..=86_400.0)
+                            .speed(0.1)
+                            .suffix(" s"),
+                    );
+                    ui.add(
+                        egui::DragValue::new(&mut comp_range.1)
+                            .clamp_range(0.0..=86_400.0)
+                            .speed(0.1)
+                            .suffix(" s"),
+                    );
+                });
+
+                ui.separator();
+                if !comp.is_empty() {
+                    let comped = selection == TakeSelection::Comp;
+                    if ui.radio(comped, "Comp").clicked() {
+                        errors.report(app_router.select_take(track_id, TakeSelection::Comp));
+                    }
+                    for region in comp.iter() {
+                        let name = takes
+                            .iter()
+                            .find(|t| t.file == region.take)
+                            .map(|t| t.get_name())
+                            .unwrap_or_default();
+                        ui.label(format!(
+                            "{:.3} s - {:.3} s: {}",
+                            region.start_frame as f64 / sample_rate,
+                            region.end_frame as f64 / sample_rate,
+                            name
+                        ));
+                    }
+                    if ui.button("Clear Comp").clicked() {
+                        errors.report(app_router.clear_comp(track_id));
+                    }
+                    ui.separator();
+                }
+
+                ui.horizontal(|ui| {
+                    if !regions.is_empty() {
+                        let edited = selection == TakeSelection::Regions;
+                        if ui.radio(edited, "Regions").clicked() {
+                            errors.report(app_router.select_take(track_id, TakeSelection::Regions));
+                        }
+                    }
+                    if selection != TakeSelection::Regions && ui.button("Edit as Regions").clicked()
+                    {
+                        errors.report(app_router.regions_from_selection(track_id));
+                    }
+                });
+                for region in regions.iter() {
+                    let name = takes
+                        .iter()
+                        .find(|t| t.file == region.take)
+                        .map(|t| t.get_name())
+                        .unwrap_or_default();
+                    show_region(ui, app_router, track_id, region, &name, errors);
+                }
+            });
+    }
+}
+
+//Rows of a region in the takes window, every change is an edit of the region
+fn show_region(
+    ui: &mut egui::Ui,
+    app_router: &mut Router<f32>,
+    track_id: u8,
+    region: &Region,
+    name: &str,
+    errors: &mut ErrorUi,
+) {
+    let sample_rate = app_router.get_sample_rate() as f64;
+    let to_secs = |frames: u64| frames as f64 / sample_rate;
+    let to_frames = |secs: f64| (secs.max(0.0) * sample_rate) as u64;
+    let id = region.id;
+    ui.horizontal(|ui| {
+        ui.label(format!("{}: {}", id, name));
+        let mut position = to_secs(region.position);
+        let moved = ui.add(
+            egui::DragValue::new(&mut position)
+                .clamp_range(0.0..=86_400.0)
+                .speed(0.01)
+                .prefix("at ")
+                .suffix(" s"),
+        );
+        if moved.changed() {
+            errors.report(app_router.move_region(track_id, id, to_frames(position)));
+        }
+        let (mut start, mut end) = (to_secs(region.position), to_secs(region.end()));
+        ui.label("Trim:");
+        let trimmed_start = ui.add(
+            egui::DragValue::new(&mut start)
+                .clamp_range(0.0..=86_400.0)
+                .speed(0.01)
+                .suffix(" s"),
+        );
+        let trimmed_end = ui.add(
+            egui::DragValue::new(&mut end)
+                .clamp_range(0.0..=86_400.0)
+                .speed(0.01)
+                .suffix(" s"),
+        );
+        if trimmed_start.changed() || trimmed_end.changed() {
+            let range = to_frames(start)..to_frames(end);
+            errors.report(app_router.trim_region(track_id, id, range));
+        }
+        let mut gain_db = region.gain_db;
+        let gained = ui.add(
+            egui::DragValue::new(&mut gain_db)
+                .clamp_range(MIN_GAIN_DB..=MAX_GAIN_DB)
+                .speed(0.1)
+                .suffix(" dB"),
+        );
+        if gained.changed() {
+            errors.report(app_router.set_region_gain(track_id, id, gain_db));
+        }
+    });
+    ui.horizontal(|ui| {
+        let (mut fade_in, mut fade_out) = (region.fade_in, region.fade_out);
+        let mut fades_changed = false;
+        for (label, fade) in [("Fade in:", &mut fade_in), ("Fade out:", &mut fade_out)] {
+            ui.label(label);
+            let mut ms = fade.frames as f64 * 1000.0 / sample_rate;
+            let faded = ui.add(
+                egui::DragValue::new(&mut ms)
+                    .clamp_range(0.0..=60_000.0)
+                    .speed(1.0)
+                    .suffix(" ms"),
+            );
+            if faded.changed() {
+                fade.frames = to_frames(ms / 1000.0);
+                fades_changed = true;
+            }
+            let curve = fade.curve;
+            ComboBox::from_id_source((label, track_id, id))
+                .selected_text(format!("{:?}", fade.curve))
+                .show_ui(ui, |ui| {
+                    for c in FadeCurve::ALL.iter() {
+                        ui.selectable_value(&mut fade.curve, *c, format!("{:?}", c));
+                    }
+                });
+            fades_changed |= fade.curve != curve;
+        }
+        if fades_changed {
+            errors.report(app_router.set_region_fades(track_id, id, fade_in, fade_out));
+        }
+
+        if ui.button("Split at Playhead").clicked() {
+            let playhead = app_router.get_playhead();
+            errors.report(app_router.split_region(track_id, id, playhead));
+        }
+        if ui.button("Duplicate").clicked() {
+            errors.report(app_router.duplicate_region(track_id, id, region.end()));
+        }
+        if ui.button("Delete").clicked() {
+            errors.report(app_router.delete_region(track_id, id));
+        }
+    });
+}
+
+//Waveforms of what the tracks play, one row per track. The rows share the view so they
+//scroll and zoom together, dragging pans it and clicking locates the playhead.
+pub struct TimelineUi {
+    view_start: f64,    //timeline frame at the left edge
+    frames_per_px: f64, //zoom
+    follow: bool,       //pages along with the playhead while the transport rolls
+    row_width: f32,     //of the last row drawn, for following
+    waveforms: WaveformCache,
+}
+
+impl TimelineUi {
+    fn new() -> Self {
+        Self {
+            view_start: 0.0,
+            frames_per_px: 500.0,
+            follow: true,
+            row_width: 0.0,
+            waveforms: WaveformCache::new(),
+        }
+    }
+
+    fn show_controls(&mut self, ui: &mut egui::Ui, rout: &Router<f32>) {
+        let files: Vec<String> = rout
+            .get_tracks()
+            .iter()
+            .flat_map(|t| t.get_files().into_iter().map(|take| take.file))
+            .collect();
+        self.waveforms.retain(&files);
+
+        let view_frames = self.row_width as f64 * self.frames_per_px;
+        let playhead = rout.get_playhead() as f64;
+        let outside = playhead < self.view_start || playhead >= self.view_start + view_frames;
+        let state = rout.get_transport_state();
+        let rolling = state == TransportState::Playing || state == TransportState::Recording;
+        if self.follow && rolling && outside {
+            self.view_start = playhead;
+        }
+
+        let sample_rate = rout.get_sample_rate() as f64;
+        ui.horizontal(|ui| {
+            ui.label("Timeline:");
+            if ui.button("-").clicked() {
+                self.frames_per_px = (self.frames_per_px * 2.0).min(TIMELINE_MAX_FRAMES_PER_PX);
+            }
+            if ui.button("+").clicked() {
+                self.frames_per_px = (self.frames_per_px / 2.0).max(1.0);
+            }
+            if ui.button("|<").clicked() {
+                self.view_start = 0.0;
+            }
+            ui.checkbox(&mut self.follow, "Follow");
+            ui.label(format!(
+                "{:.1} s - {:.1} s",
+                self.view_start / sample_rate,
+                (self.view_start + view_frames) / sample_rate
+            ));
+        });
+    }
+
+    fn show_row(
+        &mut self,
+        ui: &mut egui::Ui,
+        rout: &mut Router<f32>,
+        track_id: u8,
+        errors: &mut ErrorUi,
+    ) {
+        let width = ui.available_width().max(TIMELINE_MIN_WIDTH);
+        let (rect, response) = ui.allocate_exact_size(
+            Vec2::new(width, TIMELINE_ROW_HEIGHT),
+            Sense::click_and_drag(),
+        );
+        self.row_width = width;
+        if response.dragged() {
+            let frames = response.drag_delta().x as f64 * self.frames_per_px;
+            self.view_start = (self.view_start - frames).max(0.0);
+        }
+        let x_to_frame = |x: f32| self.view_start + (x - rect.left()) as f64 * self.frames_per_px;
+        if response.clicked() {
+            if let Some(pos) = response.interact_pointer_pos() {
+                errors.report(rout.locate(x_to_frame(pos.x) as u64));
+            }
+        }
+
+        let painter = ui.painter_at(rect);
+        painter.rect_filled(rect, 0., Color32::from_gray(25));
+        let frame_to_x = |frame: u64| {
+            rect.left() + ((frame as f64 - self.view_start) / self.frames_per_px) as f32
+        };
+        if let Some(cycle) = rout.get_cycle() {
+            let cycle_rect = Rect::from_x_y_ranges(
+                frame_to_x(cycle.start)..=frame_to_x(cycle.end),
+                rect.y_range(),
+            );
+            painter.rect_filled(cycle_rect, 0., Color32::from_gray(40));
+        }
+
+        let track = match rout.get_track(track_id) {
+            Ok(t) => t,
+            Err(_) => return,
+        };
+        let (sample_rate, view) = (
+            rout.get_sample_rate(),
+            (self.view_start, self.frames_per_px),
+        );
+        let nof_columns = width as usize;
+        //(min, max) per pixel column
+        let mut columns = vec![None; nof_columns];
+        let color = match track.get_live_take() {
+            //only the take being recorded, the others do not play meanwhile
+            Some(live) => {
+                let live = live.lock().unwrap();
+                let start = live.take.start_frame;
+                let range = start..u64::MAX;
+                add_columns(
+                    &mut columns,
+                    view,
+                    &live.waveform,
+                    start as i64,
+                    range,
+                    sample_rate,
+                );
+                Color32::from_rgb(200, 60, 60)
+            }
+            None => {
+                for (file, start, range) in playing_takes(track) {
+                    if let Some(waveform) = self.waveforms.get(&file) {
+                        add_columns(&mut columns, view, waveform, start, range, sample_rate);
+                    }
+                }
+                Color32::from_rgb(60, 200, 60)
+            }
+        };
+
+        let (center, half) = (rect.center().y, rect.height() / 2.);
+        for (idx, column) in columns.iter().enumerate() {
+            if let Some((min, max)) = column {
+                let x = rect.left() + idx as f32 + 0.5;
+                painter.line_segment(
+                    [
+                        Pos2::new(x, center - max.clamp(-1., 1.) * half),
+                        Pos2::new(x, center - min.clamp(-1., 1.) * half + 1.),
+                    ],
+                    Stroke::new(1., color),
+                );
+            }
+        }
+        let x = frame_to_x(rout.get_playhead());
+        painter.line_segment(
+            [Pos2::new(x, rect.top()), Pos2::new(x, rect.bottom())],
+            Stroke::new(1., Color32::YELLOW),
+        );
+    }
+}
+
+//Merges the peaks of a take that starts at timeline frame take_start and plays in range
+//into the columns of view (first frame, frames per column), timeline frames are at
+//sample_rate. A region may play a take that would start before frame 0.
+fn add_columns(
+    columns: &mut [Option<(f32, f32)>],
+    view: (f64, f64),
+    waveform: &Waveform,
+    take_start: i64,
+    range: Range<u64>,
+    sample_rate: u32,
+) {
+    let take_rate = waveform.get_sample_rate();
+    let first = std::cmp::max(range.start, take_start.max(0) as u64);
+    for (idx, column) in columns.iter_mut().enumerate() {
+        let from = view.0 + idx as f64 * view.1;
+        let from = std::cmp::max(from as u64, first);
+        let to = view.0 + (idx + 1) as f64 * view.1;
+        let to = std::cmp::min(to.ceil() as u64, range.end);
+        if from >= to {
+            continue;
+        }
+        let (from, to) = (
+            (from as i64 - take_start) as u64,
+            (to as i64 - take_start) as u64,
+        );
+        let take_frames = convert_frames(from, sample_rate, take_rate)
+            ..convert_frames(to, sample_rate, take_rate) + 1;
+        if let Some((min, max)) = waveform.get_peak(take_frames) {
+            *column = match *column {
+                Some((c_min, c_max)) => Some((c_min.min(min), c_max.max(max))),
+                None => Some((min, max)),
+            };
+        }
+    }
+}
+
+//(take file, timeline frame the take starts at, timeline range it plays in) of what a
+//track plays live: an auditioned take, the selected one, the takes of the comp regions or
+//the regions
+fn playing_takes(track: &Track) -> Vec<(String, i64, Range<u64>)> {
+    let files = track.get_files();
+    let find = |file: &str| files.iter().find(|t| t.file == file).cloned();
+    let selection = match track.get_audition() {
+        Some(file) => TakeSelection::Take(file),
+        None => track.get_selection(),
+    };
+    let take = match selection {
+        TakeSelection::Latest => files.last().cloned(),
+        TakeSelection::Take(file) => find(&file),
+        TakeSelection::Comp => {
+            return track
+                .get_comp()
+                .iter()
+                .filter_map(|r| find(&r.take).map(|t| (t, r.start_frame..r.end_frame)))
+                .map(|(t, range)| (t.file, t.start_frame as i64, range))
+                .collect();
+        }
+        TakeSelection::Regions => {
+            return track
+                .get_regions()
+                .iter()
+                .map(|r| (r.take.clone(), r.take_start(), r.position..r.end()))
+                .collect();
+        }
+    };
+    match take {
+        Some(t) => vec![(t.file, t.start_frame as i64, 0..u64::MAX)],
+        None => Vec::new(),
+    }
+}
+
 pub struct TrackListUi {
     add_track_window: AddTrack,
     track_list: Vec<TrackUi>,
+    timeline: TimelineUi,
 }
 
 impl TrackListUi {
@@ -110,18 +821,26 @@ impl TrackListUi {
         Self {
             add_track_window: AddTrack::default(),
             track_list: Vec::<TrackUi>::new(),
+            timeline: TimelineUi::new(),
         }
     }
 
+    //Follows the router's tracks, they change with undo and redo as well
     fn update_track_lst(&mut self, app_router: &Router<f32>) {
         let current_lst = app_router.get_tracks();
+        self.track_list
+            .retain(|x| current_lst.iter().any(|t| t.as_tup().0 == x.id));
         for item in current_lst {
             let t_as_tup = item.as_tup(); //(id, name, is_rec, is_monitored)
-            match self.track_list.iter().find(|x| x.id == t_as_tup.0) {
-                Some(_) => continue,
-                None => self
-                    .track_list
-                    .push(TrackUi::new(t_as_tup.0, t_as_tup.1, t_as_tup.2, t_as_tup.3)),
+            match self.track_list.iter_mut().find(|x| x.id == t_as_tup.0) {
+                Some(track_ui) => track_ui.sync(item),
+                None => self.track_list.push(TrackUi::new(
+                    t_as_tup.0,
+                    t_as_tup.1,
+                    t_as_tup.3,
+                    t_as_tup.2,
+                    item.get_mix_params().as_tup(),
+                )),
             }
         }
     }
@@ -131,21 +850,23 @@ impl TrackListUi {
         ctx: &egui::CtxRef,
         ui: &mut eframe::egui::Ui,
         app_router: &mut Option<Router<f32>>,
+        errors: &mut ErrorUi,
     ) {
         let rout = match app_router {
             Some(r) => r,
             None => return (),
         };
         self.update_track_lst(rout);
+        self.timeline.show_controls(ui, rout);
 
         ScrollArea::vertical()
             .auto_shrink([false; 2])
             .show(ui, |ui| {
                 for item in self.track_list.iter_mut() {
-                    item.show(ui, rout);
+                    item.show(ui, rout, &mut self.timeline, errors);
                 }
                 ui.separator();
-                self.add_track_window.get_window(ctx, rout);
+                self.add_track_window.get_window(ctx, rout, errors);
                 if ui.button("Add Track +").clicked() {
                     self.add_track_window.open = true;
                 }
@@ -164,6 +885,7 @@ impl Default for TrackListUi {
         Self {
             add_track_window: track_window,
             track_list: t_list,
+            timeline: TimelineUi::new(),
         }
     }
 }
@@ -202,6 +924,7 @@ impl AddTrack {
         &mut self,
         ctx: &egui::CtxRef,
         app_router: &mut Router<f32>,
+        errors: &mut ErrorUi,
     ) -> Option<InnerResponse<Option<()>>> {
         if self.selected_in_channels.is_empty() || self.selected_out_channels.is_empty() {
             self.update_selection_lst(app_router);
@@ -259,12 +982,12 @@ impl AddTrack {
                     )
                     .clicked()
                 {
-                    let (in_bus, out_bus) = (
-                        app_router.new_input_bus(in_chs),
-                        app_router.new_output_bus(out_chs),
-                    );
-                    app_router.new_track(self.track_name.clone(), in_bus, out_bus);
-                    close = true;
+                    let track_name = self.track_name.clone();
+                    let added = app_router.new_input_bus(in_chs).and_then(|in_bus| {
+                        let out_bus = app_router.new_output_bus(out_chs)?;
+                        app_router.new_track(track_name, in_bus, out_bus)
+                    });
+                    close = errors.report(added).is_some();
                 }
             });
 
@@ -299,21 +1022,27 @@ pub struct StudioSetup {
 
     selected_sample_format: cpal::SampleFormat,
 
+    latency: LatencyUi, //of the devices the router was opened on
+
     open: bool,
 }
 
 impl Default for StudioSetup {
     fn default() -> Self {
         let default_host_id = cpal::default_host().id();
-        let default_host = cpal::host_from_id(default_host_id);
-        let default_sample_format = default_host
-            .unwrap()
+        //a machine without devices still gets a window, the setup shows empty lists
+        let default_sample_format = cpal::default_host()
             .default_input_device()
-            .unwrap()
-            .default_input_config()
-            .unwrap()
-            .sample_format();
-        let (in_devices, out_devices) = utils::get_host_devices(default_host_id);
+            .and_then(|d| d.default_input_config().ok())
+            .map(|c| c.sample_format())
+            .unwrap_or(cpal::SampleFormat::F32);
+        let (in_devices, out_devices) = match utils::get_host_devices(default_host_id) {
+            Ok(devices) => devices,
+            Err(e) => {
+                eprintln!("StudioSetup: {}", e);
+                (Vec::<Device>::new(), Vec::<Device>::new())
+            }
+        };
 
         Self {
             host_ids: utils::get_host_ids(),
@@ -323,17 +1052,42 @@ impl Default for StudioSetup {
             out_devices: out_devices,
             selected_out_device: String::new(),
             selected_sample_format: default_sample_format,
+            latency: LatencyUi {
+                open: false,
+                in_bus_id: 0,
+                out_bus_id: 0,
+            },
             open: true,
         }
     }
 }
 
 impl StudioSetup {
+    fn open_router(&self) -> Result<Router<f32>> {
+        let host = cpal::host_from_id(self.selected_host_id)?;
+        let in_device = utils::get_input_device_by_name(&host, &self.selected_in_device)?;
+        let out_device = utils::get_output_device_by_name(&host, &self.selected_out_device)?;
+        let in_conf = in_device.default_input_config()?.config();
+        let out_conf = out_device.default_output_config()?.config();
+
+        Router::new(
+            host,
+            in_conf,
+            out_conf,
+            self.selected_in_device.clone(),
+            self.selected_out_device.clone(),
+            self.selected_sample_format,
+        )
+    }
+
     fn get_window(
         &mut self,
         ctx: &egui::CtxRef,
         app_router: &mut Option<Router<f32>>,
+        errors: &mut ErrorUi,
     ) -> Option<InnerResponse<Option<()>>> {
+        self.latency.get_window(ctx, app_router, errors);
+        let mut apply = false;
         let mut close_window = false;
         let window = Window::new("Studio Setup")
             .open(&mut self.open)
@@ -355,7 +1109,7 @@ impl StudioSetup {
                     .selected_text(format!("{:?}", self.selected_in_device))
                     .show_ui(ui, |ui| {
                         for input in self.in_devices.iter() {
-                            let d_name = input.name().unwrap();
+                            let d_name = input.name().unwrap_or_default();
                             ui.selectable_value(
                                 &mut self.selected_in_device,
                                 d_name.clone(),
@@ -368,7 +1122,7 @@ impl StudioSetup {
                     .selected_text(format!("{:?}", self.selected_out_device))
                     .show_ui(ui, |ui| {
                         for output in self.out_devices.iter() {
-                            let d_name = output.name().unwrap();
+                            let d_name = output.name().unwrap_or_default();
                             ui.selectable_value(
                                 &mut self.selected_out_device,
                                 d_name.clone(),
@@ -407,27 +1161,17 @@ impl StudioSetup {
                     .on_disabled_hover_text("Input/Output devices must be selected")
                     .clicked()
                 {
-                    let host = cpal::host_from_id(self.selected_host_id).unwrap();
-                    let in_device =
-                        utils::get_input_device_by_name(&host, &self.selected_in_device);
-                    let out_device =
-                        utils::get_output_device_by_name(&host, &self.selected_out_device);
-                    let in_conf = in_device.default_input_config().unwrap().config();
-                    let out_conf = out_device.default_output_config().unwrap().config();
-
-                    *app_router = Option::Some(Router::new(
-                        host,
-                        in_conf,
-                        out_conf,
-                        self.selected_in_device.clone(),
-                        self.selected_out_device.clone(),
-                        self.selected_sample_format,
-                    ));
-
-                    close_window = true;
+                    apply = true;
                 }
             });
 
+        if apply {
+            if let Some(router) = errors.report(self.open_router()) {
+                *app_router = Some(router);
+                close_window = true;
+            }
+        }
+
         if close_window {
             self.open = false;
         }
@@ -435,30 +1179,616 @@ impl StudioSetup {
     }
 }
 
-pub struct TransportUi;
+#[derive(PartialEq)]
+pub enum SessionAction {
+    Save,
+    Open,
+}
+
+pub struct SessionUi {
+    path: String,
+    action: SessionAction,
+    status: String,
+    open: bool,
+}
+
+impl Default for SessionUi {
+    fn default() -> Self {
+        Self {
+            path: "session.json".to_string(),
+            action: SessionAction::Save,
+            status: String::new(),
+            open: false,
+        }
+    }
+}
+
+impl SessionUi {
+    //returns true when a session was loaded into app_router
+    fn get_window(&mut self, ctx: &egui::CtxRef, app_router: &mut Option<Router<f32>>) -> bool {
+        let mut loaded = false;
+        let mut close_window = false;
+        let title = match self.action {
+            SessionAction::Save => "Save Session",
+            SessionAction::Open => "Open Session",
+        };
+
+        Window::new(title).open(&mut self.open).show(ctx, |ui| {
+            ui.horizontal(|ui| {
+                ui.label("File:");
+                ui.text_edit_singleline(&mut self.path);
+            });
+            if !self.status.is_empty() {
+                ui.label(&self.status);
+            }
+
+            let enabled = !self.path.is_empty()
+                && (self.action == SessionAction::Open || app_router.is_some());
+            if !ui.add_enabled(enabled, egui::Button::new(title)).clicked() {
+                return;
+            }
+
+            match self.action {
+                SessionAction::Save => {
+                    let session = app_router.as_ref().unwrap().to_session();
+                    match session.save(&self.path) {
+                        Ok(_) => close_window = true,
+                        Err(e) => self.status = format!("Could not save session: {}", e),
+                    }
+                }
+                SessionAction::Open => match open_session(&self.path) {
+                    Ok(r) => {
+                        *app_router = Some(r);
+                        loaded = true;
+                        close_window = true;
+                    }
+                    Err(e) => self.status = format!("Could not open session: {}", e),
+                },
+            }
+        });
+
+        if close_window {
+            self.open = false;
+            self.status = String::new();
+        }
+        loaded
+    }
+
+    //Repairs the takes a crash left unfinished in the session file of the window, the default
+    //one on start, before it is opened again
+    fn recover_takes(&self, errors: &mut ErrorUi) {
+        if !std::path::Path::new(&self.path).exists() {
+            return;
+        }
+        let session = match errors.report(Session::load(&self.path).map_err(RecorderError::from)) {
+            Some(s) => s,
+            None => return,
+        };
+        for (file, result) in recovery::recover_takes(&session.get_take_files()) {
+            match result {
+                Ok(frames) => println!("Recovered {} ({} frames)", file, frames),
+                Err(e) => errors
+                    .messages
+                    .push(format!("Could not recover {}: {}", file, e)),
+            }
+        }
+    }
+
+    fn show(&mut self, action: SessionAction) {
+        self.action = action;
+        self.status = String::new();
+        self.open = true;
+    }
+}
+
+//Imports an audio file into a new or an existing track
+pub struct ImportUi {
+    path: String,
+    track_id: Option<u8>, //None for a new track
+    position_secs: f64,
+    status: String,
+    open: bool,
+}
+
+impl Default for ImportUi {
+    fn default() -> Self {
+        Self {
+            path: String::new(),
+            track_id: None,
+            position_secs: 0.0,
+            status: String::new(),
+            open: false,
+        }
+    }
+}
+
+impl ImportUi {
+    fn get_window(&mut self, ctx: &egui::CtxRef, app_router: &mut Option<Router<f32>>) {
+        let rout = match app_router {
+            Some(r) => r,
+            None => return,
+        };
+        let mut close_window = false;
+        let tracks: Vec<(u8, String)> = rout
+            .get_tracks()
+            .iter()
+            .map(|t| (t.as_tup().0, t.as_tup().1))
+            .collect();
+
+        Window::new("Import Audio")
+            .open(&mut self.open)
+            .show(ctx, |ui| {
+                ui.horizontal(|ui| {
+                    ui.label("File:");
+                    ui.text_edit_singleline(&mut self.path);
+                });
+                let selected = match self.track_id {
+                    Some(id) => tracks
+                        .iter()
+                        .find(|t| t.0 == id)
+                        .map(|t| t.1.clone())
+                        .unwrap_or_default(),
+                    None => "New Track".to_string(),
+                };
+                ComboBox::from_label("Track")
+                    .selected_text(selected)
+                    .show_ui(ui, |ui| {
+                        ui.selectable_value(&mut self.track_id, None, "New Track");
+                        for (id, name) in tracks.iter() {
+                            ui.selectable_value(&mut self.track_id, Some(*id), name);
+                        }
+                    });
+                ui.horizontal(|ui| {
+                    ui.label("Position:");
+                    ui.add(
+                        egui::DragValue::new(&mut self.position_secs)
+                            .clamp_range(0.0..=86_400.0)
+                            .speed(0.1)
+                            .suffix(" s"),
+                    );
+                });
+                if !self.status.is_empty() {
+                    ui.label(&self.status);
+                }
+
+                if !ui
+                    .add_enabled(!self.path.is_empty(), egui::Button::new("Import"))
+                    .clicked()
+                {
+                    return;
+                }
+                let start_frame = (self.position_secs * rout.get_sample_rate() as f64) as u64;
+                let imported = match self.track_id {
+                    Some(id) => rout.import_file(id, &self.path, start_frame).map(|_| ()),
+                    None => import_track(rout, &self.path, start_frame),
+                };
+                match imported {
+                    Ok(_) => close_window = true,
+                    Err(e) => self.status = format!("Could not import {}: {}", self.path, e),
+                }
+            });
+
+        if close_window {
+            self.open = false;
+            self.status = String::new();
+        }
+    }
+}
+
+//Mixdown of the session into a stereo wav, rendered on a thread of its own
+pub struct BounceUi {
+    path: String,
+    range_secs: (f64, f64),
+    whole_session: bool, //to the end of the last take instead of range_secs.1
+    bounce: Option<Bounce>,
+    status: String,
+    open: bool,
+}
+
+impl Default for BounceUi {
+    fn default() -> Self {
+        Self {
+            path: "mixdown.wav".to_string(),
+            range_secs: (0.0, 0.0),
+            whole_session: true,
+            bounce: None,
+            status: String::new(),
+            open: false,
+        }
+    }
+}
+
+impl BounceUi {
+    //Opens the window with the range of the cycle locators, the whole session without them
+    fn show(&mut self, rout: &Router<f32>) {
+        self.open = true;
+        if self.bounce.is_some() {
+            return;
+        }
+        let rate = rout.get_sample_rate() as f64;
+        match rout.get_cycle() {
+            Some(cycle) => {
+                self.range_secs = (cycle.start as f64 / rate, cycle.end as f64 / rate);
+                self.whole_session = false;
+            }
+            None => self.whole_session = true,
+        }
+        self.status = String::new();
+    }
+
+    fn get_window(&mut self, ctx: &egui::CtxRef, app_router: &mut Option<Router<f32>>) {
+        let rout = match app_router {
+            Some(r) => r,
+            None => return,
+        };
+        let rate = rout.get_sample_rate();
+
+        if self.bounce.as_ref().is_some_and(|b| b.is_finished()) {
+            let path = self.path.clone();
+            self.status = match self.bounce.take().unwrap().join() {
+                Ok(frames) => format!("Bounced {:.1} s to {}", frames as f64 / rate as f64, path),
+                Err(e) => format!("Could not bounce to {}: {}", path, e),
+            };
+        }
+
+        Window::new("Bounce Mix")
+            .open(&mut self.open)
+            .show(ctx, |ui| {
+                let idle = self.bounce.is_none();
+                ui.add_enabled_ui(idle, |ui| {
+                    ui.horizontal(|ui| {
+                        ui.label("File:");
+                        ui.text_edit_singleline(&mut self.path);
+                    });
+                    ui.checkbox(&mut self.whole_session, "Whole Session");
+                    ui.add_enabled_ui(!self.whole_session, |ui| {
+                        ui.horizontal(|ui| {
+                            ui.label("From:");
+                            ui.add(
+                                egui::DragValue::new(&mut self.range_secs.0)
+                                    .clamp_range(0.0..=86_400.0)
+                                    .speed(0.1)
+                                    .suffix(" s"),
+                            );
+                            ui.label("To:");
+                            ui.add(
+                                egui::DragValue::new(&mut self.range_secs.1)
+                                    .clamp_range(self.range_secs.0..=86_400.0)
+                                    .speed(0.1)
+                                    .suffix(" s"),
+                            );
+                        });
+                    });
+                });
+
+                if let Some(bounce) = &self.bounce {
+                    match bounce.get_fraction() {
+                        Some(f) => ui.add(egui::ProgressBar::new(f).show_percentage()),
+                        None => ui.label(format!(
+                            "{:.1} s bounced",
+                            bounce.get_progress() as f64 / rate as f64
+                        )),
+                    };
+                } else if !self.status.is_empty() {
+                    ui.label(&self.status);
+                }
+
+                if !ui
+                    .add_enabled(idle && !self.path.is_empty(), egui::Button::new("Bounce"))
+                    .clicked()
+                {
+                    return;
+                }
+                let range = match self.whole_session {
+                    true => 0..u64::MAX,
+                    false => {
+                        let to_frame = |secs: f64| (secs * rate as f64) as u64;
+                        to_frame(self.range_secs.0)..to_frame(self.range_secs.1)
+                    }
+                };
+                match rout.start_bounce(&self.path, range) {
+                    Ok(b) => self.bounce = Some(b),
+                    Err(e) => self.status = format!("Could not bounce to {}: {}", self.path, e),
+                }
+            });
+    }
+}
+
+//Click, count-in and pre-roll settings, applied as they are edited
+pub struct MetronomeUi {
+    open: bool,
+}
+
+impl MetronomeUi {
+    fn get_window(
+        &mut self,
+        ctx: &egui::CtxRef,
+        app_router: &mut Option<Router<f32>>,
+        errors: &mut ErrorUi,
+    ) {
+        let rout = match app_router {
+            Some(r) => r,
+            None => return,
+        };
+        let current = rout.get_metronome();
+        let mut settings = current.clone();
+        let out_bus_ids: Vec<u8> = rout.get_output_meters().iter().map(|b| b.0).collect();
+
+        Window::new("Metronome")
+            .open(&mut self.open)
+            .show(ctx, |ui| {
+                ui.checkbox(&mut settings.enabled, "Click");
+                ui.horizontal(|ui| {
+                    ui.label("Tempo:");
+                    ui.add(
+                        egui::DragValue::new(&mut settings.tempo)
+                            .clamp_range(MIN_TEMPO..=MAX_TEMPO)
+                            .speed(0.5)
+                            .suffix(" bpm"),
+                    );
+                });
+                ui.horizontal(|ui| {
+                    ui.label("Time Signature:");
+                    ui.add(egui::DragValue::new(&mut settings.beats_per_bar).clamp_range(1..=32));
+                    ComboBox::from_label("/")
+                        .selected_text(settings.beat_unit.to_string())
+                        .show_ui(ui, |ui| {
+                            for unit in BEAT_UNITS {
+                                ui.selectable_value(
+                                    &mut settings.beat_unit,
+                                    unit,
+                                    unit.to_string(),
+                                );
+                            }
+                        });
+                });
+                ui.checkbox(&mut settings.accent, "Accent");
+                ui.horizontal(|ui| {
+                    ui.label("Count-in:");
+                    ui.add(
+                        egui::DragValue::new(&mut settings.count_in_bars)
+                            .clamp_range(0..=8)
+                            .suffix(" bars"),
+                    );
+                    ui.label("Pre-roll:");
+                    ui.add(
+                        egui::DragValue::new(&mut settings.pre_roll_bars)
+                            .clamp_range(0..=8)
+                            .suffix(" bars"),
+                    );
+                });
+                ui.add(
+                    egui::Slider::new(&mut settings.gain_db, MIN_GAIN_DB..=MAX_GAIN_DB)
+                        .text("Level")
+                        .suffix(" dB"),
+                );
+                let selected = match settings.out_bus_id {
+                    Some(id) => format!("Out {}", id),
+                    None => "First".to_string(),
+                };
+                ComboBox::from_label("Output")
+                    .selected_text(selected)
+                    .show_ui(ui, |ui| {
+                        ui.selectable_value(&mut settings.out_bus_id, None, "First");
+                        for id in out_bus_ids.iter() {
+                            ui.selectable_value(
+                                &mut settings.out_bus_id,
+                                Some(*id),
+                                format!("Out {}", id),
+                            );
+                        }
+                    });
+            });
+
+        if settings != current {
+            errors.report(rout.set_metronome(settings));
+        }
+    }
+}
+
+//Round trip latency dropped from the start of new takes, set by hand or measured with an
+//output bus cabled back to an input bus
+pub struct LatencyUi {
+    open: bool,
+    in_bus_id: u8,
+    out_bus_id: u8,
+}
+
+impl LatencyUi {
+    fn get_window(
+        &mut self,
+        ctx: &egui::CtxRef,
+        app_router: &mut Option<Router<f32>>,
+        errors: &mut ErrorUi,
+    ) {
+        let rout = match app_router {
+            Some(r) => r,
+            None => return,
+        };
+        let mut latency = rout.get_latency();
+        let ms = latency as f64 * 1000.0 / rout.get_sample_rate() as f64;
+        let (in_busses, out_busses) = (
+            rout.get_input_bus_channels(),
+            rout.get_output_bus_channels(),
+        );
+        let bus_name = |busses: &Vec<(u8, Vec<u8>)>, id: u8| match busses.iter().find(|b| b.0 == id)
+        {
+            Some((id, chs)) => format!("{} {:?}", id, chs),
+            None => "-".to_string(),
+        };
+        let (in_bus_id, out_bus_id) = (&mut self.in_bus_id, &mut self.out_bus_id);
+        let mut measure = false;
+
+        Window::new("Latency").open(&mut self.open).show(ctx, |ui| {
+            ui.horizontal(|ui| {
+                ui.label("Round Trip:");
+                ui.add(egui::DragValue::new(&mut latency).suffix(" frames"));
+                ui.label(format!("{:.1} ms", ms));
+            });
+            ComboBox::from_label("Input Bus")
+                .selected_text(bus_name(&in_busses, *in_bus_id))
+                .show_ui(ui, |ui| {
+                    for (id, _) in in_busses.iter() {
+                        ui.selectable_value(in_bus_id, *id, bus_name(&in_busses, *id));
+                    }
+                });
+            ComboBox::from_label("Output Bus")
+                .selected_text(bus_name(&out_busses, *out_bus_id))
+                .show_ui(ui, |ui| {
+                    for (id, _) in out_busses.iter() {
+                        ui.selectable_value(out_bus_id, *id, bus_name(&out_busses, *id));
+                    }
+                });
+            ui.label("Cable the output bus to the input bus, test pulses are played.");
+            measure = ui
+                .add_enabled(
+                    !in_busses.is_empty() && !out_busses.is_empty(),
+                    egui::Button::new("Measure"),
+                )
+                .clicked();
+        });
+
+        if measure {
+            errors.report(rout.calibrate_latency(self.in_bus_id, self.out_bus_id));
+        } else if latency != rout.get_latency() {
+            rout.set_latency(latency);
+        }
+    }
+}
+
+//New track on its own busses: the first input channels, up to as many as the file has,
+//and the first two outputs
+fn import_track(rout: &mut Router<f32>, path: &str, start_frame: u64) -> Result<()> {
+    let (in_chs, out_chs) = rout.get_io_channels();
+    let (nof_channels, _, _) = import::probe(path)?;
+    let in_bus = rout.new_input_bus(in_chs.into_iter().take(nof_channels as usize).collect())?;
+    let out_bus = rout.new_output_bus(out_chs.into_iter().take(2).collect())?;
+    rout.import_track(path, in_bus, out_bus, start_frame)?;
+    Ok(())
+}
+
+fn open_session(path: &str) -> Result<Router<f32>> {
+    let session = Session::load(path)?;
+    let host_id = utils::get_host_ids()
+        .into_iter()
+        .find(|h| h.name() == session.config.host)
+        .ok_or(RecorderError::HostUnavailable(session.config.host.clone()))?;
+    let host = cpal::host_from_id(host_id)?;
+
+    let mut router = Router::new(
+        host,
+        session.config.in_config.to_config(),
+        session.config.out_config.to_config(),
+        session.config.in_device.clone(),
+        session.config.out_device.clone(),
+        sample_format_from_str(&session.config.sample_format),
+    )?;
+    router.restore_session(&session)?;
+    router.monitor()?;
+    Ok(router)
+}
+
+pub struct TransportUi {
+    master_meters: Vec<MeterUi>, //one per output bus
+    cycle_secs: (f64, f64),      //cycle locators, kept while cycling is off
+}
 
 impl TransportUi {
     pub fn get_transport(
         &mut self,
         ui: &mut egui::Ui,
         app_router: &mut Option<Router<f32>>,
+        metronome: &mut MetronomeUi,
+        errors: &mut ErrorUi,
     ) -> InnerResponse<()> {
         ui.with_layout(egui::Layout::right_to_left(), |ui| {
             let rout = match app_router {
                 Some(r) => r,
                 None => return,
             };
+            let state = rout.get_transport_state();
+            if state == TransportState::Playing || state == TransportState::Recording {
+                ui.ctx().request_repaint();
+            }
+
             if ui.button("Stop").clicked() {
-                rout.stop_recording();
+                errors.report(rout.stop());
+            }
+            if ui.button("Pause").clicked() {
+                errors.report(rout.pause());
+            }
+            if ui.button("Play").clicked() {
+                errors.report(rout.play());
             }
-            if ui.button("Play").clicked() {}
             if ui.button("Rec.").clicked() {
-                rout.record();
+                errors.report(rout.record());
+            }
+            if ui.button("|<").clicked() {
+                errors.report(rout.locate(0));
+            }
+            if ui.button("Click").clicked() {
+                metronome.open = true;
+            }
+
+            //right to left: the cycle toggle ends up before its locators
+            let sample_rate = rout.get_sample_rate() as f64;
+            let cycle = rout.get_cycle();
+            if let Some(c) = &cycle {
+                self.cycle_secs = (c.start as f64 / sample_rate, c.end as f64 / sample_rate);
+            }
+            let (mut cycling, mut cycle_secs) = (cycle.is_some(), self.cycle_secs);
+            ui.add(
+                egui::DragValue::new(&mut cycle_secs.1)
+                    .clamp_range(0.0..=86_400.0)
+                    .speed(0.1)
+                    .suffix(" s"),
+            );
+            ui.label("-");
+            ui.add(
+                egui::DragValue::new(&mut cycle_secs.0)
+                    .clamp_range(0.0..=86_400.0)
+                    .speed(0.1)
+                    .suffix(" s"),
+            );
+            ui.checkbox(&mut cycling, "Cycle");
+            if cycling != cycle.is_some() || cycle_secs != self.cycle_secs {
+                self.cycle_secs = cycle_secs;
+                let frames = |secs: f64| (secs * sample_rate) as u64;
+                let cycle = match cycling {
+                    true => Some(frames(cycle_secs.0)..frames(cycle_secs.1)),
+                    false => None,
+                };
+                errors.report(rout.set_cycle(cycle));
+            }
+
+            let out_meters = rout.get_output_meters();
+            self.master_meters
+                .resize_with(out_meters.len(), MeterUi::new);
+            for (idx, (bus_id, levels)) in out_meters.iter().enumerate() {
+                self.master_meters[idx].show(ui, levels);
+                ui.label(format!("Out {}", bus_id));
             }
+
+            let (overruns, underruns) = rout.get_xruns();
+            ui.label(format!("xruns: {} in / {} out", overruns, underruns));
+
+            let secs = rout.get_playhead() as f64 / sample_rate;
+            let (bar, beat) = rout.get_bar_beat();
+            ui.label(format!(
+                "{:?}  {:02}:{:06.3}  {}.{}",
+                state,
+                (secs / 60.) as u64,
+                secs % 60.,
+                bar,
+                beat
+            ));
         })
     }
 }
 
+//session rates offered in the Studio menu
+const SESSION_RATES: [u32; 6] = [44_100, 48_000, 88_200, 96_000, 176_400, 192_000];
+
 pub struct ToolbarUi;
 
 impl ToolbarUi {
@@ -466,28 +1796,163 @@ impl ToolbarUi {
         &mut self,
         ui: &mut egui::Ui,
         setup: &mut StudioSetup,
-    ) -> InnerResponse<Option<()>> {
-        ui.menu_button("Studio", |ui| {
-            self.get_nested_menus(ui, setup);
+        session: &mut SessionUi,
+        import: &mut ImportUi,
+        bounce: &mut BounceUi,
+        app_router: &mut Option<Router<f32>>,
+        errors: &mut ErrorUi,
+    ) -> InnerResponse<()> {
+        ui.horizontal(|ui| {
+            ui.menu_button("Studio", |ui| {
+                self.get_nested_menus(ui, setup, session, import, bounce, app_router, errors);
+            });
+            if let Some(rout) = app_router {
+                ui.menu_button("Edit", |ui| {
+                    self.get_edit_menu(ui, rout, errors);
+                });
+            }
         })
     }
 
-    fn get_nested_menus(&mut self, ui: &mut egui::Ui, setup: &mut StudioSetup) -> () {
+    //Undo and redo, also on Ctrl+Z and Ctrl+Shift+Z or Ctrl+Y, see CpalRecorder::shortcuts
+    fn get_edit_menu(&mut self, ui: &mut egui::Ui, rout: &mut Router<f32>, errors: &mut ErrorUi) {
+        let undo = rout.get_undo_name();
+        let label = format!("Undo {}", undo.clone().unwrap_or_default());
+        if ui
+            .add_enabled(undo.is_some(), egui::Button::new(label))
+            .clicked()
+        {
+            errors.report(rout.undo());
+            ui.close_menu();
+        }
+        let redo = rout.get_redo_name();
+        let label = format!("Redo {}", redo.clone().unwrap_or_default());
+        if ui
+            .add_enabled(redo.is_some(), egui::Button::new(label))
+            .clicked()
+        {
+            errors.report(rout.redo());
+            ui.close_menu();
+        }
+        if ui.button("Clear History").clicked() {
+            rout.clear_history();
+            ui.close_menu();
+        }
+        let mut save_history = rout.get_save_history();
+        if ui
+            .checkbox(&mut save_history, "Save History with Session")
+            .changed()
+        {
+            rout.set_save_history(save_history);
+        }
+    }
+
+    fn get_nested_menus(
+        &mut self,
+        ui: &mut egui::Ui,
+        setup: &mut StudioSetup,
+        session: &mut SessionUi,
+        import: &mut ImportUi,
+        bounce: &mut BounceUi,
+        app_router: &mut Option<Router<f32>>,
+        errors: &mut ErrorUi,
+    ) -> () {
         if ui.button("Setup").clicked() {
             setup.open = true;
         }
+        if ui.button("Open Session").clicked() {
+            setup.open = false;
+            session.show(SessionAction::Open);
+        }
+        if app_router.is_some() && ui.button("Save Session").clicked() {
+            session.show(SessionAction::Save);
+        }
+        if app_router.is_some() && ui.button("Import Audio").clicked() {
+            import.open = true;
+        }
+        if let Some(rout) = app_router {
+            if ui.button("Latency").clicked() {
+                setup.latency.open = true;
+            }
+            if ui.button("Bounce Mix").clicked() {
+                bounce.show(rout);
+            }
+            ui.menu_button("Pan Law", |ui| {
+                let current = rout.get_pan_law();
+                for law in PanLaw::all() {
+                    if ui.selectable_label(law == current, law.name()).clicked() {
+                        rout.set_pan_law(law);
+                        ui.close_menu();
+                    }
+                }
+            });
+            ui.menu_button("Sample Rate", |ui| {
+                let current = rout.get_sample_rate();
+                for rate in SESSION_RATES {
+                    if ui
+                        .selectable_label(rate == current, format!("{} Hz", rate))
+                        .clicked()
+                    {
+                        errors.report(rout.set_sample_rate(rate));
+                        ui.close_menu();
+                    }
+                }
+            });
+            ui.menu_button("Recording Format", |ui| {
+                let current = rout.get_record_format();
+                for format in RecordFormat::all() {
+                    if ui
+                        .selectable_label(format == current, format.name())
+                        .clicked()
+                    {
+                        rout.set_record_format(format);
+                        ui.close_menu();
+                    }
+                }
+            });
+        }
     }
 }
 
 pub struct CpalRecorder {
     setup: StudioSetup,
+    session: SessionUi,
+    import: ImportUi,
+    bounce: BounceUi,
+    metronome: MetronomeUi,
     track_list: TrackListUi,
     transport: TransportUi,
     toolbar: ToolbarUi,
+    errors: ErrorUi,
     router: Option<Router<f32>>,
 }
 
 impl CpalRecorder {
+    //Ctrl+Z undoes, Ctrl+Shift+Z and Ctrl+Y redo (Cmd on macOS), not while typing text
+    fn shortcuts(&mut self, ctx: &egui::CtxRef) {
+        let rout = match &mut self.router {
+            Some(r) => r,
+            None => return,
+        };
+        if ctx.wants_keyboard_input() {
+            return;
+        }
+        let input = ctx.input();
+        if !input.modifiers.command {
+            return;
+        }
+        let (z, y, shift) = (
+            input.key_pressed(egui::Key::Z),
+            input.key_pressed(egui::Key::Y),
+            input.modifiers.shift,
+        );
+        if z && !shift {
+            self.errors.report(rout.undo());
+        } else if (z && shift) || y {
+            self.errors.report(rout.redo());
+        }
+    }
+
     fn conf_fonts(&self, ctx: &egui::CtxRef) {
         let mut font_def = FontDefinitions::default();
         font_def.font_data.insert(
@@ -524,9 +1989,17 @@ impl Default for CpalRecorder {
     fn default() -> Self {
         Self {
             setup: StudioSetup::default(),
+            session: SessionUi::default(),
+            import: ImportUi::default(),
+            bounce: BounceUi::default(),
+            metronome: MetronomeUi { open: false },
             track_list: TrackListUi::new(),
-            transport: TransportUi {},
+            transport: TransportUi {
+                master_meters: Vec::<MeterUi>::new(),
+                cycle_secs: (0.0, 4.0),
+            },
             toolbar: ToolbarUi {},
+            errors: ErrorUi::new(),
             router: None,
         }
     }
@@ -544,23 +2017,58 @@ impl epi::App for CpalRecorder {
         _storage: Option<&dyn eframe::epi::Storage>,
     ) {
         self.conf_fonts(ctx);
+        self.session.recover_takes(&mut self.errors);
     }
 
     fn update(&mut self, ctx: &egui::CtxRef, frame: &epi::Frame) {
-        self.setup.get_window(ctx, &mut self.router);
+        //keeps the meters moving
+        if self.router.is_some() {
+            ctx.request_repaint();
+        }
+        self.shortcuts(ctx);
+        self.setup
+            .get_window(ctx, &mut self.router, &mut self.errors);
+        if self.session.get_window(ctx, &mut self.router) {
+            self.track_list = TrackListUi::new();
+        }
+        self.import.get_window(ctx, &mut self.router);
+        self.bounce.get_window(ctx, &mut self.router);
+        self.metronome
+            .get_window(ctx, &mut self.router, &mut self.errors);
         egui::TopBottomPanel::top("Toolbar").show(ctx, |ui| {
-            self.toolbar.get_toolbar(ui, &mut self.setup);
+            self.toolbar.get_toolbar(
+                ui,
+                &mut self.setup,
+                &mut self.session,
+                &mut self.import,
+                &mut self.bounce,
+                &mut self.router,
+                &mut self.errors,
+            );
         });
         egui::TopBottomPanel::bottom("TransportUi").show(ctx, |ui| {
-            self.transport.get_transport(ui, &mut self.router);
+            self.transport.get_transport(
+                ui,
+                &mut self.router,
+                &mut self.metronome,
+                &mut self.errors,
+            );
         });
         egui::CentralPanel::default().show(ctx, |ui| {
-            self.track_list.get_track_list(ctx, ui, &mut self.router);
+            self.track_list
+                .get_track_list(ctx, ui, &mut self.router, &mut self.errors);
         });
+        self.errors.get_window(ctx);
     }
 }
 
 fn main() {
+    //any argument runs the headless command line recorder instead of the GUI
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if !args.is_empty() {
+        std::process::exit(cli::run(&args));
+    }
+
     let app: CpalRecorder = CpalRecorder::default();
     let mut win_opts = NativeOptions::default();
     win_opts.initial_window_size = Some(Vec2::new(1280., 720.));
@@ -0,0 +1,124 @@
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::Arc;
+
+const RMS_WINDOW_SECS: f32 = 0.3;
+const PEAK_DECAY_DB_PER_SEC: f32 = 20.0;
+const CLIP_LEVEL: f32 = 1.0;
+
+// Levels of every channel of one bus. Written by the audio thread once per callback,
+// read by the UI at any time, floats are stored as bits.
+pub struct MeterLevels {
+    channels: Vec<ChannelLevel>,
+}
+
+struct ChannelLevel {
+    peak: AtomicU32,  //linear, falls back at PEAK_DECAY_DB_PER_SEC
+    rms: AtomicU32,   //linear, averaged over RMS_WINDOW_SECS
+    clip: AtomicBool, //latched until reset_clip
+}
+
+impl MeterLevels {
+    pub fn new(nof_channels: usize) -> MeterLevels {
+        let mut channels = Vec::<ChannelLevel>::new();
+        for _ in 0..nof_channels {
+            channels.push(ChannelLevel {
+                peak: AtomicU32::new(0.0f32.to_bits()),
+                rms: AtomicU32::new(0.0f32.to_bits()),
+                clip: AtomicBool::new(false),
+            });
+        }
+        MeterLevels { channels: channels }
+    }
+
+    pub fn get_nof_channels(&self) -> usize {
+        self.channels.len()
+    }
+
+    //(peak, rms, clipped) of a channel
+    pub fn get_level(&self, channel_idx: usize) -> (f32, f32, bool) {
+        let level = &self.channels[channel_idx];
+        (
+            f32::from_bits(level.peak.load(Ordering::Relaxed)),
+            f32::from_bits(level.rms.load(Ordering::Relaxed)),
+            level.clip.load(Ordering::Relaxed),
+        )
+    }
+
+    pub fn reset_clip(&self) {
+        for level in self.channels.iter() {
+            level.clip.store(false, Ordering::Relaxed);
+        }
+    }
+}
+
+// Audio thread side of a meter, feed it frames and publish once per callback.
+// Never allocates after new().
+pub struct MeterWriter {
+    levels: Arc<MeterLevels>,
+    sample_rate: f32,
+    block_peaks: Vec<f32>,
+    block_sum_sq: Vec<f32>,
+    mean_sq: Vec<f32>,
+    peaks: Vec<f32>,
+}
+
+impl MeterWriter {
+    pub fn new(nof_channels: usize, sample_rate: u32) -> MeterWriter {
+        MeterWriter {
+            levels: Arc::new(MeterLevels::new(nof_channels)),
+            sample_rate: sample_rate as f32,
+            block_peaks: vec![0.0; nof_channels],
+            block_sum_sq: vec![0.0; nof_channels],
+            mean_sq: vec![0.0; nof_channels],
+            peaks: vec![0.0; nof_channels],
+        }
+    }
+
+    pub fn get_levels(&self) -> Arc<MeterLevels> {
+        self.levels.clone()
+    }
+
+    pub fn add_frame<T: cpal::Sample>(&mut self, frame: &[T]) {
+        for (ch, sample) in frame.iter().enumerate().take(self.block_peaks.len()) {
+            let value = sample.to_f32().abs();
+            if value > self.block_peaks[ch] {
+                self.block_peaks[ch] = value;
+            }
+            self.block_sum_sq[ch] += value * value;
+        }
+    }
+
+    //nof_frames is the length of the callback buffer, frames that were not added count as silence
+    pub fn publish(&mut self, nof_frames: usize) {
+        if nof_frames == 0 {
+            return;
+        }
+        let secs = nof_frames as f32 / self.sample_rate;
+        let rms_coeff = 1.0 - (-secs / RMS_WINDOW_SECS).exp();
+        let peak_decay = 10.0f32.powf(-PEAK_DECAY_DB_PER_SEC * secs / 20.0);
+
+        for ch in 0..self.block_peaks.len() {
+            let block_mean_sq = self.block_sum_sq[ch] / nof_frames as f32;
+            self.mean_sq[ch] += rms_coeff * (block_mean_sq - self.mean_sq[ch]);
+            self.peaks[ch] = f32::max(self.block_peaks[ch], self.peaks[ch] * peak_decay);
+
+            let level = &self.levels.channels[ch];
+            level
+                .peak
+                .store(self.peaks[ch].to_bits(), Ordering::Relaxed);
+            level
+                .rms
+                .store(self.mean_sq[ch].sqrt().to_bits(), Ordering::Relaxed);
+            if self.block_peaks[ch] >= CLIP_LEVEL {
+                level.clip.store(true, Ordering::Relaxed);
+            }
+
+            self.block_peaks[ch] = 0.0;
+            self.block_sum_sq[ch] = 0.0;
+        }
+    }
+}
+
+pub fn gain_to_db(gain: f32) -> f32 {
+    20.0 * gain.max(1e-9).log10()
+}
@@ -0,0 +1,260 @@
+use serde::{Deserialize, Serialize};
+
+use std::ops::Range;
+use std::thread;
+use std::time::Duration;
+
+use crate::error::{RecorderError, Result};
+use crate::frames::{frame_channel, FrameReceiver, FrameSender};
+
+// Click track generated on the fly at the output rate. The beat grid starts at timeline
+// frame 0 and is computed from the timeline position of every output frame, so the click
+// stays locked to playback wherever the transport starts. It is only mixed into an output
+// bus, takes are recorded from the inputs and bounces from the takes, neither contains it.
+
+const CLICK_RING_FRAMES: usize = 4_096;
+const CLICK_BLOCK_FRAMES: usize = 256;
+const CLICK_SECS: f64 = 0.03;
+const ACCENT_HZ: f64 = 1_760.0;
+const BEAT_HZ: f64 = 880.0;
+const ACCENT_LEVEL: f32 = 0.8;
+const BEAT_LEVEL: f32 = 0.5;
+
+pub const MIN_TEMPO: f64 = 20.0;
+pub const MAX_TEMPO: f64 = 400.0;
+pub const BEAT_UNITS: [u8; 4] = [2, 4, 8, 16];
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct MetronomeSettings {
+    pub enabled: bool, //click while the transport rolls, the count-in clicks regardless
+    pub tempo: f64,    //quarter notes per minute
+    pub beats_per_bar: u8, //time signature numerator
+    pub beat_unit: u8, //time signature denominator, one click per beat_unit note
+    pub accent: bool,  //first beat of every bar is higher and louder
+    pub count_in_bars: u8, //bars of click before recording starts
+    pub pre_roll_bars: u8, //bars of playback before the record position
+    pub gain_db: f32,
+    pub out_bus_id: Option<u8>, //None for the first output bus
+}
+
+impl Default for MetronomeSettings {
+    fn default() -> Self {
+        MetronomeSettings {
+            enabled: false,
+            tempo: 120.0,
+            beats_per_bar: 4,
+            beat_unit: 4,
+            accent: true,
+            count_in_bars: 0,
+            pre_roll_bars: 0,
+            gain_db: 0.0,
+            out_bus_id: None,
+        }
+    }
+}
+
+impl MetronomeSettings {
+    pub fn check(&self) -> Result<()> {
+        if !(MIN_TEMPO..=MAX_TEMPO).contains(&self.tempo) {
+            return Err(RecorderError::InvalidArgument(format!(
+                "tempo {} is outside {}..={} bpm",
+                self.tempo, MIN_TEMPO, MAX_TEMPO
+            )));
+        }
+        if self.beats_per_bar == 0 || !BEAT_UNITS.contains(&self.beat_unit) {
+            return Err(RecorderError::InvalidArgument(format!(
+                "invalid time signature {}/{}",
+                self.beats_per_bar, self.beat_unit
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn beat_secs(&self) -> f64 {
+        60.0 / self.tempo * 4.0 / self.beat_unit as f64
+    }
+
+    //length of n bars in frames at sample_rate
+    pub fn bars_to_frames(&self, nof_bars: u8, sample_rate: u32) -> u64 {
+        let secs = self.beat_secs() * self.beats_per_bar as f64 * nof_bars as f64;
+        (secs * sample_rate as f64).round() as u64
+    }
+
+    //(bar, beat) counted from 1 of a timeline frame
+    pub fn bar_beat(&self, frame: u64, sample_rate: u32) -> (u64, u8) {
+        let beat = (frame as f64 / sample_rate as f64 / self.beat_secs()).floor() as u64;
+        (
+            beat / self.beats_per_bar as u64 + 1,
+            (beat % self.beats_per_bar as u64) as u8 + 1,
+        )
+    }
+}
+
+//Starts the click at timeline frame start_frame (session rate, negative during a count-in),
+//count_in is the number of output frames that are clicked even if the metronome is off.
+//With a cycle (session rate) the click jumps back to its start with the playhead.
+//Every frame is sent to all nof_channels. The thread ends with the count-in when the
+//metronome is off, otherwise when the receiver is dropped.
+pub fn start_click<T: 'static + cpal::Sample + Send + Sync>(
+    settings: &MetronomeSettings,
+    sample_rate: u32,
+    out_rate: u32,
+    start_frame: i64,
+    count_in: u64,
+    cycle: Option<Range<u64>>,
+    nof_channels: usize,
+) -> FrameReceiver<T> {
+    let (click_tx, click_rx) = frame_channel::<T>(nof_channels, CLICK_RING_FRAMES);
+    let nof_frames = match settings.enabled {
+        true => u64::MAX,
+        false => count_in,
+    };
+    let start_secs = start_frame as f64 / sample_rate as f64;
+    let cycle_secs = cycle
+        .filter(|c| c.start < c.end)
+        .map(|c| c.start as f64 / sample_rate as f64..c.end as f64 / sample_rate as f64);
+    click_thread(
+        settings.clone(),
+        click_tx,
+        start_secs,
+        cycle_secs,
+        out_rate,
+        nof_frames,
+    );
+    click_rx
+}
+
+fn click_thread<T: 'static + cpal::Sample + Send + Sync>(
+    settings: MetronomeSettings,
+    mut click_tx: FrameSender<T>,
+    start_secs: f64,
+    cycle_secs: Option<Range<f64>>,
+    out_rate: u32,
+    nof_frames: u64,
+) {
+    thread::spawn(move || {
+        let nof_channels = click_tx.get_nof_channels();
+        let beat_secs = settings.beat_secs();
+        let mut block = Vec::<T>::with_capacity(CLICK_BLOCK_FRAMES * nof_channels);
+        let mut frame_idx: u64 = 0;
+        while frame_idx < nof_frames {
+            if !click_tx.is_connected() {
+                break;
+            }
+            if click_tx.free_frames() < CLICK_BLOCK_FRAMES {
+                thread::sleep(Duration::from_millis(1));
+                continue;
+            }
+            let block_frames =
+                std::cmp::min(CLICK_BLOCK_FRAMES as u64, nof_frames - frame_idx) as usize;
+            block.clear();
+            for _ in 0..block_frames {
+                let mut secs = start_secs + frame_idx as f64 / out_rate as f64;
+                //wrapped like the playhead in mix_thread, every pass clicks the same beats
+                if let Some(cycle) = &cycle_secs {
+                    if secs >= cycle.end {
+                        secs = cycle.start + (secs - cycle.end) % (cycle.end - cycle.start);
+                    }
+                }
+                let beat = (secs / beat_secs).floor();
+                let since = secs - beat * beat_secs;
+                let accented =
+                    settings.accent && (beat as i64).rem_euclid(settings.beats_per_bar as i64) == 0;
+                let sample = click_sample(since, accented);
+                for _ in 0..nof_channels {
+                    block.push(cpal::Sample::from(&sample));
+                }
+                frame_idx += 1;
+            }
+            click_tx.push_frames(&block);
+        }
+    });
+}
+
+//Decaying sine burst, since is the time in seconds since the beat
+fn click_sample(since: f64, accented: bool) -> f32 {
+    if since >= CLICK_SECS {
+        return 0.0;
+    }
+    let (hz, level) = match accented {
+        true => (ACCENT_HZ, ACCENT_LEVEL),
+        false => (BEAT_HZ, BEAT_LEVEL),
+    };
+    let envelope = (1.0 - since / CLICK_SECS).powi(2) as f32;
+    (since * hz * std::f64::consts::TAU).sin() as f32 * envelope * level
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 48_000;
+
+    fn click(start_frame: i64, cycle: Option<Range<u64>>, nof_frames: usize) -> Vec<f32> {
+        let settings = MetronomeSettings {
+            enabled: true,
+            ..MetronomeSettings::default()
+        };
+        let mut click_rx = start_click::<f32>(&settings, RATE, RATE, start_frame, 0, cycle, 1);
+        let mut frames = vec![0.0; nof_frames];
+        let mut nof_read = 0;
+        while nof_read < nof_frames {
+            match click_rx.pop_frames(&mut frames[nof_read..]) {
+                0 => thread::sleep(Duration::from_millis(1)),
+                n => nof_read += n,
+            }
+        }
+        frames
+    }
+
+    fn peak(frames: &[f32]) -> f32 {
+        frames.iter().fold(0.0, |p, s| p.max(s.abs()))
+    }
+
+    #[test]
+    fn clicks_on_the_beats() {
+        //120 bpm, a beat every 24000 frames, the first of a bar accented
+        let frames = click(0, None, 4 * 24_000 + 2_000);
+        let click_frames = (CLICK_SECS * RATE as f64) as usize;
+        for beat in 0..5 {
+            let start = beat * 24_000;
+            let level = peak(&frames[start..start + click_frames]);
+            let expected = match beat % 4 {
+                0 => ACCENT_LEVEL,
+                _ => BEAT_LEVEL,
+            };
+            assert!((level - expected).abs() < 0.05, "beat {}: {}", beat, level);
+            if beat < 4 {
+                assert_eq!(peak(&frames[start + click_frames..start + 24_000]), 0.0);
+            }
+        }
+    }
+
+    #[test]
+    fn follows_the_cycle() {
+        //the cycle ends off the beat grid, in the silence after beat 3
+        let cycle = 12_000..60_000;
+        let len = cycle.end - cycle.start;
+        let frames = click(12_000, Some(cycle.clone()), 3 * len as usize);
+        let first_pass = &frames[..len as usize];
+        for pass in 1..3 {
+            let start = pass * len as usize;
+            let other = &frames[start..start + len as usize];
+            for (idx, (a, b)) in first_pass.iter().zip(other.iter()).enumerate() {
+                assert!(
+                    (a - b).abs() < 1e-3,
+                    "pass {} frame {}: {} {}",
+                    pass,
+                    idx,
+                    a,
+                    b
+                );
+            }
+        }
+        //beat 2 at timeline frame 24000 clicks in every pass, the straight line would
+        //have left the cycle in the first one
+        for pass in frames.chunks(len as usize) {
+            assert!((peak(&pass[12_000..13_000]) - BEAT_LEVEL).abs() < 0.05);
+        }
+    }
+}
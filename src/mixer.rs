@@ -0,0 +1,464 @@
+use serde::{Deserialize, Serialize};
+
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU8, Ordering};
+use std::sync::Arc;
+
+use crate::frames::FrameReceiver;
+
+pub const MIX_BLOCK_FRAMES: usize = 256;
+
+pub const MIN_GAIN_DB: f32 = -60.0; //faders at or below this are silent
+pub const MAX_GAIN_DB: f32 = 12.0;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+pub enum PanLaw {
+    ZeroDb, //balance, centre leaves both sides untouched
+    #[default]
+    ConstantPower, //-3 dB in the centre
+    Minus4_5Db,
+    Minus6Db, //linear
+}
+
+impl PanLaw {
+    pub fn all() -> Vec<PanLaw> {
+        vec![
+            PanLaw::ZeroDb,
+            PanLaw::ConstantPower,
+            PanLaw::Minus4_5Db,
+            PanLaw::Minus6Db,
+        ]
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            PanLaw::ZeroDb => "0 dB",
+            PanLaw::ConstantPower => "-3 dB",
+            PanLaw::Minus4_5Db => "-4.5 dB",
+            PanLaw::Minus6Db => "-6 dB",
+        }
+    }
+
+    //(left, right) gain for pan in -1.0 (left) ..= 1.0 (right)
+    pub fn gains(&self, pan: f32) -> (f32, f32) {
+        let p = (pan.clamp(-1.0, 1.0) + 1.0) / 2.0;
+        let (cos, sin) = (
+            (p * std::f32::consts::FRAC_PI_2).cos(),
+            (p * std::f32::consts::FRAC_PI_2).sin(),
+        );
+        match self {
+            PanLaw::ZeroDb => ((2.0 * (1.0 - p)).min(1.0), (2.0 * p).min(1.0)),
+            PanLaw::ConstantPower => (cos, sin),
+            PanLaw::Minus4_5Db => (((1.0 - p) * cos).sqrt(), (p * sin).sqrt()),
+            PanLaw::Minus6Db => (1.0 - p, p),
+        }
+    }
+
+    fn to_u8(&self) -> u8 {
+        match self {
+            PanLaw::ZeroDb => 0,
+            PanLaw::ConstantPower => 1,
+            PanLaw::Minus4_5Db => 2,
+            PanLaw::Minus6Db => 3,
+        }
+    }
+
+    fn from_u8(law: u8) -> PanLaw {
+        match law {
+            0 => PanLaw::ZeroDb,
+            2 => PanLaw::Minus4_5Db,
+            3 => PanLaw::Minus6Db,
+            _ => PanLaw::ConstantPower,
+        }
+    }
+}
+
+// Fader, pan, mute and solo of one track. Written by the UI, read by the mix threads
+// once per block, so every field is a plain atomic (floats are stored as bits).
+pub struct MixParams {
+    gain_db: AtomicU32,
+    pan: AtomicU32,
+    mute: AtomicBool,
+    solo: AtomicBool,
+    solo_safe: AtomicBool, //not silenced when other sources are soloed, e.g. the click
+}
+
+impl MixParams {
+    pub fn new() -> MixParams {
+        MixParams {
+            gain_db: AtomicU32::new(0.0f32.to_bits()),
+            pan: AtomicU32::new(0.0f32.to_bits()),
+            mute: AtomicBool::new(false),
+            solo: AtomicBool::new(false),
+            solo_safe: AtomicBool::new(false),
+        }
+    }
+
+    pub fn get_gain_db(&self) -> f32 {
+        f32::from_bits(self.gain_db.load(Ordering::Relaxed))
+    }
+
+    pub fn set_gain_db(&self, gain_db: f32) {
+        let gain_db = gain_db.clamp(MIN_GAIN_DB, MAX_GAIN_DB);
+        self.gain_db.store(gain_db.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn get_pan(&self) -> f32 {
+        f32::from_bits(self.pan.load(Ordering::Relaxed))
+    }
+
+    pub fn set_pan(&self, pan: f32) {
+        let pan = pan.clamp(-1.0, 1.0);
+        self.pan.store(pan.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn is_muted(&self) -> bool {
+        self.mute.load(Ordering::Relaxed)
+    }
+
+    pub fn set_mute(&self, state: bool) {
+        self.mute.store(state, Ordering::Relaxed);
+    }
+
+    pub fn is_soloed(&self) -> bool {
+        self.solo.load(Ordering::Relaxed)
+    }
+
+    pub fn set_solo(&self, state: bool) {
+        self.solo.store(state, Ordering::Relaxed);
+    }
+
+    pub fn is_solo_safe(&self) -> bool {
+        self.solo_safe.load(Ordering::Relaxed)
+    }
+
+    pub fn set_solo_safe(&self, state: bool) {
+        self.solo_safe.store(state, Ordering::Relaxed);
+    }
+
+    //(gain_db, pan, mute, solo)
+    pub fn as_tup(&self) -> (f32, f32, bool, bool) {
+        (
+            self.get_gain_db(),
+            self.get_pan(),
+            self.is_muted(),
+            self.is_soloed(),
+        )
+    }
+}
+
+// Mix state shared by every mixer of a router.
+pub struct MixSettings {
+    pan_law: AtomicU8,
+    solo_active: AtomicBool, //some track is soloed, all others are silenced
+}
+
+impl MixSettings {
+    pub fn new() -> MixSettings {
+        MixSettings {
+            pan_law: AtomicU8::new(PanLaw::default().to_u8()),
+            solo_active: AtomicBool::new(false),
+        }
+    }
+
+    pub fn get_pan_law(&self) -> PanLaw {
+        PanLaw::from_u8(self.pan_law.load(Ordering::Relaxed))
+    }
+
+    pub fn set_pan_law(&self, law: PanLaw) {
+        self.pan_law.store(law.to_u8(), Ordering::Relaxed);
+    }
+
+    pub fn is_solo_active(&self) -> bool {
+        self.solo_active.load(Ordering::Relaxed)
+    }
+
+    pub fn set_solo_active(&self, state: bool) {
+        self.solo_active.store(state, Ordering::Relaxed);
+    }
+}
+
+pub fn db_to_gain(gain_db: f32) -> f32 {
+    if gain_db <= MIN_GAIN_DB {
+        return 0.0;
+    }
+    10.0f32.powf(gain_db / 20.0)
+}
+
+pub struct MixSource<T> {
+    pub rx: FrameReceiver<T>, //frames already mapped to the output bus channels
+    pub params: Arc<MixParams>,
+}
+
+pub struct Mixer<T> {
+    nof_channels: usize,
+    sources: Vec<MixSource<T>>,
+    gains: Vec<Vec<f32>>, //per source and channel, the gain the last block ended on
+    target: Vec<f32>,
+    settings: Arc<MixSettings>,
+    src_buf: Vec<T>,
+    mix_buf: Vec<f32>,
+}
+
+impl<T: cpal::Sample> Mixer<T> {
+    pub fn new(nof_channels: usize, settings: Arc<MixSettings>) -> Mixer<T> {
+        Mixer {
+            nof_channels: nof_channels,
+            sources: Vec::<MixSource<T>>::new(),
+            gains: Vec::<Vec<f32>>::new(),
+            target: vec![0.0; nof_channels],
+            settings: settings,
+            src_buf: vec![cpal::Sample::from(&0.0f32); MIX_BLOCK_FRAMES * nof_channels],
+            mix_buf: vec![0.0; MIX_BLOCK_FRAMES * nof_channels],
+        }
+    }
+
+    pub fn add_sources(&mut self, mut sources: Vec<MixSource<T>>) {
+        for source in sources.iter() {
+            let mut gains = vec![0.0; self.nof_channels];
+            target_gains(&self.settings, &source.params, &mut gains);
+            self.gains.push(gains);
+        }
+        self.sources.append(&mut sources);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sources.is_empty()
+    }
+
+    //true when every source can deliver nof_frames or will not deliver more than it holds
+    pub fn is_ready(&self, nof_frames: usize) -> bool {
+        self.sources
+            .iter()
+            .all(|s| s.rx.available_frames() >= nof_frames || s.rx.is_closed())
+    }
+
+    //Sums the same number of frames, at most MIX_BLOCK_FRAMES, of every source into out and
+    //drops sources that ended. Sources stay aligned: only as many frames as every open
+    //source holds are taken, a source that ended gives what it has left and silence after.
+    //Gain changes are ramped over the frames mixed. Returns the number of frames mixed.
+    pub fn mix(&mut self, out: &mut [T]) -> usize {
+        let block_frames = std::cmp::min(out.len() / self.nof_channels, MIX_BLOCK_FRAMES);
+        let open_frames = self
+            .sources
+            .iter()
+            .filter(|s| !s.rx.is_closed())
+            .map(|s| s.rx.available_frames())
+            .min();
+        let closed_frames = self.sources.iter().map(|s| s.rx.available_frames()).max();
+        let nof_frames = match (open_frames, closed_frames) {
+            (Some(frames), _) => std::cmp::min(frames, block_frames),
+            (None, Some(frames)) => std::cmp::min(frames, block_frames),
+            (None, None) => block_frames, //silence
+        };
+        let nof_samples = nof_frames * self.nof_channels;
+        for sample in self.mix_buf[..nof_samples].iter_mut() {
+            *sample = 0.0;
+        }
+
+        for (source_idx, source) in self.sources.iter_mut().enumerate() {
+            let popped = source.rx.pop_frames(&mut self.src_buf[..nof_samples]);
+
+            target_gains(&self.settings, &source.params, &mut self.target);
+            let (gains, target) = (&mut self.gains[source_idx], &self.target);
+            for frame_idx in 0..popped {
+                let ramp = (frame_idx + 1) as f32 / nof_frames as f32;
+                for ch in 0..self.nof_channels {
+                    let gain = gains[ch] + (target[ch] - gains[ch]) * ramp;
+                    let idx = frame_idx * self.nof_channels + ch;
+                    self.mix_buf[idx] += self.src_buf[idx].to_f32() * gain;
+                }
+            }
+            //a source that ended within the block is dropped below
+            if nof_frames > 0 {
+                gains.copy_from_slice(target);
+            }
+        }
+
+        for (out, mixed) in out.iter_mut().zip(self.mix_buf[..nof_samples].iter()) {
+            *out = cpal::Sample::from(mixed);
+        }
+
+        let mut source_idx = 0;
+        while source_idx < self.sources.len() {
+            if self.sources[source_idx].rx.is_finished() {
+                self.sources.remove(source_idx);
+                self.gains.remove(source_idx);
+            } else {
+                source_idx += 1;
+            }
+        }
+        nof_frames
+    }
+}
+
+//Gain of every output channel for one source, pan applies to (left, right) channel pairs.
+fn target_gains(settings: &MixSettings, params: &MixParams, gains: &mut [f32]) {
+    let (gain_db, pan, mute, solo) = params.as_tup();
+    let silenced = mute || (settings.is_solo_active() && !solo && !params.is_solo_safe());
+    let gain = match silenced {
+        true => 0.0,
+        false => db_to_gain(gain_db),
+    };
+    let (left, right) = match gains.len() {
+        1 => (1.0, 1.0),
+        _ => settings.get_pan_law().gains(pan),
+    };
+    for (ch, g) in gains.iter_mut().enumerate() {
+        *g = match ch % 2 {
+            0 => gain * left,
+            _ => gain * right,
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frames::{frame_channel, FrameSender};
+
+    fn source() -> (FrameSender<f32>, MixSource<f32>) {
+        let (tx, rx) = frame_channel::<f32>(1, 4 * MIX_BLOCK_FRAMES);
+        let source = MixSource {
+            rx: rx,
+            params: Arc::new(MixParams::new()),
+        };
+        (tx, source)
+    }
+
+    //frames from..to of a ramp that starts at offset
+    fn ramp(offset: f32, frames: std::ops::Range<usize>) -> Vec<f32> {
+        frames.map(|idx| offset + idx as f32).collect()
+    }
+
+    #[test]
+    fn keeps_short_sources_aligned() {
+        const SHORT: usize = 100;
+        let mut mixer = Mixer::<f32>::new(1, Arc::new(MixSettings::new()));
+        let (mut a_tx, a) = source();
+        let (mut b_tx, b) = source();
+        mixer.add_sources(vec![a, b]);
+        a_tx.push_frames(&ramp(0.0, 0..MIX_BLOCK_FRAMES));
+        b_tx.push_frames(&ramp(1_000.0, 0..MIX_BLOCK_FRAMES - SHORT));
+        assert!(!mixer.is_ready(MIX_BLOCK_FRAMES));
+
+        let mut block = vec![0.0f32; MIX_BLOCK_FRAMES];
+        let mut mixed = Vec::<f32>::new();
+        let nof_frames = mixer.mix(&mut block);
+        assert_eq!(nof_frames, MIX_BLOCK_FRAMES - SHORT);
+        mixed.extend_from_slice(&block[..nof_frames]);
+
+        b_tx.push_frames(&ramp(
+            1_000.0,
+            MIX_BLOCK_FRAMES - SHORT..2 * MIX_BLOCK_FRAMES,
+        ));
+        a_tx.push_frames(&ramp(0.0, MIX_BLOCK_FRAMES..2 * MIX_BLOCK_FRAMES));
+        while mixed.len() < 2 * MIX_BLOCK_FRAMES {
+            let nof_frames = mixer.mix(&mut block);
+            assert!(nof_frames > 0);
+            mixed.extend_from_slice(&block[..nof_frames]);
+        }
+        for (idx, sample) in mixed.iter().enumerate() {
+            assert_eq!(*sample, 1_000.0 + 2.0 * idx as f32, "frame {}", idx);
+        }
+    }
+
+    #[test]
+    fn ramps_the_gain_over_the_frames_mixed() {
+        let mut mixer = Mixer::<f32>::new(1, Arc::new(MixSettings::new()));
+        let (mut tx, source) = source();
+        let params = source.params.clone();
+        mixer.add_sources(vec![source]);
+        params.set_gain_db(-6.0);
+        let target = db_to_gain(-6.0);
+
+        let mut block = vec![0.0f32; MIX_BLOCK_FRAMES];
+        tx.push_frames(&[1.0; 100]);
+        assert_eq!(mixer.mix(&mut block), 100);
+        assert!(block[..99].windows(2).all(|w| w[1] < w[0]));
+        assert!((block[99] - target).abs() < 1e-6);
+
+        //the next block goes on from the gain reached, without a jump
+        tx.push_frames(&[1.0; MIX_BLOCK_FRAMES]);
+        assert_eq!(mixer.mix(&mut block), MIX_BLOCK_FRAMES);
+        assert!(block.iter().all(|s| (s - target).abs() < 1e-6));
+    }
+
+    #[test]
+    fn mixes_silence_without_sources() {
+        let mut mixer = Mixer::<f32>::new(2, Arc::new(MixSettings::new()));
+        let mut block = vec![1.0f32; 2 * MIX_BLOCK_FRAMES];
+        assert_eq!(mixer.mix(&mut block), MIX_BLOCK_FRAMES);
+        assert!(block.iter().all(|s| *s == 0.0));
+    }
+
+    fn gain_to_db(gain: f32) -> f32 {
+        20.0 * gain.log10()
+    }
+
+    #[test]
+    fn pan_laws_set_the_centre_level() {
+        let centre_db = [
+            (PanLaw::ZeroDb, 0.0),
+            (PanLaw::ConstantPower, -3.0),
+            (PanLaw::Minus4_5Db, -4.5),
+            (PanLaw::Minus6Db, -6.0),
+        ];
+        for (law, db) in centre_db.iter() {
+            let (left, right) = law.gains(0.0);
+            assert_eq!(left, right, "{}", law.name());
+            assert!((gain_to_db(left) - db).abs() < 0.1, "{}", law.name());
+            //hard left and right, and beyond
+            assert_eq!(law.gains(-1.0), (1.0, 0.0), "{}", law.name());
+            let (left, right) = law.gains(2.0);
+            assert!(left.abs() < 1e-6 && right == 1.0, "{}", law.name());
+        }
+    }
+
+    #[test]
+    fn pans_stereo_channel_pairs() {
+        let settings = MixSettings::new();
+        settings.set_pan_law(PanLaw::Minus6Db);
+        let params = MixParams::new();
+        params.set_gain_db(-6.0);
+        params.set_pan(0.5);
+        let mut gains = vec![0.0; 4];
+        target_gains(&settings, &params, &mut gains);
+        let gain = db_to_gain(-6.0);
+        assert_eq!(
+            gains,
+            vec![gain * 0.25, gain * 0.75, gain * 0.25, gain * 0.75]
+        );
+
+        //a mono bus is not panned
+        let mut gains = vec![0.0; 1];
+        target_gains(&settings, &params, &mut gains);
+        assert_eq!(gains, vec![gain]);
+    }
+
+    #[test]
+    fn silences_muted_and_unsoloed_sources() {
+        let settings = MixSettings::new();
+        let params = MixParams::new();
+        let mut gains = vec![0.0; 2];
+        let level = |gains: &mut Vec<f32>| {
+            target_gains(&settings, &params, gains);
+            gains.iter().sum::<f32>()
+        };
+        assert!(level(&mut gains) > 0.0);
+        params.set_mute(true);
+        assert_eq!(level(&mut gains), 0.0);
+        params.set_mute(false);
+
+        //another track is soloed
+        settings.set_solo_active(true);
+        assert_eq!(level(&mut gains), 0.0);
+        params.set_solo_safe(true);
+        assert!(level(&mut gains) > 0.0);
+        params.set_solo_safe(false);
+        params.set_solo(true);
+        assert!(level(&mut gains) > 0.0);
+        //mute wins over solo
+        params.set_mute(true);
+        assert_eq!(level(&mut gains), 0.0);
+    }
+}
@@ -0,0 +1,273 @@
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+
+use crate::error::Result;
+use crate::waveform::{
+    read_waveform, Waveform, PEAK_LEVELS, PEAK_LEVEL_FACTOR, WAVEFORM_BLOCK_FRAMES,
+};
+
+// Peak files: the waveform of a take stored next to it as <take>.peaks, so it is drawn
+// without reading the audio again. Recorded takes get theirs from the write thread when
+// they are finalized, any other take (imports, older sessions) the first time it is loaded.
+// A peak file older than its audio, e.g. after a crash recovery, is built again.
+// Layout, little endian:
+//   PEAK <version u32> <sample rate u32> <frames u64>
+//   <block frames u32> <level factor u32> <levels u32>
+//   <last block min f32, max f32>
+//   per level: <blocks u64> (<min f32> <max f32>) per block
+
+const MAGIC: &[u8; 4] = b"PEAK";
+const VERSION: u32 = 1;
+
+pub fn peak_path(file: &str) -> String {
+    format!("{}.peaks", file)
+}
+
+//The waveform of an audio file from its peak file, built and saved first if the peak file
+//is missing or out of date. A peak file that cannot be written is only reported.
+pub fn load_waveform(file: &str) -> Result<Waveform> {
+    let path = peak_path(file);
+    if is_up_to_date(file, &path) {
+        match read_peak_file(&path) {
+            Ok(waveform) => return Ok(waveform),
+            Err(e) => eprintln!("load_waveform: rebuilding {}: {}", path, e),
+        }
+    }
+    let waveform = read_waveform(file)?;
+    if let Err(e) = write_peak_file(&path, &waveform) {
+        eprintln!("load_waveform: could not write {}: {}", path, e);
+    }
+    Ok(waveform)
+}
+
+//Peak file of the take file, errors are only reported, the take itself is complete
+pub fn save_peaks(file: &str, waveform: &Waveform) {
+    let path = peak_path(file);
+    if let Err(e) = write_peak_file(&path, waveform) {
+        eprintln!("save_peaks: could not write {}: {}", path, e);
+    }
+}
+
+//Removes the peak file of a take file if there is one
+pub fn remove_peaks(file: &str) -> Result<()> {
+    match fs::remove_file(peak_path(file)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+fn is_up_to_date(file: &str, path: &str) -> bool {
+    let modified = |p: &str| fs::metadata(p).and_then(|m| m.modified());
+    match (modified(file), modified(path)) {
+        (Ok(audio), Ok(peaks)) => peaks >= audio,
+        _ => false,
+    }
+}
+
+pub fn write_peak_file(path: &str, waveform: &Waveform) -> Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    writer.write_all(MAGIC)?;
+    writer.write_all(&VERSION.to_le_bytes())?;
+    writer.write_all(&waveform.get_sample_rate().to_le_bytes())?;
+    writer.write_all(&waveform.duration().to_le_bytes())?;
+    writer.write_all(&(WAVEFORM_BLOCK_FRAMES as u32).to_le_bytes())?;
+    writer.write_all(&(PEAK_LEVEL_FACTOR as u32).to_le_bytes())?;
+    writer.write_all(&(PEAK_LEVELS as u32).to_le_bytes())?;
+    let last_block = waveform.get_last_block();
+    writer.write_all(&last_block.0.to_le_bytes())?;
+    writer.write_all(&last_block.1.to_le_bytes())?;
+    for level in waveform.get_levels().iter() {
+        writer.write_all(&(level.get_peaks().len() as u64).to_le_bytes())?;
+        for (min, max) in level.get_peaks().iter() {
+            writer.write_all(&min.to_le_bytes())?;
+            writer.write_all(&max.to_le_bytes())?;
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+pub fn read_peak_file(path: &str) -> Result<Waveform> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut magic = [0u8; 4];
+    reader.read_exact(&mut magic)?;
+    if &magic != MAGIC || read_u32(&mut reader)? != VERSION {
+        return Err(invalid_data("not a peak file"));
+    }
+    let sample_rate = read_u32(&mut reader)?;
+    let nof_frames = read_u64(&mut reader)?;
+    let layout = (
+        read_u32(&mut reader)? as u64,
+        read_u32(&mut reader)? as u64,
+        read_u32(&mut reader)? as usize,
+    );
+    //written with other resolutions
+    if layout != (WAVEFORM_BLOCK_FRAMES, PEAK_LEVEL_FACTOR, PEAK_LEVELS) {
+        return Err(invalid_data("peak file of another layout"));
+    }
+    let last_block = (read_f32(&mut reader)?, read_f32(&mut reader)?);
+    let mut levels = Vec::<Vec<(f32, f32)>>::with_capacity(PEAK_LEVELS);
+    for level in 0..PEAK_LEVELS {
+        let nof_blocks = read_u64(&mut reader)?;
+        let block_frames = WAVEFORM_BLOCK_FRAMES * PEAK_LEVEL_FACTOR.pow(level as u32);
+        if nof_blocks != nof_frames / block_frames {
+            return Err(invalid_data("peak file does not match its length"));
+        }
+        let mut peaks = Vec::<(f32, f32)>::with_capacity(nof_blocks as usize);
+        for _ in 0..nof_blocks {
+            peaks.push((read_f32(&mut reader)?, read_f32(&mut reader)?));
+        }
+        levels.push(peaks);
+    }
+    Ok(Waveform::from_levels(
+        sample_rate,
+        nof_frames,
+        levels,
+        last_block,
+    ))
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> Result<u32> {
+    let mut buf = [0u8; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> Result<u64> {
+    let mut buf = [0u8; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+fn read_f32<R: Read>(reader: &mut R) -> Result<f32> {
+    Ok(f32::from_bits(read_u32(reader)?))
+}
+
+fn invalid_data(msg: &str) -> crate::error::RecorderError {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string()).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, SystemTime};
+
+    const LAYOUT_OFFSET: usize = 20; //of the block frames, after magic, version, rate and frames
+
+    fn temp_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!(
+            "example2-peaks-{}-{}.wav",
+            name,
+            std::process::id()
+        ));
+        path.to_str().unwrap().to_string()
+    }
+
+    //mono take of nof_frames frames of value
+    fn constant_take(file: &str, value: f32, nof_frames: usize) {
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: 48_000,
+            bits_per_sample: 32,
+            sample_format: hound::SampleFormat::Float,
+        };
+        let mut writer = hound::WavWriter::create(file, spec).unwrap();
+        for _ in 0..nof_frames {
+            writer.write_sample(value).unwrap();
+        }
+        writer.finalize().unwrap();
+    }
+
+    fn set_modified(path: &str, time: SystemTime) {
+        File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(time)
+            .unwrap();
+    }
+
+    #[test]
+    fn reads_what_it_wrote() {
+        //every level gets blocks and a partial block is left over
+        let nof_frames = WAVEFORM_BLOCK_FRAMES * PEAK_LEVEL_FACTOR.pow(PEAK_LEVELS as u32) + 100;
+        let mut waveform = Waveform::new(44_100);
+        for idx in 0..nof_frames {
+            let sample = ((idx % 1_000) as f32 / 500.0) - 1.0;
+            waveform.push_frame(&[sample, -sample / 2.0]);
+        }
+        let path = peak_path(&temp_path("round-trip"));
+        write_peak_file(&path, &waveform).unwrap();
+        let read = read_peak_file(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(read.get_sample_rate(), 44_100);
+        assert_eq!(read.duration(), nof_frames);
+        assert_eq!(read.get_last_block(), waveform.get_last_block());
+        assert_eq!(read.get_levels().len(), PEAK_LEVELS);
+        for (level, written) in read.get_levels().iter().zip(waveform.get_levels().iter()) {
+            assert_eq!(level.get_peaks(), written.get_peaks());
+        }
+        for range in [
+            0..1,
+            0..nof_frames,
+            1_000..300_000,
+            nof_frames - 50..nof_frames,
+        ] {
+            assert_eq!(read.get_peak(range.clone()), waveform.get_peak(range));
+        }
+    }
+
+    #[test]
+    fn rejects_peak_files_of_another_layout() {
+        let mut waveform = Waveform::new(48_000);
+        for _ in 0..1_000 {
+            waveform.push_frame(&[0.5f32]);
+        }
+        let path = peak_path(&temp_path("layout"));
+        write_peak_file(&path, &waveform).unwrap();
+        let mut data = fs::read(&path).unwrap();
+        let block_frames = 2 * WAVEFORM_BLOCK_FRAMES as u32;
+        data[LAYOUT_OFFSET..LAYOUT_OFFSET + 4].copy_from_slice(&block_frames.to_le_bytes());
+        fs::write(&path, &data).unwrap();
+        assert!(read_peak_file(&path).is_err());
+
+        data[..4].copy_from_slice(b"RIFF");
+        fs::write(&path, &data).unwrap();
+        assert!(read_peak_file(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn rebuilds_stale_and_invalid_peak_files() {
+        let file = temp_path("stale");
+        let path = peak_path(&file);
+        constant_take(&file, 0.5, 1_000);
+        //blocks start from silence, peaks always take in 0
+        assert_eq!(
+            load_waveform(&file).unwrap().get_peak(0..1_000),
+            Some((0.0, 0.5))
+        );
+        assert!(is_up_to_date(&file, &path));
+
+        //the take changed after its peaks were written, e.g. by a crash recovery
+        constant_take(&file, -0.25, 2_000);
+        let now = SystemTime::now();
+        set_modified(&path, now - Duration::from_secs(10));
+        set_modified(&file, now);
+        assert!(!is_up_to_date(&file, &path));
+        let waveform = load_waveform(&file).unwrap();
+        assert_eq!(waveform.duration(), 2_000);
+        assert_eq!(waveform.get_peak(0..2_000), Some((-0.25, 0.0)));
+        assert!(is_up_to_date(&file, &path));
+
+        //a current peak file that does not read is replaced
+        fs::write(&path, b"PEAK").unwrap();
+        assert_eq!(load_waveform(&file).unwrap().duration(), 2_000);
+        assert_eq!(read_peak_file(&path).unwrap().duration(), 2_000);
+
+        fs::remove_file(&file).unwrap();
+        remove_peaks(&file).unwrap();
+        assert!(!std::path::Path::new(&path).exists());
+    }
+}
@@ -0,0 +1,176 @@
+use std::fs::{File, OpenOptions};
+use std::io::Read;
+use std::path::Path;
+
+use crate::error::Result;
+use crate::flac;
+use crate::formats::count_flac_frames;
+use crate::wav;
+
+// Takes that were not finalized (crash, power failure, killed process) keep the header
+// lengths of the last flush, or 0 if there was none. Everything after the header is still
+// audio, so the lengths are recomputed from the file size. FLAC takes have no total
+// length until they are finalized, it is counted from the frames that decode.
+
+//Repairs the RIFF/RF64 and data chunk lengths of a wav file and drops a trailing partial
+//frame. Returns the number of frames of the repaired file, None when it was already complete.
+pub fn repair_wav(path: &str) -> Result<Option<u64>> {
+    let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+    let file_len = file.metadata()?.len();
+
+    let layout = wav::read_layout(&mut file)?;
+    if layout.riff_len + 8 == file_len {
+        return Ok(None);
+    }
+
+    let mut data_len =
+        file_len.saturating_sub(layout.data_offset) / layout.block_align * layout.block_align;
+    if !layout.ds64 {
+        //no room for a ds64 chunk, the file stays RIFF and loses what is past 4 GB
+        let max_len = wav::max_riff_data_len(layout.data_offset);
+        data_len = std::cmp::min(data_len, max_len / layout.block_align * layout.block_align);
+    }
+    wav::write_lengths(&mut file, layout.data_offset, data_len, layout.block_align)?;
+    file.set_len(layout.data_offset + data_len + (data_len & 1))?;
+    file.sync_all()?;
+
+    Ok(Some(data_len / layout.block_align))
+}
+
+//Writes the total length into the STREAMINFO of a FLAC take that has none. A frame that
+//was cut off is left at the end of the file, decoders stop at it.
+pub fn repair_flac(path: &str) -> Result<Option<u64>> {
+    let reader = claxon::FlacReader::open(path)?;
+    if reader.streaminfo().samples.is_some() {
+        return Ok(None);
+    }
+    let nof_frames = count_flac_frames(path)?;
+    if nof_frames == 0 {
+        return Ok(None);
+    }
+    let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+    flac::write_total_samples(&mut file, nof_frames)?;
+    file.sync_all()?;
+    Ok(Some(nof_frames))
+}
+
+fn is_flac(path: &str) -> Result<bool> {
+    let mut magic = [0u8; 4];
+    let nof_bytes = File::open(path)?.read(&mut magic)?;
+    Ok(nof_bytes == 4 && &magic == b"fLaC")
+}
+
+//Checks every file that exists, returns (file, frames) of the repaired ones and
+//(file, error) of the ones that could not be read.
+pub fn recover_takes(files: &[String]) -> Vec<(String, Result<u64>)> {
+    let mut recovered = Vec::<(String, Result<u64>)>::new();
+    for file in files.iter() {
+        if !Path::new(file).exists() {
+            continue;
+        }
+        let repaired = match is_flac(file) {
+            Ok(true) => repair_flac(file),
+            Ok(false) => repair_wav(file),
+            Err(e) => Err(e),
+        };
+        match repaired {
+            Ok(Some(frames)) => recovered.push((file.clone(), Ok(frames))),
+            Ok(None) => (),
+            Err(e) => recovered.push((file.clone(), Err(e))),
+        }
+    }
+    recovered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::formats::{RecordFormat, TakeReader, TakeWriter};
+    use crate::wav::BextInfo;
+
+    const BLOCK: u64 = 4_096; //FlacWriter's block size
+
+    fn temp_path(name: &str, extension: &str) -> String {
+        let path = std::env::temp_dir().join(format!(
+            "example2-recovery-{}-{}.{}",
+            name,
+            std::process::id(),
+            extension
+        ));
+        path.to_str().unwrap().to_string()
+    }
+
+    //stereo take at 48 kHz
+    fn writer(path: &str, format: RecordFormat) -> TakeWriter {
+        let bext = BextInfo::new("recovery test".to_string(), 0);
+        TakeWriter::create::<f32>(path, format, 48_000, 2, &bext).unwrap()
+    }
+
+    #[test]
+    fn repairs_wav_takes_cut_off_mid_write() {
+        let path = temp_path("cut", "wav");
+        let mut take = writer(&path, RecordFormat::Int24);
+        for _ in 0..1_000 {
+            take.write_frame(&[0.5f32, -0.5]).unwrap();
+        }
+        //the header was flushed at 1000 frames, the file goes on into frame 1300
+        take.flush().unwrap();
+        for _ in 0..500 {
+            take.write_frame(&[0.5f32, -0.5]).unwrap();
+        }
+        take.finalize().unwrap();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .unwrap();
+        let layout = wav::read_layout(&mut file).unwrap();
+        assert_eq!(layout.block_align, 6);
+        wav::write_lengths(&mut file, layout.data_offset, 1_000 * 6, 6).unwrap();
+        file.set_len(layout.data_offset + 1_300 * 6 + 2).unwrap();
+        drop(file);
+
+        assert_eq!(repair_wav(&path).unwrap(), Some(1_300));
+        let len = std::fs::metadata(&path).unwrap().len();
+        assert_eq!(len, layout.data_offset + 1_300 * 6);
+        assert_eq!(TakeReader::open(&path).unwrap().duration(), 1_300);
+        //nothing left to repair
+        assert_eq!(repair_wav(&path).unwrap(), None);
+        std::fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn repairs_flac_takes_cut_off_mid_write() {
+        let path = temp_path("cut", "flac");
+        let mut take = writer(&path, RecordFormat::Flac24);
+        for idx in 0..10 * BLOCK + BLOCK / 2 {
+            let sample = (idx % 1_000) as f32 / 1_000.0;
+            take.write_frame(&[sample, -sample]).unwrap();
+        }
+        //never finalized, the last block is cut off
+        drop(take);
+        let len = std::fs::metadata(&path).unwrap().len();
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(len - 100).unwrap();
+        drop(file);
+        assert_eq!(
+            claxon::FlacReader::open(&path)
+                .unwrap()
+                .streaminfo()
+                .samples,
+            None
+        );
+
+        assert_eq!(repair_flac(&path).unwrap(), Some(10 * BLOCK));
+        assert_eq!(
+            claxon::FlacReader::open(&path)
+                .unwrap()
+                .streaminfo()
+                .samples,
+            Some(10 * BLOCK)
+        );
+        assert_eq!(TakeReader::open(&path).unwrap().duration(), 10 * BLOCK);
+        assert_eq!(repair_flac(&path).unwrap(), None);
+        std::fs::remove_file(path).ok();
+    }
+}
@@ -0,0 +1,364 @@
+use std::f32::consts::{FRAC_PI_2, PI};
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::{RecorderError, Result};
+use crate::formats::TakeReader;
+
+// Regions: non-destructive editing of a track's takes. A region plays length frames of a
+// take from source_offset on at timeline frame position, faded in and out and with a gain
+// of its own. The take files are never changed, trimming or splitting a region only
+// changes which part of its take it plays. Regions may overlap, they are mixed, so a fade
+// out over the fade in of the next region is a crossfade.
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+pub enum FadeCurve {
+    #[default]
+    Linear,
+    EqualPower, //sine/cosine, a crossfade of two of them keeps the level of uncorrelated takes
+    SCurve,     //slow at both ends
+}
+
+impl FadeCurve {
+    pub const ALL: [FadeCurve; 3] = [FadeCurve::Linear, FadeCurve::EqualPower, FadeCurve::SCurve];
+
+    //gain at x of the fade, 0.0 silent to 1.0 full level
+    pub fn gain(&self, x: f32) -> f32 {
+        let x = x.clamp(0.0, 1.0);
+        match self {
+            FadeCurve::Linear => x,
+            FadeCurve::EqualPower => (x * FRAC_PI_2).sin(),
+            FadeCurve::SCurve => (1.0 - (x * PI).cos()) / 2.0,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+pub struct Fade {
+    pub frames: u64, //0 for none
+    pub curve: FadeCurve,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Region {
+    pub id: u32,            //unique within the track
+    pub take: String,       //file of the take the region plays
+    pub source_offset: u64, //first frame of the take it plays
+    pub position: u64,      //timeline frame it starts at, all frames at the session rate
+    pub length: u64,
+    #[serde(default)]
+    pub fade_in: Fade,
+    #[serde(default)]
+    pub fade_out: Fade,
+    #[serde(default)]
+    pub gain_db: f32,
+}
+
+impl Region {
+    //timeline frame after the last one of the region
+    pub fn end(&self) -> u64 {
+        self.position + self.length
+    }
+
+    //timeline frame the first frame of the take would play at, before 0 if the region plays
+    //it from later on than its position
+    pub fn take_start(&self) -> i64 {
+        self.position as i64 - self.source_offset as i64
+    }
+
+    pub fn check(&self) -> Result<()> {
+        if self.length == 0 {
+            return Err(RecorderError::InvalidArgument(format!(
+                "region {} is empty",
+                self.id
+            )));
+        }
+        if self.fade_in.frames + self.fade_out.frames > self.length {
+            return Err(RecorderError::InvalidArgument(format!(
+                "fades of region {} are longer than the region",
+                self.id
+            )));
+        }
+        Ok(())
+    }
+
+    //Shortens the fades to fit a region that got shorter, the fade in first
+    pub fn fit_fades(&mut self) {
+        self.fade_in.frames = std::cmp::min(self.fade_in.frames, self.length);
+        self.fade_out.frames =
+            std::cmp::min(self.fade_out.frames, self.length - self.fade_in.frames);
+    }
+
+    //Fades and gain at timeline frame, 0.0 outside the region
+    pub fn gain_at(&self, frame: u64) -> f32 {
+        if frame < self.position || frame >= self.end() {
+            return 0.0;
+        }
+        let mut gain = 10f32.powf(self.gain_db / 20.0);
+        let into = frame - self.position;
+        if into < self.fade_in.frames {
+            gain *= self
+                .fade_in
+                .curve
+                .gain(into as f32 / self.fade_in.frames as f32);
+        }
+        let left = self.end() - frame;
+        if left <= self.fade_out.frames {
+            gain *= self
+                .fade_out
+                .curve
+                .gain(left as f32 / self.fade_out.frames as f32);
+        }
+        gain
+    }
+}
+
+pub fn find_region(regions: &[Region], id: u32) -> Result<usize> {
+    regions
+        .iter()
+        .position(|r| r.id == id)
+        .ok_or(RecorderError::InvalidArgument(format!("no region {}", id)))
+}
+
+//id for a region added to regions
+pub fn next_region_id(regions: &[Region]) -> u32 {
+    regions.iter().map(|r| r.id + 1).max().unwrap_or(1)
+}
+
+//Reads one region's take while the timeline passes it
+struct RegionCursor {
+    region: Region,
+    reader: Option<TakeReader>,
+    frame: Vec<f32>,
+}
+
+// Renders regions frame by frame at the session rate, like a TakeReader renders a take.
+pub struct RegionReader {
+    cursors: Vec<RegionCursor>,
+    position: u64, //timeline frame read next
+    end: u64,
+    nof_channels: usize,
+    mix: Vec<f32>,
+}
+
+impl RegionReader {
+    //Regions positioned at start_frame or where the first region starts if that is later.
+    //Their takes must be at sample_rate, the rate the regions are counted in.
+    pub fn open(
+        regions: &[Region],
+        sample_rate: u32,
+        nof_channels: usize,
+        start_frame: u64,
+    ) -> Result<RegionReader> {
+        let cursors: Vec<RegionCursor> = regions
+            .iter()
+            .map(|r| RegionCursor {
+                region: r.clone(),
+                reader: None,
+                frame: Vec::<f32>::with_capacity(nof_channels),
+            })
+            .collect();
+        let start = regions.iter().map(|r| r.position).min().unwrap_or(0);
+        let end = regions.iter().map(|r| r.end()).max().unwrap_or(0);
+
+        let reader = RegionReader {
+            cursors: cursors,
+            position: std::cmp::max(start, start_frame),
+            end: end,
+            nof_channels: nof_channels,
+            mix: vec![0.0; nof_channels],
+        };
+        reader.check_rates(sample_rate)?;
+        Ok(reader)
+    }
+
+    //timeline frame the regions are read from next
+    pub fn get_position(&self) -> u64 {
+        self.position
+    }
+
+    //timeline frame after the last one a region plays
+    pub fn get_end(&self) -> u64 {
+        self.end
+    }
+
+    pub fn get_nof_channels(&self) -> u16 {
+        self.nof_channels as u16
+    }
+
+    //Replaces frame with the next frame, false after the last region
+    pub fn read_frame<T: cpal::Sample>(&mut self, frame: &mut Vec<T>) -> Result<bool> {
+        frame.clear();
+        if self.position >= self.end {
+            return Ok(false);
+        }
+        for sample in self.mix.iter_mut() {
+            *sample = 0.0;
+        }
+
+        let position = self.position;
+        for cursor in self.cursors.iter_mut() {
+            let region = &cursor.region;
+            if position < region.position || position >= region.end() {
+                cursor.reader = None;
+                continue;
+            }
+            if cursor.reader.is_none() {
+                let mut reader = TakeReader::open(&region.take)?;
+                reader.seek(region.source_offset + position - region.position)?;
+                cursor.reader = Some(reader);
+            }
+            let reader = cursor.reader.as_mut().unwrap();
+            if !reader.read_frame::<f32>(&mut cursor.frame)? {
+                continue;
+            }
+
+            let gain = region.gain_at(position);
+            for (ch, sample) in self.mix.iter_mut().enumerate() {
+                if let Some(s) = cursor.frame.get(ch) {
+                    *sample += s * gain;
+                }
+            }
+        }
+
+        for sample in self.mix.iter() {
+            frame.push(cpal::Sample::from(sample));
+        }
+        self.position += 1;
+        Ok(true)
+    }
+
+    fn check_rates(&self, sample_rate: u32) -> Result<()> {
+        for cursor in self.cursors.iter() {
+            let take_rate = TakeReader::open(&cursor.region.take)?.get_sample_rate();
+            if take_rate != sample_rate {
+                return Err(RecorderError::InvalidArgument(format!(
+                    "take {} is at {} Hz, the regions at {} Hz",
+                    cursor.region.take, take_rate, sample_rate
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 48_000;
+
+    //mono take whose frame idx is value(idx)
+    fn take(name: &str, nof_frames: usize, value: impl Fn(usize) -> f32) -> String {
+        let path = std::env::temp_dir().join(format!(
+            "example2-regions-{}-{}.wav",
+            name,
+            std::process::id()
+        ));
+        let file = path.to_str().unwrap().to_string();
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: RATE,
+            bits_per_sample: 32,
+            sample_format: hound::SampleFormat::Float,
+        };
+        let mut writer = hound::WavWriter::create(&file, spec).unwrap();
+        for idx in 0..nof_frames {
+            writer.write_sample(value(idx)).unwrap();
+        }
+        writer.finalize().unwrap();
+        file
+    }
+
+    fn region(id: u32, take: &str, source_offset: u64, position: u64, length: u64) -> Region {
+        Region {
+            id: id,
+            take: take.to_string(),
+            source_offset: source_offset,
+            position: position,
+            length: length,
+            fade_in: Fade::default(),
+            fade_out: Fade::default(),
+            gain_db: 0.0,
+        }
+    }
+
+    #[test]
+    fn fades_at_the_region_edges() {
+        let mut fading = region(1, "take.wav", 0, 100, 100);
+        fading.fade_in = Fade {
+            frames: 10,
+            curve: FadeCurve::Linear,
+        };
+        fading.fade_out = Fade {
+            frames: 10,
+            curve: FadeCurve::EqualPower,
+        };
+        fading.check().unwrap();
+        assert_eq!(fading.gain_at(99), 0.0);
+        assert_eq!(fading.gain_at(100), 0.0);
+        assert_eq!(fading.gain_at(105), 0.5);
+        assert_eq!(fading.gain_at(110), 1.0);
+        assert_eq!(fading.gain_at(189), 1.0);
+        assert_eq!(fading.gain_at(190), 1.0);
+        assert!((fading.gain_at(195) - (0.5 * FRAC_PI_2).sin()).abs() < 1e-6);
+        assert!((fading.gain_at(199) - (0.1 * FRAC_PI_2).sin()).abs() < 1e-6);
+        assert_eq!(fading.gain_at(200), 0.0);
+
+        //the region gain applies on top of the fades
+        fading.gain_db = -6.0;
+        let gain = 10f32.powf(-6.0 / 20.0);
+        assert!((fading.gain_at(105) - 0.5 * gain).abs() < 1e-6);
+        assert!((fading.gain_at(150) - gain).abs() < 1e-6);
+
+        //fades longer than the region are rejected, shortened ones fit
+        fading.length = 15;
+        assert!(fading.check().is_err());
+        fading.fit_fades();
+        assert_eq!((fading.fade_in.frames, fading.fade_out.frames), (10, 5));
+        fading.check().unwrap();
+    }
+
+    #[test]
+    fn mixes_overlapping_regions() {
+        let ramp = take("ramp", 200, |idx| idx as f32 / 1_000.0);
+        let constant = take("constant", 200, |_| 0.5);
+        //the ramp fades out over the fade in of the constant take, played from frame 50 on
+        let mut a = region(1, &ramp, 0, 0, 100);
+        a.fade_out = Fade {
+            frames: 20,
+            curve: FadeCurve::EqualPower,
+        };
+        let mut b = region(2, &constant, 50, 80, 100);
+        b.fade_in = Fade {
+            frames: 20,
+            curve: FadeCurve::EqualPower,
+        };
+        b.gain_db = -6.0;
+        let regions = vec![a.clone(), b.clone()];
+
+        let mut reader = RegionReader::open(&regions, RATE, 1, 10).unwrap();
+        assert_eq!(reader.get_end(), 180);
+        let mut frame = Vec::<f32>::new();
+        let mut played = Vec::<f32>::new();
+        while reader.read_frame(&mut frame).unwrap() {
+            played.push(frame[0]);
+        }
+        std::fs::remove_file(&ramp).unwrap();
+        std::fs::remove_file(&constant).unwrap();
+
+        assert_eq!(played.len(), 170);
+        for (idx, sample) in played.iter().enumerate() {
+            let position = 10 + idx as u64;
+            let expected =
+                position as f32 / 1_000.0 * a.gain_at(position) + 0.5 * b.gain_at(position);
+            assert!(
+                (sample - expected).abs() < 1e-6,
+                "frame {}: {} instead of {}",
+                position,
+                sample,
+                expected
+            );
+        }
+    }
+}
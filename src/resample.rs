@@ -0,0 +1,215 @@
+use rubato::{FftFixedIn, Resampler};
+
+use std::marker::PhantomData;
+
+use crate::error::{RecorderError, Result};
+
+// Sample rate conversion of interleaved frames between the input device, the output device,
+// takes and the session rate. FFT based and exact for any two integer rates. The filter
+// delay is dropped from the start of the output, so converted audio stays where it was on
+// the timeline.
+
+pub const RESAMPLE_CHUNK_FRAMES: usize = 1_024; //recording, playback and bounce
+pub const MONITOR_CHUNK_FRAMES: usize = 256; //less latency for input monitoring
+
+pub struct FrameResampler<T> {
+    resampler: Option<FftFixedIn<f32>>, //None when both rates are the same
+    nof_channels: usize,
+    ratio: f64,             //output frames per input frame
+    in_buf: Vec<Vec<f32>>,  //per channel, input frames of the chunk being filled
+    out_buf: Vec<Vec<f32>>, //per channel, output of the last chunk
+    delay: usize,           //output frames still to be dropped
+    nof_in_frames: u64,     //input frames received
+    nof_out_frames: u64,    //output frames delivered
+    _sample: PhantomData<T>,
+}
+
+impl<T: cpal::Sample> FrameResampler<T> {
+    pub fn new(
+        from_rate: u32,
+        to_rate: u32,
+        nof_channels: usize,
+        chunk_frames: usize,
+    ) -> Result<FrameResampler<T>> {
+        let resampler = match from_rate == to_rate {
+            true => None,
+            false => Some(
+                FftFixedIn::<f32>::new(
+                    from_rate as usize,
+                    to_rate as usize,
+                    chunk_frames,
+                    1,
+                    nof_channels,
+                )
+                .map_err(|e| {
+                    RecorderError::InvalidArgument(format!(
+                        "cannot convert {} Hz to {} Hz: {}",
+                        from_rate, to_rate, e
+                    ))
+                })?,
+            ),
+        };
+        let (out_buf, delay) = match &resampler {
+            Some(r) => (r.output_buffer_allocate(true), r.output_delay()),
+            None => (Vec::<Vec<f32>>::new(), 0),
+        };
+        Ok(FrameResampler {
+            resampler: resampler,
+            nof_channels: nof_channels,
+            ratio: to_rate as f64 / from_rate as f64,
+            in_buf: vec![Vec::<f32>::with_capacity(chunk_frames); nof_channels],
+            out_buf: out_buf,
+            delay: delay,
+            nof_in_frames: 0,
+            nof_out_frames: 0,
+            _sample: PhantomData,
+        })
+    }
+
+    //Converts the interleaved frames of input and appends the converted frames that are
+    //complete to output. Input is buffered until a whole chunk is there.
+    pub fn process(&mut self, input: &[T], output: &mut Vec<T>) -> Result<()> {
+        let chunk_frames = match &self.resampler {
+            Some(r) => r.input_frames_next(),
+            None => {
+                output.extend_from_slice(input);
+                return Ok(());
+            }
+        };
+        for frame in input.chunks(self.nof_channels) {
+            for (ch, sample) in frame.iter().enumerate() {
+                self.in_buf[ch].push(sample.to_f32());
+            }
+            self.nof_in_frames += 1;
+            if self.in_buf[0].len() == chunk_frames {
+                self.process_chunk(output)?;
+            }
+        }
+        Ok(())
+    }
+
+    //End of the input, converts what is still buffered so that every input frame has its
+    //output frames.
+    pub fn flush(&mut self, output: &mut Vec<T>) -> Result<()> {
+        let chunk_frames = match &self.resampler {
+            Some(r) => r.input_frames_next(),
+            None => return Ok(()),
+        };
+        let expected = (self.nof_in_frames as f64 * self.ratio).round() as u64;
+        let start = output.len();
+        while self.nof_out_frames < expected {
+            //silence pushes the buffered frames and the filter delay through
+            for channel in self.in_buf.iter_mut() {
+                channel.resize(chunk_frames, 0.0);
+            }
+            self.process_chunk(output)?;
+        }
+        //the last chunk was padded, only what the input accounts for is kept
+        let excess = self.nof_out_frames.saturating_sub(expected) as usize * self.nof_channels;
+        let excess = std::cmp::min(excess, output.len() - start);
+        output.truncate(output.len() - excess);
+        self.nof_out_frames -= (excess / self.nof_channels) as u64;
+        Ok(())
+    }
+
+    fn process_chunk(&mut self, output: &mut Vec<T>) -> Result<()> {
+        let resampler = match &mut self.resampler {
+            Some(r) => r,
+            None => return Ok(()),
+        };
+        let (_, nof_out) = resampler
+            .process_into_buffer(&self.in_buf, &mut self.out_buf, None)
+            .map_err(|e| RecorderError::InvalidArgument(format!("resampler: {}", e)))?;
+        for channel in self.in_buf.iter_mut() {
+            channel.clear();
+        }
+
+        let skip = std::cmp::min(self.delay, nof_out);
+        self.delay -= skip;
+        for frame_idx in skip..nof_out {
+            for ch in 0..self.nof_channels {
+                output.push(cpal::Sample::from(&self.out_buf[ch][frame_idx]));
+            }
+        }
+        self.nof_out_frames += (nof_out - skip) as u64;
+        Ok(())
+    }
+}
+
+//A frame count at one rate in frames at another, e.g. a timeline position at the output rate
+pub fn convert_frames(frames: u64, from_rate: u32, to_rate: u32) -> u64 {
+    match from_rate == to_rate {
+        true => frames,
+        false => (frames as u128 * to_rate as u128 / from_rate as u128) as u64,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //converts nof_frames stereo frames with an impulse at impulse_frame, in blocks that do
+    //not line up with the chunks
+    fn convert(from_rate: u32, to_rate: u32, nof_frames: usize, impulse_frame: usize) -> Vec<f32> {
+        let mut input = vec![0.0f32; 2 * nof_frames];
+        input[2 * impulse_frame] = 1.0;
+        input[2 * impulse_frame + 1] = -1.0;
+        let mut resampler =
+            FrameResampler::<f32>::new(from_rate, to_rate, 2, RESAMPLE_CHUNK_FRAMES).unwrap();
+        let mut output = Vec::<f32>::new();
+        for block in input.chunks(2 * 300) {
+            resampler.process(block, &mut output).unwrap();
+        }
+        resampler.flush(&mut output).unwrap();
+        output
+    }
+
+    #[test]
+    fn flushes_every_frame_the_input_accounts_for() {
+        for (from_rate, to_rate) in [(44_100, 48_000), (48_000, 44_100), (48_000, 96_000)] {
+            for nof_frames in [1, 299, RESAMPLE_CHUNK_FRAMES, 5_000] {
+                let output = convert(from_rate, to_rate, nof_frames, 0);
+                let expected = (nof_frames as f64 * to_rate as f64 / from_rate as f64).round();
+                assert_eq!(
+                    output.len(),
+                    2 * expected as usize,
+                    "{} frames from {} Hz to {} Hz",
+                    nof_frames,
+                    from_rate,
+                    to_rate
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn removes_the_filter_delay() {
+        const IMPULSE_FRAME: usize = 2_000;
+        for (from_rate, to_rate) in [(44_100, 48_000), (48_000, 44_100), (48_000, 96_000)] {
+            let output = convert(from_rate, to_rate, 5_000, IMPULSE_FRAME);
+            let peak = output
+                .chunks(2)
+                .enumerate()
+                .max_by(|a, b| a.1[0].partial_cmp(&b.1[0]).unwrap())
+                .unwrap();
+            let expected = convert_frames(IMPULSE_FRAME as u64, from_rate, to_rate) as i64;
+            assert!(
+                (peak.0 as i64 - expected).abs() <= 1,
+                "{} Hz to {} Hz: impulse at {}, expected {}",
+                from_rate,
+                to_rate,
+                peak.0,
+                expected
+            );
+            assert!(peak.1[1] < 0.0);
+        }
+    }
+
+    #[test]
+    fn passes_the_same_rate_through() {
+        let output = convert(48_000, 48_000, 1_000, 10);
+        assert_eq!(output.len(), 2 * 1_000);
+        assert_eq!(&output[20..22], &[1.0, -1.0]);
+        assert_eq!(output.iter().filter(|s| **s != 0.0).count(), 2);
+    }
+}
@@ -1,19 +1,43 @@
-use cpal::traits::{DeviceTrait, HostTrait};
-use cpal::{Device, Host, SampleFormat, StreamConfig};
-use multiqueue::BroadcastReceiver;
+use cpal::{BufferSize, Host, SampleFormat, StreamConfig};
 
 use std::sync::mpsc::{self};
 use std::sync::mpsc::{Receiver, Sender};
 
 use std::io::Error;
-use std::thread;
+use std::ops::Range;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
 
-use crate::busses::{BusConfig, InputBus, OutputBus};
-use crate::tracks::Track;
-use crate::utils::{
-    get_flushed_broadcast_queue, get_flushed_mpsc_queue, get_input_device_by_name,
-    get_output_device_by_name,
+use crate::backend::{AudioBackend, CpalBackend};
+use crate::busses::{
+    check_channel_ids, default_channel_map, BusConfig, ChannelMap, InputBus, OutputBus, PlayMark,
 };
+use crate::calibration::{capture_frames, measure_round_trip};
+use crate::comp::CompRegion;
+use crate::error::{RecorderError, Result};
+use crate::formats::{RecordFormat, TakeWriter};
+use crate::frames::{frame_channel, FrameSender};
+use crate::history::{Change, Command, History};
+use crate::import;
+use crate::meters::MeterLevels;
+use crate::metronome::{start_click, MetronomeSettings};
+use crate::mixer::{MixParams, MixSettings, MixSource, Mixer, PanLaw, MIX_BLOCK_FRAMES};
+use crate::recovery;
+use crate::regions::Fade;
+use crate::resample::convert_frames;
+use crate::session::{
+    sample_format_to_str, Session, SessionConfig, StreamConfigState, TrackState, SESSION_VERSION,
+};
+use crate::tracks::{TakeSelection, TakeTiming, Track};
+use crate::transport::{Transport, TransportState};
+use crate::wav::BextInfo;
+
+const OUT_RING_FRAMES: usize = 4 * MIX_BLOCK_FRAMES;
+const REC_RING_FRAMES: usize = 192_000;
+const MONITOR_RING_FRAMES: usize = 4_096;
 
 struct RouteMap {
     routes: Vec<(u8, u8, Vec<u8>)>, // (input bus, output bus, track_list)
@@ -40,6 +64,12 @@ impl RouteMap {
         }
     }
 
+    pub fn remove_track(&mut self, track_id: &u8) {
+        for route in self.routes.iter_mut() {
+            route.2.retain(|id| id != track_id);
+        }
+    }
+
     pub fn get_track_busses(&self, track_id: &u8) -> Option<(u8, u8)> {
         // (in_bus, out_bus)
         for route in self.routes.iter() {
@@ -51,6 +81,10 @@ impl RouteMap {
         return None;
     }
 
+    pub fn get_routes(&self) -> Vec<(u8, u8, Vec<u8>)> {
+        self.routes.clone()
+    }
+
     pub fn get_route_track_ids(&self, in_id: &u8, out_id: &u8) -> Option<Vec<u8>> {
         for route in self.routes.iter() {
             let (input, output) = (route.0, route.1);
@@ -62,38 +96,64 @@ impl RouteMap {
     }
 }
 
+type BusSender<T> = Arc<Mutex<FrameSender<T>>>;
+
 struct MonitorLink<T> {
     out_bus_id: u8,
-    tx_to_bus: Sender<(u8, T)>,
-    pub rxs_from_monitors: Vec<Receiver<(u8, T)>>,
+    tx_to_bus: BusSender<T>,
+    pub sources: Vec<MixSource<T>>,
 }
 
 impl<T> MonitorLink<T> {
-    pub fn as_tup(self) -> (u8, Sender<(u8, T)>, Vec<Receiver<(u8, T)>>) {
-        (self.out_bus_id, self.tx_to_bus, self.rxs_from_monitors)
+    pub fn as_tup(self) -> (u8, BusSender<T>, Vec<MixSource<T>>) {
+        (self.out_bus_id, self.tx_to_bus, self.sources)
     }
 }
 
 pub struct RouteConfig {
-    pub host: Host,
     pub in_config: StreamConfig,
     pub out_config: StreamConfig,
     pub in_device: String,
     pub out_device: String,
     pub sample_format: SampleFormat,
+    pub record_format: RecordFormat, //file format of new takes
+    pub sample_rate: u32, //session rate, takes are recorded at it and timeline frames count it
+    pub latency: u64,     //round trip latency in frames, dropped from the start of takes
+    pub timecode_origin: u64, //timeline frame 0 in samples since midnight, for BWF time references
 }
 
 pub struct Router<T: 'static + cpal::Sample + hound::Sample + Send + Sync> {
     pub config: RouteConfig,
+    backend: Box<dyn AudioBackend<T>>,
     tracks: Vec<Track>,
-    input_busses: Vec<(
-        BroadcastReceiver<(u8, T)>,
-        BroadcastReceiver<(u8, T)>,
-        InputBus<T>,
-    )>, // (record_rx, monitor_rx, input_bus)
-    output_busses: Vec<(Sender<(u8, T)>, OutputBus<T>)>, //(bus_tx, output_bus)
+    input_busses: Vec<InputBus<T>>,
+    output_busses: Vec<(BusSender<T>, OutputBus<T>)>, //(bus_tx, output_bus)
     routes: RouteMap,
     monitor_txs: Vec<Sender<()>>,
+    mix_settings: Arc<MixSettings>,
+    metronome: MetronomeSettings,
+    click_mix: Arc<MixParams>, //level of the click, never silenced by a solo
+    transport: Transport,
+    history: History,
+    save_history: bool,            //the history is saved with the session
+    rec_before: Option<EditState>, //while recording, the state when it started
+    ref_clock: Arc<AtomicU64>,     //frames output bus 0 has played, see busses.rs
+}
+
+//What an edit changes, only that is part of the state before and after it
+#[derive(Clone, Copy)]
+enum EditTarget {
+    Track(u8),
+    NewTrack, //the tracks the edit adds
+    Session,  //cycle, metronome and pan law
+}
+
+#[derive(Clone)]
+struct EditState {
+    tracks: Vec<(TrackState, u8, u8)>,
+    cycle: Option<Range<u64>>,
+    metronome: MetronomeSettings,
+    pan_law: PanLaw,
 }
 
 impl<T: 'static + cpal::Sample + hound::Sample + Send + Sync> Router<T> {
@@ -104,127 +164,654 @@ impl<T: 'static + cpal::Sample + hound::Sample + Send + Sync> Router<T> {
         in_device_name: String,
         out_device_name: String,
         sample_format: SampleFormat,
+    ) -> Result<Router<T>> {
+        let backend = CpalBackend::new(host, in_device_name.clone(), out_device_name.clone())?;
+        let mut router =
+            Router::with_backend(Box::new(backend), in_config, out_config, sample_format);
+        router.config.in_device = in_device_name;
+        router.config.out_device = out_device_name;
+        Ok(router)
+    }
+
+    pub fn with_backend(
+        backend: Box<dyn AudioBackend<T>>,
+        in_config: StreamConfig,
+        out_config: StreamConfig,
+        sample_format: SampleFormat,
     ) -> Router<T> {
+        let device_name = backend.name();
+        let sample_rate = in_config.sample_rate.0;
+        let latency = default_latency(&in_config, &out_config, sample_rate);
+        let click_mix = Arc::new(MixParams::new());
+        click_mix.set_solo_safe(true);
         Router {
             config: RouteConfig {
-                host: host,
                 in_config: in_config,
                 out_config: out_config,
-                in_device: in_device_name,
-                out_device: out_device_name,
+                in_device: device_name.clone(),
+                out_device: device_name,
                 sample_format: sample_format,
+                record_format: RecordFormat::from_sample_format(sample_format),
+                sample_rate: sample_rate,
+                latency: latency,
+                timecode_origin: 0,
             },
+            backend: backend,
             tracks: Vec::<Track>::new(),
-            input_busses: Vec::<(
-                BroadcastReceiver<(u8, T)>,
-                BroadcastReceiver<(u8, T)>,
-                InputBus<T>,
-            )>::new(), //(Rx for recording, Rx for monitoring, InputBus)
-            output_busses: Vec::<(Sender<(u8, T)>, OutputBus<T>)>::new(), //(Sender for sending samples, OutputBus)
+            input_busses: Vec::<InputBus<T>>::new(),
+            output_busses: Vec::<(BusSender<T>, OutputBus<T>)>::new(), //(Sender for mixed frames, OutputBus)
             routes: RouteMap::new(),
             monitor_txs: Vec::<Sender<()>>::new(),
+            mix_settings: Arc::new(MixSettings::new()),
+            metronome: MetronomeSettings::default(),
+            click_mix: click_mix,
+            transport: Transport::new(),
+            history: History::new(),
+            save_history: false,
+            rec_before: None,
+            ref_clock: Arc::new(AtomicU64::new(0)),
         }
     }
 
-    pub fn new_input_bus(&mut self, channel_ids: Vec<u8>) -> u8 {
+    pub fn new_input_bus(&mut self, channel_ids: Vec<u8>) -> Result<u8> {
         let bus_id = self.input_busses.len() as u8;
-        let device = get_input_device_by_name(&self.config.host, &self.config.in_device);
-
-        let (bus_rec_tx, bus_rec_rx) = multiqueue::broadcast_queue::<(u8, T)>(1_000_000);
-        let (bus_mon_tx, bus_mon_rx) = multiqueue::broadcast_queue::<(u8, T)>(1_000_000);
-        let txs = vec![bus_rec_tx, bus_mon_tx];
-
-        let bus_conf = BusConfig::get_bus_config(&(channel_ids.len() as u8));
+        let bus_conf = BusConfig::get_bus_config(&channel_ids)?;
 
         let in_bus = InputBus::<T>::new(
             bus_id,
-            device,
+            self.backend.as_ref(),
             self.config.in_config.clone(),
             bus_conf,
             channel_ids,
-            txs,
-        );
+            self.ref_clock.clone(),
+        )?;
 
-        in_bus.play_stream();
-        self.input_busses
-            .push((bus_rec_rx.clone(), bus_mon_rx.clone(), in_bus));
-        (self.input_busses.len() - 1) as u8
+        in_bus.play_stream()?;
+        self.input_busses.push(in_bus);
+        Ok((self.input_busses.len() - 1) as u8)
     }
 
-    pub fn new_output_bus(&mut self, channel_ids: Vec<u8>) -> u8 {
+    pub fn new_output_bus(&mut self, channel_ids: Vec<u8>) -> Result<u8> {
         let bus_id = self.output_busses.len() as u8;
-        let device = get_output_device_by_name(&self.config.host, &self.config.out_device);
 
-        let (bus_tx, bus_rx) = mpsc::channel::<(u8, T)>();
+        let (bus_tx, bus_rx) = frame_channel::<T>(channel_ids.len(), OUT_RING_FRAMES);
         let out_bus = OutputBus::<T>::new(
             bus_id,
-            device,
+            self.backend.as_ref(),
             self.config.out_config.clone(),
             channel_ids,
             bus_rx,
-        );
+            self.ref_clock.clone(),
+        )?;
 
-        out_bus.play_stream();
-        self.output_busses.push((bus_tx, out_bus));
-        (self.output_busses.len() - 1) as u8
+        out_bus.play_stream()?;
+        self.output_busses
+            .push((Arc::new(Mutex::new(bus_tx)), out_bus));
+        Ok((self.output_busses.len() - 1) as u8)
     }
 
-    pub fn new_track(&mut self, track_name: String, in_bus_id: u8, out_bus_id: u8) {
-        let track_id = self.tracks.len() as u8;
-        println!("New track id: {}", track_id);
-        let track = Track::new(
-            track_id,
-            track_name,
-            self.config.in_config.clone(),
-            self.config.sample_format,
-        );
+    //Returns the id of the new track
+    //Track recording every channel of the input bus
+    pub fn new_track(&mut self, track_name: String, in_bus_id: u8, out_bus_id: u8) -> Result<u8> {
+        let nof_bus_channels = match self.input_busses.get(in_bus_id as usize) {
+            Some(bus) => bus.get_channel_ids().len() as u8,
+            None => return Err(RecorderError::BusNotFound(in_bus_id)),
+        };
+        let in_channels = (1..=nof_bus_channels).collect();
+        self.new_track_on_channels(track_name, in_bus_id, in_channels, out_bus_id)
+    }
 
-        self.input_busses[in_bus_id as usize].2.add_track(track_id);
-        self.output_busses[out_bus_id as usize]
-            .1
-            .add_track(track_id);
+    //Track recording the given input bus channels (1-based), in that order, so the take
+    //has one file channel per selected input.
+    pub fn new_track_on_channels(
+        &mut self,
+        track_name: String,
+        in_bus_id: u8,
+        in_channels: Vec<u8>,
+        out_bus_id: u8,
+    ) -> Result<u8> {
+        self.edit("New Track", EditTarget::NewTrack, |rout| {
+            let nof_bus_channels = match rout.input_busses.get(in_bus_id as usize) {
+                Some(bus) => bus.get_channel_ids().len() as u16,
+                None => return Err(RecorderError::BusNotFound(in_bus_id)),
+            };
+            if out_bus_id as usize >= rout.output_busses.len() {
+                return Err(RecorderError::BusNotFound(out_bus_id));
+            }
+            check_channel_ids(&in_channels, Some(nof_bus_channels))?;
+            let track_id = rout.tracks.len() as u8;
+            println!("New track id: {}", track_id);
+            let out_channels = rout.output_busses[out_bus_id as usize].1.get_channel_ids();
+            let track = Track::new(
+                track_id,
+                track_name,
+                rout.config.sample_rate,
+                in_channels.clone(),
+                default_channel_map(in_channels.len() as u8, &out_channels),
+            );
+
+            rout.input_busses[in_bus_id as usize].add_track(track_id);
+            rout.output_busses[out_bus_id as usize]
+                .1
+                .add_track(track_id);
+
+            match rout.routes.get_route_track_ids(&in_bus_id, &out_bus_id) {
+                Some(_) => rout
+                    .routes
+                    .add_track_to_route(&in_bus_id, &out_bus_id, &track_id),
+                None => {
+                    rout.routes.add_route(&in_bus_id, &out_bus_id);
+                    rout.routes
+                        .add_track_to_route(&in_bus_id, &out_bus_id, &track_id);
+                }
+            }
+            rout.tracks.push(track);
+            //keeps every output bus fed, silence until something is monitored or played
+            rout.monitor()?;
+            Ok(track_id)
+        })
+    }
 
-        match self.routes.get_route_track_ids(&in_bus_id, &out_bus_id) {
-            Some(_) => self
-                .routes
-                .add_track_to_route(&in_bus_id, &out_bus_id, &track_id),
-            None => {
-                self.routes.add_route(&in_bus_id, &out_bus_id);
-                self.routes
-                    .add_track_to_route(&in_bus_id, &out_bus_id, &track_id);
+    //Imports an audio file as a new take of a track at timeline frame start_frame, converted
+    //to the session rate, the track's channels and the record format. Returns the frames
+    //of the take.
+    pub fn import_file(&mut self, track_id: u8, path: &str, start_frame: u64) -> Result<u64> {
+        self.edit("Import", EditTarget::Track(track_id), |rout| {
+            let (timecode_origin, format) =
+                (rout.config.timecode_origin, rout.config.record_format);
+            let frames =
+                rout.get_track_mut(track_id)?
+                    .import(path, start_frame, timecode_origin, format)?;
+            //a track that is playing continues with the new take
+            if rout.transport.is_rolling() {
+                rout.monitor()?;
             }
+            Ok(frames)
+        })
+    }
+
+    //What the track plays, a take, the latest one or its comp. Playback that is rolling
+    //continues with it.
+    pub fn select_take(&mut self, track_id: u8, selection: TakeSelection) -> Result<()> {
+        self.edit("Select Take", EditTarget::Track(track_id), |rout| {
+            rout.get_track_mut(track_id)?.set_selection(selection)?;
+            rout.replay()
+        })
+    }
+
+    //Plays file instead of the selection without changing it, None ends the audition
+    pub fn audition_take(&mut self, track_id: u8, file: Option<String>) -> Result<()> {
+        self.get_track_mut(track_id)?.audition(file)?;
+        self.replay()
+    }
+
+    pub fn rename_take(&mut self, track_id: u8, file: &str, name: String) -> Result<()> {
+        self.edit("Rename Take", EditTarget::Track(track_id), |rout| {
+            rout.get_track_mut(track_id)?.rename_take(file, name)
+        })
+    }
+
+    //Deletes the take, it is removed from the comp. Its file goes into the trash folder
+    //next to it, undoing the deletion brings it back.
+    pub fn delete_take(&mut self, track_id: u8, file: &str) -> Result<()> {
+        self.edit("Delete Take", EditTarget::Track(track_id), |rout| {
+            rout.get_track_mut(track_id)?.delete_take(file)?;
+            rout.replay()
+        })
+    }
+
+    //Puts range (timeline frames) of a take into the track's comp, over what the comp
+    //played there before
+    pub fn comp_take(&mut self, track_id: u8, file: &str, range: Range<u64>) -> Result<()> {
+        self.edit("Comp", EditTarget::Track(track_id), |rout| {
+            rout.get_track_mut(track_id)?.comp_take(file, range)?;
+            rout.replay()
+        })
+    }
+
+    pub fn clear_comp(&mut self, track_id: u8) -> Result<()> {
+        self.edit("Clear Comp", EditTarget::Track(track_id), |rout| {
+            rout.get_track_mut(track_id)?
+                .set_comp(Vec::<CompRegion>::new())?;
+            rout.replay()
+        })
+    }
+
+    //The track plays regions made from what it plays now, they can be edited from then on
+    pub fn regions_from_selection(&mut self, track_id: u8) -> Result<()> {
+        self.edit("Edit as Regions", EditTarget::Track(track_id), |rout| {
+            rout.get_track_mut(track_id)?.regions_from_selection()?;
+            rout.replay()
+        })
+    }
+
+    //Moves the start and end of a region to range (timeline frames) over its take
+    pub fn trim_region(&mut self, track_id: u8, region_id: u32, range: Range<u64>) -> Result<()> {
+        self.edit("Trim Region", EditTarget::Track(track_id), |rout| {
+            rout.get_track_mut(track_id)?
+                .trim_region(region_id, range)?;
+            rout.replay()
+        })
+    }
+
+    pub fn move_region(&mut self, track_id: u8, region_id: u32, position: u64) -> Result<()> {
+        self.edit("Move Region", EditTarget::Track(track_id), |rout| {
+            rout.get_track_mut(track_id)?
+                .move_region(region_id, position)?;
+            rout.replay()
+        })
+    }
+
+    //Splits a region at timeline frame, returns the id of the part after it
+    pub fn split_region(&mut self, track_id: u8, region_id: u32, frame: u64) -> Result<u32> {
+        self.edit("Split Region", EditTarget::Track(track_id), |rout| {
+            let id = rout
+                .get_track_mut(track_id)?
+                .split_region(region_id, frame)?;
+            rout.replay()?;
+            Ok(id)
+        })
+    }
+
+    //Copies a region to timeline frame position, returns the id of the copy
+    pub fn duplicate_region(&mut self, track_id: u8, region_id: u32, position: u64) -> Result<u32> {
+        self.edit("Duplicate Region", EditTarget::Track(track_id), |rout| {
+            let id = rout
+                .get_track_mut(track_id)?
+                .duplicate_region(region_id, position)?;
+            rout.replay()?;
+            Ok(id)
+        })
+    }
+
+    pub fn delete_region(&mut self, track_id: u8, region_id: u32) -> Result<()> {
+        self.edit("Delete Region", EditTarget::Track(track_id), |rout| {
+            rout.get_track_mut(track_id)?.delete_region(region_id)?;
+            rout.replay()
+        })
+    }
+
+    pub fn set_region_fades(
+        &mut self,
+        track_id: u8,
+        region_id: u32,
+        fade_in: Fade,
+        fade_out: Fade,
+    ) -> Result<()> {
+        self.edit("Region Fades", EditTarget::Track(track_id), |rout| {
+            rout.get_track_mut(track_id)?
+                .set_region_fades(region_id, fade_in, fade_out)?;
+            rout.replay()
+        })
+    }
+
+    pub fn set_region_gain(&mut self, track_id: u8, region_id: u32, gain_db: f32) -> Result<()> {
+        self.edit("Region Gain", EditTarget::Track(track_id), |rout| {
+            rout.get_track_mut(track_id)?
+                .set_region_gain(region_id, gain_db)?;
+            rout.replay()
+        })
+    }
+
+    //Restarts playback from the playhead after the takes of a track changed
+    fn replay(&mut self) -> Result<()> {
+        match self.transport.is_rolling() {
+            true => self.monitor(),
+            false => Ok(()),
         }
-        self.tracks.push(track);
     }
 
-    pub fn record(&mut self) {
-        for input_bus in self.input_busses.iter_mut() {
-            let track_ids = input_bus.2.get_track_ids();
-            let mut bus_rx = Box::new(input_bus.0.clone());
+    //Imports an audio file into a new track named after the file, with as many of the
+    //input bus channels as the file has channels. Returns the track id.
+    pub fn import_track(
+        &mut self,
+        path: &str,
+        in_bus_id: u8,
+        out_bus_id: u8,
+        start_frame: u64,
+    ) -> Result<u8> {
+        self.edit("Import Track", EditTarget::NewTrack, |rout| {
+            let nof_bus_channels = match rout.input_busses.get(in_bus_id as usize) {
+                Some(bus) => bus.get_channel_ids().len() as u16,
+                None => return Err(RecorderError::BusNotFound(in_bus_id)),
+            };
+            let (nof_channels, _, _) = import::probe(path)?;
+            let name = Path::new(path)
+                .file_stem()
+                .map(|s| s.to_string_lossy().to_string())
+                .unwrap_or("import".to_string());
+            let in_channels = (1..=std::cmp::min(nof_channels, nof_bus_channels) as u8).collect();
+            let track_id = rout.new_track_on_channels(name, in_bus_id, in_channels, out_bus_id)?;
+            rout.import_file(track_id, path, start_frame)?;
+            Ok(track_id)
+        })
+    }
 
-            for track_id in track_ids.iter() {
-                if self.tracks[*track_id as usize].is_rec_armed() {
-                    let thread_tx = self.tracks[*track_id as usize].record::<T>();
+    pub fn play(&mut self) -> Result<()> {
+        if self.transport.is_rolling() {
+            return Ok(());
+        }
+        self.stop_monitor();
+        self.transport.play();
+        self.monitor()
+    }
+
+    //When a take cannot be started nothing is recorded and the transport stops. With a
+    //count-in only the click plays for its bars, then the transport rolls from the pre-roll
+    //bars before the playhead and takes start at the playhead.
+    pub fn record(&mut self) -> Result<()> {
+        if self.transport.get_state() == TransportState::Recording {
+            return Ok(());
+        }
+        self.stop_monitor();
+        //the takes of the recording are one command, from here to stop_recording
+        self.rec_before = Some(self.edit_state(0..self.tracks.len()));
+        self.transport.record();
+        let (start_frame, latency) = (self.transport.get_position(), self.config.latency);
+        let (count_in, pre_roll) = self.get_lead_in(start_frame);
+        self.transport.roll_from(start_frame - pre_roll);
+        let (timecode_origin, format) = (self.config.timecode_origin, self.config.record_format);
+        let (in_rate, out_rate) = (
+            self.config.in_config.sample_rate.0,
+            self.config.out_config.sample_rate.0,
+        );
+        //takes line up with the first frame output bus 0 mixes
+        let mark = self.output_busses.first().map(|b| b.1.get_mark());
+        if let Some(mark) = &mark {
+            mark.clear();
+        }
 
-                    thread_tx.send(*bus_rx.clone());
-                    bus_rx = Box::new(bus_rx.add_stream());
+        let mut result = Ok(());
+        for input_bus in self.input_busses.iter() {
+            let track_ids = input_bus.get_track_ids();
+
+            for track_id in track_ids.iter() {
+                if self.tracks[*track_id as usize].is_rec_armed() && result.is_ok() {
+                    //a fresh subscription only holds frames from the transport start on
+                    let (bus_rx, first_frame) = input_bus.subscribe_from(REC_RING_FRAMES);
+                    let timing = TakeTiming {
+                        start_frame: start_frame,
+                        roll_frames: count_in + pre_roll,
+                        latency: latency,
+                        timecode_origin: timecode_origin,
+                        cycle: self.transport.get_cycle(),
+                        sync: mark
+                            .clone()
+                            .map(|m| input_bus.sync_to(m, in_rate, out_rate)),
+                        first_frame: first_frame,
+                    };
+                    result = self.tracks[*track_id as usize]
+                        .record::<T>(bus_rx, in_rate, timing, format);
                 }
             }
         }
+        if let Err(e) = result {
+            self.stop_recording().ok();
+            self.transport.stop();
+            self.monitor().ok();
+            return Err(e);
+        }
+        self.start_mix(count_in)
+    }
+
+    pub fn pause(&mut self) -> Result<()> {
+        if !self.transport.is_rolling() {
+            return Ok(());
+        }
+        self.stop_monitor();
+        let recorded = self.stop_recording();
+        self.transport.pause();
+        let monitored = self.monitor();
+        recorded.and(monitored)
+    }
+
+    //Stops playback and recording and returns the playhead to where it started
+    pub fn stop(&mut self) -> Result<()> {
+        self.stop_monitor();
+        let recorded = self.stop_recording();
+        self.transport.stop();
+        let monitored = self.monitor();
+        recorded.and(monitored)
+    }
+
+    pub fn locate(&mut self, frame: u64) -> Result<()> {
+        let state = self.transport.get_state();
+        if state == TransportState::Playing {
+            self.stop_monitor();
+            self.transport.locate(frame);
+            return self.monitor();
+        } else if state != TransportState::Recording {
+            self.transport.locate(frame);
+        }
+        Ok(())
+    }
+
+    pub fn set_latency(&mut self, frames: u64) {
+        self.config.latency = frames;
+    }
+
+    pub fn get_latency(&self) -> u64 {
+        self.config.latency
+    }
+
+    //Measures the round trip latency with test pulses from every channel of an output bus
+    //to an input bus connected to it and takes it as the latency dropped from the start of
+    //new takes, see calibration.rs. The transport must be stopped, monitoring pauses while
+    //the pulses play. Returns the latency in frames at the session rate.
+    pub fn calibrate_latency(&mut self, in_bus_id: u8, out_bus_id: u8) -> Result<u64> {
+        if self.transport.is_rolling() {
+            return Err(RecorderError::InvalidArgument(
+                "the latency cannot be measured while the transport rolls".to_string(),
+            ));
+        }
+        if in_bus_id as usize >= self.input_busses.len() {
+            return Err(RecorderError::BusNotFound(in_bus_id));
+        }
+        let (out_tx, mark) = match self.output_busses.get(out_bus_id as usize) {
+            Some(bus) => (bus.0.clone(), bus.1.get_mark()),
+            None => return Err(RecorderError::BusNotFound(out_bus_id)),
+        };
+        //in the order a recording starts
+        self.stop_monitor();
+        let (in_rate, out_rate) = (
+            self.config.in_config.sample_rate.0,
+            self.config.out_config.sample_rate.0,
+        );
+        let input_bus = &self.input_busses[in_bus_id as usize];
+        let (mut in_rx, first_frame) = input_bus.subscribe_from(capture_frames(in_rate));
+        let sync = input_bus.sync_to(mark.clone(), in_rate, out_rate);
+        let measured = {
+            //the mix thread of the bus releases the sender when it exits
+            let mut out_tx = out_tx.lock().unwrap();
+            mark.set(out_tx.get_pushed());
+            measure_round_trip(
+                &mut out_tx,
+                &mut in_rx,
+                &sync,
+                first_frame,
+                out_rate,
+                in_rate,
+                self.config.sample_rate,
+            )
+        };
+        mark.clear();
+        let monitored = self.monitor();
+        let latency = measured?;
+        println!("Round trip latency: {} frames", latency);
+        self.config.latency = latency;
+        monitored.map(|_| latency)
+    }
+
+    //Places timeline frame 0 at samples since midnight in the time references of new takes
+    pub fn set_timecode_origin(&mut self, samples: u64) {
+        self.config.timecode_origin = samples;
+    }
+
+    //Takes recorded from now on are written in format, existing takes are kept as they are
+    pub fn set_record_format(&mut self, format: RecordFormat) {
+        self.config.record_format = format;
+    }
+
+    pub fn get_record_format(&self) -> RecordFormat {
+        self.config.record_format
+    }
+
+    //Session rate of new takes and timeline positions. Existing takes would move on the
+    //timeline, so it can only be changed before anything is recorded.
+    pub fn set_sample_rate(&mut self, sample_rate: u32) -> Result<()> {
+        if sample_rate == self.config.sample_rate {
+            return Ok(());
+        }
+        if self.tracks.iter().any(|t| !t.get_files().is_empty()) {
+            return Err(RecorderError::InvalidArgument(
+                "the session rate cannot change once there are takes".to_string(),
+            ));
+        }
+        if sample_rate == 0 {
+            return Err(RecorderError::InvalidArgument(
+                "the session rate cannot be 0 Hz".to_string(),
+            ));
+        }
+        let (in_config, out_config) = (&self.config.in_config, &self.config.out_config);
+        //keeps a latency that was set, only the default follows the rate
+        if self.config.latency == default_latency(in_config, out_config, self.config.sample_rate) {
+            self.config.latency = default_latency(in_config, out_config, sample_rate);
+        }
+        self.config.sample_rate = sample_rate;
+        for track in self.tracks.iter_mut() {
+            track.set_sample_rate(sample_rate);
+        }
+        Ok(())
+    }
+
+    pub fn get_sample_rate(&self) -> u32 {
+        self.config.sample_rate
     }
 
-    pub fn stop_recording(&mut self) {
+    //Settings apply right away, a rolling transport restarts its click from the playhead
+    //unless only the level changed.
+    pub fn set_metronome(&mut self, settings: MetronomeSettings) -> Result<()> {
+        self.edit("Metronome", EditTarget::Session, |rout| {
+            settings.check()?;
+            if let Some(out_bus_id) = settings.out_bus_id {
+                if out_bus_id as usize >= rout.output_busses.len() {
+                    return Err(RecorderError::InvalidArgument(format!(
+                        "no output bus {} for the click",
+                        out_bus_id
+                    )));
+                }
+            }
+            rout.click_mix.set_gain_db(settings.gain_db);
+            let unchanged = MetronomeSettings {
+                gain_db: rout.metronome.gain_db,
+                ..settings.clone()
+            } == rout.metronome;
+            let restart = rout.transport.is_rolling() && !unchanged;
+            rout.metronome = settings;
+            match restart {
+                true => rout.monitor(),
+                false => Ok(()),
+            }
+        })
+    }
+
+    pub fn get_metronome(&self) -> MetronomeSettings {
+        self.metronome.clone()
+    }
+
+    //(bar, beat) of the playhead on the metronome's grid
+    pub fn get_bar_beat(&self) -> (u64, u8) {
+        self.metronome
+            .bar_beat(self.transport.get_position(), self.config.sample_rate)
+    }
+
+    //Loops the transport between the locators of cycle while it rolls, None stops looping.
+    //Armed tracks record a new take for every pass, so the cycle cannot change while
+    //recording. Playback restarts right away.
+    pub fn set_cycle(&mut self, cycle: Option<Range<u64>>) -> Result<()> {
+        self.edit("Cycle", EditTarget::Session, |rout| {
+            if let Some(cycle) = &cycle {
+                if cycle.start >= cycle.end {
+                    return Err(RecorderError::InvalidArgument(format!(
+                        "empty cycle {}..{}",
+                        cycle.start, cycle.end
+                    )));
+                }
+            }
+            if cycle == rout.transport.get_cycle() {
+                return Ok(());
+            }
+            if rout.transport.get_state() == TransportState::Recording {
+                return Err(RecorderError::InvalidArgument(
+                    "the cycle cannot change while recording".to_string(),
+                ));
+            }
+            rout.transport.set_cycle(cycle);
+            rout.replay()
+        })
+    }
+
+    pub fn get_cycle(&self) -> Option<Range<u64>> {
+        self.transport.get_cycle()
+    }
+
+    //(count-in, pre-roll) in frames before recording at start_frame, the pre-roll cannot
+    //reach before timeline zero
+    fn get_lead_in(&self, start_frame: u64) -> (u64, u64) {
+        let (settings, sample_rate) = (&self.metronome, self.config.sample_rate);
+        let count_in = settings.bars_to_frames(settings.count_in_bars, sample_rate);
+        let pre_roll = settings.bars_to_frames(settings.pre_roll_bars, sample_rate);
+        (count_in, std::cmp::min(pre_roll, start_frame))
+    }
+
+    pub fn get_transport_state(&self) -> TransportState {
+        self.transport.get_state()
+    }
+
+    pub fn get_playhead(&self) -> u64 {
+        self.transport.get_position()
+    }
+
+    //Finalizes every take being recorded, returns the first error after trying all
+    pub fn stop_recording(&mut self) -> Result<()> {
+        let mut result = Ok(());
         for input_bus in self.input_busses.iter() {
-            let track_ids = input_bus.2.get_track_ids();
+            let track_ids = input_bus.get_track_ids();
             for track_id in track_ids.iter() {
-                self.tracks[*track_id as usize].stop_recording();
-                println!("Terminated Recording (Track {})", track_id);
+                if self.tracks[*track_id as usize].is_recording() {
+                    let stopped = self.tracks[*track_id as usize].stop_recording();
+                    println!("Terminated Recording (Track {})", track_id);
+                    result = result.and(stopped);
+                }
             }
         }
+        if let Some(before) = self.rec_before.take() {
+            let changes = diff_states(&before, &self.edit_state(0..self.tracks.len()));
+            self.record_command("Record", changes);
+        }
+        result
     }
 
-    pub fn monitor(&mut self) {
+    //Starts input monitoring for monitored tracks and, while the transport is rolling,
+    //playback of all unarmed tracks from the playhead and the click. Tracks whose take
+    //cannot be played are left out, the first such error is returned.
+    pub fn monitor(&mut self) -> Result<()> {
+        self.start_mix(0)
+    }
+
+    //Monitoring with count_in frames (session rate) of click before playback starts and
+    //the playhead moves
+    fn start_mix(&mut self, count_in: u64) -> Result<()> {
+        self.stop_monitor();
+        let mut result = Ok(());
         let mut links = Vec::<MonitorLink<T>>::new();
+        let (rolling, position) = (self.transport.is_rolling(), self.transport.get_position());
+        let (in_rate, out_rate) = (
+            self.config.in_config.sample_rate.0,
+            self.config.out_config.sample_rate.0,
+        );
+        let delay = convert_frames(count_in, self.config.sample_rate, out_rate);
+        let click_bus_id = self.metronome.out_bus_id.unwrap_or(0);
 
         for out in self.output_busses.iter_mut() {
             let (out_bus_id, out_bus_channels) = (out.1.get_id(), out.1.get_channel_ids());
@@ -232,13 +819,29 @@ impl<T: 'static + cpal::Sample + hound::Sample + Send + Sync> Router<T> {
             links.push(MonitorLink::<T> {
                 out_bus_id: out_bus_id,
                 tx_to_bus: out.0.clone(),
-                rxs_from_monitors: Vec::<Receiver<(u8, T)>>::new(),
+                sources: Vec::<MixSource<T>>::new(),
             });
 
-            for input in self.input_busses.iter() {
-                let in_bus_id = input.2.get_id();
+            let clicking = self.metronome.enabled || count_in > 0;
+            if rolling && clicking && out_bus_id == click_bus_id {
+                let click_rx = start_click::<T>(
+                    &self.metronome,
+                    self.config.sample_rate,
+                    out_rate,
+                    position as i64 - count_in as i64,
+                    delay,
+                    self.transport.get_cycle(),
+                    out_bus_channels.len(),
+                );
+                let links_len = links.len();
+                links[links_len - 1].sources.push(MixSource {
+                    rx: click_rx,
+                    params: self.click_mix.clone(),
+                });
+            }
 
-                let mut in_bus_rx = Box::new(get_flushed_broadcast_queue(input.1.clone()));
+            for input in self.input_busses.iter() {
+                let in_bus_id = input.get_id();
 
                 let tracks = match self.routes.get_route_track_ids(&in_bus_id, &out_bus_id) {
                     Some(t) => t,
@@ -247,64 +850,171 @@ impl<T: 'static + cpal::Sample + hound::Sample + Send + Sync> Router<T> {
 
                 // println!("Run monitor streams");
                 for track_id in tracks.iter() {
+                    let links_len = links.len();
                     if self.tracks[*track_id as usize].is_monitored() {
-                        let (monitor_tx, monitor_rx) = self.tracks[*track_id as usize]
-                            .start_monitor::<T>(out_bus_channels.clone());
-                        let links_len = links.len();
-
-                        get_flushed_mpsc_queue(&monitor_rx); //flush queue
-                        links[links_len - 1].rxs_from_monitors.push(monitor_rx);
-
-                        monitor_tx.send(*in_bus_rx.clone());
-                        in_bus_rx = Box::new(in_bus_rx.add_stream());
-                    } else if !self.tracks[*track_id as usize].is_rec_armed() {
-                        let playback_rx: Receiver<(u8, T)> = match self.tracks[*track_id as usize]
-                            .start_playback(out_bus_channels.clone())
-                        {
-                            Some(rx) => rx,
-                            None => continue,
+                        let bus_rx = input.subscribe(MONITOR_RING_FRAMES);
+                        let monitor_rx = match self.tracks[*track_id as usize].start_monitor::<T>(
+                            bus_rx,
+                            in_rate,
+                            out_bus_channels.clone(),
+                            out_rate,
+                        ) {
+                            Ok(rx) => rx,
+                            Err(e) => {
+                                eprintln!("Router::monitor: track {}: {}", track_id, e);
+                                result = result.and(Err(e));
+                                continue;
+                            }
+                        };
+
+                        links[links_len - 1].sources.push(MixSource {
+                            rx: monitor_rx,
+                            params: self.tracks[*track_id as usize].get_mix_params(),
+                        });
+                    } else if rolling && !self.tracks[*track_id as usize].is_rec_armed() {
+                        let playback_rx = match self.tracks[*track_id as usize].start_playback::<T>(
+                            out_bus_channels.clone(),
+                            out_rate,
+                            position,
+                            delay,
+                            self.transport.get_cycle(),
+                        ) {
+                            Ok(Some(rx)) => rx,
+                            Ok(None) => continue,
+                            Err(e) => {
+                                eprintln!("Router::monitor: track {}: {}", track_id, e);
+                                result = result.and(Err(e));
+                                continue;
+                            }
                         };
-                        let links_len = links.len();
-                        links[links_len - 1].rxs_from_monitors.push(playback_rx);
+                        links[links_len - 1].sources.push(MixSource {
+                            rx: playback_rx,
+                            params: self.tracks[*track_id as usize].get_mix_params(),
+                        });
                     }
                 }
             }
         }
-        self._run_monitor_out_streams(links);
+        self._run_monitor_out_streams(links, delay);
+        result
     }
 
-    fn _run_monitor_out_streams(&mut self, mut links: Vec<MonitorLink<T>>) {
+    fn _run_monitor_out_streams(&mut self, mut links: Vec<MonitorLink<T>>, count_in: u64) {
+        //only one mix thread drives the playhead
+        let mut clock = match self.transport.is_rolling() {
+            true => Some(MixClock {
+                playhead: self.transport.get_playhead(),
+                sample_rate: self.config.sample_rate,
+                out_rate: self.config.out_config.sample_rate.0,
+                count_in: count_in,
+                cycle: self.transport.get_cycle(),
+            }),
+            false => None,
+        };
+        let rolling = self.transport.is_rolling();
         while let Ok(link) = links.pop().ok_or("") {
             println!("pop");
-            let (out_id, out_tx, monitor_rxs) = link.as_tup();
-            // println!("monitor_rxs      : {}", monitor_rxs.len());
-            let (thread_tx, thread_rx) = mpsc::channel::<Vec<Receiver<(u8, T)>>>();
+            let (out_bus_id, out_tx, sources) = link.as_tup();
+            let (thread_tx, thread_rx) = mpsc::channel::<Vec<MixSource<T>>>();
             let (term_tx, term_rx) = mpsc::channel();
-            let out_channels = self.output_busses[out_id as usize].1.get_channel_ids();
+            //takes being recorded line up with the first frame mixed for output bus 0
+            let mark = match (rolling, out_bus_id) {
+                (true, 0) => Some(self.output_busses[0].1.get_mark()),
+                _ => None,
+            };
 
-            mix_thread(thread_rx, term_rx, out_tx, out_channels);
-            thread_tx.send(monitor_rxs);
+            mix_thread(
+                thread_rx,
+                term_rx,
+                out_tx,
+                self.mix_settings.clone(),
+                clock.take(),
+                mark,
+            );
+            //the mix thread only drops its receiver when it panicked
+            if thread_tx.send(sources).is_err() {
+                eprintln!("Router::monitor: mix thread quit before it got its sources");
+                continue;
+            }
             self.monitor_txs.push(term_tx);
         }
     }
 
-    pub fn set_monitor(&mut self, track_id: u8, state: bool) {
-        self.tracks[track_id as usize].set_monitor(state);
+    pub fn set_monitor(&mut self, track_id: u8, state: bool) -> Result<()> {
+        self.edit("Monitor", EditTarget::Track(track_id), |rout| {
+            rout.get_track_mut(track_id)?.set_monitor(state);
+            Ok(())
+        })
+    }
+
+    pub fn set_channel_map(&mut self, track_id: u8, out_map: ChannelMap) -> Result<()> {
+        self.edit("Channel Map", EditTarget::Track(track_id), |rout| {
+            rout.get_track_mut(track_id)?.set_out_map(out_map);
+            Ok(())
+        })
     }
 
-    pub fn set_recording(&mut self, track_id: u8, state: bool) {
-        self.tracks[track_id as usize].set_rec(state);
+    pub fn set_recording(&mut self, track_id: u8, state: bool) -> Result<()> {
+        self.edit("Arm", EditTarget::Track(track_id), |rout| {
+            rout.get_track_mut(track_id)?.set_rec(state);
+            Ok(())
+        })
+    }
+
+    //Mix parameters take effect on the next mix block, no need to restart monitoring
+    pub fn set_gain(&mut self, track_id: u8, gain_db: f32) -> Result<()> {
+        self.edit("Gain", EditTarget::Track(track_id), |rout| {
+            rout.get_track(track_id)?
+                .get_mix_params()
+                .set_gain_db(gain_db);
+            Ok(())
+        })
+    }
+
+    pub fn set_pan(&mut self, track_id: u8, pan: f32) -> Result<()> {
+        self.edit("Pan", EditTarget::Track(track_id), |rout| {
+            rout.get_track(track_id)?.get_mix_params().set_pan(pan);
+            Ok(())
+        })
+    }
+
+    pub fn set_mute(&mut self, track_id: u8, state: bool) -> Result<()> {
+        self.edit("Mute", EditTarget::Track(track_id), |rout| {
+            rout.get_track(track_id)?.get_mix_params().set_mute(state);
+            Ok(())
+        })
+    }
+
+    pub fn set_solo(&mut self, track_id: u8, state: bool) -> Result<()> {
+        self.edit("Solo", EditTarget::Track(track_id), |rout| {
+            rout.get_track(track_id)?.get_mix_params().set_solo(state);
+            let solo_active = rout.tracks.iter().any(|t| t.get_mix_params().is_soloed());
+            rout.mix_settings.set_solo_active(solo_active);
+            Ok(())
+        })
+    }
+
+    pub fn set_pan_law(&mut self, law: PanLaw) {
+        self.edit("Pan Law", EditTarget::Session, |rout| {
+            rout.mix_settings.set_pan_law(law);
+        })
+    }
+
+    pub fn get_pan_law(&self) -> PanLaw {
+        self.mix_settings.get_pan_law()
     }
 
     pub fn stop_monitor(&mut self) {
         //terminates mix monitor threads
         while let Ok(term_tx) = self.monitor_txs.pop().ok_or(err_fn) {
             println!("Terminating mix_thread");
-            term_tx.send(());
+            if term_tx.send(()).is_err() {
+                eprintln!("Router::stop_monitor: mix thread had already quit");
+            }
         }
         //terminates track monitor threads
         for input_bus in self.input_busses.iter() {
-            let track_ids = input_bus.2.get_track_ids();
+            let track_ids = input_bus.get_track_ids();
             for track_id in track_ids.iter() {
                 self.tracks[*track_id as usize].stop_monitor();
                 println!("Terminated Monitor (Track {})", track_id);
@@ -312,6 +1022,391 @@ impl<T: 'static + cpal::Sample + hound::Sample + Send + Sync> Router<T> {
         }
     }
 
+    //Renders what every track plays through the monitor mix into a stereo wav in
+    //the sample format of the record format.
+    //range is in frames, use 0..u64::MAX for the whole session. Returns the frames written.
+    pub fn bounce(&mut self, path: &str, range: Range<u64>) -> Result<u64> {
+        self.start_bounce(path, range)?.join()
+    }
+
+    //Like bounce, the frames are rendered on a thread of their own, see Bounce
+    pub fn start_bounce(&mut self, path: &str, range: Range<u64>) -> Result<Bounce> {
+        let out_channels: Vec<u8> = vec![1, 2];
+        let mut mixer = Mixer::<T>::new(out_channels.len(), self.mix_settings.clone());
+        for track in self.tracks.iter() {
+            let (track_id, _, _, _) = track.as_tup();
+            //the track's output mapping, from the channels of its bus onto the bounce
+            let bus_channels = match self.routes.get_track_busses(&track_id) {
+                Some((_, out_bus_id)) => self
+                    .output_busses
+                    .get(out_bus_id as usize)
+                    .map(|b| b.1.get_channel_ids()),
+                None => None,
+            };
+            let bus_channels = bus_channels.unwrap_or(out_channels.clone());
+            if let Some(rx) =
+                track.bounce_playback::<T>(out_channels.clone(), &bus_channels, range.start)?
+            {
+                mixer.add_sources(vec![MixSource {
+                    rx: rx,
+                    params: track.get_mix_params(),
+                }]);
+            }
+        }
+
+        let bext = BextInfo::new(
+            format!("bounce of frames {}..{}", range.start, range.end),
+            self.config.timecode_origin + range.start,
+        );
+        let writer = TakeWriter::create::<T>(
+            path,
+            self.config.record_format.to_wav(),
+            self.config.sample_rate,
+            out_channels.len() as u16,
+            &bext,
+        )?;
+
+        let nof_frames = range.end.saturating_sub(range.start);
+        let progress = Arc::new(AtomicU64::new(0));
+        let handle = bounce_thread(
+            mixer,
+            writer,
+            out_channels.len(),
+            nof_frames,
+            progress.clone(),
+            path.to_string(),
+        );
+        Ok(Bounce {
+            progress: progress,
+            nof_frames: nof_frames,
+            handle: handle,
+        })
+    }
+
+    //(input overruns, output underruns) in frames since the busses were created
+    pub fn get_xruns(&self) -> (u64, u64) {
+        (
+            self.input_busses.iter().map(|b| b.get_overruns()).sum(),
+            self.output_busses.iter().map(|b| b.1.get_underruns()).sum(),
+        )
+    }
+
+    //input bus levels of a track, what it records
+    pub fn get_input_meter(&self, track_id: u8) -> Option<Arc<MeterLevels>> {
+        let (in_bus_id, _) = self.routes.get_track_busses(&track_id)?;
+        Some(self.input_busses[in_bus_id as usize].get_meter())
+    }
+
+    //(output bus id, levels) of every output bus
+    pub fn get_output_meters(&self) -> Vec<(u8, Arc<MeterLevels>)> {
+        self.output_busses
+            .iter()
+            .map(|b| (b.1.get_id(), b.1.get_meter()))
+            .collect()
+    }
+
+    pub fn to_session(&self) -> Session {
+        let tracks = self.tracks.iter().map(track_state).collect();
+
+        Session {
+            version: SESSION_VERSION,
+            config: SessionConfig {
+                host: self.backend.name(),
+                in_device: self.config.in_device.clone(),
+                out_device: self.config.out_device.clone(),
+                in_config: StreamConfigState::from_config(&self.config.in_config),
+                out_config: StreamConfigState::from_config(&self.config.out_config),
+                sample_format: sample_format_to_str(&self.config.sample_format),
+                record_format: Some(self.config.record_format),
+                sample_rate: Some(self.config.sample_rate),
+                latency: self.config.latency,
+                timecode_origin: self.config.timecode_origin,
+                pan_law: self.get_pan_law(),
+                metronome: self.get_metronome(),
+                cycle: self.get_cycle(),
+            },
+            input_busses: self
+                .input_busses
+                .iter()
+                .map(|x| x.get_channel_ids())
+                .collect(),
+            output_busses: self
+                .output_busses
+                .iter()
+                .map(|x| x.1.get_channel_ids())
+                .collect(),
+            routes: self.routes.get_routes(),
+            tracks: tracks,
+            history: match self.save_history {
+                true => Some(self.history.clone()),
+                false => None,
+            },
+        }
+    }
+
+    //Recreates busses, tracks and takes of a saved session on an empty router.
+    pub fn restore_session(&mut self, session: &Session) -> Result<()> {
+        //restoring is not a command, the history is the one saved with the session
+        self.history.pause();
+        let restored = self.restore_session_state(session);
+        self.history.resume();
+        restored?;
+        self.save_history = session.history.is_some();
+        self.history = session.history.clone().unwrap_or_default();
+        Ok(())
+    }
+
+    fn restore_session_state(&mut self, session: &Session) -> Result<()> {
+        //takes of a session that ended in a crash were never finalized, they are repaired
+        //before the tracks read their lengths
+        for (file, result) in recovery::recover_takes(&session.get_take_files()) {
+            match result {
+                Ok(frames) => println!("Recovered {} ({} frames)", file, frames),
+                Err(e) => eprintln!("Router::restore_session: could not recover {}: {}", file, e),
+            }
+        }
+        //older sessions recorded at the input rate
+        if let Some(rate) = session.config.sample_rate {
+            self.set_sample_rate(rate)?;
+        }
+        self.config.latency = session.config.latency;
+        self.config.timecode_origin = session.config.timecode_origin;
+        if let Some(format) = session.config.record_format {
+            self.config.record_format = format;
+        }
+        self.set_pan_law(session.config.pan_law);
+        for channel_ids in session.input_busses.iter() {
+            self.new_input_bus(channel_ids.clone())?;
+        }
+        for channel_ids in session.output_busses.iter() {
+            self.new_output_bus(channel_ids.clone())?;
+        }
+        self.set_metronome(session.config.metronome.clone())?;
+        self.set_cycle(session.config.cycle.clone())?;
+
+        let mut saved_tracks = session.tracks.clone();
+        saved_tracks.sort_by_key(|t| t.id);
+        for saved in saved_tracks.iter() {
+            let route = session.routes.iter().find(|r| r.2.contains(&saved.id));
+            let (in_bus_id, out_bus_id) = match route {
+                Some(r) => (r.0, r.1),
+                None => {
+                    eprintln!("Router::restore_session: track {} has no route", saved.id);
+                    continue;
+                }
+            };
+            let track_id = match saved.in_channels.is_empty() {
+                true => self.new_track(saved.name.clone(), in_bus_id, out_bus_id)?,
+                false => self.new_track_on_channels(
+                    saved.name.clone(),
+                    in_bus_id,
+                    saved.in_channels.clone(),
+                    out_bus_id,
+                )?,
+            };
+
+            self.apply_track_state(track_id, saved)?;
+        }
+        Ok(())
+    }
+
+    //Puts a track back into a saved state, its takes are not checked against the disk
+    fn apply_track_state(&mut self, track_id: u8, saved: &TrackState) -> Result<()> {
+        let track = self.get_track_mut(track_id)?;
+        track.set_files(saved.takes.clone());
+        //a session edited by hand may refer to takes that are gone
+        let restored = track
+            .set_comp(saved.comp.clone())
+            .and_then(|_| track.set_regions(saved.regions.clone()))
+            .and_then(|_| track.set_selection(saved.selection.clone()));
+        if let Err(e) = restored {
+            eprintln!("Router::apply_track_state: track {}: {}", saved.id, e);
+        }
+        track.set_rec(saved.rec);
+        track.set_monitor(saved.monitor);
+        if !saved.out_map.is_empty() {
+            track.set_out_map(saved.out_map.clone());
+        }
+        self.set_gain(track_id, saved.gain_db)?;
+        self.set_pan(track_id, saved.pan)?;
+        self.set_mute(track_id, saved.mute)?;
+        self.set_solo(track_id, saved.solo)
+    }
+
+    //Runs change, what it changes is recorded in the history as one command called name.
+    //Changes it makes through other edits are part of it and not recorded on their own.
+    fn edit<R, F: FnOnce(&mut Self) -> R>(
+        &mut self,
+        name: &str,
+        target: EditTarget,
+        change: F,
+    ) -> R {
+        if self.history.is_paused() {
+            return change(self);
+        }
+        let nof_tracks = self.tracks.len();
+        let edited = |rout: &Self| match target {
+            EditTarget::Track(track_id) => track_id as usize..track_id as usize + 1,
+            EditTarget::NewTrack => nof_tracks..rout.tracks.len(),
+            EditTarget::Session => 0..0,
+        };
+        let before = self.edit_state(edited(self));
+        self.history.pause();
+        let result = change(self);
+        self.history.resume();
+        let changes = diff_states(&before, &self.edit_state(edited(self)));
+        self.record_command(name, changes);
+        result
+    }
+
+    fn record_command(&mut self, name: &str, changes: Vec<Change>) {
+        self.history.push(Command {
+            name: name.to_string(),
+            changes: changes,
+        });
+    }
+
+    //State of the tracks with an index in track_range and of the session settings
+    fn edit_state(&self, track_range: Range<usize>) -> EditState {
+        let mut tracks = Vec::<(TrackState, u8, u8)>::new();
+        let nof_edited = track_range.end.saturating_sub(track_range.start);
+        for track in self.tracks.iter().skip(track_range.start).take(nof_edited) {
+            let (track_id, _, _, _) = track.as_tup();
+            if let Some((in_bus_id, out_bus_id)) = self.routes.get_track_busses(&track_id) {
+                tracks.push((track_state(track), in_bus_id, out_bus_id));
+            }
+        }
+        EditState {
+            tracks: tracks,
+            cycle: self.get_cycle(),
+            metronome: self.get_metronome(),
+            pan_law: self.get_pan_law(),
+        }
+    }
+
+    //Takes back the last command, playback that is rolling continues with the state before it
+    pub fn undo(&mut self) -> Result<()> {
+        self.check_not_recording()?;
+        match self.history.take_undo() {
+            Some(command) => self.apply_command(&command, true),
+            None => Ok(()),
+        }
+    }
+
+    pub fn redo(&mut self) -> Result<()> {
+        self.check_not_recording()?;
+        match self.history.take_redo() {
+            Some(command) => self.apply_command(&command, false),
+            None => Ok(()),
+        }
+    }
+
+    //the takes being recorded are not in the history yet
+    fn check_not_recording(&self) -> Result<()> {
+        match self.transport.get_state() {
+            TransportState::Recording => Err(RecorderError::InvalidArgument(
+                "nothing can be undone or redone while recording".to_string(),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    //Name of the command undo would take back
+    pub fn get_undo_name(&self) -> Option<String> {
+        self.history.get_undo_name()
+    }
+
+    pub fn get_redo_name(&self) -> Option<String> {
+        self.history.get_redo_name()
+    }
+
+    pub fn clear_history(&mut self) {
+        self.history.clear();
+    }
+
+    //Whether the undo history is saved with the session and restored with it
+    pub fn set_save_history(&mut self, save: bool) {
+        self.save_history = save;
+    }
+
+    pub fn get_save_history(&self) -> bool {
+        self.save_history
+    }
+
+    //The state before the command if undo, after it otherwise. Changes are undone in the
+    //reverse order they were made in. Returns the first error after applying all.
+    fn apply_command(&mut self, command: &Command, undo: bool) -> Result<()> {
+        self.history.pause();
+        let mut result = Ok(());
+        let changes: Vec<&Change> = match undo {
+            true => command.changes.iter().rev().collect(),
+            false => command.changes.iter().collect(),
+        };
+        for change in changes {
+            result = result.and(self.apply_change(change, undo));
+        }
+        self.history.resume();
+        //monitoring and arming may have changed as well as what plays
+        result.and(self.monitor())
+    }
+
+    fn apply_change(&mut self, change: &Change, undo: bool) -> Result<()> {
+        match (change, undo) {
+            (Change::Track { before, .. }, true) => self.apply_track_state(before.id, before),
+            (Change::Track { after, .. }, false) => self.apply_track_state(after.id, after),
+            (Change::NewTrack { track, .. }, true) => self.remove_track(track.id),
+            (
+                Change::NewTrack {
+                    track,
+                    in_bus_id,
+                    out_bus_id,
+                },
+                false,
+            ) => {
+                let track_id = self.new_track_on_channels(
+                    track.name.clone(),
+                    *in_bus_id,
+                    track.in_channels.clone(),
+                    *out_bus_id,
+                )?;
+                self.apply_track_state(track_id, track)
+            }
+            (Change::Cycle { before, .. }, true) => self.set_cycle(before.clone()),
+            (Change::Cycle { after, .. }, false) => self.set_cycle(after.clone()),
+            (Change::Metronome { before, .. }, true) => self.set_metronome(before.clone()),
+            (Change::Metronome { after, .. }, false) => self.set_metronome(after.clone()),
+            (Change::PanLaw { before, .. }, true) => {
+                self.set_pan_law(*before);
+                Ok(())
+            }
+            (Change::PanLaw { after, .. }, false) => {
+                self.set_pan_law(*after);
+                Ok(())
+            }
+        }
+    }
+
+    //Removes a track added by a command that is undone. Track ids are their index, so only
+    //the last track can go, the commands that added tracks after it were undone before.
+    fn remove_track(&mut self, track_id: u8) -> Result<()> {
+        if track_id as usize + 1 != self.tracks.len() {
+            return Err(RecorderError::InvalidArgument(format!(
+                "track {} is not the last track",
+                track_id
+            )));
+        }
+        self.stop_monitor();
+        for bus in self.input_busses.iter_mut() {
+            bus.remove_track(track_id);
+        }
+        for (_, bus) in self.output_busses.iter_mut() {
+            bus.remove_track(track_id);
+        }
+        self.routes.remove_track(&track_id);
+        self.tracks.pop();
+        self.monitor()
+    }
+
     pub fn get_io_channels(&self) -> (Vec<u8>, Vec<u8>) {
         //(input_channel_ids, output_channel_ids)
         (
@@ -320,65 +1415,447 @@ impl<T: 'static + cpal::Sample + hound::Sample + Send + Sync> Router<T> {
         )
     }
 
+    //(bus id, channel ids) of every input bus
+    pub fn get_input_bus_channels(&self) -> Vec<(u8, Vec<u8>)> {
+        self.input_busses
+            .iter()
+            .map(|b| (b.get_id(), b.get_channel_ids()))
+            .collect()
+    }
+
+    //(bus id, channel ids) of every output bus
+    pub fn get_output_bus_channels(&self) -> Vec<(u8, Vec<u8>)> {
+        self.output_busses
+            .iter()
+            .map(|b| (b.1.get_id(), b.1.get_channel_ids()))
+            .collect()
+    }
+
     pub fn get_tracks(&self) -> &Vec<Track> {
         &self.tracks
     }
+
+    pub fn get_track(&self, track_id: u8) -> Result<&Track> {
+        self.tracks
+            .get(track_id as usize)
+            .ok_or(RecorderError::TrackNotFound(track_id))
+    }
+
+    fn get_track_mut(&mut self, track_id: u8) -> Result<&mut Track> {
+        self.tracks
+            .get_mut(track_id as usize)
+            .ok_or(RecorderError::TrackNotFound(track_id))
+    }
 }
 
-fn mix_thread<T: 'static + cpal::Sample + Send>(
-    thread_rx: Receiver<Vec<Receiver<(u8, T)>>>,
+//Playhead a mix thread advances, it mixes at out_rate and the timeline counts sample_rate
+//A bounce while it renders, see Router::start_bounce. The file is finished when the thread
+//is joined.
+pub struct Bounce {
+    progress: Arc<AtomicU64>, //frames written so far
+    nof_frames: u64,          //of the range, u64::MAX for the whole session
+    handle: JoinHandle<Result<u64>>,
+}
+
+impl Bounce {
+    pub fn get_progress(&self) -> u64 {
+        self.progress.load(Ordering::Relaxed)
+    }
+
+    //Part of the range written, None for the whole session, whose end is only known
+    //once it is reached
+    pub fn get_fraction(&self) -> Option<f32> {
+        match self.nof_frames {
+            0 => Some(1.0),
+            u64::MAX => None,
+            n => Some(self.get_progress() as f32 / n as f32),
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.handle.is_finished()
+    }
+
+    //Waits for the bounce, returns the frames written
+    pub fn join(self) -> Result<u64> {
+        match self.handle.join() {
+            Ok(result) => result,
+            Err(_) => Err(RecorderError::InvalidArgument(
+                "the bounce thread panicked".to_string(),
+            )),
+        }
+    }
+}
+
+//Mixes nof_frames, or until every source has ended, into writer as fast as the sources
+//deliver and counts the frames written on progress
+fn bounce_thread<T: 'static + cpal::Sample + hound::Sample + Send + Sync>(
+    mut mixer: Mixer<T>,
+    mut writer: TakeWriter,
+    nof_channels: usize,
+    nof_frames: u64,
+    progress: Arc<AtomicU64>,
+    path: String,
+) -> JoinHandle<Result<u64>> {
+    thread::spawn(move || {
+        let mut block = vec![cpal::Sample::from(&0.0f32); MIX_BLOCK_FRAMES * nof_channels];
+        let mut frame_idx: u64 = 0;
+        while frame_idx < nof_frames && !mixer.is_empty() {
+            //playback threads fill their rings far faster than real time
+            if !mixer.is_ready(MIX_BLOCK_FRAMES) {
+                thread::sleep(Duration::from_micros(100));
+                continue;
+            }
+            let block_frames = std::cmp::min(MIX_BLOCK_FRAMES as u64, nof_frames - frame_idx);
+            let block_len = block_frames as usize * nof_channels;
+            let mixed = mixer.mix(&mut block[..block_len]);
+            for frame in block[..mixed * nof_channels].chunks(nof_channels) {
+                writer.write_frame(frame)?;
+            }
+            frame_idx += mixed as u64;
+            progress.store(frame_idx, Ordering::Relaxed);
+        }
+
+        writer.finalize()?;
+        println!("Bounced {} frames to {}", frame_idx, path);
+        Ok(frame_idx)
+    })
+}
+
+struct MixClock {
+    playhead: Arc<AtomicU64>,
+    sample_rate: u32,
+    out_rate: u32,
+    count_in: u64,             //frames at out_rate the playhead waits before it moves
+    cycle: Option<Range<u64>>, //the playhead wraps from its end to its start
+}
+
+fn mix_thread<T: 'static + cpal::Sample + Send + Sync>(
+    thread_rx: Receiver<Vec<MixSource<T>>>,
     term_rx: Receiver<()>,
-    out_tx: Sender<(u8, T)>,
-    out_channels: Vec<u8>,
+    out_tx: BusSender<T>,
+    mix_settings: Arc<MixSettings>,
+    clock: Option<MixClock>,
+    mut mark: Option<Arc<PlayMark>>,
 ) {
     println!("Mix Thread spawned!");
     thread::spawn(move || {
-        //TODO: support i16 and u16 sample formats
+        //the previous mix thread of this bus releases the sender when it exits
+        let mut out_tx = out_tx.lock().unwrap();
+        let nof_channels = out_tx.get_nof_channels();
+        let mut mixer = Mixer::<T>::new(nof_channels, mix_settings);
+        let mut block = vec![cpal::Sample::from(&0.0f32); MIX_BLOCK_FRAMES * nof_channels];
+        let silence = block.clone();
+        let start = clock.as_ref().map(|c| c.playhead.load(Ordering::SeqCst));
+        let mut nof_mixed: u64 = 0;
+        let (mut filled, mut started) = (false, false);
 
-        let mut track_rxs = match thread_rx.recv() {
-            Ok(tx) => tx,
-            Err(e) => panic!("mix_thread: Oh no! {}", e),
+        match thread_rx.recv() {
+            Ok(sources) => mixer.add_sources(sources),
+            Err(_) => return,
         };
 
         loop {
-            for ch in out_channels.iter() {
-                let mut samples_avg = 0.0;
-                for idx in 0..(track_rxs.len()) {
-                    loop {
-                        let (dest_ch, sample) = match track_rxs[idx].recv() {
-                            Ok(t) => t,
-                            Err(_) => {
-                                track_rxs.remove(idx);
-                                break;
-                            }
-                        };
-
-                        if dest_ch != *ch || sample.to_f32().is_nan() {
-                            continue;
-                        }
-                        samples_avg += sample.to_f32();
-                        break;
-                    }
-                }
-                samples_avg = samples_avg as f32 / track_rxs.len() as f32;
-                if samples_avg.is_nan() {
-                    continue;
-                }
-                out_tx.send((*ch, cpal::Sample::from(&samples_avg)));
-            }
-
             if let Ok(_) = term_rx.try_recv() {
                 break;
             }
+            if let Ok(sources) = thread_rx.try_recv() {
+                mixer.add_sources(sources);
+            }
 
-            match thread_rx.try_recv() {
-                Ok(mut rxs) => track_rxs.append(&mut rxs),
-                Err(_) => continue,
+            //the sources start behind a full ring of silence, which gives them that long to
+            //be late, and it is kept full until they are ready
+            if !filled && out_tx.free_frames() >= MIX_BLOCK_FRAMES {
+                out_tx.push_frames(&silence);
+                continue;
+            }
+            filled = true;
+            //waits for late sources unless the output is about to run dry, then it gets
+            //silence and every source is heard that much later, still in step
+            let urgent = !started || out_tx.queued_frames() < MIX_BLOCK_FRAMES;
+            let ready = mixer.is_ready(MIX_BLOCK_FRAMES);
+            if out_tx.free_frames() < MIX_BLOCK_FRAMES || !(urgent || ready) {
+                thread::sleep(Duration::from_micros(500));
+                continue;
+            }
+            if !ready {
+                out_tx.push_frames(&silence);
+                continue;
+            }
+
+            let nof_frames = mixer.mix(&mut block);
+            if nof_frames > 0 {
+                started = true;
+                if let Some(mark) = mark.take() {
+                    mark.set(out_tx.get_pushed());
+                }
+            }
+            out_tx.push_frames(&block[..nof_frames * nof_channels]);
+            if let (Some(c), Some(start)) = (&clock, start) {
+                nof_mixed += nof_frames as u64;
+                let rolled = nof_mixed.saturating_sub(c.count_in);
+                let mut position = start + convert_frames(rolled, c.out_rate, c.sample_rate);
+                if let Some(cycle) = &c.cycle {
+                    if position >= cycle.end {
+                        let len = cycle.end - cycle.start;
+                        position = cycle.start + (position - cycle.end) % len;
+                    }
+                }
+                c.playhead.store(position, Ordering::SeqCst);
             }
         }
     });
 }
 
+//Input plus output buffer at sample_rate when the buffer size is known, 0 otherwise.
+fn default_latency(in_config: &StreamConfig, out_config: &StreamConfig, sample_rate: u32) -> u64 {
+    let mut latency = 0;
+    for config in [in_config, out_config] {
+        if let BufferSize::Fixed(frames) = config.buffer_size {
+            latency += convert_frames(frames as u64, config.sample_rate.0, sample_rate);
+        }
+    }
+    latency
+}
+
 pub fn err_fn(error: Error) {
     eprintln!("an error occurred on stream: {}", error);
 }
+
+fn track_state(track: &Track) -> TrackState {
+    let (id, name, rec, monitor) = track.as_tup();
+    let (gain_db, pan, mute, solo) = track.get_mix_params().as_tup();
+    TrackState {
+        id: id,
+        name: name,
+        takes: track.get_files(),
+        files: Vec::<String>::new(),
+        selection: track.get_selection(),
+        comp: track.get_comp(),
+        regions: track.get_regions(),
+        in_channels: track.get_in_channels(),
+        out_map: track.get_out_map(),
+        rec: rec,
+        monitor: monitor,
+        gain_db: gain_db,
+        pan: pan,
+        mute: mute,
+        solo: solo,
+    }
+}
+
+//What changed from before to after, in the order it is redone
+fn diff_states(before: &EditState, after: &EditState) -> Vec<Change> {
+    let mut changes = Vec::<Change>::new();
+    if before.pan_law != after.pan_law {
+        changes.push(Change::PanLaw {
+            before: before.pan_law,
+            after: after.pan_law,
+        });
+    }
+    if before.metronome != after.metronome {
+        changes.push(Change::Metronome {
+            before: before.metronome.clone(),
+            after: after.metronome.clone(),
+        });
+    }
+    if before.cycle != after.cycle {
+        changes.push(Change::Cycle {
+            before: before.cycle.clone(),
+            after: after.cycle.clone(),
+        });
+    }
+    for (track, in_bus_id, out_bus_id) in after.tracks.iter() {
+        match before.tracks.iter().find(|t| t.0.id == track.id) {
+            Some((old, _, _)) if old != track => changes.push(Change::Track {
+                before: Box::new(old.clone()),
+                after: Box::new(track.clone()),
+            }),
+            Some(_) => (),
+            None => changes.push(Change::NewTrack {
+                track: track.clone(),
+                in_bus_id: *in_bus_id,
+                out_bus_id: *out_bus_id,
+            }),
+        }
+    }
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::virtual_backend::VirtualBackend;
+    use cpal::{SampleRate, StreamConfig};
+
+    fn empty_router() -> Router<f32> {
+        let config = StreamConfig {
+            channels: 2,
+            sample_rate: SampleRate(48_000),
+            buffer_size: BufferSize::Default,
+        };
+        Router::with_backend(
+            Box::new(VirtualBackend::<f32>::silent().buffer_frames(256)),
+            config.clone(),
+            config,
+            SampleFormat::F32,
+        )
+    }
+
+    fn router() -> Router<f32> {
+        let mut rout = empty_router();
+        rout.new_output_bus(vec![1, 2]).unwrap();
+        rout.new_input_bus(vec![1, 2]).unwrap();
+        rout
+    }
+
+    fn gain_db(rout: &Router<f32>, track_id: u8) -> f32 {
+        rout.get_track(track_id)
+            .unwrap()
+            .get_mix_params()
+            .get_gain_db()
+    }
+
+    #[test]
+    fn undoes_edits_of_one_track() {
+        let mut rout = router();
+        rout.new_track("a".to_string(), 0, 0).unwrap();
+        rout.new_track("b".to_string(), 0, 0).unwrap();
+        assert_eq!(rout.get_undo_name(), Some("New Track".to_string()));
+        rout.set_gain(0, -6.0).unwrap();
+        rout.set_gain(1, -3.0).unwrap();
+
+        rout.undo().unwrap();
+        assert_eq!(gain_db(&rout, 0), -6.0);
+        assert_eq!(gain_db(&rout, 1), 0.0);
+        rout.undo().unwrap();
+        assert_eq!(gain_db(&rout, 0), 0.0);
+        rout.undo().unwrap();
+        assert!(rout.get_track(1).is_err());
+        rout.redo().unwrap();
+        assert!(rout.get_track(1).is_ok());
+        rout.redo().unwrap();
+        assert_eq!(gain_db(&rout, 0), -6.0);
+    }
+
+    #[test]
+    fn restores_sessions_without_commands() {
+        let mut rout = router();
+        rout.new_track("a".to_string(), 0, 0).unwrap();
+        rout.set_gain(0, -6.0).unwrap();
+        rout.set_cycle(Some(0..48_000)).unwrap();
+        let session = rout.to_session();
+        assert!(session.history.is_none());
+
+        let mut restored = empty_router();
+        restored.restore_session(&session).unwrap();
+        assert_eq!(gain_db(&restored, 0), -6.0);
+        assert_eq!(restored.get_cycle(), Some(0..48_000));
+        assert_eq!(restored.get_undo_name(), None);
+        //edits after it are recorded again
+        restored.set_pan(0, 0.5).unwrap();
+        assert_eq!(restored.get_undo_name(), Some("Pan".to_string()));
+    }
+
+    //float stereo wav of nof_frames frames of frame in dir
+    fn constant_file(dir: &Path, name: &str, frame: [f32; 2], nof_frames: usize) -> String {
+        let path = dir.join(name).to_str().unwrap().to_string();
+        let spec = hound::WavSpec {
+            channels: 2,
+            sample_rate: 48_000,
+            bits_per_sample: 32,
+            sample_format: hound::SampleFormat::Float,
+        };
+        let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+        for _ in 0..nof_frames {
+            writer.write_sample(frame[0]).unwrap();
+            writer.write_sample(frame[1]).unwrap();
+        }
+        writer.finalize().unwrap();
+        path
+    }
+
+    #[test]
+    fn bounces_the_takes_of_every_track() {
+        let dir = std::env::temp_dir().join(format!("example2-bounce-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut rout = router();
+        rout.set_record_format(RecordFormat::Float32);
+        rout.set_pan_law(PanLaw::ZeroDb);
+        let a = rout
+            .new_track(dir.join("a").to_str().unwrap().to_string(), 0, 0)
+            .unwrap();
+        let path = constant_file(&dir, "in_a.wav", [0.25, 0.5], 1_000);
+        rout.import_file(a, &path, 0).unwrap();
+        //b is played with its channels swapped
+        let b = rout
+            .new_track(dir.join("b").to_str().unwrap().to_string(), 0, 0)
+            .unwrap();
+        let path = constant_file(&dir, "in_b.wav", [0.125, 0.0625], 500);
+        rout.import_file(b, &path, 200).unwrap();
+        rout.set_channel_map(b, vec![(1, 2), (2, 1)]).unwrap();
+
+        let expected = |idx: usize| match (200..700).contains(&idx) {
+            true => [0.3125, 0.625],
+            false => [0.25, 0.5],
+        };
+        let read = |path: &Path| -> Vec<f32> {
+            let mut reader = hound::WavReader::open(path).unwrap();
+            reader.samples::<f32>().map(|s| s.unwrap()).collect()
+        };
+
+        //the whole session ends with the last take
+        let mix = dir.join("mix.wav");
+        assert_eq!(
+            rout.bounce(mix.to_str().unwrap(), 0..u64::MAX).unwrap(),
+            1_000
+        );
+        let samples = read(&mix);
+        assert_eq!(samples.len(), 2 * 1_000);
+        for (idx, frame) in samples.chunks(2).enumerate() {
+            assert_eq!(frame, &expected(idx)[..], "frame {}", idx);
+        }
+
+        let part = dir.join("part.wav");
+        let bounce = rout.start_bounce(part.to_str().unwrap(), 650..750).unwrap();
+        assert_eq!(bounce.join().unwrap(), 100);
+        let samples = read(&part);
+        assert_eq!(samples.len(), 2 * 100);
+        for (idx, frame) in samples.chunks(2).enumerate() {
+            assert_eq!(frame, &expected(650 + idx)[..], "frame {}", 650 + idx);
+        }
+        std::fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn undoes_the_deletion_of_a_take() {
+        let dir = std::env::temp_dir().join(format!("example2-delete-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut rout = router();
+        let track_id = rout
+            .new_track(dir.join("a").to_str().unwrap().to_string(), 0, 0)
+            .unwrap();
+        let path = constant_file(&dir, "in.wav", [0.25, 0.5], 100);
+        rout.import_file(track_id, &path, 0).unwrap();
+        rout.set_gain(track_id, -6.0).unwrap();
+        let take = rout.get_track(track_id).unwrap().get_files()[0].clone();
+
+        rout.delete_take(track_id, &take.file).unwrap();
+        assert!(rout.get_track(track_id).unwrap().get_files().is_empty());
+        assert!(!Path::new(&take.file).exists());
+        assert!(take.get_trash_path().exists());
+        //the edits before it are kept
+        assert_eq!(rout.get_undo_name(), Some("Delete Take".to_string()));
+
+        rout.undo().unwrap();
+        assert_eq!(
+            rout.get_track(track_id).unwrap().get_files(),
+            vec![take.clone()]
+        );
+        assert!(Path::new(&take.file).exists());
+        assert!(!take.get_trash_path().exists());
+        rout.undo().unwrap();
+        assert_eq!(gain_db(&rout, track_id), 0.0);
+        std::fs::remove_dir_all(dir).ok();
+    }
+}
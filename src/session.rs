@@ -0,0 +1,344 @@
+use cpal::{BufferSize, SampleFormat, SampleRate, StreamConfig};
+use serde::{Deserialize, Serialize};
+
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, ErrorKind};
+use std::ops::Range;
+
+use crate::comp::CompRegion;
+use crate::formats::RecordFormat;
+use crate::history::History;
+use crate::metronome::MetronomeSettings;
+use crate::mixer::PanLaw;
+use crate::regions::Region;
+use crate::tracks::{Take, TakeSelection};
+
+pub const SESSION_VERSION: u32 = 2;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Session {
+    pub version: u32,
+    pub config: SessionConfig,
+    pub input_busses: Vec<Vec<u8>>,     //channel ids per input bus
+    pub output_busses: Vec<Vec<u8>>,    //channel ids per output bus
+    pub routes: Vec<(u8, u8, Vec<u8>)>, // (input bus, output bus, track_list)
+    pub tracks: Vec<TrackState>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub history: Option<History>, //undo history, only if it is saved with the session
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SessionConfig {
+    pub host: String,
+    pub in_device: String,
+    pub out_device: String,
+    pub in_config: StreamConfigState,
+    pub out_config: StreamConfigState,
+    pub sample_format: String,
+    #[serde(default)]
+    pub latency: u64,
+    #[serde(default)]
+    pub pan_law: PanLaw,
+    #[serde(default)]
+    pub timecode_origin: u64, //timeline frame 0 in samples since midnight
+    #[serde(default)]
+    pub record_format: Option<RecordFormat>, //None in older sessions, the device sample format
+    #[serde(default)]
+    pub sample_rate: Option<u32>, //session rate, None in older sessions: the input rate
+    #[serde(default)]
+    pub metronome: MetronomeSettings,
+    #[serde(default)]
+    pub cycle: Option<Range<u64>>, //transport loop, None when cycling is off
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct StreamConfigState {
+    pub channels: u16,
+    pub sample_rate: u32,
+    pub buffer_size: Option<u32>, //None for BufferSize::Default
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct TrackState {
+    pub id: u8,
+    pub name: String,
+    #[serde(default)]
+    pub takes: Vec<Take>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub files: Vec<String>, //version 1 takes, all starting at frame 0
+    #[serde(default)]
+    pub selection: TakeSelection,
+    #[serde(default)]
+    pub comp: Vec<CompRegion>,
+    #[serde(default)]
+    pub regions: Vec<Region>,
+    #[serde(default)]
+    pub in_channels: Vec<u8>, //input bus channels the track records, the whole bus when empty
+    pub rec: bool,
+    pub monitor: bool,
+    #[serde(default)]
+    pub out_map: Vec<(u8, u8)>, //(track channel, output channel), default map when empty
+    #[serde(default)]
+    pub gain_db: f32,
+    #[serde(default)]
+    pub pan: f32,
+    #[serde(default)]
+    pub mute: bool,
+    #[serde(default)]
+    pub solo: bool,
+}
+
+impl Session {
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        let writer = BufWriter::new(File::create(path)?);
+        serde_json::to_writer_pretty(writer, self)?;
+        Ok(())
+    }
+
+    pub fn load(path: &str) -> io::Result<Session> {
+        let reader = BufReader::new(File::open(path)?);
+        let mut session: Session = serde_json::from_reader(reader)?;
+        if session.version > SESSION_VERSION {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "session version {} is newer than supported version {}",
+                    session.version, SESSION_VERSION
+                ),
+            ));
+        }
+        session.migrate();
+        Ok(session)
+    }
+
+    //Files of the takes of every track
+    pub fn get_take_files(&self) -> Vec<String> {
+        let mut files = Vec::<String>::new();
+        for track in self.tracks.iter() {
+            files.extend(track.takes.iter().map(|t| t.file.clone()));
+        }
+        files
+    }
+
+    fn migrate(&mut self) {
+        for track in self.tracks.iter_mut() {
+            for file in track.files.drain(..) {
+                track.takes.push(Take {
+                    file: file,
+                    start_frame: 0,
+                    name: String::new(),
+                });
+            }
+        }
+        self.version = SESSION_VERSION;
+    }
+}
+
+impl StreamConfigState {
+    pub fn from_config(config: &StreamConfig) -> StreamConfigState {
+        StreamConfigState {
+            channels: config.channels,
+            sample_rate: config.sample_rate.0,
+            buffer_size: match config.buffer_size {
+                BufferSize::Default => None,
+                BufferSize::Fixed(n) => Some(n),
+            },
+        }
+    }
+
+    pub fn to_config(&self) -> StreamConfig {
+        StreamConfig {
+            channels: self.channels,
+            sample_rate: SampleRate(self.sample_rate),
+            buffer_size: match self.buffer_size {
+                Some(n) => BufferSize::Fixed(n),
+                None => BufferSize::Default,
+            },
+        }
+    }
+}
+
+pub fn sample_format_to_str(format: &SampleFormat) -> String {
+    match format {
+        SampleFormat::I16 => "i16".to_string(),
+        SampleFormat::U16 => "u16".to_string(),
+        SampleFormat::F32 => "f32".to_string(),
+    }
+}
+
+pub fn sample_format_from_str(format: &str) -> SampleFormat {
+    match format {
+        "i16" => SampleFormat::I16,
+        "u16" => SampleFormat::U16,
+        _ => SampleFormat::F32,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!(
+            "example2-session-{}-{}.json",
+            name,
+            std::process::id()
+        ));
+        path.to_str().unwrap().to_string()
+    }
+
+    fn stream_config(channels: u16) -> StreamConfigState {
+        StreamConfigState {
+            channels: channels,
+            sample_rate: 48_000,
+            buffer_size: Some(256),
+        }
+    }
+
+    //a session with most settings off their defaults
+    fn session() -> Session {
+        Session {
+            version: SESSION_VERSION,
+            config: SessionConfig {
+                host: "JACK".to_string(),
+                in_device: "in".to_string(),
+                out_device: "out".to_string(),
+                in_config: stream_config(4),
+                out_config: stream_config(2),
+                sample_format: sample_format_to_str(&SampleFormat::F32),
+                latency: 96,
+                pan_law: PanLaw::Minus6Db,
+                timecode_origin: 48_000 * 3_600,
+                record_format: Some(RecordFormat::Flac24),
+                sample_rate: Some(96_000),
+                metronome: MetronomeSettings::default(),
+                cycle: Some(48_000..96_000),
+            },
+            input_busses: vec![vec![1, 2], vec![3, 4]],
+            output_busses: vec![vec![1, 2]],
+            routes: vec![(0, 0, vec![0]), (1, 0, vec![1])],
+            tracks: vec![TrackState {
+                id: 1,
+                name: "Vocals".to_string(),
+                takes: vec![Take {
+                    file: "vocals-1.flac".to_string(),
+                    start_frame: 12_000,
+                    name: "verse".to_string(),
+                }],
+                files: Vec::<String>::new(),
+                selection: TakeSelection::Take("vocals-1.flac".to_string()),
+                comp: Vec::<CompRegion>::new(),
+                regions: Vec::<Region>::new(),
+                in_channels: vec![2],
+                rec: true,
+                monitor: false,
+                out_map: vec![(1, 1), (1, 2)],
+                gain_db: -3.5,
+                pan: 0.25,
+                mute: false,
+                solo: true,
+            }],
+            history: None,
+        }
+    }
+
+    #[test]
+    fn migrates_version_1_takes() {
+        //version 1 sessions list the files of a track, every one starting at frame 0
+        let path = temp_path("v1");
+        std::fs::write(
+            &path,
+            r#"{
+                "version": 1,
+                "config": {
+                    "host": "ALSA",
+                    "in_device": "in",
+                    "out_device": "out",
+                    "in_config": {"channels": 2, "sample_rate": 44100, "buffer_size": null},
+                    "out_config": {"channels": 2, "sample_rate": 44100, "buffer_size": null},
+                    "sample_format": "i16"
+                },
+                "input_busses": [[1, 2]],
+                "output_busses": [[1, 2]],
+                "routes": [[0, 0, [0]]],
+                "tracks": [{
+                    "id": 0,
+                    "name": "Track 0",
+                    "files": ["take-1.wav", "take-2.wav"],
+                    "rec": false,
+                    "monitor": true
+                }]
+            }"#,
+        )
+        .unwrap();
+
+        let session = Session::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(session.version, SESSION_VERSION);
+        let track = &session.tracks[0];
+        assert!(track.files.is_empty());
+        assert_eq!(
+            track.takes,
+            vec![
+                Take {
+                    file: "take-1.wav".to_string(),
+                    start_frame: 0,
+                    name: String::new(),
+                },
+                Take {
+                    file: "take-2.wav".to_string(),
+                    start_frame: 0,
+                    name: String::new(),
+                },
+            ]
+        );
+        assert_eq!(track.selection, TakeSelection::Latest);
+        assert_eq!(session.config.pan_law, PanLaw::default());
+        assert_eq!(session.config.record_format, None);
+        assert_eq!(session.config.sample_rate, None);
+        assert!(session.history.is_none());
+    }
+
+    #[test]
+    fn loads_what_it_saved() {
+        let session = session();
+        let path = temp_path("round-trip");
+        session.save(&path).unwrap();
+        let loaded = Session::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.version, SESSION_VERSION);
+        assert_eq!(loaded.config.host, session.config.host);
+        assert_eq!(loaded.config.in_config.channels, 4);
+        assert_eq!(loaded.config.out_config.buffer_size, Some(256));
+        assert_eq!(
+            sample_format_from_str(&loaded.config.sample_format),
+            SampleFormat::F32
+        );
+        assert_eq!(loaded.config.latency, 96);
+        assert_eq!(loaded.config.pan_law, PanLaw::Minus6Db);
+        assert_eq!(
+            loaded.config.timecode_origin,
+            session.config.timecode_origin
+        );
+        assert_eq!(loaded.config.record_format, Some(RecordFormat::Flac24));
+        assert_eq!(loaded.config.sample_rate, Some(96_000));
+        assert_eq!(loaded.config.metronome, session.config.metronome);
+        assert_eq!(loaded.config.cycle, Some(48_000..96_000));
+        assert_eq!(loaded.input_busses, session.input_busses);
+        assert_eq!(loaded.output_busses, session.output_busses);
+        assert_eq!(loaded.routes, session.routes);
+        assert_eq!(loaded.tracks, session.tracks);
+    }
+
+    #[test]
+    fn rejects_newer_versions() {
+        let path = temp_path("newer");
+        let mut session = session();
+        session.version = SESSION_VERSION + 1;
+        session.save(&path).unwrap();
+        let result = Session::load(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+}
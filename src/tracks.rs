@@ -1,20 +1,335 @@
-use cpal::{SampleFormat, StreamConfig};
-use multiqueue::BroadcastReceiver;
-
 use std::sync::mpsc::{Receiver, Sender};
 use std::sync::{Arc, Mutex};
-use std::thread;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use std::fs::OpenOptions;
+use std::io;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use crate::busses::{map_frame, ChannelMap, FrameSync};
+use crate::comp::{place_region, CompReader, CompRegion, CROSSFADE_SECS};
+use crate::error::{RecorderError, Result};
+use crate::formats::{RecordFormat, TakeReader, TakeWriter};
+use crate::frames::{frame_channel, FrameReceiver, FrameSender};
+use crate::import;
+use crate::mixer::MixParams;
+use crate::peaks;
+use crate::regions::{find_region, next_region_id, Fade, FadeCurve, Region, RegionReader};
+use crate::resample::{
+    convert_frames, FrameResampler, MONITOR_CHUNK_FRAMES, RESAMPLE_CHUNK_FRAMES,
+};
+use crate::wav::BextInfo;
+use crate::waveform::Waveform;
+
+const PLAYBACK_RING_FRAMES: usize = 48_000;
+const MONITOR_RING_FRAMES: usize = 4_096;
+const MONITOR_BLOCK_FRAMES: usize = 64;
+const WRITE_BLOCK_FRAMES: usize = 1_024;
+const PLAYBACK_BLOCK_FRAMES: usize = 1_024;
+const HEADER_FLUSH_SECS: u64 = 1; //a crash loses at most this much of a take's header length
+const SYNC_TIMEOUT_SECS: u64 = 2; //a take waits this long for its playback to start
+const TRASH_DIR: &str = "trash"; //next to the takes, where deleted takes go
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Take {
+    pub file: String,
+    pub start_frame: u64, //timeline position of the first frame in the file
+    #[serde(default)]
+    pub name: String, //given by the user, the file stem when empty
+}
+
+impl Take {
+    //Where the file goes when the take is deleted, see Track::delete_take
+    pub fn get_trash_path(&self) -> PathBuf {
+        let path = Path::new(&self.file);
+        let name = path.file_name().unwrap_or_default();
+        path.with_file_name(TRASH_DIR).join(name)
+    }
+
+    pub fn get_name(&self) -> String {
+        match self.name.is_empty() {
+            true => Path::new(&self.file)
+                .file_stem()
+                .map(|s| s.to_string_lossy().to_string())
+                .unwrap_or(self.file.clone()),
+            false => self.name.clone(),
+        }
+    }
+}
+
+//What a track plays
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
+pub enum TakeSelection {
+    #[default]
+    Latest, //the take recorded or imported last
+    Take(String), //file of one take
+    Comp,         //the track's comp regions
+    Regions,      //the track's edited regions, see regions.rs
+}
+
+//A take while it is recorded, its waveform grows with every frame written
+pub struct LiveTake {
+    pub take: Take,
+    pub waveform: Waveform,
+}
+
+//Where a recorded take goes on the timeline, all in frames at the session rate
+pub struct TakeTiming {
+    pub start_frame: u64,          //transport position recording starts at
+    pub roll_frames: u64, //input before start_frame is reached (count-in, pre-roll), not recorded
+    pub latency: u64,     //delay of the device path from the output back to the input, dropped
+    pub timecode_origin: u64, //timeline frame 0 in samples since midnight, for the BWF time reference
+    pub cycle: Option<Range<u64>>, //transport cycle, every pass after the first is a new take
+    pub sync: Option<FrameSync>, //finds the input frame heard while the roll started to play
+    pub first_frame: u64, //input bus frame the recording starts at, see InputBus::subscribe_from
+}
+
+//File names {track}_{n}.{extension} of new takes, n counts up from the number of takes and
+//skips names that are taken
+struct TakeNamer {
+    name: String,
+    extension: String,
+    used: Vec<String>,
+    n: usize,
+}
+
+impl TakeNamer {
+    fn new(name: &str, extension: &str, takes: &[Take]) -> TakeNamer {
+        TakeNamer {
+            name: name.to_string(),
+            extension: extension.to_string(),
+            used: takes.iter().map(|t| t.file.clone()).collect(),
+            n: takes.len() + 1,
+        }
+    }
+
+    fn next(&mut self) -> String {
+        loop {
+            let fname = format!("{}_{}.{}", self.name, self.n, self.extension);
+            self.n += 1;
+            if !self.used.contains(&fname) {
+                self.used.push(fname.clone());
+                return fname;
+            }
+        }
+    }
+}
+
+//Which written frames of a recording go into which take
+struct WriteSchedule {
+    skip_frames: u64, //converted frames dropped before the first take
+    lead_frames: u64, //silent frames the first take starts with
+    sync: Option<PendingSync>,
+    cycle: Option<CycleSplit>,
+}
+
+//How far the input was from the start of the roll, known once its first frame has played.
+//Nothing is written until then.
+struct PendingSync {
+    sync: FrameSync,
+    first_frame: u64,
+    in_rate: u32,
+    sample_rate: u32,
+    started: Instant,
+}
+
+impl PendingSync {
+    //Converted frames the roll started to play after the first frame recorded, negative when
+    //it played before
+    fn get_offset(&self) -> Option<i64> {
+        let in_frames = self.sync.get_input_frame()? - self.first_frame as i64;
+        let frames =
+            convert_frames(in_frames.unsigned_abs(), self.in_rate, self.sample_rate) as i64;
+        Some(match in_frames < 0 {
+            true => -frames,
+            false => frames,
+        })
+    }
+}
+
+impl WriteSchedule {
+    //Adds the offset of the roll to the frames skipped once it is known, true while the
+    //recording has to wait for it
+    fn is_waiting(&mut self, terminated: bool) -> bool {
+        let pending = match &self.sync {
+            Some(p) => p,
+            None => return false,
+        };
+        let offset = match pending.get_offset() {
+            Some(offset) => offset,
+            None if terminated || pending.started.elapsed().as_secs() >= SYNC_TIMEOUT_SECS => {
+                eprintln!("write_thread: the playback did not start, the take may not line up");
+                0
+            }
+            None => return true,
+        };
+        let skip_frames = self.skip_frames as i64 + offset;
+        self.skip_frames = skip_frames.max(0) as u64;
+        self.lead_frames = (-skip_frames).max(0) as u64;
+        self.sync = None;
+        false
+    }
+}
+
+//Cycle recording, a new take is started for every pass of the transport through the cycle
+struct CycleSplit {
+    split_at: u64,    //frames of the take being written, the first one ends with the cycle
+    pass_frames: u64, //frames of every later take, the cycle length
+    start_frame: u64, //timeline position of the later takes, the cycle start
+    namer: TakeNamer,
+    track_name: String,
+    timecode_origin: u64,
+}
+
+//Source of a playback thread, a take, or a comp or regions rendered like one
+enum PlaybackReader {
+    Take(TakeReader),
+    Comp(CompReader),
+    Regions(RegionReader),
+}
+
+impl PlaybackReader {
+    fn get_nof_channels(&self) -> u16 {
+        match self {
+            PlaybackReader::Take(reader) => reader.get_nof_channels(),
+            PlaybackReader::Comp(reader) => reader.get_nof_channels(),
+            PlaybackReader::Regions(reader) => reader.get_nof_channels(),
+        }
+    }
+
+    fn read_frame<T: cpal::Sample>(&mut self, frame: &mut Vec<T>) -> Result<bool> {
+        match self {
+            PlaybackReader::Take(reader) => reader.read_frame(frame),
+            PlaybackReader::Comp(reader) => reader.read_frame(frame),
+            PlaybackReader::Regions(reader) => reader.read_frame(frame),
+        }
+    }
+}
+
+struct PlaybackStart<T> {
+    reader: PlaybackReader,
+    pre_roll: u64, //frames of silence at the output rate before the reader starts
+    resampler: FrameResampler<T>, //from the reader's rate to the output rate
+}
+
+//What a track plays, detached from the track so that a looping playback thread can open it
+//again at the start of every cycle pass
+struct PlaybackSource {
+    selection: TakeSelection, //an auditioned take is selected here
+    files: Vec<Take>,
+    comp: Vec<CompRegion>,
+    regions: Vec<Region>,
+    sample_rate: u32, //session rate
+    nof_channels: usize,
+}
+
+//Where a playback thread starts and what it repeats
+struct PlaybackTiming {
+    start_frame: u64, //timeline frame at the session rate
+    delay: u64,       //frames of silence at out_rate before the first frame
+    out_rate: u32,
+    cycle: Option<Range<u64>>, //at its end playback goes on from its start
+}
+
+impl PlaybackSource {
+    //Reader positioned at timeline frame start_frame, None when there is nothing to play
+    //from there on
+    fn open<T: cpal::Sample>(
+        &self,
+        start_frame: u64,
+        out_rate: u32,
+    ) -> Result<Option<PlaybackStart<T>>> {
+        let take = match &self.selection {
+            TakeSelection::Latest => self.files.last(),
+            TakeSelection::Take(file) => self.files.iter().find(|t| t.file == *file),
+            TakeSelection::Comp => {
+                let reader = CompReader::open(
+                    &self.comp,
+                    &self.files,
+                    self.sample_rate,
+                    self.nof_channels,
+                    start_frame,
+                )?;
+                if reader.get_position() >= reader.get_end() {
+                    return Ok(None);
+                }
+                let pre_roll = reader.get_position() - start_frame;
+                let reader = PlaybackReader::Comp(reader);
+                return self.start_at(reader, pre_roll, self.sample_rate, out_rate);
+            }
+            TakeSelection::Regions => {
+                let reader = RegionReader::open(
+                    &self.regions,
+                    self.sample_rate,
+                    self.nof_channels,
+                    start_frame,
+                )?;
+                if reader.get_position() >= reader.get_end() {
+                    return Ok(None);
+                }
+                let pre_roll = reader.get_position() - start_frame;
+                let reader = PlaybackReader::Regions(reader);
+                return self.start_at(reader, pre_roll, self.sample_rate, out_rate);
+            }
+        };
+        let take = match take {
+            Some(t) => t,
+            None => return Ok(None),
+        };
 
-use hound::{WavReader, WavSpec, WavWriter};
-use std::fs::File;
-use std::io::{BufReader, BufWriter};
+        let mut reader = TakeReader::open(&take.file)?;
+        //silence until the take starts, or seek into it if it already has. Timeline frames
+        //are at the session rate, the take may have been recorded or imported at another.
+        let take_rate = reader.get_sample_rate();
+        let pre_roll = take.start_frame.saturating_sub(start_frame);
+        let seek = start_frame.saturating_sub(take.start_frame);
+        let seek = convert_frames(seek, self.sample_rate, take_rate);
+        if seek >= reader.duration() {
+            return Ok(None);
+        }
+        reader.seek(seek)?;
+        self.start_at(PlaybackReader::Take(reader), pre_roll, take_rate, out_rate)
+    }
+
+    fn start_at<T: cpal::Sample>(
+        &self,
+        reader: PlaybackReader,
+        pre_roll: u64,
+        reader_rate: u32,
+        out_rate: u32,
+    ) -> Result<Option<PlaybackStart<T>>> {
+        let resampler = FrameResampler::<T>::new(
+            reader_rate,
+            out_rate,
+            reader.get_nof_channels() as usize,
+            RESAMPLE_CHUNK_FRAMES,
+        )?;
+        Ok(Some(PlaybackStart {
+            reader: reader,
+            pre_roll: convert_frames(pre_roll, self.sample_rate, out_rate),
+            resampler: resampler,
+        }))
+    }
+}
 
 pub struct Track {
     id: u8,
     name: String,
-    files: Vec<String>,
-    wav_spec: WavSpec,
-    term_tx: Vec<Sender<()>>,
+    files: Vec<Take>,
+    selection: TakeSelection,
+    comp: Vec<CompRegion>,
+    regions: Vec<Region>,
+    audition: Option<String>, //take file played instead of the selection, not by bounces
+    sample_rate: u32,
+    in_map: ChannelMap,  //(input bus channel, track channel), one per file channel
+    out_map: ChannelMap, //(track channel, output device channel)
+    term_tx: Vec<Sender<()>>, //monitor and playback threads
+    rec_term_tx: Option<(Sender<()>, WriteThreadHandle)>, //write thread of the take being recorded
+    live: Option<LiveTakeHandle>, //the take being recorded and its waveform so far
+    mix: Arc<MixParams>, //fader, pan, mute and solo read by the mix threads
     rec: bool,
     monitor: bool,
 }
@@ -23,82 +338,324 @@ impl Track {
     pub fn new(
         id: u8,
         name: String,
-        stream_config: StreamConfig,
-        sample_format: SampleFormat,
+        sample_rate: u32,     //session rate, takes are recorded at it
+        in_channels: Vec<u8>, //input bus channels in file channel order
+        out_map: ChannelMap,
     ) -> Track {
-        let wav_spec = wav_spec_from_config(&stream_config, &sample_format);
+        let mut in_map = ChannelMap::new();
+        for (idx, ch) in in_channels.iter().enumerate() {
+            in_map.push((*ch, (idx + 1) as u8));
+        }
         Track {
             id: id,
             name: name.clone(),
-            files: Vec::<String>::new(),
-            wav_spec: wav_spec,
+            files: Vec::<Take>::new(),
+            selection: TakeSelection::Latest,
+            comp: Vec::<CompRegion>::new(),
+            regions: Vec::<Region>::new(),
+            audition: None,
+            sample_rate: sample_rate,
+            in_map: in_map,
+            out_map: out_map,
             term_tx: Vec::<Sender<()>>::new(),
+            rec_term_tx: None,
+            live: None,
+            mix: Arc::new(MixParams::new()),
             rec: false,
             monitor: false,
         }
     }
 
+    //Records the frames of bus_rx into a new take placed by timing. The take is written in
+    //format, at the session rate the input (in_rate) is converted to.
     pub fn record<T: 'static + cpal::Sample + hound::Sample + Send + Sync>(
         &mut self,
-    ) -> Sender<BroadcastReceiver<(u8, T)>> {
-        self.add_file();
+        bus_rx: FrameReceiver<T>,
+        in_rate: u32,
+        timing: TakeTiming,
+        format: RecordFormat,
+    ) -> Result<()> {
+        //the input from where the roll started to play on, heard latency frames later
+        let skip_frames = timing.roll_frames + timing.latency;
+        let sync = timing.sync.map(|sync| PendingSync {
+            sync: sync,
+            first_frame: timing.first_frame,
+            in_rate: in_rate,
+            sample_rate: self.sample_rate,
+            started: Instant::now(),
+        });
+        self.add_file(timing.start_frame, format.extension());
+        let cycle = match timing.cycle {
+            Some(cycle) if cycle.end > timing.start_frame => Some(CycleSplit {
+                split_at: cycle.end - timing.start_frame,
+                pass_frames: cycle.end - cycle.start,
+                start_frame: cycle.start,
+                namer: TakeNamer::new(&self.name, format.extension(), &self.files),
+                track_name: self.name.clone(),
+                timecode_origin: timing.timecode_origin,
+            }),
+            _ => None,
+        };
 
-        let writer = WavWriter::create(self.files.last().unwrap(), self.wav_spec).unwrap();
+        let take = self.files.last().unwrap().clone();
+        let bext = BextInfo::new(
+            format!("{} take {}", self.name, self.files.len()),
+            timing.timecode_origin + take.start_frame,
+        );
+        let path = take.file.clone();
+        let resampler = FrameResampler::<T>::new(
+            in_rate,
+            self.sample_rate,
+            self.in_map.len(),
+            RESAMPLE_CHUNK_FRAMES,
+        );
+        let writer = resampler.and_then(|r| {
+            TakeWriter::create::<T>(
+                &path,
+                format,
+                self.sample_rate,
+                self.in_map.len() as u16,
+                &bext,
+            )
+            .map(|w| (w, r))
+        });
+        let (writer, resampler) = match writer {
+            Ok(w) => w,
+            Err(e) => {
+                //no take without a file
+                self.files.pop();
+                return Err(e);
+            }
+        };
         let writer = Arc::new(Mutex::new(Some(writer)));
+        let live = Arc::new(Mutex::new(LiveTake {
+            take: take,
+            waveform: Waveform::new(self.sample_rate),
+        }));
 
-        let (thread_tx, thread_rx) = std::sync::mpsc::channel::<BroadcastReceiver<(u8, T)>>();
         let (term_tx, term_rx) = std::sync::mpsc::channel();
-        write_thread(writer, thread_rx, term_rx);
-        self.term_tx.push(term_tx);
+        let handle = write_thread(
+            writer,
+            live.clone(),
+            bus_rx,
+            self.in_map.clone(),
+            resampler,
+            term_rx,
+            WriteSchedule {
+                skip_frames: skip_frames,
+                lead_frames: 0,
+                sync: sync,
+                cycle: cycle,
+            },
+        );
+        self.rec_term_tx = Some((term_tx, handle));
+        self.live = Some(live);
+        Ok(())
+    }
 
-        thread_tx
+    //Converts the audio file at path into a new take of the track that starts at timeline
+    //frame start_frame, see import::convert_file. Returns the frames of the take.
+    pub fn import(
+        &mut self,
+        path: &str,
+        start_frame: u64,
+        timecode_origin: u64,
+        format: RecordFormat,
+    ) -> Result<u64> {
+        if self.is_recording() {
+            return Err(RecorderError::InvalidArgument(format!(
+                "track {} is recording",
+                self.name
+            )));
+        }
+        self.add_file(start_frame, format.extension());
+        let take = self.files.last().unwrap().clone();
+        let bext = BextInfo::new(
+            format!("{} import of {}", self.name, path),
+            timecode_origin + start_frame,
+        );
+        let imported = import::convert_file(
+            path,
+            &take.file,
+            format,
+            self.sample_rate,
+            self.in_map.len() as u16,
+            &bext,
+        );
+        if imported.is_err() {
+            //no take without a complete file
+            self.files.pop();
+            std::fs::remove_file(&take.file).ok();
+        }
+        imported
     }
 
+    //Plays the selected take, the comp or an auditioned take from timeline frame
+    //start_frame, converted to out_rate, after delay frames (at out_rate) of silence, e.g. a
+    //count-in. With a cycle playback goes back to its start whenever it reaches its end.
     pub fn start_playback<T: 'static + cpal::Sample + hound::Sample + Send + Sync>(
         &mut self,
         out_channels: Vec<u8>,
-    ) -> Option<Receiver<(u8, T)>> {
-        let playback_file = match self.files.last() {
-            Some(f) => f,
-            None => return None,
-        };
-        let reader = match WavReader::open(playback_file) {
-            Ok(r) => r,
-            Err(_) => return None,
-        };
-
+        out_rate: u32,
+        start_frame: u64,
+        delay: u64,
+        cycle: Option<Range<u64>>,
+    ) -> Result<Option<FrameReceiver<T>>> {
+        let source = self.playback_source(true);
+        let start = source.open::<T>(start_frame, out_rate)?;
+        //a cycle may reach takes that start_frame is past
+        if start.is_none() && (cycle.is_none() || self.files.is_empty()) {
+            return Ok(None);
+        }
         let (term_tx, term_rx) = std::sync::mpsc::channel();
-        let (playback_tx, playback_rx) = std::sync::mpsc::channel::<(u8, T)>();
-        playback_thread(reader, playback_tx, term_rx, out_channels);
+        let (playback_tx, playback_rx) =
+            frame_channel::<T>(out_channels.len(), PLAYBACK_RING_FRAMES);
+        let timing = PlaybackTiming {
+            start_frame: start_frame,
+            delay: delay,
+            out_rate: out_rate,
+            cycle: cycle,
+        };
+        playback_thread(
+            source,
+            start,
+            playback_tx,
+            term_rx,
+            out_channels,
+            self.out_map.clone(),
+            timing,
+        );
         self.term_tx.push(term_tx);
 
-        Some(playback_rx)
+        Ok(Some(playback_rx))
     }
 
+    //Playback at the session rate that is not tied to the track's thread stack, it ends
+    //with the file or when the returned receiver is dropped. The output mapping goes from
+    //bus_channels, the channels of the track's output bus, to out_channels by position, the
+    //channels of a wider bus wrap around.
+    pub fn bounce_playback<T: 'static + cpal::Sample + hound::Sample + Send + Sync>(
+        &self,
+        out_channels: Vec<u8>,
+        bus_channels: &[u8],
+        start_frame: u64,
+    ) -> Result<Option<FrameReceiver<T>>> {
+        let source = self.playback_source(false);
+        let start = match source.open::<T>(start_frame, self.sample_rate)? {
+            Some(s) => s,
+            None => return Ok(None),
+        };
+        let (_, term_rx) = std::sync::mpsc::channel();
+        let (playback_tx, playback_rx) =
+            frame_channel::<T>(out_channels.len(), PLAYBACK_RING_FRAMES);
+        let out_map: ChannelMap = self
+            .out_map
+            .iter()
+            .filter_map(|(src, dest)| {
+                let idx = bus_channels.iter().position(|ch| ch == dest)?;
+                Some((*src, out_channels[idx % out_channels.len()]))
+            })
+            .collect();
+        let timing = PlaybackTiming {
+            start_frame: start_frame,
+            delay: 0,
+            out_rate: self.sample_rate,
+            cycle: None,
+        };
+        playback_thread(
+            source,
+            Some(start),
+            playback_tx,
+            term_rx,
+            out_channels,
+            out_map,
+            timing,
+        );
+        Ok(Some(playback_rx))
+    }
+
+    //The selected take, the comp or the regions, an auditioned take instead if live
+    fn playback_source(&self, live: bool) -> PlaybackSource {
+        let selection = match (&self.audition, live) {
+            (Some(file), true) => TakeSelection::Take(file.clone()),
+            _ => self.selection.clone(),
+        };
+        PlaybackSource {
+            selection: selection,
+            files: self.files.clone(),
+            comp: self.comp.clone(),
+            regions: self.regions.clone(),
+            sample_rate: self.sample_rate,
+            nof_channels: self.in_map.len(),
+        }
+    }
+
+    //Maps the frames of bus_rx onto out_chs and converts them from in_rate to out_rate,
+    //returns the mapped frames for the mix.
     pub fn start_monitor<T: 'static + cpal::Sample + Send + Sync>(
         &mut self,
+        bus_rx: FrameReceiver<T>,
+        in_rate: u32,
         out_chs: Vec<u8>,
-    ) -> (
-        Sender<BroadcastReceiver<(u8, T)>>, // tx for sending bus_rx
-        Receiver<(u8, T)>,                  //rx for receiving Samples
-    ) {
-        let (thread_tx, thread_rx) = std::sync::mpsc::channel();
+        out_rate: u32,
+    ) -> Result<FrameReceiver<T>> {
+        let resampler =
+            FrameResampler::<T>::new(in_rate, out_rate, self.in_map.len(), MONITOR_CHUNK_FRAMES)?;
         let (term_tx, term_rx) = std::sync::mpsc::channel();
-        let (monitor_tx, monitor_rx) = std::sync::mpsc::channel::<(u8, T)>();
+        let (monitor_tx, monitor_rx) = frame_channel::<T>(out_chs.len(), MONITOR_RING_FRAMES);
 
-        monitor_thread(thread_rx, monitor_tx, term_rx);
+        monitor_thread(
+            bus_rx,
+            monitor_tx,
+            term_rx,
+            self.in_map.clone(),
+            resampler,
+            out_chs,
+            self.out_map.clone(),
+        );
         self.term_tx.push(term_tx);
 
-        (thread_tx, monitor_rx)
+        Ok(monitor_rx)
     }
 
-    //Must be called after stop_monitor
-    pub fn stop_recording(&mut self) {
-        self.stop_thread();
+    //Returns once the queued frames are written and the take is finalized
+    pub fn stop_recording(&mut self) -> Result<()> {
+        if let Some((tx, handle)) = self.rec_term_tx.take() {
+            //the write thread only quits early on an error, which the join returns
+            tx.send(()).ok();
+            self.live = None;
+            return match handle.join() {
+                Ok((passes, result)) => {
+                    if !passes.is_empty() {
+                        self.files.extend(passes);
+                        self.selection = TakeSelection::Latest;
+                    }
+                    result
+                }
+                Err(_) => Err(RecorderError::Io(io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("write thread of track {} panicked", self.id),
+                ))),
+            };
+        }
+        Ok(())
     }
 
+    //Stops monitor and playback threads of the track
     pub fn stop_monitor(&mut self) {
-        self.stop_thread();
+        while let Some(tx) = self.term_tx.pop() {
+            //playback threads quit by themselves at the end of the take
+            tx.send(()).ok();
+        }
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.rec_term_tx.is_some()
+    }
+
+    //While recording, the take being written (with cycling the one of the current pass)
+    pub fn get_live_take(&self) -> Option<LiveTakeHandle> {
+        self.live.clone()
     }
 
     pub fn set_rec(&mut self, state: bool) {
@@ -121,123 +678,790 @@ impl Track {
         (self.id, self.name.clone(), self.rec, self.monitor)
     }
 
-    fn stop_thread(&mut self) {
-        let tx = match self.term_tx.pop() {
-            Some(t) => t,
-            None => return,
+    pub fn get_mix_params(&self) -> Arc<MixParams> {
+        self.mix.clone()
+    }
+
+    pub fn get_nof_channels(&self) -> u8 {
+        self.in_map.len() as u8
+    }
+
+    //Rate of new takes, existing takes keep theirs and are converted when played
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate;
+    }
+
+    //Input bus channels the track records, in file channel order
+    pub fn get_in_channels(&self) -> Vec<u8> {
+        self.in_map.iter().map(|x| x.0).collect()
+    }
+
+    pub fn get_out_map(&self) -> ChannelMap {
+        self.out_map.clone()
+    }
+
+    pub fn set_out_map(&mut self, out_map: ChannelMap) {
+        self.out_map = out_map;
+    }
+
+    pub fn get_files(&self) -> Vec<Take> {
+        self.files.clone()
+    }
+
+    //Takes that were deleted are moved back from the trash, e.g. when the deletion is undone
+    pub fn set_files(&mut self, files: Vec<Take>) {
+        for take in files.iter() {
+            let trashed = take.get_trash_path();
+            if Path::new(&take.file).exists() || !trashed.exists() {
+                continue;
+            }
+            if let Err(e) = std::fs::rename(&trashed, &take.file) {
+                eprintln!("Track::set_files: could not restore {}: {}", take.file, e);
+            }
+        }
+        self.files = files;
+    }
+
+    pub fn get_selection(&self) -> TakeSelection {
+        self.selection.clone()
+    }
+
+    pub fn set_selection(&mut self, selection: TakeSelection) -> Result<()> {
+        match &selection {
+            TakeSelection::Take(file) => {
+                self.find_take(file)?;
+            }
+            TakeSelection::Comp if self.comp.is_empty() => {
+                return Err(RecorderError::InvalidArgument(format!(
+                    "track {} has no comp",
+                    self.name
+                )))
+            }
+            TakeSelection::Regions if self.regions.is_empty() => {
+                return Err(RecorderError::InvalidArgument(format!(
+                    "track {} has no regions",
+                    self.name
+                )))
+            }
+            _ => (),
+        }
+        self.selection = selection;
+        Ok(())
+    }
+
+    //Live playback plays the take instead of the selection until it is set back to None
+    pub fn audition(&mut self, file: Option<String>) -> Result<()> {
+        if let Some(f) = &file {
+            self.find_take(f)?;
+        }
+        self.audition = file;
+        Ok(())
+    }
+
+    pub fn get_audition(&self) -> Option<String> {
+        self.audition.clone()
+    }
+
+    pub fn rename_take(&mut self, file: &str, name: String) -> Result<()> {
+        let idx = self.find_take(file)?;
+        self.files[idx].name = name;
+        Ok(())
+    }
+
+    //Removes the take, its comp regions and its regions, its file is moved into the trash
+    //folder next to it
+    pub fn delete_take(&mut self, file: &str) -> Result<()> {
+        if self.is_recording() {
+            return Err(RecorderError::InvalidArgument(format!(
+                "track {} is recording",
+                self.name
+            )));
+        }
+        let idx = self.find_take(file)?;
+        let take = self.files.remove(idx);
+        self.comp.retain(|r| r.take != take.file);
+        self.regions.retain(|r| r.take != take.file);
+        if self.audition.as_ref() == Some(&take.file) {
+            self.audition = None;
+        }
+        let selected = match &self.selection {
+            TakeSelection::Take(f) => *f == take.file,
+            TakeSelection::Comp => self.comp.is_empty(),
+            TakeSelection::Regions => self.regions.is_empty(),
+            TakeSelection::Latest => false,
+        };
+        if selected {
+            self.selection = TakeSelection::Latest;
+        }
+        peaks::remove_peaks(&take.file)?;
+        let trashed = take.get_trash_path();
+        if let Some(dir) = trashed.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        match std::fs::rename(&take.file, &trashed) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(RecorderError::Io(e)),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn get_comp(&self) -> Vec<CompRegion> {
+        self.comp.clone()
+    }
+
+    //The comp plays the take in range (timeline frames) from now on, see comp::place_region
+    pub fn comp_take(&mut self, file: &str, range: Range<u64>) -> Result<()> {
+        self.find_take(file)?;
+        place_region(
+            &mut self.comp,
+            CompRegion {
+                take: file.to_string(),
+                start_frame: range.start,
+                end_frame: range.end,
+            },
+        )
+    }
+
+    pub fn set_comp(&mut self, comp: Vec<CompRegion>) -> Result<()> {
+        let mut placed = Vec::<CompRegion>::new();
+        for region in comp {
+            self.find_take(&region.take)?;
+            place_region(&mut placed, region)?;
+        }
+        if placed.is_empty() && self.selection == TakeSelection::Comp {
+            self.selection = TakeSelection::Latest;
+        }
+        self.comp = placed;
+        Ok(())
+    }
+
+    pub fn get_regions(&self) -> Vec<Region> {
+        self.regions.clone()
+    }
+
+    pub fn set_regions(&mut self, regions: Vec<Region>) -> Result<()> {
+        for (idx, region) in regions.iter().enumerate() {
+            self.check_region(region)?;
+            if regions[..idx].iter().any(|r| r.id == region.id) {
+                return Err(RecorderError::InvalidArgument(format!(
+                    "track {} has two regions {}",
+                    self.name, region.id
+                )));
+            }
+        }
+        if regions.is_empty() && self.selection == TakeSelection::Regions {
+            self.selection = TakeSelection::Latest;
+        }
+        self.regions = regions;
+        Ok(())
+    }
+
+    //Replaces the regions with what the track plays now, a region over the whole take or
+    //one per comp region with its crossfades, and plays them from then on
+    pub fn regions_from_selection(&mut self) -> Result<()> {
+        let mut regions = Vec::<Region>::new();
+        let take = match &self.selection {
+            TakeSelection::Regions => return Ok(()),
+            TakeSelection::Latest => self.files.last(),
+            TakeSelection::Take(file) => self.files.get(self.find_take(file)?),
+            TakeSelection::Comp => None,
         };
-        tx.send(());
+        if let Some(take) = take {
+            regions.push(Region {
+                id: 1,
+                take: take.file.clone(),
+                source_offset: 0,
+                position: take.start_frame,
+                length: self.take_frames(&take.file)?,
+                fade_in: Fade::default(),
+                fade_out: Fade::default(),
+                gain_db: 0.0,
+            });
+        }
+        let half_fade = (CROSSFADE_SECS * self.sample_rate as f64 / 2.0) as u64;
+        if self.selection == TakeSelection::Comp {
+            for comp_region in self.comp.iter() {
+                let take = &self.files[self.find_take(&comp_region.take)?];
+                let take_end = take.start_frame + self.take_frames(&take.file)?;
+                let start = std::cmp::max(
+                    comp_region.start_frame.saturating_sub(half_fade),
+                    take.start_frame,
+                );
+                let end = std::cmp::min(comp_region.end_frame + half_fade, take_end);
+                if start >= end {
+                    continue;
+                }
+                let fade = |frames: u64| Fade {
+                    frames: frames,
+                    curve: FadeCurve::Linear,
+                };
+                let mut region = Region {
+                    id: next_region_id(&regions),
+                    take: take.file.clone(),
+                    source_offset: start - take.start_frame,
+                    position: start,
+                    length: end - start,
+                    fade_in: fade((comp_region.start_frame + half_fade).saturating_sub(start)),
+                    fade_out: fade(
+                        end.saturating_sub(comp_region.end_frame.saturating_sub(half_fade)),
+                    ),
+                    gain_db: 0.0,
+                };
+                region.fit_fades();
+                regions.push(region);
+            }
+        }
+        if regions.is_empty() {
+            return Err(RecorderError::InvalidArgument(format!(
+                "track {} plays nothing",
+                self.name
+            )));
+        }
+        self.regions = regions;
+        self.selection = TakeSelection::Regions;
+        Ok(())
     }
 
-    fn add_file(&mut self) {
-        let fname = format!("{}_{}.wav", self.name, self.files.len() + 1);
-        self.files.push(fname);
+    //Plays range (timeline frames) of the region's take instead, the region's start and
+    //end move over the take while the take stays where it is
+    pub fn trim_region(&mut self, id: u32, range: Range<u64>) -> Result<()> {
+        self.edit_region(id, |region| {
+            let source_offset = range.start as i64 - region.take_start();
+            if range.start >= range.end || source_offset < 0 {
+                return Err(RecorderError::InvalidArgument(format!(
+                    "region {} cannot be trimmed to {}..{}",
+                    region.id, range.start, range.end
+                )));
+            }
+            region.source_offset = source_offset as u64;
+            region.position = range.start;
+            region.length = range.end - range.start;
+            region.fit_fades();
+            Ok(())
+        })
+    }
+
+    pub fn move_region(&mut self, id: u32, position: u64) -> Result<()> {
+        self.edit_region(id, |region| {
+            region.position = position;
+            Ok(())
+        })
+    }
+
+    pub fn set_region_fades(&mut self, id: u32, fade_in: Fade, fade_out: Fade) -> Result<()> {
+        self.edit_region(id, |region| {
+            region.fade_in = fade_in;
+            region.fade_out = fade_out;
+            Ok(())
+        })
+    }
+
+    pub fn set_region_gain(&mut self, id: u32, gain_db: f32) -> Result<()> {
+        self.edit_region(id, |region| match gain_db.is_finite() {
+            true => {
+                region.gain_db = gain_db;
+                Ok(())
+            }
+            false => Err(RecorderError::InvalidArgument(format!(
+                "region gain {} dB",
+                gain_db
+            ))),
+        })
+    }
+
+    //Splits the region at timeline frame, the part after it is a new region whose id is
+    //returned. The fade in stays with the first part, the fade out with the second.
+    pub fn split_region(&mut self, id: u32, frame: u64) -> Result<u32> {
+        let idx = find_region(&self.regions, id)?;
+        let mut first = self.regions[idx].clone();
+        if frame <= first.position || frame >= first.end() {
+            return Err(RecorderError::InvalidArgument(format!(
+                "frame {} is not inside region {}",
+                frame, id
+            )));
+        }
+        let mut second = first.clone();
+        second.id = next_region_id(&self.regions);
+        second.source_offset += frame - first.position;
+        second.position = frame;
+        second.length = first.end() - frame;
+        second.fade_in = Fade::default();
+        second.fit_fades();
+        first.length = frame - first.position;
+        first.fade_out = Fade::default();
+        first.fit_fades();
+
+        let second_id = second.id;
+        self.regions[idx] = first;
+        self.regions.insert(idx + 1, second);
+        self.selection = TakeSelection::Regions;
+        Ok(second_id)
+    }
+
+    //A copy of the region at timeline frame position, returns its id
+    pub fn duplicate_region(&mut self, id: u32, position: u64) -> Result<u32> {
+        let idx = find_region(&self.regions, id)?;
+        let mut copy = self.regions[idx].clone();
+        copy.id = next_region_id(&self.regions);
+        copy.position = position;
+        let copy_id = copy.id;
+        self.regions.push(copy);
+        self.selection = TakeSelection::Regions;
+        Ok(copy_id)
+    }
+
+    //The take of the region stays, without regions the track plays the latest take again
+    pub fn delete_region(&mut self, id: u32) -> Result<()> {
+        let idx = find_region(&self.regions, id)?;
+        self.regions.remove(idx);
+        if self.regions.is_empty() && self.selection == TakeSelection::Regions {
+            self.selection = TakeSelection::Latest;
+        }
+        Ok(())
+    }
+
+    //Applies edit to a copy of region id and keeps it if it still fits its take, the
+    //track plays its regions from then on
+    fn edit_region<F: FnOnce(&mut Region) -> Result<()>>(
+        &mut self,
+        id: u32,
+        edit: F,
+    ) -> Result<()> {
+        let idx = find_region(&self.regions, id)?;
+        let mut region = self.regions[idx].clone();
+        edit(&mut region)?;
+        self.check_region(&region)?;
+        self.regions[idx] = region;
+        self.selection = TakeSelection::Regions;
+        Ok(())
+    }
+
+    fn check_region(&self, region: &Region) -> Result<()> {
+        region.check()?;
+        self.find_take(&region.take)?;
+        if region.source_offset + region.length > self.take_frames(&region.take)? {
+            return Err(RecorderError::InvalidArgument(format!(
+                "region {} ends after its take",
+                region.id
+            )));
+        }
+        Ok(())
+    }
+
+    //Length of a take in timeline frames, regions only play takes at the session rate
+    fn take_frames(&self, file: &str) -> Result<u64> {
+        let reader = TakeReader::open(file)?;
+        if reader.get_sample_rate() != self.sample_rate {
+            return Err(RecorderError::InvalidArgument(format!(
+                "take {} is at {} Hz, the session at {} Hz",
+                file,
+                reader.get_sample_rate(),
+                self.sample_rate
+            )));
+        }
+        Ok(reader.duration())
+    }
+
+    fn find_take(&self, file: &str) -> Result<usize> {
+        self.files
+            .iter()
+            .position(|t| t.file == file)
+            .ok_or(RecorderError::InvalidArgument(format!(
+                "track {} has no take {}",
+                self.name, file
+            )))
+    }
+
+    //A new take is what the track plays next
+    fn add_file(&mut self, start_frame: u64, extension: &str) {
+        //numbers of deleted takes are not reused while later ones exist
+        let fname = TakeNamer::new(&self.name, extension, &self.files).next();
+        self.files.push(Take {
+            file: fname,
+            start_frame: start_frame,
+            name: String::new(),
+        });
+        self.selection = TakeSelection::Latest;
+        self.audition = None;
     }
 }
 
+//The header is rewritten and the file synced to disk every HEADER_FLUSH_SECS, so a take
+//stays readable up to the last flush if the process dies before it is finalized. Returns the
+//takes started for later cycle passes, they are kept when writing fails.
 fn write_thread<T: 'static + cpal::Sample + hound::Sample + Send + Sync>(
     writer: WavWriterHandle,
-    thread_rx: Receiver<BroadcastReceiver<(u8, T)>>,
+    live: LiveTakeHandle,
+    mut bus_rx: FrameReceiver<T>,
+    in_map: ChannelMap,
+    mut resampler: FrameResampler<T>,
     term_rx: Receiver<()>,
-) {
+    mut schedule: WriteSchedule,
+) -> WriteThreadHandle {
     thread::spawn(move || {
-        if let Ok(mut guard) = writer.try_lock() {
-            if let Some(writer) = guard.as_mut() {
-                let bus_rx: BroadcastReceiver<(u8, T)> = match thread_rx.recv() {
-                    Ok(rx) => rx,
-                    Err(e) => panic!("write_thread: Oh no! {}", e),
-                };
-                // println!("Received");
-
-                //Start reading from bus_rx and writing to file.
-                loop {
-                    //Tries to receive info from broadcast buffer.
-                    if let Ok(t) = bus_rx.try_recv() {
-                        let sample: T = cpal::Sample::from(&t.1);
-                        writer.write_sample(sample).ok();
+        let mut passes = Vec::<Take>::new();
+        let mut guard = match writer.try_lock() {
+            Ok(g) => g,
+            Err(_) => {
+                return (
+                    passes,
+                    Err(RecorderError::Io(io::Error::new(
+                        io::ErrorKind::WouldBlock,
+                        "take writer is in use",
+                    ))),
+                )
+            }
+        };
+        let mut result = Ok(());
+        if let Some(writer) = guard.as_mut() {
+            let nof_channels = bus_rx.get_nof_channels();
+            let mut buffer = vec![cpal::Sample::from(&0.0f32); WRITE_BLOCK_FRAMES * nof_channels];
+            let track_channels: Vec<u8> = in_map.iter().map(|x| x.1).collect();
+            let mut track_frame = vec![cpal::Sample::from(&0.0f32); track_channels.len()];
+            let silence = track_frame.clone();
+            let mut track_block = Vec::<T>::with_capacity(WRITE_BLOCK_FRAMES * track_frame.len());
+            let mut converted = Vec::<T>::new();
+            let flush_frames = writer.get_sample_rate() as u64 * HEADER_FLUSH_SECS;
+            let mut unflushed_frames = 0;
+            let mut take_frames: u64 = 0;
+            let mut path = live.lock().unwrap().take.file.clone();
+            //second handle to the take, only used to sync it to disk
+            let mut sync_file = OpenOptions::new().write(true).open(&path);
+
+            //Start reading from bus_rx and writing to file.
+            'write: loop {
+                //Looks for signal to terminate thread, the frames already queued are kept.
+                let terminated = term_rx.try_recv().is_ok();
+                //the input is kept queued until it is known where the take starts in it
+                if schedule.is_waiting(terminated) {
+                    thread::sleep(Duration::from_millis(1));
+                    continue;
+                }
+
+                let nof_frames = bus_rx.pop_frames(&mut buffer);
+                track_block.clear();
+                for frame in buffer[..nof_frames * nof_channels].chunks(nof_channels) {
+                    //only the track's channels of the bus frame, in file order
+                    map_frame(frame, &in_map, &track_channels, &mut track_frame);
+                    track_block.extend_from_slice(&track_frame);
+                }
+                //nothing more will be queued for the take
+                let last_block = terminated && bus_rx.available_frames() == 0;
+                converted.clear();
+                let mut converting = resampler.process(&track_block, &mut converted);
+                if last_block {
+                    converting = converting.and_then(|_| resampler.flush(&mut converted));
+                }
+                if let Err(e) = converting {
+                    result = Err(e);
+                    break 'write;
+                }
+
+                let mut live_take = live.lock().unwrap();
+                while schedule.lead_frames > 0 {
+                    if let Err(e) = writer.write_frame(&silence) {
+                        result = Err(e);
+                        break 'write;
+                    }
+                    live_take.waveform.push_frame(&silence);
+                    schedule.lead_frames -= 1;
+                    take_frames += 1;
+                    unflushed_frames += 1;
+                }
+                for frame in converted.chunks(track_frame.len()) {
+                    if schedule.skip_frames > 0 {
+                        schedule.skip_frames -= 1;
+                        continue;
                     }
-                    //Looks for signal to terminate thread.
-                    match term_rx.try_recv() {
-                        Ok(_) => {
-                            println!("Write thread killed!");
-                            break;
+                    //the transport went back to the cycle start
+                    if let Some(cycle) = schedule.cycle.as_mut() {
+                        if take_frames == cycle.split_at {
+                            if let Err(e) = next_pass::<T>(cycle, writer, &mut passes) {
+                                result = Err(e);
+                                break 'write;
+                            }
+                            take_frames = 0;
+                            cycle.split_at = cycle.pass_frames;
+                            //the take of the last pass is finalized, so is its waveform
+                            peaks::save_peaks(&live_take.take.file, &live_take.waveform);
+                            let take = passes[passes.len() - 1].clone();
+                            path = take.file.clone();
+                            sync_file = OpenOptions::new().write(true).open(&path);
+                            *live_take = LiveTake {
+                                take: take,
+                                waveform: Waveform::new(writer.get_sample_rate()),
+                            };
                         }
-                        Err(_) => continue,
                     }
+                    //e.g. disk full, keep what was written so far
+                    if let Err(e) = writer.write_frame(frame) {
+                        result = Err(e);
+                        break 'write;
+                    }
+                    live_take.waveform.push_frame(frame);
+                    take_frames += 1;
+                    unflushed_frames += 1;
+                }
+                drop(live_take);
+
+                if unflushed_frames >= flush_frames {
+                    unflushed_frames = 0;
+                    if let Err(e) = writer.flush() {
+                        result = Err(e);
+                        break 'write;
+                    }
+                    if let Ok(file) = &sync_file {
+                        if let Err(e) = file.sync_data() {
+                            eprintln!("write_thread: could not sync {}: {}", path, e);
+                        }
+                    }
+                }
+
+                if last_block {
+                    println!("Write thread killed!");
+                    break;
+                }
+                if nof_frames == 0 {
+                    thread::sleep(Duration::from_millis(1));
                 }
             }
         }
-    });
+        if let Some(writer) = guard.take() {
+            if let Err(e) = writer.finalize() {
+                return (passes, Err(e));
+            }
+            let live_take = live.lock().unwrap();
+            peaks::save_peaks(&live_take.take.file, &live_take.waveform);
+        }
+        (passes, result)
+    })
+}
+
+//Starts the take of the next cycle pass in writer and finalizes the one before. The new take
+//is added to passes as soon as its file exists.
+fn next_pass<T: cpal::Sample + hound::Sample>(
+    cycle: &mut CycleSplit,
+    writer: &mut TakeWriter,
+    passes: &mut Vec<Take>,
+) -> Result<()> {
+    let take = Take {
+        file: cycle.namer.next(),
+        start_frame: cycle.start_frame,
+        name: String::new(),
+    };
+    let bext = BextInfo::new(
+        format!("{} cycle pass {}", cycle.track_name, passes.len() + 2),
+        cycle.timecode_origin + cycle.start_frame,
+    );
+    let next = TakeWriter::create::<T>(
+        &take.file,
+        writer.get_format(),
+        writer.get_sample_rate(),
+        writer.get_nof_channels(),
+        &bext,
+    )?;
+    passes.push(take);
+    std::mem::replace(writer, next).finalize()
 }
 
 fn playback_thread<T: 'static + cpal::Sample + hound::Sample + Send + Sync>(
-    mut reader: WavReader<BufReader<File>>,
-    playback_tx: Sender<(u8, T)>,
+    source: PlaybackSource,
+    start: Option<PlaybackStart<T>>,
+    mut playback_tx: FrameSender<T>,
     term_rx: Receiver<()>,
     out_channels: Vec<u8>,
+    out_map: ChannelMap,
+    timing: PlaybackTiming,
 ) {
     println!("Playback Thread spawned!");
-    thread::spawn(move || loop {
-        //hound reads first sample as R, cpal expects L
-        // playback_tx.send((cpal::Sample::from(&0.0)));
-        let mut ch_idx = 0;
+    thread::spawn(move || {
+        let nof_channels = source.nof_channels;
+        let silence = vec![cpal::Sample::from(&0.0f32); PLAYBACK_BLOCK_FRAMES * nof_channels];
+        let mut out_frame = vec![cpal::Sample::from(&0.0f32); out_channels.len()];
+        let mut out_block = Vec::<T>::with_capacity(PLAYBACK_BLOCK_FRAMES * out_channels.len());
 
-        for sample in reader.samples() {
-            playback_tx.send((out_channels[ch_idx], sample.unwrap()));
-            ch_idx += 1;
-            if ch_idx >= out_channels.len() {
-                ch_idx = 0;
+        //blocks until the whole block is queued, false when playback should end
+        let mut send = |block: &[T]| -> bool {
+            out_block.clear();
+            for frame in block.chunks(nof_channels) {
+                map_frame(frame, &out_map, &out_channels, &mut out_frame);
+                out_block.extend_from_slice(&out_frame);
             }
+            let mut queued = &out_block[..];
+            loop {
+                //receiver is gone (monitor stopped or bounce finished)
+                if !playback_tx.is_connected() || term_rx.try_recv().is_ok() {
+                    return false;
+                }
+                let fits = std::cmp::min(queued.len() / out_frame.len(), playback_tx.free_frames());
+                let nof_pushed = playback_tx.push_frames(&queued[..fits * out_frame.len()]);
+                queued = &queued[nof_pushed * out_frame.len()..];
+                if queued.is_empty() {
+                    return true;
+                }
+                thread::sleep(Duration::from_millis(1));
+            }
+        };
+
+        if !send_silence(timing.delay, &silence, nof_channels, &mut send) {
+            return;
+        }
 
-            match term_rx.try_recv() {
-                Ok(_) => break,
-                Err(_) => continue,
+        let (mut start_frame, mut start) = (timing.start_frame, start);
+        loop {
+            //output frames to the end of the cycle, the pass is cut or filled up to it
+            let pass_frames = timing.cycle.as_ref().map(|c| {
+                let frames = c.end.saturating_sub(start_frame);
+                convert_frames(frames, source.sample_rate, timing.out_rate)
+            });
+            let mut nof_sent = 0;
+            if let Some(start) = start.take() {
+                nof_sent = match play_pass(start, pass_frames, &mut send) {
+                    Some(n) => n,
+                    None => return,
+                };
+            }
+            let cycle = match &timing.cycle {
+                Some(c) => c,
+                None => break,
+            };
+            let nof_fill = pass_frames.unwrap_or(0).saturating_sub(nof_sent);
+            if !send_silence(nof_fill, &silence, nof_channels, &mut send) {
+                return;
             }
+
+            start_frame = cycle.start;
+            start = match source.open::<T>(start_frame, timing.out_rate) {
+                Ok(s) => s,
+                Err(e) => {
+                    eprintln!("playback_thread: {}", e);
+                    return;
+                }
+            };
         }
+        println!("Playback Thread finished!");
     });
 }
 
+//Sends the frames of start, at most nof_frames if given. Returns the number of frames sent,
+//None when playback should end.
+fn play_pass<T: cpal::Sample>(
+    start: PlaybackStart<T>,
+    nof_frames: Option<u64>,
+    send: &mut impl FnMut(&[T]) -> bool,
+) -> Option<u64> {
+    let PlaybackStart {
+        mut reader,
+        pre_roll,
+        mut resampler,
+    } = start;
+    let nof_frames = nof_frames.unwrap_or(u64::MAX);
+    let nof_channels = reader.get_nof_channels() as usize;
+    let silence = vec![cpal::Sample::from(&0.0f32); PLAYBACK_BLOCK_FRAMES * nof_channels];
+    let mut frame = Vec::<T>::with_capacity(nof_channels);
+    let mut block = Vec::<T>::with_capacity(PLAYBACK_BLOCK_FRAMES * nof_channels);
+    let mut converted = Vec::<T>::new();
+
+    let mut nof_sent = std::cmp::min(pre_roll, nof_frames);
+    if !send_silence(nof_sent, &silence, nof_channels, send) {
+        return None;
+    }
+
+    let mut finished = false;
+    while !finished && nof_sent < nof_frames {
+        block.clear();
+        while block.len() < PLAYBACK_BLOCK_FRAMES * nof_channels {
+            if let Ok(true) = reader.read_frame::<T>(&mut frame) {
+                block.extend_from_slice(&frame);
+            } else {
+                finished = true;
+                break;
+            }
+        }
+        converted.clear();
+        let mut converting = resampler.process(&block, &mut converted);
+        if finished {
+            converting = converting.and_then(|_| resampler.flush(&mut converted));
+        }
+        if let Err(e) = converting {
+            eprintln!("playback_thread: {}", e);
+            return None;
+        }
+
+        let nof_converted = (converted.len() / nof_channels) as u64;
+        let nof_block = std::cmp::min(nof_converted, nof_frames - nof_sent);
+        if !send(&converted[..nof_block as usize * nof_channels]) {
+            return None;
+        }
+        nof_sent += nof_block;
+    }
+    Some(nof_sent)
+}
+
+//Sends nof_frames of silence in blocks of at most silence, false when playback should end
+fn send_silence<T>(
+    nof_frames: u64,
+    silence: &[T],
+    nof_channels: usize,
+    send: &mut impl FnMut(&[T]) -> bool,
+) -> bool {
+    let block_frames = (silence.len() / nof_channels) as u64;
+    let mut remaining = nof_frames;
+    while remaining > 0 {
+        let nof_block = std::cmp::min(remaining, block_frames);
+        if !send(&silence[..nof_block as usize * nof_channels]) {
+            return false;
+        }
+        remaining -= nof_block;
+    }
+    true
+}
+
 fn monitor_thread<T: 'static + cpal::Sample + Send + Sync>(
-    thread_rx: Receiver<BroadcastReceiver<(u8, T)>>,
-    monitor_tx: Sender<(u8, T)>,
+    mut bus_rx: FrameReceiver<T>,
+    mut monitor_tx: FrameSender<T>,
     term_rx: Receiver<()>,
+    in_map: ChannelMap,
+    mut resampler: FrameResampler<T>,
+    out_channels: Vec<u8>,
+    out_map: ChannelMap,
 ) {
     println!("Monitor Thread spawned!");
     thread::spawn(move || {
-        let bus_rx: BroadcastReceiver<(u8, T)> = thread_rx.recv().unwrap();
-        println!("Received");
+        let nof_channels = bus_rx.get_nof_channels();
+        let mut buffer = vec![cpal::Sample::from(&0.0f32); MONITOR_BLOCK_FRAMES * nof_channels];
+        let track_channels: Vec<u8> = in_map.iter().map(|x| x.1).collect();
+        let mut track_frame = vec![cpal::Sample::from(&0.0f32); track_channels.len()];
+        let mut track_block = Vec::<T>::with_capacity(MONITOR_BLOCK_FRAMES * track_frame.len());
+        let mut converted = Vec::<T>::new();
+        let mut out_frame = vec![cpal::Sample::from(&0.0f32); out_channels.len()];
         loop {
-            let tup = match bus_rx.try_recv() {
-                Ok(t) => t,
-                Err(_) => continue,
-            };
-            monitor_tx.send(tup);
-            match term_rx.try_recv() {
-                Ok(_) => {
-                    println!("Monitor Thread killed!");
-                    break;
-                }
-                Err(_) => continue,
+            let nof_frames = bus_rx.pop_frames(&mut buffer);
+            track_block.clear();
+            for frame in buffer[..nof_frames * nof_channels].chunks(nof_channels) {
+                map_frame(frame, &in_map, &track_channels, &mut track_frame);
+                track_block.extend_from_slice(&track_frame);
+            }
+            converted.clear();
+            if let Err(e) = resampler.process(&track_block, &mut converted) {
+                eprintln!("monitor_thread: {}", e);
+                break;
+            }
+            for frame in converted.chunks(track_frame.len()) {
+                map_frame(frame, &out_map, &out_channels, &mut out_frame);
+                monitor_tx.push_frames(&out_frame);
+            }
+            if nof_frames == 0 {
+                thread::sleep(Duration::from_micros(500));
+            }
+            if term_rx.try_recv().is_ok() || !monitor_tx.is_connected() {
+                println!("Monitor Thread killed!");
+                break;
             }
         }
     });
 }
 
-pub type WavWriterHandle = Arc<Mutex<Option<WavWriter<BufWriter<File>>>>>;
-
-pub fn wav_spec_from_config(config: &StreamConfig, sample_f: &SampleFormat) -> WavSpec {
-    WavSpec {
-        channels: config.channels as _,
-        sample_rate: config.sample_rate.0 as _,
-        bits_per_sample: (sample_f.sample_size() * 8) as _,
-        sample_format: sample_format(*sample_f),
-    }
-}
-
-pub fn sample_format(format: cpal::SampleFormat) -> hound::SampleFormat {
-    match format {
-        cpal::SampleFormat::U16 => hound::SampleFormat::Int,
-        cpal::SampleFormat::I16 => hound::SampleFormat::Int,
-        cpal::SampleFormat::F32 => hound::SampleFormat::Float,
-    }
-}
+pub type WavWriterHandle = Arc<Mutex<Option<TakeWriter>>>;
+pub type LiveTakeHandle = Arc<Mutex<LiveTake>>;
+//the takes of later cycle passes and how writing ended
+type WriteThreadHandle = JoinHandle<(Vec<Take>, Result<()>)>;
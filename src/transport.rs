@@ -0,0 +1,180 @@
+use std::ops::Range;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TransportState {
+    Stopped,
+    Playing,
+    Recording,
+    Paused,
+}
+
+pub struct Transport {
+    state: TransportState,
+    playhead: Arc<AtomicU64>, //position in frames, advanced by the mix thread while rolling
+    start_position: u64,      //where the last play/record started, stop returns here
+    cycle: Option<Range<u64>>, //locators the playhead loops between while rolling
+}
+
+impl Transport {
+    pub fn new() -> Transport {
+        Transport {
+            state: TransportState::Stopped,
+            playhead: Arc::new(AtomicU64::new(0)),
+            start_position: 0,
+            cycle: None,
+        }
+    }
+
+    pub fn play(&mut self) {
+        self.start(TransportState::Playing);
+    }
+
+    pub fn record(&mut self) {
+        self.start(TransportState::Recording);
+    }
+
+    pub fn pause(&mut self) {
+        if self.is_rolling() {
+            self.state = TransportState::Paused;
+        }
+    }
+
+    pub fn stop(&mut self) {
+        self.state = TransportState::Stopped;
+        self.playhead.store(self.start_position, Ordering::SeqCst);
+    }
+
+    pub fn locate(&mut self, frame: u64) {
+        self.start_position = frame;
+        self.playhead.store(frame, Ordering::SeqCst);
+    }
+
+    //Moves the playhead of a transport that just started back to frame for a pre-roll,
+    //stop still returns to where it started
+    pub fn roll_from(&mut self, frame: u64) {
+        self.playhead.store(frame, Ordering::SeqCst);
+    }
+
+    //None turns cycling off, a cycle takes effect the next time the transport starts
+    pub fn set_cycle(&mut self, cycle: Option<Range<u64>>) {
+        self.cycle = cycle;
+    }
+
+    pub fn get_cycle(&self) -> Option<Range<u64>> {
+        self.cycle.clone()
+    }
+
+    pub fn get_state(&self) -> TransportState {
+        self.state
+    }
+
+    pub fn is_rolling(&self) -> bool {
+        self.state == TransportState::Playing || self.state == TransportState::Recording
+    }
+
+    pub fn get_position(&self) -> u64 {
+        self.playhead.load(Ordering::SeqCst)
+    }
+
+    pub fn get_playhead(&self) -> Arc<AtomicU64> {
+        self.playhead.clone()
+    }
+
+    //A transport that starts outside the cycle starts at the cycle start
+    fn start(&mut self, state: TransportState) {
+        if let Some(cycle) = &self.cycle {
+            if !cycle.contains(&self.get_position()) {
+                self.playhead.store(cycle.start, Ordering::SeqCst);
+            }
+        }
+        if self.state != TransportState::Paused {
+            self.start_position = self.get_position();
+        }
+        self.state = state;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //the mix thread advancing the playhead
+    fn roll(transport: &Transport, nof_frames: u64) {
+        transport
+            .get_playhead()
+            .fetch_add(nof_frames, Ordering::SeqCst);
+    }
+
+    #[test]
+    fn pauses_only_while_rolling() {
+        let mut transport = Transport::new();
+        transport.pause();
+        assert_eq!(transport.get_state(), TransportState::Stopped);
+        transport.record();
+        assert_eq!(transport.get_state(), TransportState::Recording);
+        assert!(transport.is_rolling());
+        transport.pause();
+        assert_eq!(transport.get_state(), TransportState::Paused);
+        assert!(!transport.is_rolling());
+        transport.play();
+        assert_eq!(transport.get_state(), TransportState::Playing);
+        transport.stop();
+        assert_eq!(transport.get_state(), TransportState::Stopped);
+    }
+
+    #[test]
+    fn stops_where_it_started() {
+        let mut transport = Transport::new();
+        transport.locate(1_000);
+        transport.play();
+        roll(&transport, 500);
+        transport.pause();
+        assert_eq!(transport.get_position(), 1_500);
+        //resuming keeps the start of the first play
+        transport.play();
+        roll(&transport, 500);
+        transport.stop();
+        assert_eq!(transport.get_position(), 1_000);
+    }
+
+    #[test]
+    fn locate_moves_the_start() {
+        let mut transport = Transport::new();
+        transport.play();
+        roll(&transport, 500);
+        transport.locate(2_000);
+        assert_eq!(transport.get_position(), 2_000);
+        roll(&transport, 500);
+        transport.stop();
+        assert_eq!(transport.get_position(), 2_000);
+    }
+
+    #[test]
+    fn pre_roll_stops_where_it_started() {
+        let mut transport = Transport::new();
+        transport.locate(48_000);
+        transport.record();
+        transport.roll_from(24_000);
+        assert_eq!(transport.get_position(), 24_000);
+        roll(&transport, 48_000);
+        transport.stop();
+        assert_eq!(transport.get_position(), 48_000);
+    }
+
+    #[test]
+    fn starts_outside_the_cycle_at_its_start() {
+        let mut transport = Transport::new();
+        transport.set_cycle(Some(1_000..2_000));
+        transport.locate(1_500);
+        transport.play();
+        assert_eq!(transport.get_position(), 1_500);
+        transport.stop();
+        transport.locate(3_000);
+        transport.play();
+        assert_eq!(transport.get_position(), 1_000);
+        transport.stop();
+        assert_eq!(transport.get_position(), 1_000);
+    }
+}
@@ -1,9 +1,10 @@
 use cpal::traits::{DeviceTrait, HostTrait};
 use cpal::{
-    available_hosts, host_from_id, Device, Host, HostId, SampleFormat, StreamConfig,
-    SupportedInputConfigs, SupportedOutputConfigs,
+    available_hosts, host_from_id, Device, Host, HostId, SupportedInputConfigs,
+    SupportedOutputConfigs,
 };
-use multiqueue;
+
+use crate::error::{RecorderError, Result};
 
 pub fn get_host_ids() -> Vec<HostId> {
     let mut host_ids = Vec::<HostId>::new();
@@ -13,73 +14,44 @@ pub fn get_host_ids() -> Vec<HostId> {
     host_ids
 }
 
-pub fn get_host_devices(host_id: HostId) -> (Vec<Device>, Vec<Device>) {
-    let host = match host_from_id(host_id) {
-        Ok(h) => h,
-        Err(e) => panic!("utils::get_host_devices(): Oh no! {}", e),
-    };
+pub fn get_host_devices(host_id: HostId) -> Result<(Vec<Device>, Vec<Device>)> {
+    let host = host_from_id(host_id)?;
     let mut in_devices: Vec<Device> = Vec::<Device>::new();
     let mut out_devices: Vec<Device> = Vec::<Device>::new();
 
-    for input in host.input_devices().unwrap() {
+    for input in host.input_devices()? {
         in_devices.push(input);
     }
-    for out in host.output_devices().unwrap() {
+    for out in host.output_devices()? {
         out_devices.push(out);
     }
-    (in_devices, out_devices)
+    Ok((in_devices, out_devices))
 }
 
 pub fn get_supported_configs(
     host: &Host,
     input: &String,
     output: &String,
-) -> (SupportedInputConfigs, SupportedOutputConfigs) {
+) -> Result<(SupportedInputConfigs, SupportedOutputConfigs)> {
     let (input, output) = (
-        get_input_device_by_name(host, input),
-        get_output_device_by_name(host, output),
+        get_input_device_by_name(host, input)?,
+        get_output_device_by_name(host, output)?,
     );
-    let in_configs = match input.supported_input_configs() {
-        Ok(iter) => iter,
-        Err(e) => panic!("utils::get_supported_configs().in_configs: Oh no! {}", e),
-    };
-    let out_configs = match output.supported_output_configs() {
-        Ok(iter) => iter,
-        Err(e) => panic!("utils::get_supported_configs().out_configs: Oh no! {}", e),
-    };
-    (in_configs, out_configs)
+    let in_configs = input.supported_input_configs()?;
+    let out_configs = output.supported_output_configs()?;
+    Ok((in_configs, out_configs))
 }
 
-pub fn get_input_device_by_name(host: &Host, device_name: &String) -> Device {
-    let mut devices = match host.input_devices() {
-        Ok(d) => d,
-        Err(e) => panic!("Input devices Not Found: {}", e),
-    };
+pub fn get_input_device_by_name(host: &Host, device_name: &String) -> Result<Device> {
+    let mut devices = host.input_devices()?;
     devices
         .find(|x| x.name().map(|y| y == *device_name).unwrap_or(false))
-        .unwrap()
+        .ok_or(RecorderError::DeviceNotFound(device_name.clone()))
 }
 
-pub fn get_output_device_by_name(host: &Host, device_name: &String) -> Device {
-    let mut devices = match host.output_devices() {
-        Ok(d) => d,
-        Err(e) => panic!("Output devices Not Found: {}", e),
-    };
+pub fn get_output_device_by_name(host: &Host, device_name: &String) -> Result<Device> {
+    let mut devices = host.output_devices()?;
     devices
         .find(|x| x.name().map(|y| y == *device_name).unwrap_or(false))
-        .unwrap()
-}
-
-pub fn get_flushed_broadcast_queue<T: 'static + cpal::Sample + hound::Sample + Send + Sync>(
-    queue: multiqueue::BroadcastReceiver<(u8, T)>,
-) -> multiqueue::BroadcastReceiver<(u8, T)> {
-    while queue.try_recv().is_ok() {}
-    return queue;
-}
-
-pub fn get_flushed_mpsc_queue<T: 'static + cpal::Sample + Send + Sync>(
-    queue: &std::sync::mpsc::Receiver<(u8, T)>,
-) -> &std::sync::mpsc::Receiver<(u8, T)> {
-    while queue.try_recv().is_ok() {}
-    return queue;
+        .ok_or(RecorderError::DeviceNotFound(device_name.clone()))
 }
@@ -0,0 +1,489 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use cpal::StreamConfig;
+
+use crate::backend::{AudioBackend, BusStream, InputCallback, OutputCallback};
+use crate::error::Result;
+use crate::formats::TakeReader;
+
+// In-process device for the tests: input streams read from an in-memory buffer (silence
+// once it runs out), output streams append everything they are asked to play into a capture
+// buffer. As a loopback device the input streams hear what the first output stream played.
+pub struct VirtualBackend<T: 'static + cpal::Sample + Send + Sync> {
+    input: Arc<Vec<T>>, //interleaved, laid out for the input StreamConfig
+    captures: Arc<Mutex<Vec<Capture<T>>>>, //one capture buffer per output stream
+    loopback: Option<Arc<Mutex<Loopback<T>>>>,
+    buffer_frames: usize,
+}
+
+type Capture<T> = Arc<Mutex<Vec<T>>>;
+
+//The cable of a loopback device. What the first output stream plays reaches the input
+//streams delay_frames later, in the same callback, so they never drift apart.
+struct Loopback<T> {
+    frames: Vec<T>, //interleaved, played and not heard yet, delay_frames of silence at first
+    nof_channels: usize, //of the output, 0 until its stream is built
+    delay_frames: usize,
+    inputs: Vec<LoopbackInput<T>>,
+}
+
+struct LoopbackInput<T> {
+    nof_channels: usize,
+    playing: Arc<AtomicBool>,
+    alive: Arc<AtomicBool>,
+    data_clb: InputCallback<T>,
+}
+
+impl<T: cpal::Sample> Loopback<T> {
+    //Plays buffer into the cable, the input streams hear as many frames of what was played
+    //before, input channels from the output channel of the same number or the last one
+    fn play(&mut self, buffer: &[T]) {
+        self.frames.extend_from_slice(buffer);
+        let heard: Vec<T> = self.frames.drain(..buffer.len()).collect();
+        let nof_frames = buffer.len() / self.nof_channels;
+        let out_channels = self.nof_channels;
+
+        self.inputs.retain(|i| i.alive.load(Ordering::SeqCst));
+        for input in self.inputs.iter_mut() {
+            if !input.playing.load(Ordering::SeqCst) {
+                continue;
+            }
+            let mut in_buffer = vec![cpal::Sample::from(&0.0f32); nof_frames * input.nof_channels];
+            for (frame, played) in in_buffer
+                .chunks_mut(input.nof_channels)
+                .zip(heard.chunks(out_channels))
+            {
+                for (ch, sample) in frame.iter_mut().enumerate() {
+                    *sample = played[std::cmp::min(ch, out_channels - 1)];
+                }
+            }
+            (input.data_clb)(&in_buffer);
+        }
+    }
+}
+
+impl<T: 'static + cpal::Sample + Send + Sync> VirtualBackend<T> {
+    pub fn new(input: Vec<T>) -> VirtualBackend<T> {
+        VirtualBackend {
+            input: Arc::new(input),
+            captures: Arc::new(Mutex::new(Vec::new())),
+            loopback: None,
+            buffer_frames: 512,
+        }
+    }
+
+    //Input read from the audio file at path (any take format), it must have the channels
+    //and the rate of the input StreamConfig
+    pub fn from_file(path: &str) -> Result<VirtualBackend<T>> {
+        let mut reader = TakeReader::open(path)?;
+        let nof_channels = reader.get_nof_channels() as usize;
+        let mut input = Vec::<T>::with_capacity(reader.duration() as usize * nof_channels);
+        let mut frame = Vec::<T>::with_capacity(nof_channels);
+        while reader.read_frame(&mut frame)? {
+            input.extend_from_slice(&frame);
+        }
+        Ok(VirtualBackend::new(input))
+    }
+
+    pub fn silent() -> VirtualBackend<T> {
+        VirtualBackend::new(Vec::<T>::new())
+    }
+
+    //Device with a loopback cable from the first output stream to every input stream, what
+    //is played comes back delay_frames later. Inputs and output must run at the same rate.
+    pub fn loopback(delay_frames: usize) -> VirtualBackend<T> {
+        let mut backend = VirtualBackend::silent();
+        backend.loopback = Some(Arc::new(Mutex::new(Loopback {
+            frames: Vec::<T>::new(),
+            nof_channels: 0,
+            delay_frames: delay_frames,
+            inputs: Vec::<LoopbackInput<T>>::new(),
+        })));
+        backend
+    }
+
+    pub fn buffer_frames(mut self, frames: usize) -> VirtualBackend<T> {
+        self.buffer_frames = frames;
+        self
+    }
+
+    pub fn get_captured(&self, out_stream_idx: usize) -> Vec<T> {
+        let captures = self.captures.lock().unwrap();
+        match captures.get(out_stream_idx) {
+            Some(c) => c.lock().unwrap().clone(),
+            None => Vec::<T>::new(),
+        }
+    }
+
+    pub fn nof_captures(&self) -> usize {
+        self.captures.lock().unwrap().len()
+    }
+
+    //callbacks are paced at the stream's sample rate
+    fn period(&self, config: &StreamConfig) -> Duration {
+        let secs = self.buffer_frames as f64 / config.sample_rate.0 as f64;
+        Duration::from_secs_f64(secs)
+    }
+}
+
+//Clones share the input and the capture buffers, so a clone can inspect what a router played.
+impl<T: 'static + cpal::Sample + Send + Sync> Clone for VirtualBackend<T> {
+    fn clone(&self) -> Self {
+        VirtualBackend {
+            input: self.input.clone(),
+            captures: self.captures.clone(),
+            loopback: self.loopback.clone(),
+            buffer_frames: self.buffer_frames,
+        }
+    }
+}
+
+impl<T: 'static + cpal::Sample + Send + Sync> AudioBackend<T> for VirtualBackend<T> {
+    fn build_input_stream(
+        &self,
+        config: &StreamConfig,
+        mut data_clb: InputCallback<T>,
+    ) -> Result<Box<dyn BusStream>> {
+        //driven by the output stream
+        if let Some(l) = &self.loopback {
+            let (playing, alive) = (
+                Arc::new(AtomicBool::new(false)),
+                Arc::new(AtomicBool::new(true)),
+            );
+            l.lock().unwrap().inputs.push(LoopbackInput {
+                nof_channels: config.channels as usize,
+                playing: playing.clone(),
+                alive: alive.clone(),
+                data_clb: data_clb,
+            });
+            return Ok(Box::new(VirtualStream {
+                playing: playing,
+                alive: alive,
+            }));
+        }
+
+        let input = self.input.clone();
+        let buf_len = self.buffer_frames * config.channels as usize;
+        let mut pos = 0;
+
+        Ok(virtual_stream(self.period(config), move || {
+            let mut buffer = vec![cpal::Sample::from(&0.0f32); buf_len];
+            for sample in buffer.iter_mut() {
+                if pos < input.len() {
+                    *sample = input[pos];
+                    pos += 1;
+                }
+            }
+            data_clb(&buffer);
+        }))
+    }
+
+    fn build_output_stream(
+        &self,
+        config: &StreamConfig,
+        mut data_clb: OutputCallback<T>,
+    ) -> Result<Box<dyn BusStream>> {
+        let capture = Arc::new(Mutex::new(Vec::<T>::new()));
+        let mut captures = self.captures.lock().unwrap();
+        let loopback = match (&self.loopback, captures.is_empty()) {
+            (Some(l), true) => {
+                let mut looped = l.lock().unwrap();
+                looped.nof_channels = config.channels as usize;
+                looped.frames =
+                    vec![cpal::Sample::from(&0.0f32); looped.delay_frames * looped.nof_channels];
+                Some(l.clone())
+            }
+            _ => None,
+        };
+        captures.push(capture.clone());
+        let buf_len = self.buffer_frames * config.channels as usize;
+
+        Ok(virtual_stream(self.period(config), move || {
+            let mut buffer = vec![cpal::Sample::from(&0.0f32); buf_len];
+            data_clb(&mut buffer);
+            capture.lock().unwrap().extend_from_slice(&buffer);
+            if let Some(l) = &loopback {
+                l.lock().unwrap().play(&buffer);
+            }
+        }))
+    }
+
+    fn name(&self) -> String {
+        "Virtual".to_string()
+    }
+}
+
+pub struct VirtualStream {
+    playing: Arc<AtomicBool>,
+    alive: Arc<AtomicBool>,
+}
+
+impl BusStream for VirtualStream {
+    fn play(&self) -> Result<()> {
+        self.playing.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn pause(&self) -> Result<()> {
+        self.playing.store(false, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+impl Drop for VirtualStream {
+    fn drop(&mut self) {
+        self.alive.store(false, Ordering::SeqCst);
+    }
+}
+
+fn virtual_stream<F: FnMut() + Send + 'static>(
+    period: Duration,
+    mut tick: F,
+) -> Box<dyn BusStream> {
+    let playing = Arc::new(AtomicBool::new(false));
+    let alive = Arc::new(AtomicBool::new(true));
+    let (playing_ref, alive_ref) = (playing.clone(), alive.clone());
+
+    thread::spawn(move || {
+        while alive_ref.load(Ordering::SeqCst) {
+            if !playing_ref.load(Ordering::SeqCst) {
+                thread::sleep(Duration::from_millis(1));
+                continue;
+            }
+            tick();
+            thread::sleep(period);
+        }
+    });
+
+    Box::new(VirtualStream {
+        playing: playing,
+        alive: alive,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::formats::RecordFormat;
+    use crate::mixer::PanLaw;
+    use crate::router::Router;
+    use cpal::{BufferSize, SampleFormat, SampleRate};
+    use hound::WavReader;
+    use std::path::{Path, PathBuf};
+    use std::time::Instant;
+
+    const RATE: u32 = 48_000;
+    const RAMP_FRAMES: usize = 48_000;
+    const STEP: f32 = 1.0 / 1_048_576.0; //2^-20, every ramp value is exact in f32
+
+    fn config(channels: u16) -> StreamConfig {
+        StreamConfig {
+            channels: channels,
+            sample_rate: SampleRate(RATE),
+            buffer_size: BufferSize::Default,
+        }
+    }
+
+    //stereo frames that say where they are: frame i is ((i + 1) * STEP, -(i + 1) * STEP)
+    fn ramp() -> Vec<f32> {
+        let mut samples = Vec::<f32>::with_capacity(RAMP_FRAMES * 2);
+        for i in 0..RAMP_FRAMES {
+            samples.push((i + 1) as f32 * STEP);
+            samples.push(-((i + 1) as f32) * STEP);
+        }
+        samples
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("example2-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn router(backend: &VirtualBackend<f32>) -> Router<f32> {
+        let mut rout = Router::with_backend(
+            Box::new(backend.clone()),
+            config(2),
+            config(2),
+            SampleFormat::F32,
+        );
+        rout.new_input_bus(vec![1, 2]).unwrap();
+        rout.new_output_bus(vec![1, 2]).unwrap();
+        rout
+    }
+
+    //Checks that samples hold a run of the ramp that goes on to its last frame, followed
+    //by silence only. Returns the number of ramp frames.
+    fn check_ramp_run(samples: &[f32]) -> usize {
+        let first = samples
+            .chunks(2)
+            .position(|f| f[0] != 0.0)
+            .expect("no ramp frames");
+        let start = (samples[first * 2] / STEP) as usize - 1;
+        let nof_ramp = RAMP_FRAMES - start;
+        for (idx, frame) in samples.chunks(2).skip(first).enumerate() {
+            let expected = match idx < nof_ramp {
+                true => (start + idx + 1) as f32 * STEP,
+                false => 0.0,
+            };
+            assert_eq!(frame, &[expected, -expected][..], "frame {}", first + idx);
+        }
+        nof_ramp
+    }
+
+    //Polls what the first output stream played until done says it is there, fails after
+    //ten seconds
+    fn wait_for_output(backend: &VirtualBackend<f32>, done: impl Fn(&[f32]) -> bool) -> Vec<f32> {
+        let started = Instant::now();
+        loop {
+            let captured = backend.get_captured(0);
+            if done(&captured) {
+                return captured;
+            }
+            assert!(
+                started.elapsed() < Duration::from_secs(10),
+                "the output did not come out"
+            );
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn records_the_input_into_a_take() {
+        let dir = temp_dir("record");
+        let backend = VirtualBackend::from_file(&ramp_file(&dir))
+            .unwrap()
+            .buffer_frames(256);
+        let mut rout = router(&backend);
+        rout.set_record_format(RecordFormat::Float32);
+        rout.set_latency(0);
+        let name = dir.join("rec").to_str().unwrap().to_string();
+        let track_id = rout.new_track(name, 0, 0).unwrap();
+        rout.set_recording(track_id, true).unwrap();
+
+        let started = Instant::now();
+        rout.record().unwrap();
+        thread::sleep(Duration::from_millis(1500));
+        rout.stop().unwrap();
+        let elapsed = started.elapsed().as_secs_f64();
+
+        let take = rout.get_track(track_id).unwrap().get_files()[0].clone();
+        assert_eq!(take.start_frame, 0);
+        let mut reader = WavReader::open(&take.file).unwrap();
+        assert_eq!(reader.spec().channels, 2);
+        assert_eq!(reader.spec().sample_rate, RATE);
+        let samples: Vec<f32> = reader.samples::<f32>().map(|s| s.unwrap()).collect();
+        let nof_frames = samples.len() / 2;
+        assert_eq!(reader.duration() as usize, nof_frames);
+        //the device is paced at most as fast as real time and a take holds no gaps
+        assert!(nof_frames as f64 <= elapsed * RATE as f64);
+        assert!(nof_frames as f64 >= 0.5 * elapsed * RATE as f64);
+        //the input ran for a moment before the take started, the rest of it is in there
+        assert!(check_ramp_run(&samples) > RAMP_FRAMES / 2);
+        std::fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn monitors_the_input_on_the_output() {
+        let backend = VirtualBackend::new(ramp()).buffer_frames(256);
+        let mut rout = router(&backend);
+        rout.set_pan_law(PanLaw::ZeroDb); //unity gain on both sides of a centred track
+        let track_id = rout.new_track("monitor".to_string(), 0, 0).unwrap();
+        rout.set_monitor(track_id, true).unwrap();
+        rout.monitor().unwrap();
+        //the output runs dry until the monitor starts, then it is fed without a break
+        wait_for_output(&backend, |c| c.iter().any(|s| *s != 0.0));
+        let underruns = rout.get_xruns().1;
+        //the last ramp frame and a few blocks after it
+        let last = RAMP_FRAMES as f32 * STEP;
+        let captured = wait_for_output(&backend, |c| {
+            c.chunks(2)
+                .position(|f| f[0] == last)
+                .is_some_and(|end| c.len() / 2 > end + 1_024)
+        });
+        rout.stop_monitor();
+
+        assert_eq!(backend.nof_captures(), 1);
+        //every input frame comes out, one after the other
+        assert!(check_ramp_run(&captured) > RAMP_FRAMES / 2);
+        assert_eq!(rout.get_xruns(), (0, underruns));
+    }
+
+    //the ramp as a float WAV file in dir
+    fn ramp_file(dir: &Path) -> String {
+        let path = dir.join("ramp.wav").to_str().unwrap().to_string();
+        let spec = hound::WavSpec {
+            channels: 2,
+            sample_rate: RATE,
+            bits_per_sample: 32,
+            sample_format: hound::SampleFormat::Float,
+        };
+        let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+        for sample in ramp() {
+            writer.write_sample(sample).unwrap();
+        }
+        writer.finalize().unwrap();
+        path
+    }
+
+    #[test]
+    fn plays_a_take_on_the_output() {
+        let dir = temp_dir("play");
+        let path = ramp_file(&dir);
+
+        let backend = VirtualBackend::silent().buffer_frames(256);
+        let mut rout = router(&backend);
+        rout.set_pan_law(PanLaw::ZeroDb);
+        //the take lands next to the track name, keep it out of the working directory
+        let name = dir.join("play").to_str().unwrap().to_string();
+        let track_id = rout.new_track(name, 0, 0).unwrap();
+        rout.import_file(track_id, &path, 0).unwrap();
+        rout.play().unwrap();
+        thread::sleep(Duration::from_millis(1500));
+        rout.stop().unwrap();
+
+        //the whole take, from its first frame on
+        let captured = backend.get_captured(0);
+        let first = captured.chunks(2).position(|f| f[0] != 0.0).unwrap();
+        assert_eq!(captured[first * 2], STEP);
+        assert_eq!(check_ramp_run(&captured), RAMP_FRAMES);
+        std::fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn records_over_a_take_in_step_with_it() {
+        let dir = temp_dir("overdub");
+        let path = ramp_file(&dir);
+        let delay_frames = 1_000;
+        let backend = VirtualBackend::loopback(delay_frames).buffer_frames(256);
+        let mut rout = router(&backend);
+        rout.set_pan_law(PanLaw::ZeroDb);
+        rout.set_record_format(RecordFormat::Float32);
+        rout.set_latency(delay_frames as u64);
+        let name = dir.join("play").to_str().unwrap().to_string();
+        let play_id = rout.new_track(name, 0, 0).unwrap();
+        rout.import_file(play_id, &path, 0).unwrap();
+        let name = dir.join("rec").to_str().unwrap().to_string();
+        let rec_id = rout.new_track(name, 0, 0).unwrap();
+        rout.set_recording(rec_id, true).unwrap();
+
+        rout.record().unwrap();
+        thread::sleep(Duration::from_millis(1500));
+        rout.stop().unwrap();
+
+        //the loopback hears the take, what was recorded at a timeline frame is what played there
+        let take = rout.get_track(rec_id).unwrap().get_files()[0].clone();
+        assert_eq!(take.start_frame, 0);
+        let mut reader = WavReader::open(&take.file).unwrap();
+        let samples: Vec<f32> = reader.samples::<f32>().map(|s| s.unwrap()).collect();
+        assert!(samples.len() / 2 > RAMP_FRAMES);
+        for (idx, frame) in samples.chunks(2).take(RAMP_FRAMES).enumerate() {
+            let expected = (idx + 1) as f32 * STEP;
+            assert_eq!(frame, &[expected, -expected][..], "frame {}", idx);
+        }
+        std::fs::remove_dir_all(dir).ok();
+    }
+}
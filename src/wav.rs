@@ -0,0 +1,596 @@
+use hound::{SampleFormat, WavSpec};
+
+use std::collections::hash_map::RandomState;
+use std::fs::File;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::error::{RecorderError, Result};
+
+// Broadcast wave (EBU Tech 3285) files with automatic RF64 (EBU Tech 3306) switchover.
+// Header layout of the files written here:
+//   RIFF/RF64 <len> WAVE
+//   JUNK/ds64 <28>  riff len, data len, sample count (u64 each), empty table
+//   bext <602>      version 1, no coding history
+//   fmt  <16>       PCM or IEEE float
+//   data <len>      samples
+// The JUNK chunk is rewritten to ds64 once the file outgrows 4 GB.
+
+pub const ORIGINATOR: &str = "cpal-Recorder";
+
+const DS64_LEN: u32 = 28;
+const BEXT_LEN: u32 = 602;
+#[cfg(not(test))]
+const MAX_RIFF_LEN: u64 = u32::MAX as u64;
+#[cfg(test)]
+const MAX_RIFF_LEN: u64 = 1 << 20; //small enough for the tests to get past it
+const RF64_LEN: u32 = u32::MAX; //length fields of an RF64 file, the real ones are in ds64
+
+// bext metadata of a file.
+#[derive(Clone, Debug)]
+pub struct BextInfo {
+    pub description: String,
+    pub originator: String,
+    pub originator_reference: String,
+    pub origination_date: String, //yyyy-mm-dd
+    pub origination_time: String, //hh:mm:ss
+    pub time_reference: u64,      //first sample of the file in samples since midnight
+    pub umid: [u8; 64],
+}
+
+impl BextInfo {
+    //Metadata of a file created now, date and time are UTC
+    pub fn new(description: String, time_reference: u64) -> BextInfo {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let (date, time) = utc_date_time(now.as_secs());
+        let umid = new_umid(now.as_nanos());
+        let reference: String = umid[16..32].iter().map(|b| format!("{:02X}", b)).collect();
+        BextInfo {
+            description: description,
+            originator: ORIGINATOR.to_string(),
+            originator_reference: reference,
+            origination_date: date,
+            origination_time: time,
+            time_reference: time_reference,
+            umid: umid,
+        }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::<u8>::with_capacity(BEXT_LEN as usize);
+        push_str(&mut bytes, &self.description, 256);
+        push_str(&mut bytes, &self.originator, 32);
+        push_str(&mut bytes, &self.originator_reference, 32);
+        push_str(&mut bytes, &self.origination_date, 10);
+        push_str(&mut bytes, &self.origination_time, 8);
+        bytes.extend(&(self.time_reference as u32).to_le_bytes());
+        bytes.extend(&((self.time_reference >> 32) as u32).to_le_bytes());
+        bytes.extend(&1u16.to_le_bytes()); //version
+        bytes.extend(&self.umid);
+        bytes.extend(&[0u8; 190]); //reserved
+        bytes
+    }
+}
+
+//Samples since midnight (UTC) of the current time, for time references by time of day
+pub fn time_of_day(sample_rate: u32) -> u64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let secs = (now.as_secs() % 86_400) as f64 + now.subsec_nanos() as f64 / 1e9;
+    (secs * sample_rate as f64) as u64
+}
+
+pub struct BwfWriter {
+    writer: BufWriter<File>,
+    spec: WavSpec,
+    bytes_per_sample: u16,
+    data_offset: u64,
+    data_len: u64, //bytes
+    finalized: bool,
+}
+
+impl BwfWriter {
+    pub fn create(path: &str, spec: WavSpec, bext: &BextInfo) -> Result<BwfWriter> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        let header = header_bytes(&spec, bext);
+        writer.write_all(&header)?;
+        Ok(BwfWriter {
+            writer: writer,
+            spec: spec,
+            bytes_per_sample: spec.bits_per_sample.div_ceil(8),
+            data_offset: header.len() as u64,
+            data_len: 0,
+            finalized: false,
+        })
+    }
+
+    pub fn write_sample<S: hound::Sample>(&mut self, sample: S) -> Result<()> {
+        sample.write(&mut self.writer, self.spec.bits_per_sample)?;
+        self.data_len += self.bytes_per_sample as u64;
+        Ok(())
+    }
+
+    //Updates the header lengths and flushes, the file is valid up to here even if
+    //it is never finalized. More samples can be written afterwards.
+    pub fn flush(&mut self) -> Result<()> {
+        let position = self.writer.seek(SeekFrom::Current(0))?;
+        let block_align = self.bytes_per_sample as u64 * self.spec.channels as u64;
+        write_lengths(
+            &mut self.writer,
+            self.data_offset,
+            self.data_len,
+            block_align,
+        )?;
+        self.writer.seek(SeekFrom::Start(position))?;
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    //Writes the pad byte of an odd length data chunk, nothing can be written afterwards
+    pub fn finalize(mut self) -> Result<()> {
+        self.finalized = true;
+        if self.data_len & 1 == 1 {
+            self.writer.write_all(&[0u8])?;
+        }
+        self.flush()
+    }
+}
+
+impl Drop for BwfWriter {
+    fn drop(&mut self) {
+        if !self.finalized {
+            self.flush().ok();
+        }
+    }
+}
+
+// Reads RIFF and RF64 wav files, the takes written here and plain wavs (e.g. by hound).
+pub struct BwfReader {
+    reader: BufReader<File>,
+    layout: WavLayout,
+    bytes_per_sample: u16,
+    remaining: u64, //samples
+}
+
+impl BwfReader {
+    pub fn open(path: &str) -> Result<BwfReader> {
+        let mut file = File::open(path)?;
+        let file_len = file.metadata()?.len();
+        let mut layout = read_layout(&mut file)?;
+        //a file that was cut short only holds what made it to disk
+        let on_disk = file_len.saturating_sub(layout.data_offset);
+        layout.data_len = std::cmp::min(layout.data_len, on_disk);
+
+        let mut reader = BufReader::new(file);
+        reader.seek(SeekFrom::Start(layout.data_offset))?;
+        let bytes_per_sample = layout.spec.bits_per_sample.div_ceil(8);
+        Ok(BwfReader {
+            reader: reader,
+            remaining: layout.data_len / bytes_per_sample as u64,
+            bytes_per_sample: bytes_per_sample,
+            layout: layout,
+        })
+    }
+
+    pub fn spec(&self) -> WavSpec {
+        self.layout.spec
+    }
+
+    //in frames
+    pub fn duration(&self) -> u64 {
+        self.layout.data_len / self.layout.block_align
+    }
+
+    pub fn seek(&mut self, frame: u64) -> Result<()> {
+        let frame = std::cmp::min(frame, self.duration());
+        let offset = frame * self.layout.block_align;
+        self.reader
+            .seek(SeekFrom::Start(self.layout.data_offset + offset))?;
+        self.remaining = (self.layout.data_len - offset) / self.bytes_per_sample as u64;
+        Ok(())
+    }
+
+    //None at the end of the data
+    pub fn read_sample<S: hound::Sample>(&mut self) -> Option<Result<S>> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        let spec = self.layout.spec;
+        let sample = S::read(
+            &mut self.reader,
+            spec.sample_format,
+            self.bytes_per_sample,
+            spec.bits_per_sample,
+        );
+        Some(sample.map_err(|e| e.into()))
+    }
+}
+
+// Where the parts of a wav file are, lengths as found in the header.
+pub struct WavLayout {
+    pub spec: WavSpec,
+    pub block_align: u64,
+    pub riff_len: u64,
+    pub data_offset: u64,
+    pub data_len: u64,
+    pub ds64: bool, //a ds64 chunk, or the JUNK chunk reserved for it, follows the RIFF header
+}
+
+pub fn read_layout<R: Read + Seek>(reader: &mut R) -> Result<WavLayout> {
+    let mut riff = [0u8; 12];
+    reader.seek(SeekFrom::Start(0))?;
+    read_header_bytes(reader, &mut riff)?;
+    let rf64 = match &riff[0..4] {
+        b"RIFF" => false,
+        b"RF64" => true,
+        _ => return Err(format_error("not a RIFF WAVE file")),
+    };
+    if &riff[8..12] != b"WAVE" {
+        return Err(format_error("not a RIFF WAVE file"));
+    }
+
+    let mut riff_len = le_u32(&riff[4..8]) as u64;
+    let mut ds64_data_len = None;
+    let mut ds64 = false;
+    let mut spec = None;
+    let mut block_align = 0;
+    let mut pos = 12u64;
+    loop {
+        let mut header = [0u8; 8];
+        reader.seek(SeekFrom::Start(pos))?;
+        if read_header_bytes(reader, &mut header).is_err() {
+            return Err(format_error("no data chunk"));
+        }
+        let chunk_len = le_u32(&header[4..8]) as u64;
+        match &header[0..4] {
+            b"ds64" if pos == 12 && rf64 => {
+                let mut lens = [0u8; 16];
+                read_header_bytes(reader, &mut lens)?;
+                riff_len = le_u64(&lens[0..8]);
+                ds64_data_len = Some(le_u64(&lens[8..16]));
+                ds64 = true;
+            }
+            b"JUNK" if pos == 12 && chunk_len >= DS64_LEN as u64 => ds64 = true,
+            b"fmt " => {
+                let (fmt_spec, fmt_block_align) = read_fmt(reader, chunk_len)?;
+                spec = Some(fmt_spec);
+                block_align = fmt_block_align;
+            }
+            b"data" => {
+                let data_len = match (chunk_len as u32 == RF64_LEN, ds64_data_len) {
+                    (true, Some(len)) => len,
+                    _ => chunk_len,
+                };
+                let spec = spec.ok_or(format_error("no fmt chunk before the data chunk"))?;
+                return Ok(WavLayout {
+                    spec: spec,
+                    block_align: block_align,
+                    riff_len: riff_len,
+                    data_offset: pos + 8,
+                    data_len: data_len,
+                    ds64: ds64,
+                });
+            }
+            _ => (),
+        }
+        //chunks are padded to an even length
+        pos += 8 + chunk_len + (chunk_len & 1);
+    }
+}
+
+//Writes the header lengths for data_len bytes of samples. The file becomes RF64 when it
+//outgrows RIFF, which needs the reserved JUNK chunk at offset 12. The RIFF length counts
+//the pad byte of an odd length data chunk, the data chunk length does not.
+pub fn write_lengths<W: Write + Seek>(
+    writer: &mut W,
+    data_offset: u64,
+    data_len: u64,
+    block_align: u64,
+) -> io::Result<()> {
+    let riff_len = data_offset - 8 + data_len + (data_len & 1);
+    if riff_len <= MAX_RIFF_LEN {
+        writer.seek(SeekFrom::Start(0))?;
+        writer.write_all(b"RIFF")?;
+        writer.write_all(&(riff_len as u32).to_le_bytes())?;
+        writer.seek(SeekFrom::Start(data_offset - 4))?;
+        writer.write_all(&(data_len as u32).to_le_bytes())?;
+        return Ok(());
+    }
+
+    writer.seek(SeekFrom::Start(0))?;
+    writer.write_all(b"RF64")?;
+    writer.write_all(&RF64_LEN.to_le_bytes())?;
+    writer.seek(SeekFrom::Start(12))?;
+    writer.write_all(b"ds64")?;
+    writer.write_all(&DS64_LEN.to_le_bytes())?;
+    writer.write_all(&riff_len.to_le_bytes())?;
+    writer.write_all(&data_len.to_le_bytes())?;
+    writer.write_all(&(data_len / block_align).to_le_bytes())?;
+    writer.write_all(&0u32.to_le_bytes())?;
+    writer.seek(SeekFrom::Start(data_offset - 4))?;
+    writer.write_all(&RF64_LEN.to_le_bytes())?;
+    Ok(())
+}
+
+//Most bytes of data a file without a ds64 chunk can hold, even so that a pad byte fits
+pub fn max_riff_data_len(data_offset: u64) -> u64 {
+    (MAX_RIFF_LEN - (data_offset - 8)) & !1
+}
+
+fn header_bytes(spec: &WavSpec, bext: &BextInfo) -> Vec<u8> {
+    let bytes_per_sample = spec.bits_per_sample.div_ceil(8);
+    let block_align = bytes_per_sample * spec.channels;
+    let format_tag: u16 = match spec.sample_format {
+        SampleFormat::Int => 1,
+        SampleFormat::Float => 3,
+    };
+
+    let mut header = Vec::<u8>::new();
+    header.extend(b"RIFF");
+    header.extend(&0u32.to_le_bytes());
+    header.extend(b"WAVE");
+    header.extend(b"JUNK");
+    header.extend(&DS64_LEN.to_le_bytes());
+    header.extend(&[0u8; DS64_LEN as usize]);
+    header.extend(b"bext");
+    header.extend(&BEXT_LEN.to_le_bytes());
+    header.extend(bext.to_bytes());
+    header.extend(b"fmt ");
+    header.extend(&16u32.to_le_bytes());
+    header.extend(&format_tag.to_le_bytes());
+    header.extend(&spec.channels.to_le_bytes());
+    header.extend(&spec.sample_rate.to_le_bytes());
+    header.extend(&(spec.sample_rate * block_align as u32).to_le_bytes());
+    header.extend(&block_align.to_le_bytes());
+    header.extend(&spec.bits_per_sample.to_le_bytes());
+    header.extend(b"data");
+    header.extend(&0u32.to_le_bytes());
+    header
+}
+
+//(spec, block align) of a fmt chunk, WAVE_FORMAT_EXTENSIBLE takes the format of its sub format
+fn read_fmt<R: Read>(reader: &mut R, chunk_len: u64) -> Result<(WavSpec, u64)> {
+    if chunk_len < 16 {
+        return Err(format_error("fmt chunk is too short"));
+    }
+    let mut fmt = vec![0u8; std::cmp::min(chunk_len, 40) as usize];
+    read_header_bytes(reader, &mut fmt)?;
+    let mut format_tag = u16::from_le_bytes([fmt[0], fmt[1]]);
+    if format_tag == 0xFFFE && fmt.len() >= 26 {
+        format_tag = u16::from_le_bytes([fmt[24], fmt[25]]);
+    }
+    let sample_format = match format_tag {
+        1 => SampleFormat::Int,
+        3 => SampleFormat::Float,
+        _ => return Err(format_error("only PCM and IEEE float wavs are supported")),
+    };
+    let block_align = u16::from_le_bytes([fmt[12], fmt[13]]) as u64;
+    if block_align == 0 {
+        return Err(format_error("block align of 0"));
+    }
+    let spec = WavSpec {
+        channels: u16::from_le_bytes([fmt[2], fmt[3]]),
+        sample_rate: le_u32(&fmt[4..8]),
+        bits_per_sample: u16::from_le_bytes([fmt[14], fmt[15]]),
+        sample_format: sample_format,
+    };
+    Ok((spec, block_align))
+}
+
+fn read_header_bytes<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<()> {
+    reader
+        .read_exact(buf)
+        .map_err(|_| format_error("file ends within the header"))
+}
+
+//Basic SMPTE 330M UMID with a random material number, the extended part stays empty
+fn new_umid(seed: u128) -> [u8; 64] {
+    let mut umid = [0u8; 64];
+    //universal label, material type not identified, UUID/UL material number
+    umid[..12].copy_from_slice(&[
+        0x06, 0x0A, 0x2B, 0x34, 0x01, 0x01, 0x01, 0x05, 0x01, 0x01, 0x0F, 0x20,
+    ]);
+    umid[12] = 0x13; //length of the rest of the basic UMID, instance number stays 0
+    for (idx, part) in umid[16..32].chunks_mut(8).enumerate() {
+        //RandomState is seeded randomly per process
+        let mut hasher = RandomState::new().build_hasher();
+        (seed, idx, std::process::id()).hash(&mut hasher);
+        part.copy_from_slice(&hasher.finish().to_le_bytes());
+    }
+    umid
+}
+
+//(yyyy-mm-dd, hh:mm:ss) of a unix time, days to date after
+//http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+fn utc_date_time(unix_secs: u64) -> (String, String) {
+    let days = (unix_secs / 86_400) as i64 + 719_468;
+    let secs = unix_secs % 86_400;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (
+        format!("{:04}-{:02}-{:02}", year, month, day),
+        format!("{:02}:{:02}:{:02}", secs / 3_600, secs / 60 % 60, secs % 60),
+    )
+}
+
+//ASCII field of a fixed length, padded with 0
+fn push_str(bytes: &mut Vec<u8>, text: &str, len: usize) {
+    let mut field: Vec<u8> = text.bytes().filter(|b| b.is_ascii()).take(len).collect();
+    field.resize(len, 0);
+    bytes.extend(field);
+}
+
+fn le_u32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+fn le_u64(bytes: &[u8]) -> u64 {
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(&bytes[..8]);
+    u64::from_le_bytes(buf)
+}
+
+pub fn format_error(msg: &'static str) -> RecorderError {
+    RecorderError::Wav(hound::Error::FormatError(msg))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> String {
+        let path =
+            std::env::temp_dir().join(format!("example2-wav-{}-{}.wav", name, std::process::id()));
+        path.to_str().unwrap().to_string()
+    }
+
+    fn spec(channels: u16, bits_per_sample: u16) -> WavSpec {
+        WavSpec {
+            channels: channels,
+            sample_rate: 48_000,
+            bits_per_sample: bits_per_sample,
+            sample_format: SampleFormat::Int,
+        }
+    }
+
+    fn bext() -> BextInfo {
+        let mut umid = [0u8; 64];
+        for (idx, byte) in umid.iter_mut().enumerate() {
+            *byte = idx as u8 + 1;
+        }
+        BextInfo {
+            description: "Take 1".to_string(),
+            originator: ORIGINATOR.to_string(),
+            originator_reference: "0123456789ABCDEF".to_string(),
+            origination_date: "2021-03-04".to_string(),
+            origination_time: "05:06:07".to_string(),
+            time_reference: 0x1_2345_6789,
+            umid: umid,
+        }
+    }
+
+    //Field of a fixed length, cut at the first 0
+    fn field(bytes: &[u8]) -> &str {
+        let len = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+        std::str::from_utf8(&bytes[..len]).unwrap()
+    }
+
+    fn read_samples(path: &str) -> Vec<i32> {
+        let mut reader = BwfReader::open(path).unwrap();
+        let mut samples = Vec::<i32>::new();
+        while let Some(sample) = reader.read_sample::<i32>() {
+            samples.push(sample.unwrap());
+        }
+        samples
+    }
+
+    #[test]
+    fn writes_the_bext_chunk() {
+        let path = temp_path("bext");
+        let mut writer = BwfWriter::create(&path, spec(2, 16), &bext()).unwrap();
+        for sample in 0..8i32 {
+            writer.write_sample(sample as i16).unwrap();
+        }
+        writer.finalize().unwrap();
+
+        let bytes = std::fs::read(&path).unwrap();
+        assert_eq!(&bytes[0..4], b"RIFF");
+        assert_eq!(le_u32(&bytes[4..8]) as usize, bytes.len() - 8);
+        assert_eq!(&bytes[12..16], b"JUNK");
+        assert_eq!(le_u32(&bytes[16..20]), DS64_LEN);
+        assert_eq!(&bytes[48..52], b"bext");
+        assert_eq!(le_u32(&bytes[52..56]), BEXT_LEN);
+        let chunk = &bytes[56..56 + BEXT_LEN as usize];
+        assert_eq!(field(&chunk[0..256]), "Take 1");
+        assert_eq!(field(&chunk[256..288]), ORIGINATOR);
+        assert_eq!(field(&chunk[288..320]), "0123456789ABCDEF");
+        assert_eq!(field(&chunk[320..330]), "2021-03-04");
+        assert_eq!(field(&chunk[330..338]), "05:06:07");
+        assert_eq!(le_u32(&chunk[338..342]), 0x2345_6789);
+        assert_eq!(le_u32(&chunk[342..346]), 1);
+        assert_eq!(u16::from_le_bytes([chunk[346], chunk[347]]), 1);
+        assert_eq!(&chunk[348..412], &bext().umid[..]);
+        assert!(chunk[412..].iter().all(|b| *b == 0));
+        assert_eq!(&bytes[658..662], b"fmt ");
+
+        let layout = read_layout(&mut File::open(&path).unwrap()).unwrap();
+        assert_eq!(layout.data_offset, 690);
+        assert_eq!(layout.data_len, 16);
+        assert_eq!(read_samples(&path), (0..8).collect::<Vec<i32>>());
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn pads_odd_length_data_chunks() {
+        let path = temp_path("pad");
+        let mut writer = BwfWriter::create(&path, spec(1, 24), &bext()).unwrap();
+        for sample in [1i32, -2, 3] {
+            writer.write_sample(sample).unwrap();
+        }
+        writer.finalize().unwrap();
+
+        let bytes = std::fs::read(&path).unwrap();
+        assert_eq!(bytes.len(), 690 + 9 + 1);
+        assert_eq!(bytes[bytes.len() - 1], 0);
+        assert_eq!(le_u32(&bytes[4..8]) as usize, bytes.len() - 8);
+        assert_eq!(le_u32(&bytes[686..690]), 9);
+        assert_eq!(BwfReader::open(&path).unwrap().duration(), 3);
+        assert_eq!(read_samples(&path), vec![1, -2, 3]);
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn switches_to_rf64_past_the_riff_limit() {
+        let path = temp_path("rf64");
+        let mut writer = BwfWriter::create(&path, spec(2, 16), &bext()).unwrap();
+        let max_len = max_riff_data_len(690);
+        let nof_samples = (max_len / 2) as i32;
+        for sample in 0..nof_samples {
+            writer.write_sample(sample as i16).unwrap();
+        }
+        writer.flush().unwrap();
+        let layout = read_layout(&mut File::open(&path).unwrap()).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        assert_eq!(&bytes[0..4], b"RIFF");
+        assert_eq!(&bytes[12..16], b"JUNK");
+        assert_eq!(layout.data_len, max_len);
+
+        //one frame more does not fit
+        for sample in nof_samples..nof_samples + 2 {
+            writer.write_sample(sample as i16).unwrap();
+        }
+        writer.finalize().unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        let data_len = max_len + 4;
+        assert_eq!(&bytes[0..4], b"RF64");
+        assert_eq!(le_u32(&bytes[4..8]), RF64_LEN);
+        assert_eq!(&bytes[12..16], b"ds64");
+        assert_eq!(le_u32(&bytes[16..20]), DS64_LEN);
+        assert_eq!(le_u64(&bytes[20..28]) as usize, bytes.len() - 8);
+        assert_eq!(le_u64(&bytes[28..36]), data_len);
+        assert_eq!(le_u64(&bytes[36..44]), data_len / 4);
+        assert_eq!(le_u32(&bytes[44..48]), 0);
+        assert_eq!(le_u32(&bytes[686..690]), RF64_LEN);
+
+        let layout = read_layout(&mut File::open(&path).unwrap()).unwrap();
+        assert!(layout.ds64);
+        assert_eq!(layout.data_len, data_len);
+        let samples = read_samples(&path);
+        assert_eq!(samples.len() as i32, nof_samples + 2);
+        let wrapped = (0..nof_samples + 2).map(|sample| sample as i16 as i32);
+        assert!(samples.into_iter().eq(wrapped));
+        std::fs::remove_file(&path).ok();
+    }
+}
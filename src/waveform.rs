@@ -0,0 +1,225 @@
+use std::collections::HashMap;
+use std::ops::Range;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+
+use crate::error::Result;
+use crate::formats::TakeReader;
+use crate::peaks;
+
+// Waveform overviews of takes for the timeline: the lowest and highest sample of all
+// channels over blocks of frames, at PEAK_LEVELS resolutions. Level 0 has blocks of
+// WAVEFORM_BLOCK_FRAMES frames, every level above merges PEAK_LEVEL_FACTOR blocks of the
+// one below, so any range is answered from a handful of blocks whatever the zoom. Built
+// from the file for takes on disk and frame by frame for the take being recorded.
+
+pub const WAVEFORM_BLOCK_FRAMES: u64 = 256;
+pub const PEAK_LEVEL_FACTOR: u64 = 4;
+pub const PEAK_LEVELS: usize = 6;
+
+#[derive(Clone, Debug)]
+pub struct Waveform {
+    sample_rate: u32, //of the take, blocks are counted in its frames
+    nof_frames: u64,
+    levels: Vec<PeakLevel>,
+}
+
+#[derive(Clone, Debug)]
+pub struct PeakLevel {
+    block_frames: u64,
+    peaks: Vec<(f32, f32)>, //(min, max) per complete block
+    block: (f32, f32),      //of the block being filled
+}
+
+impl PeakLevel {
+    fn new(level: usize) -> PeakLevel {
+        PeakLevel {
+            block_frames: WAVEFORM_BLOCK_FRAMES * PEAK_LEVEL_FACTOR.pow(level as u32),
+            peaks: Vec::<(f32, f32)>::new(),
+            block: (0.0, 0.0),
+        }
+    }
+
+    pub fn get_peaks(&self) -> &[(f32, f32)] {
+        &self.peaks
+    }
+}
+
+impl Waveform {
+    pub fn new(sample_rate: u32) -> Waveform {
+        Waveform {
+            sample_rate: sample_rate,
+            nof_frames: 0,
+            levels: (0..PEAK_LEVELS).map(PeakLevel::new).collect(),
+        }
+    }
+
+    //A waveform read back from its levels, see peaks::read_peak_file. last_block is the
+    //(min, max) of the frames after the last complete block of level 0.
+    pub fn from_levels(
+        sample_rate: u32,
+        nof_frames: u64,
+        levels: Vec<Vec<(f32, f32)>>,
+        last_block: (f32, f32),
+    ) -> Waveform {
+        let mut waveform = Waveform::new(sample_rate);
+        waveform.nof_frames = nof_frames;
+        for (level, peaks) in waveform.levels.iter_mut().zip(levels) {
+            level.peaks = peaks;
+        }
+        waveform.levels[0].block = last_block;
+        waveform
+    }
+
+    pub fn push_frame<T: cpal::Sample>(&mut self, frame: &[T]) {
+        let block = &mut self.levels[0].block;
+        for sample in frame.iter() {
+            let sample = sample.to_f32();
+            *block = (block.0.min(sample), block.1.max(sample));
+        }
+        self.nof_frames += 1;
+        if !self.nof_frames.is_multiple_of(WAVEFORM_BLOCK_FRAMES) {
+            return;
+        }
+        //a complete block goes into the levels above until one of them is not complete
+        for idx in 0..self.levels.len() {
+            let level = &mut self.levels[idx];
+            let complete = level.block;
+            level.peaks.push(complete);
+            level.block = (0.0, 0.0);
+            let nof_peaks = level.peaks.len() as u64;
+            match self.levels.get_mut(idx + 1) {
+                Some(above) => {
+                    above.block = (above.block.0.min(complete.0), above.block.1.max(complete.1));
+                }
+                None => break,
+            }
+            if !nof_peaks.is_multiple_of(PEAK_LEVEL_FACTOR) {
+                break;
+            }
+        }
+    }
+
+    pub fn get_sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    //frames pushed so far
+    pub fn duration(&self) -> u64 {
+        self.nof_frames
+    }
+
+    pub fn get_levels(&self) -> &[PeakLevel] {
+        &self.levels
+    }
+
+    //(min, max) of the frames after the last complete block of level 0
+    pub fn get_last_block(&self) -> (f32, f32) {
+        self.levels[0].block
+    }
+
+    //(min, max) over frames (in the take's frames), from the coarsest level whose blocks
+    //are not longer than the range. None past the end.
+    pub fn get_peak(&self, frames: Range<u64>) -> Option<(f32, f32)> {
+        let end = std::cmp::min(frames.end, self.nof_frames);
+        if frames.start >= end {
+            return None;
+        }
+        let len = end - frames.start;
+        let level = self
+            .levels
+            .iter()
+            .rposition(|l| l.block_frames <= len)
+            .unwrap_or(0);
+        self.peak_at(level, frames.start..end)
+    }
+
+    //The blocks of level that frames touch, the part after its complete blocks from the
+    //levels below
+    fn peak_at(&self, level: usize, frames: Range<u64>) -> Option<(f32, f32)> {
+        let peaks = &self.levels[level];
+        let complete = peaks.peaks.len() as u64 * peaks.block_frames;
+        let mut peak: Option<(f32, f32)> = None;
+        let mut merge = |p: (f32, f32)| {
+            peak = Some(match peak {
+                Some(m) => (m.0.min(p.0), m.1.max(p.1)),
+                None => p,
+            });
+        };
+        if frames.start < complete {
+            let first = (frames.start / peaks.block_frames) as usize;
+            let last = ((std::cmp::min(frames.end, complete) - 1) / peaks.block_frames) as usize;
+            for block in peaks.peaks[first..=last].iter() {
+                merge(*block);
+            }
+        }
+        if frames.end > complete {
+            let tail = std::cmp::max(frames.start, complete)..frames.end;
+            let tail_peak = match level {
+                0 => Some(peaks.block),
+                _ => self.peak_at(level - 1, tail),
+            };
+            if let Some(p) = tail_peak {
+                merge(p);
+            }
+        }
+        peak
+    }
+}
+
+//Waveform of an audio file read from start to end
+pub fn read_waveform(path: &str) -> Result<Waveform> {
+    let mut reader = TakeReader::open(path)?;
+    let mut waveform = Waveform::new(reader.get_sample_rate());
+    let mut frame = Vec::<f32>::with_capacity(reader.get_nof_channels() as usize);
+    while reader.read_frame::<f32>(&mut frame)? {
+        waveform.push_frame(&frame);
+    }
+    Ok(waveform)
+}
+
+// Waveforms of take files, loaded in the background the first time they are asked for so
+// drawing never waits for the disk. See peaks::load_waveform.
+pub struct WaveformCache {
+    waveforms: HashMap<String, Option<Waveform>>, //None while reading or if it failed
+    loaded_tx: Sender<(String, Result<Waveform>)>,
+    loaded_rx: Receiver<(String, Result<Waveform>)>,
+}
+
+impl WaveformCache {
+    pub fn new() -> WaveformCache {
+        let (loaded_tx, loaded_rx) = mpsc::channel();
+        WaveformCache {
+            waveforms: HashMap::new(),
+            loaded_tx: loaded_tx,
+            loaded_rx: loaded_rx,
+        }
+    }
+
+    //The waveform of file once it is read
+    pub fn get(&mut self, file: &str) -> Option<&Waveform> {
+        while let Ok((loaded, result)) = self.loaded_rx.try_recv() {
+            match result {
+                Ok(waveform) => {
+                    self.waveforms.insert(loaded, Some(waveform));
+                }
+                Err(e) => eprintln!("WaveformCache: could not read {}: {}", loaded, e),
+            }
+        }
+        if !self.waveforms.contains_key(file) {
+            self.waveforms.insert(file.to_string(), None);
+            let (file, loaded_tx) = (file.to_string(), self.loaded_tx.clone());
+            thread::spawn(move || {
+                let result = peaks::load_waveform(&file);
+                loaded_tx.send((file, result)).ok();
+            });
+        }
+        self.waveforms.get(file).and_then(|w| w.as_ref())
+    }
+
+    //Forgets the waveforms of files that are not in files, a take that is recorded again
+    //under the same name is read again
+    pub fn retain(&mut self, files: &[String]) {
+        self.waveforms.retain(|file, _| files.contains(file));
+    }
+}